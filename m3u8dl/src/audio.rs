@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use url::Url;
+
+use crate::exit::ExitKind;
+use crate::logging::status;
+
+/// Durations further apart than this (in seconds) trigger a mismatch warning.
+const DURATION_TOLERANCE: f64 = 1.0;
+
+/// An external audio track, ready to be muxed in.
+#[derive(Debug, Clone)]
+pub struct AudioTrack {
+    pub path: PathBuf,
+    /// ISO 639 language code to label the stream with.
+    pub language: Option<String>,
+}
+
+/// The audio streams that go into the output.
+#[derive(Debug, Clone, Default)]
+pub struct AudioMix {
+    /// External tracks, in the order they were given.
+    pub tracks: Vec<AudioTrack>,
+    /// Keep the stream's own audio after the external tracks instead of replacing it.
+    pub keep_original: bool,
+}
+
+impl AudioMix {
+    /// Add the external tracks as inputs after the segments (input 0), with
+    /// `-map` arguments that take the video from the segments and the audio
+    /// from each track in turn. Each track is taken to hold one audio stream.
+    pub fn add_to(&self, command: &mut Command) {
+        if self.tracks.is_empty() {
+            return;
+        }
+        for track in &self.tracks {
+            command.arg("-i").arg(&track.path);
+        }
+        command.arg("-map").arg("0:v");
+        for (index, track) in self.tracks.iter().enumerate() {
+            command.arg("-map").arg(format!("{}:a", index + 1));
+            if let Some(language) = &track.language {
+                command
+                    .arg(format!("-metadata:s:a:{}", index))
+                    .arg(format!("language={}", language));
+            }
+        }
+        if self.keep_original {
+            command.arg("-map").arg("0:a?");
+        }
+    }
+}
+
+/// Split an optional language label off an `--external-audio` value, as in
+/// `eng=https://example.com/audio.aac`.
+pub fn split_language(source: &str) -> (Option<&str>, &str) {
+    match source.split_once('=') {
+        Some((language, rest))
+            if (2..=3).contains(&language.len())
+                && language.chars().all(|c| c.is_ascii_alphabetic()) =>
+        {
+            (Some(language), rest)
+        }
+        _ => (None, source),
+    }
+}
+
+/// Make an external audio track available locally: URLs are downloaded into
+/// `output_folder/audio` (prefixed with the track's position, so that tracks
+/// with the same file name don't clash), anything else is treated as a local
+/// file path.
+pub async fn fetch_external_audio(
+    client: &Client,
+    source: &str,
+    track: usize,
+    output_folder: &str,
+) -> Result<PathBuf> {
+    let url = match Url::parse(source) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => {
+            let path = PathBuf::from(source);
+            if !path.is_file() {
+                return Err(anyhow::anyhow!(
+                    "External audio file {} does not exist",
+                    path.display()
+                )
+                .context(ExitKind::Usage));
+            }
+            return Ok(path);
+        }
+    };
+
+    let filename = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("external_audio");
+    let audio_folder = Path::new(output_folder).join("audio");
+    fs::create_dir_all(&audio_folder)?;
+    let output_path = audio_folder.join(format!("{}-{}", track + 1, filename));
+
+    let audio_content = client
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await
+        .context("Failed to download external audio")?;
+    fs::write(&output_path, audio_content).context("Failed to write external audio to file")?;
+
+    status!("Downloaded external audio from {}", url);
+    Ok(output_path)
+}
+
+/// Warn when the external audio's length doesn't line up with the video's,
+/// which usually means it is offset, truncated or from a different cut.
+pub fn check_duration(audio: &Path, video_duration: f64) {
+    let Some(audio_duration) = media_duration(audio) else {
+        tracing::debug!("Could not probe the duration of {}", audio.display());
+        return;
+    };
+
+    if (audio_duration - video_duration).abs() > DURATION_TOLERANCE {
+        status!(
+            "Warning: external audio is {:.1}s long but the video is {:.1}s; \
+             the tracks may be offset or truncated.",
+            audio_duration,
+            video_duration
+        );
+    }
+}
+
+/// Container duration in seconds as reported by ffprobe.
+pub fn media_duration(path: &Path) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "csv=p=0",
+        ])
+        .arg(path)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
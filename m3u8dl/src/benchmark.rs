@@ -0,0 +1,152 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt};
+use reqwest::Client;
+use url::Url;
+
+use crate::exit::ExitKind;
+use crate::http;
+use crate::playlist;
+use crate::progress::Phases;
+
+/// Concurrency levels tried, in order, during a benchmark run.
+const CONCURRENCY_LEVELS: [usize; 6] = [1, 2, 4, 8, 16, 32];
+
+/// Upper bound on the number of segments fetched per concurrency level.
+const SAMPLE_SEGMENTS: usize = 64;
+
+struct RoundStats {
+    concurrency: usize,
+    segments: usize,
+    bytes: u64,
+    elapsed: Duration,
+    latencies: Vec<Duration>,
+}
+
+impl RoundStats {
+    fn segments_per_sec(&self) -> f64 {
+        self.segments as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn megabytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / 1_000_000.0 / self.elapsed.as_secs_f64()
+    }
+
+    /// Nearest-rank percentile over the (sorted) per-segment latencies, in milliseconds.
+    fn latency_ms(&self, percentile: f64) -> u128 {
+        let rank = ((percentile / 100.0) * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.saturating_sub(1)].as_millis()
+    }
+}
+
+/// Download a sample of the playlist's segments into memory at several
+/// concurrency levels and report the throughput of each.
+pub async fn run(m3u8_url: &str, base_url: Option<&Url>, client: Client) -> Result<()> {
+    let client = Arc::new(client);
+
+    let phases = Phases::show();
+    let segments = playlist::fetch_segments(
+        &client,
+        m3u8_url,
+        base_url,
+        Default::default(),
+        false,
+        &phases,
+    )
+    .await
+    .context(ExitKind::Playlist)?
+    .segments;
+    anyhow::ensure!(
+        !segments.is_empty(),
+        "Playlist contains no segments to benchmark"
+    );
+
+    let sample: Vec<Url> = segments
+        .into_iter()
+        .take(SAMPLE_SEGMENTS)
+        .map(|segment| segment.url())
+        .collect();
+    println!(
+        "Benchmarking with {} segments per round (nothing is written to disk).",
+        sample.len()
+    );
+    println!(
+        "{:>12} {:>12} {:>10} {:>9} {:>9} {:>9}",
+        "concurrency", "segments/s", "MB/s", "p50 ms", "p90 ms", "p99 ms"
+    );
+
+    let mut rounds = Vec::new();
+    for concurrency in CONCURRENCY_LEVELS {
+        let round = run_round(&client, &sample, concurrency).await?;
+        println!(
+            "{:>12} {:>12.2} {:>10.2} {:>9} {:>9} {:>9}",
+            round.concurrency,
+            round.segments_per_sec(),
+            round.megabytes_per_sec(),
+            round.latency_ms(50.0),
+            round.latency_ms(90.0),
+            round.latency_ms(99.0)
+        );
+        rounds.push(round);
+    }
+
+    if let Some(best) = rounds
+        .iter()
+        .max_by(|a, b| a.megabytes_per_sec().total_cmp(&b.megabytes_per_sec()))
+    {
+        println!(
+            "Best throughput at --concurrency {} ({:.2} MB/s).",
+            best.concurrency,
+            best.megabytes_per_sec()
+        );
+    }
+    println!("Responses: {}.", http::protocol_summary());
+    println!("HTTP status codes: {}.", http::status_summary());
+
+    Ok(())
+}
+
+async fn run_round(
+    client: &Arc<Client>,
+    sample: &[Url],
+    concurrency: usize,
+) -> Result<RoundStats> {
+    let started = Instant::now();
+
+    let results = stream::iter(sample.iter().cloned())
+        .map(|ts_url| {
+            let client = Arc::clone(client);
+            tokio::spawn(async move {
+                let request_started = Instant::now();
+                let response = client.get(ts_url).send().await?;
+                http::record_response(&response);
+                let response = response.error_for_status()?;
+                let body = response.bytes().await?;
+                Ok::<_, anyhow::Error>((body.len() as u64, request_started.elapsed()))
+            })
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let elapsed = started.elapsed();
+
+    let mut bytes = 0;
+    let mut latencies = Vec::with_capacity(results.len());
+    for result in results {
+        let (size, latency) = result??;
+        bytes += size;
+        latencies.push(latency);
+    }
+    latencies.sort();
+
+    Ok(RoundStats {
+        concurrency,
+        segments: latencies.len(),
+        bytes,
+        elapsed,
+        latencies,
+    })
+}
@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+
+/// Thresholds at which a run of failing segments is treated as a systemic failure.
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Trip after this many consecutive segments fail with the same class of error.
+    pub consecutive: usize,
+    /// Number of most recent segment outcomes considered for the percentage check.
+    pub window: usize,
+    /// Trip when at least this percentage of the window failed with the same class of error.
+    pub percent: f64,
+}
+
+/// Aborts a download once segment failures stop looking isolated.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    streak: Option<(String, usize)>,
+    window: VecDeque<Option<String>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            streak: None,
+            window: VecDeque::with_capacity(config.window),
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.streak = None;
+        self.push(None);
+    }
+
+    /// Record a segment that failed for good, or whose first attempt failed
+    /// and was deferred, returning a description of the systemic failure if
+    /// this failure trips the breaker.
+    pub fn record_failure(&mut self, class: &str) -> Option<String> {
+        let streak = match self.streak.take() {
+            Some((streak_class, count)) if streak_class == class => count + 1,
+            _ => 1,
+        };
+        self.streak = Some((class.to_string(), streak));
+        self.push(Some(class.to_string()));
+
+        if streak >= self.config.consecutive {
+            return Some(format!(
+                "{} consecutive segments failed with {}",
+                streak, class
+            ));
+        }
+
+        if self.config.window > 0 && self.window.len() == self.config.window {
+            let same_class = self
+                .window
+                .iter()
+                .filter(|outcome| outcome.as_deref() == Some(class))
+                .count();
+            let percent = same_class as f64 * 100.0 / self.window.len() as f64;
+            if percent >= self.config.percent {
+                return Some(format!(
+                    "{} of the last {} segments failed with {}",
+                    same_class,
+                    self.window.len(),
+                    class
+                ));
+            }
+        }
+
+        None
+    }
+
+    fn push(&mut self, outcome: Option<String>) {
+        self.window.push_back(outcome);
+        while self.window.len() > self.config.window {
+            self.window.pop_front();
+        }
+    }
+}
+
+/// Coarse category of a segment failure, used to tell systemic failures from noise.
+pub fn error_class(error: &anyhow::Error) -> String {
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(error) => match error.status() {
+            Some(status) => format!("HTTP {}", status.as_u16()),
+            None if error.is_timeout() => "timeouts".to_string(),
+            None if error.is_connect() => "connection errors".to_string(),
+            None => "network errors".to_string(),
+        },
+        None => "local errors".to_string(),
+    }
+}
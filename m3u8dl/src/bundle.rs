@@ -0,0 +1,326 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::cleanup::Cleanup;
+use crate::exit::{self, ExitKind};
+use crate::integrity;
+use crate::logging::status;
+
+/// Placeholder in the recipe for the concat list, which `merge` unpacks.
+pub const LIST: &str = "{list}";
+/// Placeholder in the recipe for the output file, which `merge` is given.
+pub const OUTPUT: &str = "{output}";
+
+/// Layout version of the bundle; `merge` refuses bundles of any other.
+const VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const FILE_LIST: &str = "file_list.txt";
+const SEGMENTS: &str = "segments";
+
+/// `manifest.json`, the first entry of a bundle: what it holds and how to
+/// mux it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    playlist: String,
+    /// Seconds of content in the bundled segments.
+    duration: f64,
+    /// In the order they are concatenated, which is also their order in the tar.
+    segments: Vec<BundledSegment>,
+    /// ffmpeg's arguments, with [`LIST`] and [`OUTPUT`] standing in for the
+    /// concat list and the output file.
+    recipe: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundledSegment {
+    /// Path within the bundle, e.g. `segments/00000.ts`.
+    file: String,
+    size: u64,
+    sha256: String,
+}
+
+/// `--bundle`: pack `files` (the segments as they would be concatenated), a
+/// concat list and a manifest with `recipe` into a tar at `path`, for
+/// [`merge`] to mux on another machine. Returns the number of segments packed.
+pub fn write(
+    path: &Path,
+    playlist_url: &Url,
+    duration: f64,
+    files: &[PathBuf],
+    recipe: Vec<String>,
+) -> Result<usize> {
+    let mut segments = Vec::new();
+    for (number, file) in files.iter().enumerate() {
+        let extension = file
+            .extension()
+            .map_or("ts".into(), |extension| extension.to_string_lossy());
+        segments.push(BundledSegment {
+            file: format!("{}/{:05}.{}", SEGMENTS, number, extension),
+            size: fs::metadata(file)?.len(),
+            sha256: integrity::file_sha256(file)
+                .with_context(|| format!("Failed to read {}", file.display()))?,
+        });
+    }
+    let manifest = Manifest {
+        version: VERSION,
+        playlist: playlist_url.to_string(),
+        duration,
+        segments,
+        recipe,
+    };
+    let list: String = manifest
+        .segments
+        .iter()
+        .map(|segment| format!("file '{}'\n", segment.file))
+        .collect();
+
+    let written = File::create(path)
+        .map_err(anyhow::Error::new)
+        .and_then(|file| {
+            let mut tar = BufWriter::new(file);
+            let json = serde_json::to_vec_pretty(&manifest)?;
+            append(&mut tar, MANIFEST, json.len() as u64, &mut json.as_slice())?;
+            append(&mut tar, FILE_LIST, list.len() as u64, &mut list.as_bytes())?;
+            for (file, segment) in files.iter().zip(&manifest.segments) {
+                append(
+                    &mut tar,
+                    &segment.file,
+                    segment.size,
+                    &mut File::open(file)?,
+                )?;
+            }
+            // Two empty blocks end the archive
+            tar.write_all(&[0; 2 * BLOCK])?;
+            tar.into_inner()
+                .map_err(io::IntoInnerError::into_error)?
+                .sync_all()?;
+            Ok(())
+        });
+    if let Err(error) = written {
+        let _ = fs::remove_file(path);
+        return Err(error.context(format!("Failed to write bundle {}", path.display())));
+    }
+    Ok(manifest.segments.len())
+}
+
+/// `merge`: unpack a bundle into `temp_dir`, checking every segment against
+/// the manifest's size and SHA-256, then run the bundle's ffmpeg recipe to
+/// make `output`. The unpacked files are removed afterwards.
+pub fn merge(bundle: &Path, output: &str, temp_dir: &str) -> Result<()> {
+    let file = File::open(bundle)
+        .with_context(|| format!("Failed to open bundle {}", bundle.display()))
+        .context(ExitKind::Usage)?;
+    let mut tar = BufReader::new(file);
+    let cleanup = Cleanup::create(temp_dir)?;
+    let dir = Path::new(temp_dir);
+
+    let manifest: Manifest = {
+        let content = read_entry(&mut tar, MANIFEST)?;
+        serde_json::from_slice(&content).context("Invalid bundle manifest")?
+    };
+    anyhow::ensure!(
+        manifest.version == VERSION,
+        "Bundle {} has layout version {}; this m3u8dl reads version {}",
+        bundle.display(),
+        manifest.version,
+        VERSION
+    );
+    let list = read_entry(&mut tar, FILE_LIST)?;
+    cleanup.expect(FILE_LIST);
+    fs::write(dir.join(FILE_LIST), list).context("Failed to write the concat list")?;
+
+    fs::create_dir_all(dir.join(SEGMENTS))?;
+    for segment in &manifest.segments {
+        // Unpacked files stay inside the segments folder
+        let contained = segment
+            .file
+            .strip_prefix("segments/")
+            .is_some_and(|name| !name.is_empty() && !name.contains(['/', '\\']) && name != "..");
+        anyhow::ensure!(
+            contained,
+            "Bundle manifest lists an invalid segment path: {}",
+            segment.file
+        );
+        let (name, size) = next_header(&mut tar)?
+            .with_context(|| format!("Bundle ends before {}; it may be truncated", segment.file))?;
+        anyhow::ensure!(
+            name == segment.file && size == segment.size,
+            "Bundle entry {} ({} bytes) doesn't match the manifest's {} ({} bytes)",
+            name,
+            size,
+            segment.file,
+            segment.size
+        );
+        cleanup.expect(&segment.file);
+        let mut unpacked = BufWriter::new(File::create(dir.join(&segment.file))?);
+        let mut entry = (&mut tar).take(size);
+        let sha256 = integrity::copy_sha256(&mut entry, &mut unpacked)?;
+        anyhow::ensure!(entry.limit() == 0, "Bundle ends inside {}", segment.file);
+        unpacked.flush()?;
+        skip_padding(&mut tar, size)?;
+        anyhow::ensure!(
+            sha256 == segment.sha256,
+            "{} in bundle {} is corrupt: expected SHA-256 {}, got {}",
+            segment.file,
+            bundle.display(),
+            segment.sha256,
+            sha256
+        );
+    }
+    status!(
+        "Unpacked and verified {} segments ({:.1}s of {}).",
+        manifest.segments.len(),
+        manifest.duration,
+        manifest.playlist
+    );
+
+    let list_path = dir.join(FILE_LIST);
+    let args: Vec<&std::ffi::OsStr> = manifest
+        .recipe
+        .iter()
+        .map(|arg| match arg.as_str() {
+            LIST => list_path.as_os_str(),
+            OUTPUT => output.as_ref(),
+            arg => arg.as_ref(),
+        })
+        .collect();
+    let mut command = Command::new("ffmpeg");
+    command.args(args);
+    tracing::debug!("Running {:?}", command);
+    let result = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|error| exit::spawn_error(error, "ffmpeg command"))?;
+    if !result.status.success() {
+        cleanup.keep();
+        return Err(anyhow::anyhow!(
+            "Error executing ffmpeg command: {}",
+            String::from_utf8_lossy(&result.stderr)
+        )
+        .context(ExitKind::Ffmpeg));
+    }
+    status!("Successfully created {}", output);
+    Ok(())
+}
+
+const BLOCK: usize = 512;
+
+/// Append a regular file entry of `size` bytes read from `content`.
+fn append(tar: &mut impl Write, name: &str, size: u64, content: &mut impl Read) -> Result<()> {
+    tar.write_all(&header(name, size)?)?;
+    let copied = io::copy(&mut content.take(size), tar)?;
+    anyhow::ensure!(copied == size, "{} changed size while being bundled", name);
+    let padding = padding(size);
+    tar.write_all(&[0; BLOCK][..padding])?;
+    Ok(())
+}
+
+/// A ustar header for a regular file.
+fn header(name: &str, size: u64) -> Result<[u8; BLOCK]> {
+    anyhow::ensure!(name.len() < 100, "Bundle entry name {} is too long", name);
+    anyhow::ensure!(
+        size < 1 << 33,
+        "{} is too large to bundle (8 GiB at most)",
+        name
+    );
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is taken with its own field read as spaces
+    header[148..156].fill(b' ');
+    let checksum = checksum(&header);
+    octal(&mut header[148..155], checksum);
+    Ok(header)
+}
+
+/// Write `value` as zero-padded octal digits ending in a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+fn checksum(header: &[u8; BLOCK]) -> u64 {
+    header.iter().map(|&byte| u64::from(byte)).sum()
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+/// Read the next entry's header, returning its name and size, or `None` at
+/// the end of the archive.
+fn next_header(tar: &mut impl Read) -> Result<Option<(String, u64)>> {
+    let mut header = [0; BLOCK];
+    tar.read_exact(&mut header)
+        .context("Failed to read the bundle; it may be truncated")?;
+    if header.iter().all(|&byte| byte == 0) {
+        return Ok(None);
+    }
+
+    let field = |range: std::ops::Range<usize>| {
+        let field = &header[range];
+        let end = field
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(field.len());
+        String::from_utf8_lossy(&field[..end]).trim().to_string()
+    };
+    let number = |range: std::ops::Range<usize>| u64::from_str_radix(&field(range), 8).ok();
+    let stored = number(148..156);
+    let mut blank = header;
+    blank[148..156].fill(b' ');
+    anyhow::ensure!(
+        stored == Some(checksum(&blank)),
+        "Not a bundle, or a corrupt one: bad tar header checksum"
+    );
+    anyhow::ensure!(
+        matches!(header[156], b'0' | 0),
+        "Bundle entry {} is not a regular file",
+        field(0..100)
+    );
+    let size = number(124..136).context("Bundle entry with an invalid size")?;
+    Ok(Some((field(0..100), size)))
+}
+
+/// Read the next entry, which must be `name`, into memory.
+fn read_entry(tar: &mut impl Read, name: &str) -> Result<Vec<u8>> {
+    let (found, size) =
+        next_header(tar)?.with_context(|| format!("Bundle is empty; expected {} first", name))?;
+    anyhow::ensure!(
+        found == name,
+        "Expected {} in the bundle, found {}",
+        name,
+        found
+    );
+    let mut content = Vec::new();
+    tar.take(size).read_to_end(&mut content)?;
+    anyhow::ensure!(content.len() as u64 == size, "Bundle ends inside {}", name);
+    skip_padding(tar, size)?;
+    Ok(content)
+}
+
+fn skip_padding(tar: &mut impl Read, size: u64) -> io::Result<()> {
+    let mut padding = [0; BLOCK];
+    tar.read_exact(&mut padding[..self::padding(size)])
+}
@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, HashSet};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::exit::{self, ExitKind};
+
+/// Probed capabilities, per ffmpeg binary, so that each binary is only asked once per process.
+static PROBED: Mutex<BTreeMap<String, Arc<Capabilities>>> = Mutex::new(BTreeMap::new());
+
+/// The components an ffmpeg build was compiled with.
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    demuxers: HashSet<String>,
+    muxers: HashSet<String>,
+    encoders: HashSet<String>,
+}
+
+/// A component the run will use, and the option that needs it.
+#[derive(Debug, Clone)]
+pub struct Requirement {
+    pub kind: Component,
+    pub name: String,
+    /// What needs the component, e.g. "--compress" or "the output file".
+    pub needed_by: &'static str,
+    /// How to do without it, if there is a way.
+    pub alternative: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    Demuxer,
+    Muxer,
+    Encoder,
+}
+
+impl Requirement {
+    pub fn new(kind: Component, name: impl Into<String>, needed_by: &'static str) -> Self {
+        Self {
+            kind,
+            name: name.into(),
+            needed_by,
+            alternative: None,
+        }
+    }
+
+    pub fn or_else(mut self, alternative: &'static str) -> Self {
+        self.alternative = Some(alternative);
+        self
+    }
+}
+
+impl Capabilities {
+    /// Ask `ffmpeg` for its demuxers, muxers and encoders, reusing an earlier answer.
+    pub fn probe(ffmpeg: &str) -> Result<Arc<Self>> {
+        if let Some(capabilities) = PROBED.lock().unwrap().get(ffmpeg) {
+            return Ok(Arc::clone(capabilities));
+        }
+
+        let capabilities = Arc::new(Self {
+            demuxers: list(ffmpeg, "-demuxers")?,
+            muxers: list(ffmpeg, "-muxers")?,
+            encoders: list(ffmpeg, "-encoders")?,
+        });
+        tracing::debug!(
+            "{} has {} demuxers, {} muxers and {} encoders",
+            ffmpeg,
+            capabilities.demuxers.len(),
+            capabilities.muxers.len(),
+            capabilities.encoders.len()
+        );
+        PROBED
+            .lock()
+            .unwrap()
+            .insert(ffmpeg.to_string(), Arc::clone(&capabilities));
+        Ok(capabilities)
+    }
+
+    fn has(&self, requirement: &Requirement) -> bool {
+        let names = match requirement.kind {
+            Component::Demuxer => &self.demuxers,
+            Component::Muxer => &self.muxers,
+            Component::Encoder => &self.encoders,
+        };
+        names.contains(&requirement.name)
+    }
+
+    /// Fail, naming every missing component, unless all of `requirements` are available.
+    pub fn check(&self, ffmpeg: &str, requirements: &[Requirement]) -> Result<()> {
+        let missing: Vec<String> = requirements
+            .iter()
+            .filter(|requirement| !self.has(requirement))
+            .map(|requirement| {
+                let kind = match requirement.kind {
+                    Component::Demuxer => "demuxer",
+                    Component::Muxer => "muxer",
+                    Component::Encoder => "encoder",
+                };
+                let mut line = format!(
+                    "  the {} {} (needed by {})",
+                    requirement.name, kind, requirement.needed_by
+                );
+                if let Some(alternative) = requirement.alternative {
+                    line.push_str(&format!("; {}", alternative));
+                }
+                line
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow::anyhow!(
+            "{} is missing components this run needs:\n{}",
+            ffmpeg,
+            missing.join("\n")
+        )
+        .context(ExitKind::Ffmpeg))
+    }
+}
+
+/// Names from one of ffmpeg's component listings, such as `ffmpeg -muxers`.
+///
+/// Entries follow a line of dashes, each a column of flags and then the
+/// name, which for some demuxers is a comma-separated list (`mov,mp4,m4a`).
+fn list(ffmpeg: &str, option: &str) -> Result<HashSet<String>> {
+    let output = Command::new(ffmpeg)
+        .arg("-hide_banner")
+        .arg(option)
+        .output()
+        .map_err(|error| exit::spawn_error(error, "ffmpeg"))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffmpeg {} failed: {}",
+            option,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .context(ExitKind::Ffmpeg));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let names = stdout
+        .lines()
+        .skip_while(|line| !is_separator(line))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .flat_map(|names| names.split(','))
+        .map(str::to_string)
+        .collect();
+    Ok(names)
+}
+
+fn is_separator(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && line.chars().all(|c| c == '-')
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt};
+use reqwest::header;
+use reqwest::{Client, StatusCode};
+use url::Url;
+
+use crate::exit::ExitKind;
+use crate::http;
+use crate::logging::{self, status};
+use crate::playlist::{self, VariantPreferences};
+use crate::progress::{Phases, SegmentProgress};
+
+/// Unreachable URLs listed one by one; past this only their number is given.
+const MAX_LISTED: usize = 20;
+
+/// `--check`: fetch and parse the playlist, then probe every segment and
+/// initialization section with a HEAD request, without downloading any
+/// bodies. Servers that refuse HEAD are asked for a one-byte range instead.
+///
+/// Fails with [`ExitKind::Segments`] if any URL isn't reachable.
+pub async fn run(
+    client: &Client,
+    m3u8_url: &str,
+    base_url: Option<&Url>,
+    preferences: VariantPreferences<'_>,
+    scan_page: bool,
+    concurrency: usize,
+) -> Result<()> {
+    let phases = Phases::show();
+    let playlist =
+        playlist::fetch_segments(client, m3u8_url, base_url, preferences, scan_page, &phases)
+            .await
+            .context(ExitKind::Playlist)?;
+    phases.finish();
+
+    let urls: Vec<(&str, Url)> = playlist
+        .init_sections
+        .iter()
+        .map(|init| ("init section", init.url.clone()))
+        .chain(
+            playlist
+                .segments
+                .iter()
+                .map(|segment| ("segment", segment.url())),
+        )
+        .collect();
+    status!(
+        "Checking {} segments and {} initialization sections of {}.",
+        playlist.segments.len(),
+        playlist.init_sections.len(),
+        playlist.url
+    );
+
+    let progress = SegmentProgress::new(urls.len());
+    logging::show_progress(Some(progress.bar().clone()));
+    let results: Vec<_> = stream::iter(&urls)
+        .map(|(kind, url)| {
+            let progress = &progress;
+            async move {
+                let reached = probe(client, url).await;
+                progress.finish_segment(None);
+                (kind, url, reached)
+            }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await;
+    logging::show_progress(None);
+    progress.bar().finish_and_clear();
+
+    let mut statuses = BTreeMap::new();
+    let mut unreachable = Vec::new();
+    for (kind, url, reached) in results {
+        let outcome = match &reached {
+            Ok(status) => status.as_u16().to_string(),
+            Err(_) => "no response".to_string(),
+        };
+        *statuses.entry(outcome).or_insert(0) += 1;
+        match reached {
+            Ok(status) if status.is_success() => {}
+            Ok(status) => unreachable.push(format!("{} {}: HTTP {}", kind, url, status)),
+            Err(error) => unreachable.push(format!("{} {}: {}", kind, url, error)),
+        }
+    }
+
+    let summary = statuses
+        .iter()
+        .map(|(outcome, count)| format!("{}: {}", outcome, count))
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!(
+        "{} of {} URLs reachable ({}).",
+        urls.len() - unreachable.len(),
+        urls.len(),
+        summary
+    );
+    if unreachable.is_empty() {
+        return Ok(());
+    }
+    for line in unreachable.iter().take(MAX_LISTED) {
+        println!("  unreachable {}", line);
+    }
+    if unreachable.len() > MAX_LISTED {
+        println!("  ... and {} more", unreachable.len() - MAX_LISTED);
+    }
+    Err(anyhow::anyhow!(
+        "{} of {} URLs are unreachable",
+        unreachable.len(),
+        urls.len()
+    )
+    .context(ExitKind::Segments))
+}
+
+/// The status `url` answers with, asking for its headers only.
+async fn probe(client: &Client, url: &Url) -> reqwest::Result<StatusCode> {
+    let response = client.head(url.clone()).send().await?;
+    http::record_response(&response);
+    if !matches!(
+        response.status(),
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+    ) {
+        return Ok(response.status());
+    }
+    // Dropping the response unread stops any body a server sends regardless
+    let response = client
+        .get(url.clone())
+        .header(header::RANGE, "bytes=0-0")
+        .send()
+        .await?;
+    http::record_response(&response);
+    Ok(response.status())
+}
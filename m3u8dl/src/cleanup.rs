@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+
+use crate::logging::status;
+
+/// Owns the run's temp folder and removes it when dropped, including on
+/// early-error paths, but only if this run created it and everything in it
+/// is a file the run is known to have written.
+pub struct Cleanup {
+    dir: PathBuf,
+    created: bool,
+    manifest: Mutex<State>,
+}
+
+struct State {
+    keep: bool,
+    /// Keep the folder unless the run finishes, for `--project`.
+    keep_until_done: bool,
+    done: bool,
+    files: HashSet<PathBuf>,
+    /// Downloaded files, each of which implies its `.part`, `.part.validator`
+    /// and `.validator`.
+    downloads: HashSet<PathBuf>,
+}
+
+/// Suffixes of the in-progress and cache files kept next to a download.
+const PARTIAL_SUFFIXES: [&str; 3] = [".part", ".part.validator", ".validator"];
+
+/// Marks a temp folder as one a run created, so that a later run resuming
+/// from it (after it was kept) may still remove it.
+const OWNER_MARKER: &str = ".m3u8dl-temp";
+
+impl State {
+    fn expects(&self, relative: &Path) -> bool {
+        if self.files.contains(relative) || self.downloads.contains(relative) {
+            return true;
+        }
+        let relative = relative.to_string_lossy();
+        PARTIAL_SUFFIXES.iter().any(|suffix| {
+            relative
+                .strip_suffix(suffix)
+                .is_some_and(|download| self.downloads.contains(Path::new(download)))
+        })
+    }
+}
+
+impl Cleanup {
+    /// Create the temp folder (if needed) and take charge of it. A folder an
+    /// earlier run created and kept counts as created too.
+    pub fn create(dir: &str) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        let marker = dir.join(OWNER_MARKER);
+        let created = !dir.exists() || marker.is_file();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create temp folder '{}'", dir.display()))?;
+        if created {
+            let note = "Created by m3u8dl, which removes it when done.\n";
+            if let Err(error) = fs::write(&marker, note) {
+                tracing::debug!("Failed to mark {} as this run's: {}", dir.display(), error);
+            }
+        }
+
+        Ok(Self {
+            dir,
+            created,
+            manifest: Mutex::new(State {
+                keep: false,
+                keep_until_done: false,
+                done: false,
+                files: HashSet::from([PathBuf::from(OWNER_MARKER)]),
+                downloads: HashSet::new(),
+            }),
+        })
+    }
+
+    /// Record a file (relative to the temp folder) that the run may write.
+    pub fn expect(&self, relative: impl Into<PathBuf>) {
+        self.manifest.lock().unwrap().files.insert(relative.into());
+    }
+
+    /// Record a file the run downloads, along with the partial files used while downloading it.
+    pub fn expect_download(&self, relative: impl Into<PathBuf>) {
+        self.manifest.lock().unwrap().downloads.insert(relative.into());
+    }
+
+    /// Leave the temp folder in place, e.g. so that a later run can reuse it.
+    pub fn keep(&self) {
+        self.manifest.lock().unwrap().keep = true;
+    }
+
+    /// Whether [`Cleanup::keep`] was called.
+    pub fn kept(&self) -> bool {
+        self.manifest.lock().unwrap().keep
+    }
+
+    /// Leave the temp folder in place if the run fails, even early on, so
+    /// that it can be continued; [`Cleanup::done`] lets it go again.
+    pub fn keep_until_done(&self) {
+        self.manifest.lock().unwrap().keep_until_done = true;
+    }
+
+    /// The run made its output, so [`Cleanup::keep_until_done`] no longer holds.
+    pub fn done(&self) {
+        self.manifest.lock().unwrap().done = true;
+    }
+
+    fn remove(&self) -> Result<()> {
+        let state = self.manifest.lock().unwrap();
+        if state.keep || (state.keep_until_done && !state.done) {
+            return Ok(());
+        }
+        if !self.created {
+            status!(
+                "Leaving temp folder '{}' in place: it existed before this run.",
+                self.dir.display()
+            );
+            return Ok(());
+        }
+
+        let mut unexpected = Vec::new();
+        find_unexpected(&self.dir, Path::new(""), &state, &mut unexpected)?;
+        if let Some(first) = unexpected.first() {
+            status!(
+                "Not removing temp folder '{}': it contains {} file(s) this run didn't write, e.g. {}.",
+                self.dir.display(),
+                unexpected.len(),
+                first.display()
+            );
+            return Ok(());
+        }
+
+        fs::remove_dir_all(&self.dir).context("Failed to remove temp folder")
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        if let Err(error) = self.remove() {
+            status!("Warning: {:#}", error);
+        }
+    }
+}
+
+/// Collect every file under `dir` whose path relative to the temp folder isn't expected.
+fn find_unexpected(
+    dir: &Path,
+    relative: &Path,
+    expected: &State,
+    unexpected: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            find_unexpected(&entry.path(), &path, expected, unexpected)?;
+        } else if !expected.expects(&path) {
+            unexpected.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path of its own under the system temp folder for the test `name`,
+    /// with nothing there yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let name = format!("m3u8dl-cleanup-{}-{}", name, std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn removes_a_folder_holding_only_expected_files() {
+        let dir = temp_path("expected");
+        let cleanup = Cleanup::create(dir.to_str().unwrap()).unwrap();
+        cleanup.expect("file_list.txt");
+        cleanup.expect(Path::new("init").join("init.mp4"));
+        cleanup.expect_download("seg0.ts");
+        fs::create_dir(dir.join("init")).unwrap();
+        for file in ["file_list.txt", "init/init.mp4", "seg0.ts", "seg0.ts.part.validator"] {
+            fs::write(dir.join(file), b"").unwrap();
+        }
+        drop(cleanup);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn leaves_a_pre_existing_folder() {
+        let dir = temp_path("pre-existing");
+        fs::create_dir_all(&dir).unwrap();
+        let cleanup = Cleanup::create(dir.to_str().unwrap()).unwrap();
+        cleanup.expect_download("seg0.ts");
+        fs::write(dir.join("seg0.ts"), b"").unwrap();
+        drop(cleanup);
+        assert!(dir.join("seg0.ts").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_a_folder_with_files_missing_from_the_manifest() {
+        let dir = temp_path("mismatch");
+        let cleanup = Cleanup::create(dir.to_str().unwrap()).unwrap();
+        cleanup.expect_download("seg0.ts");
+        fs::write(dir.join("seg0.ts"), b"").unwrap();
+        fs::write(dir.join("seg0.tsx"), b"").unwrap();
+        fs::write(dir.join("notes.txt"), b"keep me").unwrap();
+        drop(cleanup);
+        assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), b"keep me");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keep_until_done_holds_only_until_done() {
+        let dir = temp_path("until-done");
+        let cleanup = Cleanup::create(dir.to_str().unwrap()).unwrap();
+        cleanup.keep_until_done();
+        drop(cleanup);
+        assert!(dir.exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let cleanup = Cleanup::create(dir.to_str().unwrap()).unwrap();
+        cleanup.keep_until_done();
+        cleanup.done();
+        drop(cleanup);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn kept_folder_is_removed_after_the_resumed_run_succeeds() {
+        let dir = temp_path("resumed");
+        let cleanup = Cleanup::create(dir.to_str().unwrap()).unwrap();
+        cleanup.expect_download("seg0.ts");
+        fs::write(dir.join("seg0.ts"), b"").unwrap();
+        fs::write(dir.join("seg1.ts.part"), b"").unwrap();
+        // The first run fails part way and keeps what it downloaded
+        cleanup.keep();
+        drop(cleanup);
+        assert!(dir.join("seg0.ts").exists());
+
+        let cleanup = Cleanup::create(dir.to_str().unwrap()).unwrap();
+        cleanup.expect_download("seg0.ts");
+        cleanup.expect_download("seg1.ts");
+        fs::write(dir.join("seg1.ts"), b"").unwrap();
+        drop(cleanup);
+        assert!(!dir.exists());
+    }
+}
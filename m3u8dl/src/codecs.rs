@@ -0,0 +1,241 @@
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use serde::Deserialize;
+
+/// The streams of a media file, as reported by ffprobe.
+#[derive(Debug, Deserialize)]
+pub struct MediaInfo {
+    #[serde(default)]
+    pub streams: Vec<StreamInfo>,
+    /// Only there when ffprobe is asked for `-show_format`.
+    pub format: Option<FormatInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FormatInfo {
+    /// Container duration in seconds, as the decimal string ffprobe prints.
+    pub duration: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamInfo {
+    /// `video`, `audio`, `subtitle` or `data`.
+    #[serde(default)]
+    pub codec_type: String,
+    /// ffmpeg's name for the codec, e.g. `h264`, `hevc` or `ac3`.
+    #[serde(default)]
+    pub codec_name: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The frame rate as a fraction, e.g. `30000/1001`; `0/0` when unknown.
+    pub r_frame_rate: Option<String>,
+    pub avg_frame_rate: Option<String>,
+}
+
+/// Extra ffmpeg arguments, and warnings, that suit the source's codecs.
+#[derive(Debug, Default)]
+pub struct Tuning {
+    pub args: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let streams: Vec<String> = self
+            .streams
+            .iter()
+            .map(|stream| match (stream.width, stream.height) {
+                (Some(width), Some(height)) => format!(
+                    "{} {} ({}x{})",
+                    stream.codec_type, stream.codec_name, width, height
+                ),
+                _ => format!("{} {}", stream.codec_type, stream.codec_name),
+            })
+            .collect();
+        f.write_str(&streams.join(", "))
+    }
+}
+
+impl MediaInfo {
+    /// The codec of the first stream of `codec_type`, such as `video`.
+    pub fn codec(&self, codec_type: &str) -> Option<&str> {
+        self.streams
+            .iter()
+            .find(|stream| stream.codec_type == codec_type)
+            .map(|stream| stream.codec_name.as_str())
+    }
+
+    /// Frames per second of the first video stream, if ffprobe could tell.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let video = self
+            .streams
+            .iter()
+            .find(|stream| stream.codec_type == "video")?;
+        [&video.r_frame_rate, &video.avg_frame_rate]
+            .into_iter()
+            .flatten()
+            .find_map(|rate| parse_fraction(rate))
+    }
+
+    /// Adjust the remux for these codecs, given whether the output is an
+    /// MP4/MOV file and whether `--compress` re-encodes it.
+    pub fn tuning(&self, mp4_output: bool, compress: bool) -> Tuning {
+        let mut tuning = Tuning::default();
+        let video = self.codec("video");
+        let audio = self.codec("audio");
+
+        match video {
+            // Apple players only play HEVC in MP4 when it is tagged hvc1, not hev1
+            Some("hevc") if mp4_output && !compress => {
+                tuning
+                    .args
+                    .extend(["-tag:v".to_string(), "hvc1".to_string()]);
+            }
+            Some(codec @ ("mpeg2video" | "mpeg1video")) if mp4_output && !compress => {
+                tuning.warnings.push(format!(
+                    "{} video can't be copied into mp4; use --compress, or an mkv or ts output",
+                    codec
+                ));
+            }
+            _ => {}
+        }
+        // Keep AC-3 as it is rather than re-encoding it to stereo AAC
+        if let Some("ac3" | "eac3") = audio {
+            if compress {
+                tuning.args.extend(["-c:a".to_string(), "copy".to_string()]);
+            }
+        }
+        // MP4 stores AAC with one AudioSpecificConfig instead of an ADTS header
+        // on every frame. All the segments go through a single ffmpeg run, so
+        // the conversion happens exactly once, whichever --concat-method is used
+        if let Some("aac") = audio {
+            if mp4_output && !compress {
+                tuning
+                    .args
+                    .extend(["-bsf:a".to_string(), "aac_adtstoasc".to_string()]);
+            }
+        }
+
+        tuning
+    }
+}
+
+/// The streams of `path` according to ffprobe, or `None` if it can't tell.
+pub fn probe(path: &Path) -> Option<MediaInfo> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_streams", "-of", "json"])
+        .arg(path)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    serde_json::from_slice(&output.stdout).ok()
+}
+
+/// `30000/1001` or `25`, as a number; `None` for ffprobe's `0/0`.
+fn parse_fraction(value: &str) -> Option<f64> {
+    let rate = match value.split_once('/') {
+        Some((numerator, denominator)) => {
+            numerator.trim().parse::<f64>().ok()? / denominator.trim().parse::<f64>().ok()?
+        }
+        None => value.trim().parse().ok()?,
+    };
+    (rate.is_finite() && rate > 0.0).then_some(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    /// ffprobe's `-show_streams` output for one video and one audio stream.
+    fn media(video: &str, audio: &str) -> MediaInfo {
+        serde_json::from_value(serde_json::json!({
+            "streams": [
+                { "codec_type": "video", "codec_name": video, "width": 1280, "height": 720 },
+                { "codec_type": "audio", "codec_name": audio },
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn has_adtstoasc(tuning: &Tuning) -> bool {
+        tuning.args.windows(2).any(|pair| pair == ["-bsf:a", "aac_adtstoasc"])
+    }
+
+    #[test]
+    fn aac_into_mp4_gets_adtstoasc() {
+        let tuning = media("h264", "aac").tuning(true, false);
+        assert_eq!(tuning.args, ["-bsf:a", "aac_adtstoasc"]);
+        assert!(tuning.warnings.is_empty());
+    }
+
+    #[test]
+    fn other_containers_and_codecs_skip_adtstoasc() {
+        // mkv and ts keep ADTS as it is
+        assert!(!has_adtstoasc(&media("h264", "aac").tuning(false, false)));
+        // --compress re-encodes the audio, so there is no ADTS left to convert
+        assert!(!has_adtstoasc(&media("h264", "aac").tuning(true, true)));
+        for audio in ["mp3", "ac3", "eac3", "opus"] {
+            assert!(!has_adtstoasc(&media("h264", audio).tuning(true, false)), "{}", audio);
+        }
+    }
+
+    #[test]
+    fn hevc_into_mp4_is_tagged_hvc1() {
+        let tuning = media("hevc", "aac").tuning(true, false);
+        assert_eq!(tuning.args, ["-tag:v", "hvc1", "-bsf:a", "aac_adtstoasc"]);
+        assert!(media("hevc", "aac").tuning(false, false).args.is_empty());
+    }
+
+    /// Needs a real ffmpeg and ffprobe on the PATH: `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn aac_in_ts_remuxes_into_a_decodable_m4a() {
+        let folder = std::env::temp_dir().join(format!("m3u8dl-codecs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let source = folder.join("seg.ts");
+        let output = folder.join("out.m4a");
+        let ffmpeg = |args: &[&str]| {
+            let status = Command::new("ffmpeg")
+                .args(["-v", "error", "-y"])
+                .args(args)
+                .status()
+                .expect("ffmpeg is not on the PATH");
+            assert!(status.success(), "ffmpeg {:?} failed", args);
+        };
+
+        ffmpeg(&[
+            "-f", "lavfi", "-i", "sine=frequency=440:duration=1", "-c:a", "aac", "-f", "mpegts",
+            source.to_str().unwrap(),
+        ]);
+        let source_info = probe(&source).expect("ffprobe is not on the PATH");
+        assert_eq!(source_info.codec("audio"), Some("aac"));
+        let tuning = source_info.tuning(true, false);
+        assert!(has_adtstoasc(&tuning));
+
+        let mut remux = vec!["-i", source.to_str().unwrap(), "-c", "copy"];
+        remux.extend(tuning.args.iter().map(String::as_str));
+        remux.push(output.to_str().unwrap());
+        ffmpeg(&remux);
+
+        let output_info = probe(&output).expect("ffprobe couldn't read the remuxed file");
+        assert_eq!(output_info.codec("audio"), Some("aac"));
+        // Decoding the whole file fails on a stream that still carries ADTS headers
+        let decode = Command::new("ffmpeg")
+            .args(["-v", "error", "-xerror", "-i"])
+            .arg(&output)
+            .args(["-f", "null", "-"])
+            .output()
+            .unwrap();
+        assert!(decode.status.success(), "{}", String::from_utf8_lossy(&decode.stderr));
+        assert!(decode.stderr.is_empty(), "{}", String::from_utf8_lossy(&decode.stderr));
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::provenance::Provenance;
+
+/// Sidecar written next to a finished output so an identical re-run can be skipped.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompletionRecord {
+    /// The playlist URL exactly as given on the command line.
+    pub playlist_url: String,
+    /// The media playlist actually downloaded (the chosen variant, for a master playlist).
+    pub variant_url: String,
+    /// Seconds of content in the output, per the playlist's `#EXTINF` tags.
+    pub duration: f64,
+    /// SHA-256 of the output file, as lowercase hex.
+    pub output_sha256: String,
+    /// Where the output came from, as also embedded in its metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+/// `<output>.m3u8dl.json`
+pub fn record_path(output_file: &str) -> PathBuf {
+    PathBuf::from(format!("{}.m3u8dl.json", output_file))
+}
+
+/// Whether `output_file` is a finished download of `playlist_url` whose
+/// contents are unchanged since the record was written.
+pub fn is_complete(output_file: &str, playlist_url: &str) -> bool {
+    let Ok(record) = fs::read_to_string(record_path(output_file)) else {
+        return false;
+    };
+    let Ok(record) = serde_json::from_str::<CompletionRecord>(&record) else {
+        tracing::debug!("Ignoring unreadable completion record for {}", output_file);
+        return false;
+    };
+    record.playlist_url == playlist_url
+        && sha256_file(output_file).is_ok_and(|hash| hash == record.output_sha256)
+}
+
+/// Hash the finished output and write its completion record.
+pub fn write(
+    output_file: &str,
+    playlist_url: &str,
+    variant_url: &str,
+    duration: f64,
+    provenance: Option<Provenance>,
+) -> Result<()> {
+    let record = CompletionRecord {
+        playlist_url: playlist_url.to_string(),
+        variant_url: variant_url.to_string(),
+        duration,
+        output_sha256: sha256_file(output_file).context("Failed to hash output file")?,
+        provenance,
+    };
+    let path = record_path(output_file);
+    fs::write(&path, serde_json::to_string_pretty(&record)?)
+        .with_context(|| format!("Failed to write completion record {}", path.display()))
+}
+
+/// SHA-256 of the file at `path`, as lowercase hex.
+pub fn sha256_file(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread::JoinHandle;
+
+use crate::logging::status;
+
+/// How ffmpeg is given the downloaded segments to join.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConcatMethod {
+    /// The concat demuxer, reading `file_list.txt`.
+    Demuxer,
+    /// The concat protocol (`concat:a.ts|b.ts|...`), joining the files' bytes.
+    Protocol,
+    /// The files written to ffmpeg's stdin one after another.
+    Pipe,
+}
+
+/// Longest `concat:` argument passed to ffmpeg. Windows limits the whole
+/// command line to 32767 characters; Linux limits a single argument to 128 KiB.
+#[cfg(windows)]
+const MAX_PROTOCOL_ARG: usize = 30_000;
+#[cfg(not(windows))]
+const MAX_PROTOCOL_ARG: usize = 120_000;
+
+/// The segments as an ffmpeg input, for one [`ConcatMethod`].
+#[derive(Debug, Clone)]
+pub enum ConcatInput {
+    List(String),
+    Protocol(String),
+    Pipe(Vec<PathBuf>),
+}
+
+impl ConcatInput {
+    /// The input for `method`, given the concat list file and the files it lists.
+    ///
+    /// A `concat:` argument too long for the command line, or a path with a
+    /// `|` in it (the protocol's separator), falls back to piping the files.
+    pub fn new(method: ConcatMethod, list_file: &str, files: &[PathBuf]) -> Self {
+        match method {
+            ConcatMethod::Demuxer => ConcatInput::List(list_file.to_string()),
+            ConcatMethod::Protocol => {
+                let paths: Vec<String> = files
+                    .iter()
+                    .map(|file| file.to_string_lossy().into_owned())
+                    .collect();
+                let argument = format!("concat:{}", paths.join("|"));
+                if paths.iter().any(|path| path.contains('|')) {
+                    status!("A segment path contains '|', piping the segments to ffmpeg instead.");
+                } else if argument.len() > MAX_PROTOCOL_ARG {
+                    status!(
+                        "The concat: input for {} segments is too long for the command line, \
+                         piping them to ffmpeg instead.",
+                        files.len()
+                    );
+                } else {
+                    return ConcatInput::Protocol(argument);
+                }
+                ConcatInput::Pipe(files.to_vec())
+            }
+            ConcatMethod::Pipe => ConcatInput::Pipe(files.to_vec()),
+        }
+    }
+
+    /// Add the input arguments to an ffmpeg command.
+    pub fn add_to(&self, command: &mut Command) {
+        match self {
+            ConcatInput::List(list_file) => {
+                command
+                    .arg("-f")
+                    .arg("concat")
+                    .arg("-safe")
+                    .arg("0")
+                    .arg("-i")
+                    .arg(list_file);
+            }
+            ConcatInput::Protocol(argument) => {
+                command.arg("-i").arg(argument);
+            }
+            ConcatInput::Pipe(_) => {
+                command.arg("-i").arg("pipe:0");
+            }
+        }
+    }
+
+    /// What ffmpeg's stdin should be: the pipe [`ConcatInput::feed`] writes to,
+    /// or nothing, so that ffmpeg doesn't read keystrokes from the terminal.
+    pub fn stdin(&self) -> Stdio {
+        match self {
+            ConcatInput::Pipe(_) => Stdio::piped(),
+            _ => Stdio::null(),
+        }
+    }
+
+    /// For [`ConcatInput::Pipe`], write the files to the spawned ffmpeg's
+    /// stdin on a thread of their own, closing it at the end.
+    pub fn feed(&self, ffmpeg: &mut Child) -> Option<JoinHandle<io::Result<()>>> {
+        let ConcatInput::Pipe(files) = self else {
+            return None;
+        };
+        let files = files.clone();
+        let mut stdin = ffmpeg.stdin.take()?;
+        Some(std::thread::spawn(move || {
+            for file in &files {
+                io::copy(&mut File::open(file)?, &mut stdin)?;
+            }
+            Ok(())
+        }))
+    }
+}
+
+/// Wait for a [`ConcatInput::feed`] thread. A pipe closed early is left for
+/// ffmpeg's own exit status to explain.
+pub fn finish_feed(feeder: Option<JoinHandle<io::Result<()>>>) -> anyhow::Result<()> {
+    let Some(feeder) = feeder else {
+        return Ok(());
+    };
+    match feeder.join() {
+        Ok(Err(error)) if error.kind() != io::ErrorKind::BrokenPipe => {
+            Err(anyhow::Error::new(error).context("Failed to pipe the segments to ffmpeg"))
+        }
+        Ok(_) => Ok(()),
+        Err(_) => Err(anyhow::anyhow!("Segment pipe thread panicked")),
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use indicatif::HumanDuration;
+use tokio::time::Instant;
+
+use crate::exit::ExitKind;
+
+/// `--deadline`: when the run has to be over by, and how long it was given.
+/// `None` unless [`start`] was called.
+static DEADLINE: Mutex<Option<(Instant, Duration)>> = Mutex::new(None);
+/// Set once the download has stopped at the deadline to mux what it has.
+static SALVAGING: AtomicBool = AtomicBool::new(false);
+
+/// Give the run `limit` from now.
+pub fn start(limit: Duration) {
+    *DEADLINE.lock().unwrap() = Some((Instant::now() + limit, limit));
+}
+
+/// Resolves once the deadline has passed; never, without one.
+pub async fn passed() {
+    let deadline = DEADLINE.lock().unwrap().map(|(at, _)| at);
+    match deadline {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Like [`passed`], but never resolves once the download has stopped at the
+/// deadline to [`salvage`] what it has, so that the output still gets made.
+pub async fn enforce() {
+    passed().await;
+    // The download notices the deadline in the same wake-up; let it go first
+    tokio::task::yield_now().await;
+    if salvaging() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Let the run go on past the deadline to mux what was downloaded before it.
+pub fn salvage() {
+    SALVAGING.store(true, Ordering::Relaxed);
+}
+
+/// Whether the download stopped at the deadline, so the output is partial.
+pub fn salvaging() -> bool {
+    SALVAGING.load(Ordering::Relaxed)
+}
+
+/// The error for a run that ran out of time.
+pub fn exceeded() -> anyhow::Error {
+    let limit = DEADLINE
+        .lock()
+        .unwrap()
+        .map_or(Duration::ZERO, |(_, limit)| limit);
+    anyhow::anyhow!(
+        "The run didn't finish within its --deadline of {}",
+        HumanDuration(limit)
+    )
+    .context(ExitKind::Deadline)
+}
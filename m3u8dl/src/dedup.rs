@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+
+use crate::integrity;
+
+/// `--dedup`: a segment whose bytes match those of the segment next to it in
+/// the playlist becomes a hard link to it, so that a packager repeating the
+/// same segment under different URLs costs the disk space only once.
+#[derive(Default)]
+pub struct Dedup {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Content digest and file of each downloaded segment, by playlist index.
+    stored: HashMap<usize, (String, PathBuf)>,
+    merged: usize,
+    saved: u64,
+}
+
+impl Dedup {
+    /// Hash segment `index`, just downloaded to `path`, and if an adjacent
+    /// segment has the same bytes, replace it with a hard link to that one.
+    ///
+    /// The hashing runs on the blocking pool, off the download path.
+    pub async fn merge(&self, index: usize, path: &Path) -> Result<()> {
+        let hashed = path.to_path_buf();
+        let digest = tokio::task::spawn_blocking(move || integrity::file_sha256(&hashed))
+            .await?
+            .context("Failed to hash segment")?;
+
+        let existing = {
+            let mut state = self.state.lock().unwrap();
+            let adjacent = [index.checked_sub(1), index.checked_add(1)]
+                .into_iter()
+                .flatten()
+                .filter_map(|other| state.stored.get(&other))
+                .find(|(other, file)| *other == digest && file != path)
+                .map(|(_, file)| file.clone());
+            state
+                .stored
+                .entry(index)
+                .or_insert_with(|| (digest, path.to_path_buf()));
+            match adjacent {
+                Some(existing) => existing,
+                None => return Ok(()),
+            }
+        };
+
+        // Linked under the segment's partial name, so it is replaced in one step
+        let link = PathBuf::from(format!("{}.part", path.display()));
+        let _ = fs::remove_file(&link);
+        if fs::hard_link(&existing, &link).is_err() {
+            // Another file system, or links aren't supported: keep the copy
+            return Ok(());
+        }
+        let size = fs::metadata(path)?.len();
+        fs::rename(&link, path).context("Failed to replace segment with a link")?;
+        tracing::debug!(
+            "{} has the same bytes as {}, linked",
+            path.display(),
+            existing.display()
+        );
+
+        let mut state = self.state.lock().unwrap();
+        state.merged += 1;
+        state.saved += size;
+        Ok(())
+    }
+
+    /// For the end-of-run report, e.g. `Merged 3 byte-identical segments, saving 1.2 MiB.`
+    pub fn summary(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        (state.merged > 0).then(|| {
+            format!(
+                "Merged {} byte-identical segments, saving {:.1} MiB.",
+                state.merged,
+                state.saved as f64 / (1024.0 * 1024.0)
+            )
+        })
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header;
+use reqwest::Client;
+use serde::Deserialize;
+use url::Url;
+
+use crate::logging::status;
+
+/// How long one DNS-over-HTTPS query may take before it counts as failed.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Record types asked for: A and AAAA.
+const RECORD_TYPES: [(u16, &str); 2] = [(1, "A"), (28, "AAAA")];
+
+/// `--doh`: where hostnames are resolved, and whether the system resolver
+/// may step in when that fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DohOptions {
+    /// A resolver speaking the JSON API (`application/dns-json`), such as
+    /// `https://cloudflare-dns.com/dns-query` or `https://dns.google/resolve`.
+    pub endpoint: Url,
+    /// `--doh-only`: fail rather than fall back to the system resolver.
+    pub only: bool,
+}
+
+/// Resolves the hosts every request of a client connects to over
+/// DNS-over-HTTPS, for networks whose own resolver blocks or poisons CDN
+/// hostnames.
+///
+/// Addresses are kept for the rest of the run rather than for their TTL; a
+/// run is short next to how long CDNs keep theirs.
+pub struct DohResolver(Arc<Inner>);
+
+struct Inner {
+    options: DohOptions,
+    /// Queries go out on a client of their own, through the same proxy as
+    /// the rest of the run but with the system resolver, since the
+    /// resolver's own hostname has to be looked up too.
+    client: Client,
+    verbose: bool,
+    resolved: Mutex<HashMap<String, Vec<IpAddr>>>,
+}
+
+/// The JSON API's answer to one query.
+#[derive(Deserialize)]
+struct DnsResponse {
+    /// The DNS response code; 0 is NOERROR.
+    #[serde(rename = "Status")]
+    status: u16,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DnsAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+impl DohResolver {
+    /// A resolver sending its queries with `client`.
+    pub fn new(options: DohOptions, client: Client, verbose: bool) -> Self {
+        Self(Arc::new(Inner {
+            options,
+            client,
+            verbose,
+            resolved: Mutex::new(HashMap::new()),
+        }))
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let inner = Arc::clone(&self.0);
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addresses = inner.addresses(&host).await?;
+            // reqwest puts the URL's port in place of this one
+            let addrs: Addrs = Box::new(
+                addresses
+                    .into_iter()
+                    .map(|address| SocketAddr::new(address, 0)),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+impl Inner {
+    async fn addresses(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Some(addresses) = self.resolved.lock().unwrap().get(host) {
+            return Ok(addresses.clone());
+        }
+        let addresses = match self.query(host).await {
+            Ok(addresses) => {
+                self.report(format!(
+                    "Resolved {} to {} over DNS-over-HTTPS",
+                    host,
+                    list(&addresses)
+                ));
+                addresses
+            }
+            Err(error) if self.options.only => {
+                return Err(error.context(format!(
+                    "Failed to resolve {} over DNS-over-HTTPS (--doh-only)",
+                    host
+                )));
+            }
+            Err(error) => {
+                let addresses: Vec<IpAddr> = tokio::net::lookup_host((host, 0))
+                    .await
+                    .with_context(|| format!("Failed to resolve {}", host))?
+                    .map(|address| address.ip())
+                    .collect();
+                self.report(format!(
+                    "DNS-over-HTTPS failed for {} ({:#}); the system resolver gave {}",
+                    host,
+                    error,
+                    list(&addresses)
+                ));
+                addresses
+            }
+        };
+        self.resolved
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), addresses.clone());
+        Ok(addresses)
+    }
+
+    /// Ask the resolver for the host's A and AAAA records.
+    async fn query(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let mut addresses = Vec::new();
+        for (record_type, type_name) in RECORD_TYPES {
+            let mut url = self.options.endpoint.clone();
+            url.query_pairs_mut()
+                .append_pair("name", host)
+                .append_pair("type", type_name);
+            let body = self
+                .client
+                .get(url)
+                .header(header::ACCEPT, "application/dns-json")
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            let response: DnsResponse = serde_json::from_slice(&body).with_context(|| {
+                format!("{} didn't answer in the JSON API", self.options.endpoint)
+            })?;
+            anyhow::ensure!(
+                response.status == 0,
+                "the resolver answered {} for {} records (DNS response code {})",
+                response_code(response.status),
+                type_name,
+                response.status
+            );
+            // CNAMEs along the way come with the addresses they lead to
+            addresses.extend(
+                response
+                    .answer
+                    .iter()
+                    .filter(|answer| answer.record_type == record_type)
+                    .filter_map(|answer| answer.data.parse::<IpAddr>().ok()),
+            );
+        }
+        anyhow::ensure!(!addresses.is_empty(), "the resolver has no address for it");
+        Ok(addresses)
+    }
+
+    fn report(&self, message: String) {
+        if self.verbose {
+            status!("{}", message);
+        } else {
+            tracing::debug!("{}", message);
+        }
+    }
+}
+
+/// The name of a DNS response code, for the common ones.
+fn response_code(code: u16) -> &'static str {
+    match code {
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        5 => "REFUSED",
+        _ => "an error",
+    }
+}
+
+fn list(addresses: &[IpAddr]) -> String {
+    let addresses: Vec<String> = addresses.iter().map(IpAddr::to_string).collect();
+    addresses.join(", ")
+}
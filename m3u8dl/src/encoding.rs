@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Bitrate of each aac audio stream `--compress` encodes, in bits per second.
+pub const AUDIO_BITRATE: u64 = 128_000;
+/// Share of a `--target-size` left for the container's own overhead.
+const CONTAINER_OVERHEAD: f64 = 0.02;
+/// Lowest video bitrate worked out from a `--target-size` that is still
+/// worth encoding at, in bits per second.
+const MIN_VIDEO_BITRATE: u64 = 100_000;
+
+/// How `--compress` encodes the video with libx264.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Constant quality (CRF 23), whatever size that comes to.
+    Quality,
+    /// Average bitrate in bits per second, from `--video-bitrate` or worked
+    /// out from `--target-size`.
+    Bitrate(u64),
+}
+
+/// Parse a file size such as `500M`, `1.5G` or `700MB`; K, M and G count in
+/// powers of 1024, and a bare number is bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    parse_scaled(value, 1024.0, "a size such as 500M or 1.5G")
+}
+
+/// Parse a bitrate such as `2500k` or `2.5M`, in bits per second as ffmpeg
+/// counts them (k is 1000).
+pub fn parse_bitrate(value: &str) -> Result<u64, String> {
+    parse_scaled(value, 1000.0, "a bitrate such as 2500k or 2.5M")
+}
+
+fn parse_scaled(value: &str, unit: f64, expected: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid value '{}': expected {}", value, expected);
+    let trimmed = value.trim();
+    let trimmed = trimmed
+        .strip_suffix(['B', 'b'])
+        .filter(|rest| rest.ends_with(|c: char| c.is_ascii_alphabetic()))
+        .unwrap_or(trimmed);
+    let (number, scale) = match trimmed.char_indices().last() {
+        Some((at, suffix)) if suffix.is_ascii_alphabetic() => {
+            let power = match suffix.to_ascii_uppercase() {
+                'K' => 1,
+                'M' => 2,
+                'G' => 3,
+                _ => return Err(invalid()),
+            };
+            (&trimmed[..at], unit.powi(power))
+        }
+        _ => (trimmed, 1.0),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err(invalid());
+    }
+    Ok((number * scale).round() as u64)
+}
+
+/// The video bitrate that makes `duration` seconds of output about
+/// `target_size` bytes, after `audio_streams` aac streams and the
+/// container's overhead.
+pub fn bitrate_for_size(target_size: u64, duration: f64, audio_streams: usize) -> Result<u64> {
+    anyhow::ensure!(
+        duration > 0.0,
+        "--target-size needs the playlist's #EXTINF durations to work out a bitrate"
+    );
+    let total = target_size as f64 * 8.0 * (1.0 - CONTAINER_OVERHEAD) / duration;
+    let video = total - (AUDIO_BITRATE * audio_streams as u64) as f64;
+    anyhow::ensure!(
+        video >= MIN_VIDEO_BITRATE as f64,
+        "--target-size of {} bytes is too small for {:.0}s of video: it leaves {:.0} kbit/s \
+         for the video, below the {} kbit/s worth encoding at",
+        target_size,
+        duration,
+        video.max(0.0) / 1000.0,
+        MIN_VIDEO_BITRATE / 1000
+    );
+    Ok(video as u64)
+}
+
+/// `-passlogfile` prefix of a two-pass encode, in the temp folder.
+pub const PASSLOG: &str = "ffmpeg2pass";
+
+/// The files a two-pass libx264 encode leaves in the temp folder.
+pub fn passlog_files() -> [String; 4] {
+    let log = format!("{}-0.log", PASSLOG);
+    [
+        format!("{}.mbtree", log),
+        format!("{}.temp", log),
+        format!("{}.mbtree.temp", log),
+        log,
+    ]
+}
+
+/// Remove what the two passes left in `temp_dir`, once the output is made.
+pub fn remove_passlog(temp_dir: &Path) {
+    for file in passlog_files().iter().map(|name| temp_dir.join(name)) {
+        if let Err(error) = fs::remove_file(&file) {
+            if file.exists() {
+                tracing::debug!("Failed to remove {}: {}", file.display(), error);
+            }
+        }
+    }
+}
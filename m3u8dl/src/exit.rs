@@ -0,0 +1,77 @@
+use std::fmt;
+use std::io;
+
+/// Documented in `--help`; keep in sync with [`ExitKind`].
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  any other error
+  2  invalid arguments
+  3  the playlist could not be fetched
+  4  segments failed to download
+  5  ffmpeg failed
+  6  ffmpeg was not found
+  7  the output failed verification; the segments were kept
+  8  the run passed its --deadline";
+
+/// Error category that decides the process exit code.
+///
+/// Attached to an error as anyhow context, so it also becomes the top-level message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitKind {
+    Usage,
+    Playlist,
+    Segments,
+    Ffmpeg,
+    FfmpegNotFound,
+    Verify,
+    Deadline,
+}
+
+impl ExitKind {
+    fn code(self) -> u8 {
+        match self {
+            ExitKind::Usage => 2,
+            ExitKind::Playlist => 3,
+            ExitKind::Segments => 4,
+            ExitKind::Ffmpeg => 5,
+            ExitKind::FfmpegNotFound => 6,
+            ExitKind::Verify => 7,
+            ExitKind::Deadline => 8,
+        }
+    }
+}
+
+impl fmt::Display for ExitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExitKind::Usage => "Invalid arguments",
+            ExitKind::Playlist => "Failed to fetch the playlist",
+            ExitKind::Segments => "Failed to download segments",
+            ExitKind::Ffmpeg => "ffmpeg failed",
+            ExitKind::FfmpegNotFound => {
+                "ffmpeg was not found; make sure it is installed and on PATH"
+            }
+            ExitKind::Verify => "The output failed verification",
+            ExitKind::Deadline => "The run passed its deadline",
+        })
+    }
+}
+
+/// Process exit code for an error returned from the run.
+pub fn code(error: &anyhow::Error) -> u8 {
+    // Looks through every layer of context, not just the outermost one
+    error.downcast_ref::<ExitKind>().map_or(1, |kind| kind.code())
+}
+
+/// Categorize a failure to start an external tool such as ffmpeg or ffprobe.
+pub fn spawn_error(error: io::Error, tool: &str) -> anyhow::Error {
+    let kind = if error.kind() == io::ErrorKind::NotFound {
+        ExitKind::FfmpegNotFound
+    } else {
+        ExitKind::Ffmpeg
+    };
+    anyhow::Error::new(error)
+        .context(format!("Failed to execute {}", tool))
+        .context(kind)
+}
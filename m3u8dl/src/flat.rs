@@ -0,0 +1,233 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::fsretry::{self, Target};
+use crate::gaps::SegmentFailure;
+use crate::logging::status;
+use crate::playlist::MediaPlaylist;
+
+/// `index.json` of a `--flat-output` folder: everything needed to reassemble
+/// or stream the download without the original playlist.
+#[derive(Debug, Serialize)]
+struct Index {
+    playlist: String,
+    /// Seconds of content in the listed segments.
+    duration: f64,
+    /// Bytes of the listed segments, the initialization sections not included.
+    size: u64,
+    init_sections: Vec<IndexedInit>,
+    segments: Vec<IndexedSegment>,
+    /// Segments that failed to download (with `--ignore-errors`) and are left out.
+    missing: Vec<MissingSegment>,
+}
+
+#[derive(Debug, Serialize)]
+struct IndexedInit {
+    file: String,
+    /// Index of the first segment this section applies to.
+    first_segment: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct IndexedSegment {
+    /// Position in the original playlist.
+    index: usize,
+    file: String,
+    duration: f64,
+    /// Seconds into the content at which the segment starts.
+    start: f64,
+    size: u64,
+    /// Byte offset of the segment in the concatenation of all listed segments.
+    offset: u64,
+    /// The initialization section to put before the segment, for fMP4.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    init: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MissingSegment {
+    index: usize,
+    duration: f64,
+}
+
+/// Move the downloaded segments from `temp_dir` into `dir` as `00000.ts`,
+/// `00001.ts`, ... (numbered in playlist order, keeping their extension),
+/// along with the initialization sections and an `index.json`.
+pub fn write(
+    dir: &Path,
+    playlist: &MediaPlaylist,
+    temp_dir: &str,
+    failures: &[SegmentFailure],
+    init_sections: &[PathBuf],
+) -> Result<usize> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create flat output folder '{}'", dir.display()))?;
+
+    // Moving across file systems copies every file, which is slower; say so once
+    let mut copying = false;
+    let mut place = |from: &Path, to: &Path| -> Result<()> {
+        if move_file(from, to)? && !copying {
+            copying = true;
+            status!(
+                "'{}' is on another file system than the temp folder; copying the segments there.",
+                dir.display()
+            );
+        }
+        Ok(())
+    };
+
+    let mut inits = Vec::new();
+    for (section, path) in playlist.init_sections.iter().zip(init_sections) {
+        let file = format!("init-{}.mp4", inits.len() + 1);
+        place(path, &dir.join(&file))?;
+        inits.push(IndexedInit {
+            file,
+            first_segment: section.first_segment,
+        });
+    }
+
+    let mut segments = Vec::new();
+    let (mut start, mut offset) = (0.0, 0);
+    for segment in &playlist.segments {
+        if failures
+            .iter()
+            .any(|failure| failure.index == segment.index)
+        {
+            continue;
+        }
+        let url = segment.url();
+        let downloaded = crate::segment_filename(&url)
+            .map(|filename| Path::new(temp_dir).join(filename))
+            .filter(|path| path.is_file())
+            .with_context(|| format!("Segment {} wasn't downloaded", segment.index))?;
+        let extension = downloaded
+            .extension()
+            .map_or("ts".into(), |extension| extension.to_string_lossy());
+        let file = format!("{:05}.{}", segments.len(), extension);
+        place(&downloaded, &dir.join(&file))?;
+
+        let size = fs::metadata(dir.join(&file))?.len();
+        let init = inits
+            .iter()
+            .rev()
+            .find(|init| init.first_segment <= segment.index)
+            .map(|init| init.file.clone());
+        segments.push(IndexedSegment {
+            index: segment.index,
+            file,
+            duration: segment.duration,
+            start,
+            size,
+            offset,
+            init,
+        });
+        start += segment.duration;
+        offset += size;
+    }
+
+    let index = Index {
+        playlist: playlist.url.to_string(),
+        duration: start,
+        size: offset,
+        init_sections: inits,
+        segments,
+        missing: failures
+            .iter()
+            .map(|failure| MissingSegment {
+                index: failure.index,
+                duration: failure.duration,
+            })
+            .collect(),
+    };
+    let json = serde_json::to_string_pretty(&index)?;
+    let index_path = dir.join("index.json");
+    fsretry::retry(Target::Output, "write", &index_path, || fs::write(&index_path, &json))?;
+    Ok(index.segments.len())
+}
+
+/// Move `from` to `to`, copying it instead across file systems. Returns
+/// whether it was copied.
+fn move_file(from: &Path, to: &Path) -> Result<bool> {
+    move_with(from, to, |from, to| fs::rename(from, to))
+}
+
+/// [`move_file`], moving with `rename`.
+fn move_with(
+    from: &Path,
+    to: &Path,
+    rename: impl FnOnce(&Path, &Path) -> io::Result<()>,
+) -> Result<bool> {
+    match rename(from, to) {
+        Ok(()) => Ok(false),
+        Err(error) if fsretry::crosses_devices(&error) => {
+            fsretry::retry(Target::Output, "copy a segment to", to, || {
+                fs::copy(from, to).and_then(|_| fs::remove_file(from))
+            })?;
+            Ok(true)
+        }
+        Err(error) => Err(anyhow::Error::new(error).context(format!(
+            "Failed to move {} to {}",
+            from.display(),
+            to.display()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A folder of its own under the system temp folder for the test `name`
+    /// holding `from.ts`.
+    fn folder_with_segment(name: &str) -> PathBuf {
+        let name = format!("m3u8dl-flat-{}-{}", name, std::process::id());
+        let folder = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("from.ts"), b"segment").unwrap();
+        folder
+    }
+
+    #[test]
+    fn move_copies_across_file_systems() {
+        let folder = folder_with_segment("exdev");
+        let (from, to) = (folder.join("from.ts"), folder.join("to.ts"));
+        let copied = move_with(&from, &to, |_, _| {
+            Err(io::Error::from_raw_os_error(fsretry::CROSS_DEVICE))
+        })
+        .unwrap();
+        assert!(copied);
+        assert_eq!(fs::read(&to).unwrap(), b"segment");
+        assert!(!from.exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn move_renames_on_one_file_system() {
+        let folder = folder_with_segment("rename");
+        let (from, to) = (folder.join("from.ts"), folder.join("to.ts"));
+        assert!(!move_file(&from, &to).unwrap());
+        assert_eq!(fs::read(&to).unwrap(), b"segment");
+        assert!(!from.exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn move_passes_up_other_errors() {
+        let folder = folder_with_segment("denied");
+        let (from, to) = (folder.join("from.ts"), folder.join("to.ts"));
+        let error = move_with(&from, &to, |_, _| {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        })
+        .unwrap_err();
+        let cause = error.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(cause.kind(), io::ErrorKind::PermissionDenied);
+        assert!(from.exists());
+        assert!(!to.exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::fsretry::{self, Target};
+
+/// Outcome of joining an fMP4 stream without ffmpeg.
+#[derive(Debug)]
+pub enum Remux {
+    Written,
+    /// The stream needs ffmpeg, for the given reason; nothing was written.
+    Unsupported(String),
+}
+
+/// One ISO-BMFF box: its four-character type and its bytes, header included.
+struct Mp4Box<'a> {
+    kind: [u8; 4],
+    header_len: usize,
+    bytes: &'a [u8],
+}
+
+impl Mp4Box<'_> {
+    fn payload(&self) -> &[u8] {
+        &self.bytes[self.header_len..]
+    }
+}
+
+/// Boxes that can sit between fragments but mean nothing once the fragments
+/// are joined into one file (`sidx` offsets, for one, would point at the
+/// wrong bytes), so they are dropped.
+const DROPPED_BOXES: [&[u8; 4]; 7] = [
+    b"styp", b"sidx", b"ssix", b"prft", b"emsg", b"free", b"skip",
+];
+
+/// Boxes in a track fragment that carry sample encryption information.
+const ENCRYPTION_BOXES: [&[u8; 4]; 3] = [b"senc", b"saiz", b"saio"];
+
+/// Write the initialization section followed by every fragment's
+/// `moof`/`mdat` pairs to `output`, renumbering the fragments so their
+/// sequence numbers increase through the file.
+///
+/// Encrypted streams, fragments for tracks the initialization section
+/// doesn't declare, and anything that isn't a plain sequence of `moof`/`mdat`
+/// pairs are reported as [`Remux::Unsupported`] so that the caller can fall
+/// back to ffmpeg.
+pub fn join(init: &Path, fragments: &[PathBuf], output: &Path) -> Result<Remux> {
+    let init_bytes = fs::read(init).context("Failed to read initialization section")?;
+    let tracks = match check_init(&init_bytes) {
+        Ok(tracks) => tracks,
+        Err(reason) => return Ok(Remux::Unsupported(reason)),
+    };
+
+    let part_path = PathBuf::from(format!("{}.part", output.display()));
+    let file = fsretry::retry(Target::Output, "create", &part_path, || File::create(&part_path))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&init_bytes)?;
+
+    let mut sequence_number = 0u32;
+    for fragment in fragments {
+        let bytes = fs::read(fragment)
+            .with_context(|| format!("Failed to read fragment {}", fragment.display()))?;
+        if let Err(reason) = write_fragment(&bytes, &tracks, &mut sequence_number, &mut writer)? {
+            drop(writer);
+            let _ = fs::remove_file(&part_path);
+            return Ok(Remux::Unsupported(format!(
+                "{}: {}",
+                fragment.display(),
+                reason
+            )));
+        }
+    }
+
+    writer.flush()?;
+    drop(writer);
+    fsretry::retry(Target::Output, "move the joined output to", output, || {
+        fs::rename(&part_path, output)
+    })?;
+    Ok(Remux::Written)
+}
+
+/// Write the initialization section followed by the fragments, byte for
+/// byte, which makes a fragmented MP4 file ffmpeg can read as one input.
+pub fn concat(init: &Path, fragments: &[PathBuf], output: &Path) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(output).context("Failed to create joined fMP4")?);
+    for path in std::iter::once(init).chain(fragments.iter().map(PathBuf::as_path)) {
+        let mut file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        io::copy(&mut file, &mut writer).context("Failed to write joined fMP4")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Check the initialization section is `ftyp` then a fragmented, unencrypted
+/// `moov`, returning the IDs of the tracks it declares.
+fn check_init(bytes: &[u8]) -> Result<HashSet<u32>, String> {
+    let boxes = parse_boxes(bytes)?;
+    let kinds: Vec<&[u8; 4]> = boxes.iter().map(|b| &b.kind).collect();
+    if kinds.first() != Some(&b"ftyp") || kinds.get(1) != Some(&b"moov") {
+        return Err("initialization section doesn't start with ftyp and moov".to_string());
+    }
+
+    let moov = parse_boxes(boxes[1].payload())?;
+    if !moov.iter().any(|b| &b.kind == b"mvex") {
+        return Err("initialization section isn't fragmented (no mvex)".to_string());
+    }
+    if moov.iter().any(|b| &b.kind == b"pssh") {
+        return Err("stream is encrypted (pssh)".to_string());
+    }
+
+    let mut tracks = HashSet::new();
+    for trak in moov.iter().filter(|b| &b.kind == b"trak") {
+        let children = parse_boxes(trak.payload())?;
+        let tkhd = find(&children, b"tkhd")?;
+        let version = *tkhd.payload().first().ok_or("truncated tkhd")?;
+        let offset = if version == 1 { 20 } else { 12 };
+        tracks.insert(read_u32(tkhd.payload(), offset).ok_or("truncated tkhd")?);
+
+        let stsd = descend(&children, &[b"mdia", b"minf", b"stbl", b"stsd"])?;
+        // Full box header and entry count precede the sample entries
+        let entries = parse_boxes(stsd.get(8..).ok_or("truncated stsd")?)?;
+        if let Some(entry) = entries
+            .iter()
+            .find(|b| matches!(&b.kind, b"encv" | b"enca"))
+        {
+            return Err(format!(
+                "stream is encrypted ({})",
+                String::from_utf8_lossy(&entry.kind)
+            ));
+        }
+    }
+    if tracks.is_empty() {
+        return Err("initialization section declares no tracks".to_string());
+    }
+    Ok(tracks)
+}
+
+/// Write one fragment's `moof`/`mdat` pairs with renumbered `mfhd` sequence
+/// numbers. The inner result says why the fragment can't be joined as-is.
+fn write_fragment(
+    bytes: &[u8],
+    tracks: &HashSet<u32>,
+    sequence_number: &mut u32,
+    writer: &mut impl Write,
+) -> Result<Result<(), String>> {
+    let boxes = match parse_boxes(bytes) {
+        Ok(boxes) => boxes,
+        Err(reason) => return Ok(Err(reason)),
+    };
+
+    let mut boxes = boxes
+        .iter()
+        .filter(|b| !DROPPED_BOXES.contains(&&b.kind))
+        .peekable();
+    if boxes.peek().is_none() {
+        return Ok(Err("fragment has no moof".to_string()));
+    }
+    while let Some(moof) = boxes.next() {
+        if &moof.kind != b"moof" {
+            return Ok(Err(format!(
+                "unexpected {} box where a moof should be",
+                String::from_utf8_lossy(&moof.kind)
+            )));
+        }
+        let Some(mdat) = boxes.next().filter(|b| &b.kind == b"mdat") else {
+            return Ok(Err("moof isn't followed by an mdat".to_string()));
+        };
+
+        *sequence_number += 1;
+        let moof_bytes = match renumber(moof, tracks, *sequence_number) {
+            Ok(moof_bytes) => moof_bytes,
+            Err(reason) => return Ok(Err(reason)),
+        };
+        writer.write_all(&moof_bytes)?;
+        writer.write_all(mdat.bytes)?;
+    }
+    Ok(Ok(()))
+}
+
+/// Copy a `moof` with its `mfhd` sequence number replaced, after checking
+/// its track fragments can be moved to another position in the file.
+fn renumber(moof: &Mp4Box, tracks: &HashSet<u32>, sequence_number: u32) -> Result<Vec<u8>, String> {
+    let children = parse_boxes(moof.payload())?;
+    for traf in children.iter().filter(|b| &b.kind == b"traf") {
+        let traf_children = parse_boxes(traf.payload())?;
+        if let Some(encryption) = traf_children
+            .iter()
+            .find(|b| ENCRYPTION_BOXES.contains(&&b.kind))
+        {
+            return Err(format!(
+                "stream is encrypted ({})",
+                String::from_utf8_lossy(&encryption.kind)
+            ));
+        }
+
+        let tfhd = find(&traf_children, b"tfhd")?;
+        let flags = read_u32(tfhd.payload(), 0).ok_or("truncated tfhd")? & 0x00ff_ffff;
+        let track_id = read_u32(tfhd.payload(), 4).ok_or("truncated tfhd")?;
+        if !tracks.contains(&track_id) {
+            return Err(format!(
+                "fragment carries track {}, which the initialization section doesn't declare",
+                track_id
+            ));
+        }
+        // An explicit base data offset is absolute, so it breaks once the fragment moves
+        if flags & 0x01 != 0 {
+            return Err("fragment uses absolute base data offsets".to_string());
+        }
+    }
+
+    let mfhd = find(&children, b"mfhd")?;
+    // The sequence number follows the mfhd header, version and flags
+    let offset = mfhd.bytes.as_ptr() as usize - moof.bytes.as_ptr() as usize + mfhd.header_len + 4;
+    let mut bytes = moof.bytes.to_vec();
+    bytes
+        .get_mut(offset..offset + 4)
+        .ok_or("truncated mfhd")?
+        .copy_from_slice(&sequence_number.to_be_bytes());
+    Ok(bytes)
+}
+
+/// Split `bytes` into consecutive boxes.
+fn parse_boxes(mut bytes: &[u8]) -> Result<Vec<Mp4Box<'_>>, String> {
+    let mut boxes = Vec::new();
+    while !bytes.is_empty() {
+        let size = read_u32(bytes, 0).ok_or("truncated box header")?;
+        let kind: [u8; 4] = bytes
+            .get(4..8)
+            .and_then(|kind| kind.try_into().ok())
+            .ok_or("truncated box header")?;
+        let (size, header_len) = match size {
+            0 => (bytes.len(), 8),
+            1 => {
+                let size = bytes
+                    .get(8..16)
+                    .and_then(|size| size.try_into().ok())
+                    .map(u64::from_be_bytes)
+                    .ok_or("truncated box header")?;
+                (usize::try_from(size).map_err(|_| "box too large")?, 16)
+            }
+            size => (size as usize, 8),
+        };
+        if size < header_len || size > bytes.len() {
+            return Err(format!(
+                "{} box has an invalid size",
+                String::from_utf8_lossy(&kind)
+            ));
+        }
+        boxes.push(Mp4Box {
+            kind,
+            header_len,
+            bytes: &bytes[..size],
+        });
+        bytes = &bytes[size..];
+    }
+    Ok(boxes)
+}
+
+fn find<'a>(boxes: &'a [Mp4Box<'a>], kind: &[u8; 4]) -> Result<&'a Mp4Box<'a>, String> {
+    boxes
+        .iter()
+        .find(|b| &b.kind == kind)
+        .ok_or_else(|| format!("missing {} box", String::from_utf8_lossy(kind)))
+}
+
+/// Follow a path of nested container boxes, returning the payload of the last one.
+fn descend<'a>(boxes: &[Mp4Box<'a>], path: &[&[u8; 4]]) -> Result<&'a [u8], String> {
+    let (first, rest) = path.split_first().ok_or("empty box path")?;
+    let payload = boxes
+        .iter()
+        .find(|b| &&b.kind == first)
+        .map(|b| &b.bytes[b.header_len..])
+        .ok_or_else(|| format!("missing {} box", String::from_utf8_lossy(*first)))?;
+    if rest.is_empty() {
+        return Ok(payload);
+    }
+    descend(&parse_boxes(payload)?, rest)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u32::from_be_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(kind);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn init(sample_entry: &[u8; 4]) -> Vec<u8> {
+        let mut tkhd = vec![0; 12];
+        tkhd.extend_from_slice(&1u32.to_be_bytes());
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend(mp4_box(sample_entry, &[]));
+        let stbl = mp4_box(b"stbl", &mp4_box(b"stsd", &stsd));
+        let mdia = mp4_box(b"mdia", &mp4_box(b"minf", &stbl));
+        let trak = mp4_box(b"trak", &[mp4_box(b"tkhd", &tkhd), mdia].concat());
+        let moov = mp4_box(b"moov", &[mp4_box(b"mvex", &[]), trak].concat());
+        [mp4_box(b"ftyp", b"isom\0\0\0\0"), moov].concat()
+    }
+
+    fn fragment(track_id: u32, tfhd_flags: u32, data: &[u8]) -> Vec<u8> {
+        let mfhd = mp4_box(b"mfhd", &[0, 0, 0, 0, 0, 0, 0, 7]);
+        let tfhd = [tfhd_flags.to_be_bytes(), track_id.to_be_bytes()].concat();
+        let moof = mp4_box(
+            b"moof",
+            &[mfhd, mp4_box(b"traf", &mp4_box(b"tfhd", &tfhd))].concat(),
+        );
+        [mp4_box(b"styp", b"msdh"), moof, mp4_box(b"mdat", data)].concat()
+    }
+
+    /// Join `init` and `fragments` in a folder of its own for the test `name`.
+    fn join_files(name: &str, init: &[u8], fragments: &[Vec<u8>]) -> (Remux, Option<Vec<u8>>) {
+        let folder =
+            std::env::temp_dir().join(format!("m3u8dl-fmp4-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("init.mp4"), init).unwrap();
+        let paths: Vec<PathBuf> = fragments
+            .iter()
+            .enumerate()
+            .map(|(index, bytes)| {
+                let path = folder.join(format!("seg{}.m4s", index));
+                fs::write(&path, bytes).unwrap();
+                path
+            })
+            .collect();
+        let output = folder.join("joined.mp4");
+        let remux = join(&folder.join("init.mp4"), &paths, &output).unwrap();
+        let joined = fs::read(&output).ok();
+        assert!(!folder.join("joined.mp4.part").exists());
+        fs::remove_dir_all(folder).unwrap();
+        (remux, joined)
+    }
+
+    #[test]
+    fn parse_boxes_reads_each_size_form() {
+        let mut large = 1u32.to_be_bytes().to_vec();
+        large.extend_from_slice(b"mdat");
+        large.extend_from_slice(&20u64.to_be_bytes());
+        large.extend_from_slice(b"abcd");
+        let bytes = [mp4_box(b"ftyp", b"isom"), large, mp4_box(b"free", &[]), {
+            let mut to_end = 0u32.to_be_bytes().to_vec();
+            to_end.extend_from_slice(b"mdat");
+            to_end.extend_from_slice(b"rest");
+            to_end
+        }]
+        .concat();
+
+        let boxes = parse_boxes(&bytes).unwrap();
+        let kinds: Vec<&[u8; 4]> = boxes.iter().map(|b| &b.kind).collect();
+        assert_eq!(kinds, [b"ftyp", b"mdat", b"free", b"mdat"]);
+        assert_eq!(boxes[0].payload(), b"isom");
+        assert_eq!(
+            (boxes[1].header_len, boxes[1].payload()),
+            (16, &b"abcd"[..])
+        );
+        assert!(boxes[2].payload().is_empty());
+        assert_eq!(boxes[3].payload(), b"rest");
+    }
+
+    #[test]
+    fn parse_boxes_rejects_truncated_and_oversized_boxes() {
+        assert_eq!(
+            parse_boxes(&[0, 0, 0, 8, b'f']).err().unwrap(),
+            "truncated box header"
+        );
+        let mut oversized = mp4_box(b"moof", b"1234");
+        oversized[3] = 64;
+        assert_eq!(
+            parse_boxes(&oversized).err().unwrap(),
+            "moof box has an invalid size"
+        );
+        let mut undersized = mp4_box(b"moof", b"");
+        undersized[3] = 4;
+        assert_eq!(
+            parse_boxes(&undersized).err().unwrap(),
+            "moof box has an invalid size"
+        );
+        assert!(parse_boxes(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn join_renumbers_fragments_and_drops_styp() {
+        let init = init(b"avc1");
+        let (remux, joined) = join_files(
+            "join",
+            &init,
+            &[fragment(1, 0, b"one"), fragment(1, 0, b"two")],
+        );
+        assert!(matches!(remux, Remux::Written));
+        let joined = joined.unwrap();
+        assert!(joined.starts_with(&init));
+
+        let boxes = parse_boxes(&joined[init.len()..]).unwrap();
+        let kinds: Vec<&[u8; 4]> = boxes.iter().map(|b| &b.kind).collect();
+        assert_eq!(kinds, [b"moof", b"mdat", b"moof", b"mdat"]);
+        for (moof, sequence_number) in [(&boxes[0], 1u32), (&boxes[2], 2)] {
+            let mfhd = find(&parse_boxes(moof.payload()).unwrap(), b"mfhd")
+                .unwrap()
+                .payload()
+                .to_vec();
+            assert_eq!(read_u32(&mfhd, 4), Some(sequence_number));
+        }
+        assert_eq!(boxes[3].payload(), b"two");
+    }
+
+    #[test]
+    fn join_leaves_unsupported_streams_to_ffmpeg() {
+        let unsupported = |name: &str, init: Vec<u8>, fragment: Vec<u8>| match join_files(
+            name,
+            &init,
+            &[fragment],
+        ) {
+            (Remux::Unsupported(reason), None) => reason,
+            (remux, _) => panic!("{:?}", remux),
+        };
+        let reason = unsupported("encv", init(b"encv"), fragment(1, 0, b""));
+        assert_eq!(reason, "stream is encrypted (encv)");
+        let reason = unsupported("track", init(b"avc1"), fragment(2, 0, b""));
+        assert!(reason.ends_with(
+            "fragment carries track 2, which the initialization section doesn't declare"
+        ));
+        let reason = unsupported("offset", init(b"avc1"), fragment(1, 1, b""));
+        assert!(reason.ends_with("fragment uses absolute base data offsets"));
+    }
+}
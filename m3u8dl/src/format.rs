@@ -0,0 +1,19 @@
+/// Output container, overriding the one ffmpeg would infer from the file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Mp4,
+    Mkv,
+    Ts,
+    Mpegts,
+}
+
+impl OutputFormat {
+    /// The ffmpeg muxer name, as passed to `-f`.
+    pub fn muxer(self) -> &'static str {
+        match self {
+            OutputFormat::Mp4 => "mp4",
+            OutputFormat::Mkv => "matroska",
+            OutputFormat::Ts | OutputFormat::Mpegts => "mpegts",
+        }
+    }
+}
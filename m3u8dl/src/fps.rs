@@ -0,0 +1,163 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::thread;
+
+use crate::codecs;
+use crate::logging::status;
+use crate::project;
+
+/// Frame rates closer than this are the same, as `29.97` and `30000/1001`
+/// are.
+const TOLERANCE: f64 = 0.01;
+
+/// A frame rate as given to `--normalize-fps`, e.g. `25`, `29.97` or
+/// `30000/1001`, which ffmpeg takes as it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameRate {
+    text: String,
+    value: f64,
+}
+
+impl FrameRate {
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+}
+
+impl fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+pub fn parse_frame_rate(value: &str) -> Result<FrameRate, String> {
+    let invalid = || {
+        format!(
+            "invalid value '{}': expected a frame rate such as 25, 29.97 or 30000/1001",
+            value
+        )
+    };
+    let text = value.trim();
+    let rate = match text.split_once('/') {
+        Some((numerator, denominator)) => {
+            let numerator: f64 = numerator.parse().map_err(|_| invalid())?;
+            let denominator: f64 = denominator.parse().map_err(|_| invalid())?;
+            numerator / denominator
+        }
+        None => text.parse().map_err(|_| invalid())?,
+    };
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(invalid());
+    }
+    Ok(FrameRate {
+        text: text.to_string(),
+        value: rate,
+    })
+}
+
+/// The frame rate of one discontinuity group, from a segment in it.
+#[derive(Debug)]
+pub struct GroupRate {
+    /// Index of the group's first segment in the playlist.
+    pub first_segment: usize,
+    pub rate: f64,
+}
+
+/// Probe a downloaded segment of each discontinuity group for its frame
+/// rate, several at a time. `samples` pairs each group's first segment
+/// with the file probed for it; groups ffprobe can't tell about are left
+/// out. With `--project`, rates probed by an earlier run are reused and new
+/// ones are kept.
+pub fn probe_groups(samples: &[(usize, PathBuf)]) -> Vec<GroupRate> {
+    let parallel = thread::available_parallelism().map_or(4, |threads| threads.get());
+    let mut groups = Vec::new();
+    let mut probed = Vec::new();
+    for chunk in samples.chunks(parallel) {
+        let rates: Vec<(Option<f64>, bool)> = thread::scope(|scope| {
+            let handles: Vec<_> = chunk
+                .iter()
+                .map(|(_, path)| {
+                    scope.spawn(move || {
+                        let name = path.file_name().map(|name| name.to_string_lossy());
+                        if let Some(rate) = name.and_then(|name| project::frame_rate(&name)) {
+                            return (Some(rate), false);
+                        }
+                        let media = codecs::probe(path);
+                        (media.and_then(|media| media.frame_rate()), true)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or((None, false)))
+                .collect()
+        });
+        for ((first_segment, path), (rate, fresh)) in chunk.iter().zip(rates) {
+            let Some(rate) = rate else {
+                tracing::debug!("No frame rate for {}", path.display());
+                continue;
+            };
+            if let (true, Some(name)) = (fresh, path.file_name()) {
+                probed.push((name.to_string_lossy().into_owned(), rate));
+            }
+            groups.push(GroupRate {
+                first_segment: *first_segment,
+                rate,
+            });
+        }
+    }
+    if project::active() && !probed.is_empty() {
+        if let Err(error) = project::cache_frame_rates(&probed) {
+            status!("Warning: {:#}", error);
+        }
+    }
+    groups
+}
+
+/// Whether the groups' frame rates differ, so that copying their streams
+/// makes a variable frame rate file.
+pub fn is_variable(groups: &[GroupRate]) -> bool {
+    groups
+        .windows(2)
+        .any(|pair| !same(pair[0].rate, pair[1].rate))
+}
+
+/// Whether every group is at `rate` already.
+pub fn all_at(groups: &[GroupRate], rate: &FrameRate) -> bool {
+    groups.iter().all(|group| same(group.rate, rate.value()))
+}
+
+/// Where the frame rate changes, e.g. "25 fps from segment 0, 29.97 fps
+/// from segment 120".
+pub fn describe(groups: &[GroupRate]) -> String {
+    let mut changes: Vec<&GroupRate> = Vec::new();
+    for group in groups {
+        if !changes
+            .last()
+            .is_some_and(|last| same(last.rate, group.rate))
+        {
+            changes.push(group);
+        }
+    }
+    let changes: Vec<String> = changes
+        .iter()
+        .map(|group| {
+            format!(
+                "{} fps from segment {}",
+                format_rate(group.rate),
+                group.first_segment
+            )
+        })
+        .collect();
+    changes.join(", ")
+}
+
+/// `29.97` for 29.97002997, `25` for 25.0.
+fn format_rate(rate: f64) -> String {
+    let rate = format!("{:.3}", rate);
+    rate.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn same(a: f64, b: f64) -> bool {
+    (a - b).abs() < TOLERANCE
+}
@@ -0,0 +1,192 @@
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use crate::logging::status;
+
+/// Wait before the first retry, doubling for each one after it.
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Retries for writes to the temp folder (`--temp-io-retries`).
+static TEMP_RETRIES: AtomicU32 = AtomicU32::new(0);
+/// Retries for writes next to the output (`--output-io-retries`).
+static OUTPUT_RETRIES: AtomicU32 = AtomicU32::new(3);
+
+/// Where a write goes, since each place gets its own number of retries.
+#[derive(Debug, Clone, Copy)]
+pub enum Target {
+    /// The temp folder, usually on a local disk.
+    Temp,
+    /// The output, its sidecar files and `--flat-output`, perhaps on a
+    /// network mount.
+    Output,
+}
+
+impl Target {
+    fn retries(self) -> u32 {
+        match self {
+            Target::Temp => TEMP_RETRIES.load(Ordering::Relaxed),
+            Target::Output => OUTPUT_RETRIES.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether writes to `target` are retried at all.
+pub fn retries(target: Target) -> bool {
+    target.retries() > 0
+}
+
+pub fn configure(temp_retries: u32, output_retries: u32) {
+    TEMP_RETRIES.store(temp_retries, Ordering::Relaxed);
+    OUTPUT_RETRIES.store(output_retries, Ordering::Relaxed);
+}
+
+/// Run a file system operation, retrying it with backoff while it fails
+/// with an error a network file system gives under load. Other errors, and
+/// the last one once the retries for `target` run out, fail with `action`
+/// (e.g. "move the segment into place"), the path and the OS error code.
+pub fn retry<T>(
+    target: Target,
+    action: &str,
+    path: &Path,
+    mut operation: impl FnMut() -> io::Result<T>,
+) -> anyhow::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(error) => match backoff(target, attempt, action, path, &error) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(failure(error, action, path, attempt)),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+/// [`retry`] for async code, waiting without blocking the runtime.
+pub async fn retry_async<T>(
+    target: Target,
+    action: &str,
+    path: &Path,
+    mut operation: impl FnMut() -> io::Result<T>,
+) -> anyhow::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(error) => match backoff(target, attempt, action, path, &error) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(failure(error, action, path, attempt)),
+            },
+        }
+        attempt += 1;
+    }
+}
+
+/// How long to wait before retrying after `error` on `attempt` (counting
+/// from zero), or `None` to give up. Reports the retry.
+pub fn backoff(
+    target: Target,
+    attempt: u32,
+    action: &str,
+    path: &Path,
+    error: &io::Error,
+) -> Option<Duration> {
+    if attempt >= target.retries() || !retryable(error) {
+        return None;
+    }
+    let delay = FIRST_BACKOFF
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_BACKOFF);
+    status!(
+        "Warning: failed to {} {} ({}); retrying in {:.1}s ({} of {}).",
+        action,
+        path.display(),
+        describe(error),
+        delay.as_secs_f64(),
+        attempt + 1,
+        target.retries()
+    );
+    Some(delay)
+}
+
+/// The error for an operation that failed for good after `attempt` retries.
+pub fn failure(error: io::Error, action: &str, path: &Path, attempt: u32) -> anyhow::Error {
+    let message = match attempt {
+        0 => format!(
+            "Failed to {} {} ({})",
+            action,
+            path.display(),
+            describe(&error)
+        ),
+        retries => format!(
+            "Failed to {} {} after {} retries ({})",
+            action,
+            path.display(),
+            retries,
+            describe(&error)
+        ),
+    };
+    anyhow::Error::new(error).context(message)
+}
+
+/// "Input/output error, errno 5", or just the message without an OS code.
+fn describe(error: &io::Error) -> String {
+    match error.raw_os_error() {
+        Some(code) => {
+            let message = io::Error::from_raw_os_error(code).to_string();
+            let message = message.split(" (os error").next().unwrap_or_default();
+            format!("{}, errno {}", message, code)
+        }
+        None => error.to_string(),
+    }
+}
+
+/// The OS error for a rename or hard link between file systems.
+#[cfg(unix)]
+pub const CROSS_DEVICE: i32 = libc::EXDEV;
+/// `ERROR_NOT_SAME_DEVICE`
+#[cfg(windows)]
+pub const CROSS_DEVICE: i32 = 17;
+
+/// Whether a rename or hard link failed with `error` because its two paths
+/// are on different file systems, so it takes a copy instead.
+pub fn crosses_devices(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(CROSS_DEVICE)
+}
+
+/// Whether `error` is one a network file system gives under load, which a
+/// moment later may well not happen again.
+#[cfg(unix)]
+fn retryable(error: &io::Error) -> bool {
+    let transient = [
+        libc::EIO,
+        libc::EAGAIN,
+        libc::EINTR,
+        libc::EBUSY,
+        libc::ETIMEDOUT,
+        libc::ENETDOWN,
+        libc::ENETUNREACH,
+        libc::ENETRESET,
+        libc::ECONNABORTED,
+        libc::ECONNRESET,
+        libc::EHOSTDOWN,
+        libc::ESTALE,
+    ];
+    error
+        .raw_os_error()
+        .is_some_and(|code| transient.contains(&code))
+}
+
+#[cfg(windows)]
+fn retryable(error: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION, ERROR_NETWORK_BUSY,
+    // ERROR_UNEXP_NET_ERR, ERROR_NETNAME_DELETED, ERROR_SEM_TIMEOUT
+    let transient = [32, 33, 54, 59, 64, 121];
+    error
+        .raw_os_error()
+        .is_some_and(|code| transient.contains(&code))
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeSet;
+
+use crate::playlist::{format_timestamp, Segment};
+
+/// A segment that could not be downloaded and was left out of the output.
+#[derive(Debug, Clone)]
+pub struct SegmentFailure {
+    pub index: usize,
+    pub duration: f64,
+    pub reason: String,
+}
+
+/// A run of consecutive dropped segments.
+#[derive(Debug)]
+pub struct MissingRange {
+    /// Start of the range in the source playlist's timeline, in seconds.
+    pub source_start: f64,
+    /// End of the range in the source playlist's timeline, in seconds.
+    pub source_end: f64,
+    /// Position in the output where the content is missing, in seconds.
+    pub output_at: f64,
+    pub segments: usize,
+    pub reasons: BTreeSet<String>,
+}
+
+impl std::fmt::Display for MissingRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reasons: Vec<&str> = self.reasons.iter().map(String::as_str).collect();
+        write!(
+            f,
+            "missing {}–{} ({} segment{}, {}), at {} in the output",
+            format_timestamp(self.source_start),
+            format_timestamp(self.source_end),
+            self.segments,
+            if self.segments == 1 { "" } else { "s" },
+            reasons.join(", "),
+            format_timestamp(self.output_at)
+        )
+    }
+}
+
+/// Group dropped segments into consecutive ranges, mapping each onto the
+/// output's timeline (which is shorter than the playlist by every earlier gap).
+pub fn missing_ranges(segments: &[Segment], failures: &[SegmentFailure]) -> Vec<MissingRange> {
+    let mut failures: Vec<&SegmentFailure> = failures.iter().collect();
+    failures.sort_by_key(|failure| failure.index);
+
+    // Cumulative EXTINF start time of every segment
+    let starts: Vec<f64> = segments
+        .iter()
+        .scan(0.0, |elapsed, segment| {
+            let start = *elapsed;
+            *elapsed += segment.duration;
+            Some(start)
+        })
+        .collect();
+
+    let mut ranges: Vec<MissingRange> = Vec::new();
+    let mut dropped = 0.0;
+    let mut last_index: Option<usize> = None;
+
+    for failure in failures {
+        let source_start = starts[failure.index];
+        let source_end = source_start + failure.duration;
+
+        match ranges.last_mut() {
+            Some(range) if last_index.map(|index| index + 1) == Some(failure.index) => {
+                range.source_end = source_end;
+                range.segments += 1;
+                range.reasons.insert(failure.reason.clone());
+            }
+            _ => ranges.push(MissingRange {
+                source_start,
+                source_end,
+                output_at: source_start - dropped,
+                segments: 1,
+                reasons: BTreeSet::from([failure.reason.clone()]),
+            }),
+        }
+
+        dropped += failure.duration;
+        last_index = Some(failure.index);
+    }
+
+    ranges
+}
+
+/// Short, human-readable cause of a segment failure (the HTTP status when there is one).
+pub fn failure_reason(error: &anyhow::Error) -> String {
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(error) if error.status().is_some() => error.status().unwrap().as_u16().to_string(),
+        Some(error) if error.is_timeout() => "timeout".to_string(),
+        Some(error) if error.is_connect() => "connection failed".to_string(),
+        _ => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+    use crate::playlist::parse_segments;
+
+    fn segments(durations: &[f64]) -> Vec<Segment> {
+        let content: String = durations
+            .iter()
+            .enumerate()
+            .map(|(index, duration)| format!("#EXTINF:{},\nseg{}.ts\n", duration, index))
+            .collect();
+        let base = Url::parse("https://cdn.example/index.m3u8").unwrap();
+        parse_segments(content, &base).unwrap()
+    }
+
+    fn failure(segments: &[Segment], index: usize, reason: &str) -> SegmentFailure {
+        SegmentFailure {
+            index,
+            duration: segments[index].duration,
+            reason: reason.to_string(),
+        }
+    }
+
+    #[test]
+    fn missing_ranges_groups_consecutive_failures() {
+        let segments = segments(&[4.0, 4.0, 6.0, 6.0, 4.0]);
+        let failures = [
+            failure(&segments, 3, "404"),
+            failure(&segments, 1, "404"),
+            failure(&segments, 4, "timeout"),
+        ];
+        let ranges = missing_ranges(&segments, &failures);
+        assert_eq!(ranges.len(), 2);
+
+        assert_eq!((ranges[0].source_start, ranges[0].source_end), (4.0, 8.0));
+        assert_eq!((ranges[0].output_at, ranges[0].segments), (4.0, 1));
+
+        // The output is 4 seconds behind the playlist by then
+        assert_eq!((ranges[1].source_start, ranges[1].source_end), (14.0, 24.0));
+        assert_eq!((ranges[1].output_at, ranges[1].segments), (10.0, 2));
+        assert_eq!(
+            ranges[1].to_string(),
+            "missing 00:00:14–00:00:24 (2 segments, 404, timeout), at 00:00:10 in the output"
+        );
+    }
+
+    #[test]
+    fn missing_ranges_is_empty_without_failures() {
+        assert!(missing_ranges(&segments(&[4.0, 4.0]), &[]).is_empty());
+    }
+
+    #[test]
+    fn missing_ranges_reports_a_single_segment() {
+        let segments = segments(&[600.0, 272.0, 12.0]);
+        let ranges = missing_ranges(&segments, &[failure(&segments, 2, "404")]);
+        assert_eq!(
+            ranges[0].to_string(),
+            "missing 00:14:32–00:14:44 (1 segment, 404), at 00:14:32 in the output"
+        );
+    }
+}
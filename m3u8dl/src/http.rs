@@ -0,0 +1,455 @@
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::future::InspectOk;
+use futures::TryFutureExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Proxy, Response, Version};
+use tower_layer::Layer;
+use tower_service::Service;
+use url::Url;
+
+use crate::doh::{self, DohOptions, DohResolver};
+use crate::logging::status;
+
+/// HTTP version to force, instead of letting each connection negotiate one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum HttpVersion {
+    #[value(name = "1.1")]
+    Http1,
+    #[value(name = "2")]
+    Http2,
+}
+
+/// A `--header` sent with every request.
+///
+/// The value is marked sensitive, so it prints as `Sensitive` in debug
+/// output, since it is often a token or cookie.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    /// Parse `Name: value`, expanding `${env:VAR}` in the value so that
+    /// secrets can stay out of the command line.
+    fn from_str(header: &str) -> Result<Self, Self::Err> {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format!("expected 'Name: value', got '{}'", header))?;
+        let name = HeaderName::from_str(name.trim())
+            .map_err(|_| format!("invalid header name '{}'", name.trim()))?;
+        let value = expand_env(value.trim())?;
+        let mut value = HeaderValue::from_str(&value)
+            .map_err(|_| format!("invalid value for header '{}'", name))?;
+        value.set_sensitive(true);
+        Ok(Header { name, value })
+    }
+}
+
+/// Replace each `${env:VAR}` in `value` with the environment variable's value.
+fn expand_env(value: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${env:") {
+        let reference = &rest[start + "${env:".len()..];
+        let end = reference
+            .find('}')
+            .ok_or_else(|| "unterminated ${env:...} reference".to_string())?;
+        let variable = &reference[..end];
+        let resolved = env::var(variable).map_err(|error| match error {
+            env::VarError::NotPresent => {
+                format!("environment variable '{}' is not set", variable)
+            }
+            env::VarError::NotUnicode(_) => {
+                format!("environment variable '{}' is not valid UTF-8", variable)
+            }
+        })?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&resolved);
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Connection settings shared by every HTTP client the run creates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientOptions {
+    /// Only speak this HTTP version (HTTP/2 from the first byte), or negotiate when `None`.
+    pub http_version: Option<HttpVersion>,
+    /// How long an idle pooled connection is kept open.
+    pub pool_idle_timeout: Option<Duration>,
+    /// Upper bound on idle pooled connections per host.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Default timeout for each request.
+    pub timeout: Option<Duration>,
+    /// Proxy every request goes through (`http://`, `https://`, `socks5://` or `socks5h://`).
+    pub proxy: Option<Url>,
+    /// Host globs (`*.cdn.example.com`) that connect directly instead of through `proxy`.
+    pub proxy_bypass: Vec<String>,
+    /// Report which route each host takes.
+    pub verbose: bool,
+    /// Sent with every request, replacing reqwest's own header of the same name.
+    pub headers: Vec<Header>,
+    /// Resolve hostnames over DNS-over-HTTPS rather than with the system resolver.
+    pub doh: Option<DohOptions>,
+}
+
+/// Clients built so far, so that every part of a run asking for the same
+/// options shares one connection pool.
+static CLIENTS: Mutex<Vec<(ClientOptions, Client)>> = Mutex::new(Vec::new());
+
+/// A client for `options`, reusing an earlier one built with the same options.
+pub fn build_client(options: ClientOptions) -> Result<Client> {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some((_, client)) = clients.iter().find(|(built, _)| *built == options) {
+        return Ok(client.clone());
+    }
+    let client = new_client(options.clone())?;
+    clients.push((options, client.clone()));
+    Ok(client)
+}
+
+fn new_client(options: ClientOptions) -> Result<Client> {
+    // DNS-over-HTTPS queries take the same route as everything else, but not
+    // the --header values, which are meant for the media hosts
+    let doh = match options.doh.clone() {
+        Some(doh) => {
+            let client = new_client(ClientOptions {
+                timeout: Some(doh::QUERY_TIMEOUT),
+                headers: Vec::new(),
+                doh: None,
+                ..options.clone()
+            })
+            .context("Failed to build the DNS-over-HTTPS client")?;
+            Some(DohResolver::new(doh, client, options.verbose))
+        }
+        None => None,
+    };
+    let mut builder = Client::builder().connector_layer(CountConnections);
+    if let Some(proxy) = options.proxy {
+        builder = builder.proxy(routed_proxy(proxy, options.proxy_bypass, options.verbose));
+    }
+    match options.http_version {
+        Some(HttpVersion::Http1) => builder = builder.http1_only(),
+        Some(HttpVersion::Http2) => builder = builder.http2_prior_knowledge(),
+        None => {}
+    }
+    if let Some(timeout) = options.timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = options.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(timeout);
+    }
+    if let Some(max_idle) = options.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if !options.headers.is_empty() {
+        let mut headers = HeaderMap::new();
+        for header in options.headers {
+            headers.append(header.name, header.value);
+        }
+        builder = builder.default_headers(headers);
+    }
+    if let Some(resolver) = doh {
+        builder = builder.dns_resolver(Arc::new(resolver));
+    }
+    builder.build().context("Failed to build HTTP client")
+}
+
+/// Responses seen so far, by protocol: HTTP/1.0, HTTP/1.1, HTTP/2, HTTP/3.
+static RESPONSES: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Connections the clients have opened so far. Every other response came
+/// over a pooled connection that an earlier request had opened.
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Connector layer that counts the connections a client opens.
+#[derive(Clone)]
+struct CountConnections;
+
+impl<S> Layer<S> for CountConnections {
+    type Service = CountedConnector<S>;
+
+    fn layer(&self, connector: S) -> CountedConnector<S> {
+        CountedConnector(connector)
+    }
+}
+
+#[derive(Clone)]
+struct CountedConnector<S>(S);
+
+impl<S: Service<R>, R> Service<R> for CountedConnector<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = InspectOk<S::Future, fn(&S::Response)>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, destination: R) -> Self::Future {
+        self.0.call(destination).inspect_ok(count_connection)
+    }
+}
+
+fn count_connection<T>(_: &T) {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Responses seen so far, by HTTP status code.
+static STATUSES: Mutex<BTreeMap<u16, usize>> = Mutex::new(BTreeMap::new());
+
+/// Responses seen so far, by host and HTTP status code.
+static BY_HOST: Mutex<BTreeMap<(String, u16), usize>> = Mutex::new(BTreeMap::new());
+
+/// Count a response towards the protocol and status code summaries.
+pub fn record_response(response: &Response) {
+    record_version(response.version());
+    let status = response.status().as_u16();
+    *STATUSES.lock().unwrap().entry(status).or_default() += 1;
+    let host = response.url().host_str().unwrap_or_default().to_string();
+    *BY_HOST.lock().unwrap().entry((host, status)).or_default() += 1;
+}
+
+/// Response counts by host and status code, for `--metrics-port`.
+pub fn responses_by_host() -> BTreeMap<(String, u16), usize> {
+    BY_HOST.lock().unwrap().clone()
+}
+
+fn record_version(version: Version) {
+    let slot = match version {
+        Version::HTTP_10 => 0,
+        Version::HTTP_11 => 1,
+        Version::HTTP_2 => 2,
+        Version::HTTP_3 => 3,
+        _ => return,
+    };
+    RESPONSES[slot].fetch_add(1, Ordering::Relaxed);
+}
+
+/// One-line summary of the protocols responses arrived over and the
+/// connections they took, e.g. `30 over HTTP/1.1; 4 new connections, 26 pooled`.
+pub fn protocol_summary() -> String {
+    let counts: Vec<String> = ["HTTP/1.0", "HTTP/1.1", "HTTP/2", "HTTP/3"]
+        .iter()
+        .zip(&RESPONSES)
+        .map(|(name, count)| (name, count.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .map(|(name, count)| format!("{} over {}", count, name))
+        .collect();
+    if counts.is_empty() {
+        return "no responses".to_string();
+    }
+    let responses: usize = RESPONSES.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+    let connections = CONNECTIONS.load(Ordering::Relaxed);
+    format!(
+        "{}; {} new connections, {} pooled",
+        counts.join(", "),
+        connections,
+        responses.saturating_sub(connections)
+    )
+}
+
+/// Check `--proxy`/`--proxy-bypass` before anything is requested, so a typo
+/// can't quietly route traffic (and proxy credentials) the wrong way.
+pub fn validate_proxy(proxy: &Url, bypass: &[String]) -> Result<()> {
+    anyhow::ensure!(
+        matches!(proxy.scheme(), "http" | "https" | "socks5" | "socks5h"),
+        "Unsupported proxy scheme '{}' (use http, https, socks5 or socks5h)",
+        proxy.scheme()
+    );
+    let proxy_host = proxy.host_str().context("Proxy URL has no host")?;
+
+    for rule in bypass {
+        anyhow::ensure!(
+            !rule.is_empty()
+                && rule
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '*')),
+            "Invalid --proxy-bypass rule '{}': expected a host name glob such as *.cdn.com",
+            rule
+        );
+        anyhow::ensure!(
+            rule != "*",
+            "--proxy-bypass '*' bypasses every host; leave out --proxy instead"
+        );
+        anyhow::ensure!(
+            !host_matches(rule, proxy_host),
+            "--proxy-bypass rule '{}' matches the proxy host {} itself",
+            rule,
+            proxy_host
+        );
+    }
+    Ok(())
+}
+
+/// A proxy that every host goes through except those matching a bypass glob.
+fn routed_proxy(proxy: Url, bypass: Vec<String>, verbose: bool) -> Proxy {
+    let reported = Mutex::new(HashSet::new());
+    Proxy::custom(move |url| {
+        let host = url.host_str()?;
+        let direct = bypass.iter().any(|rule| host_matches(rule, host));
+        if reported.lock().unwrap().insert(host.to_string()) {
+            let route = if direct {
+                "directly"
+            } else {
+                "through the proxy"
+            };
+            if verbose {
+                status!("Connecting to {} {}", host, route);
+            } else {
+                tracing::debug!("Connecting to {} {}", host, route);
+            }
+        }
+        (!direct).then(|| proxy.clone())
+    })
+}
+
+/// Case-insensitive host glob match, where `*` stands for any run of characters.
+fn host_matches(rule: &str, host: &str) -> bool {
+    let rule = rule.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    let mut parts = rule.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = host.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Histogram of the status codes seen, e.g. `200: 498, 429: 2, 503: 7`.
+pub fn status_summary() -> String {
+    let statuses = STATUSES.lock().unwrap();
+    if statuses.is_empty() {
+        return "no responses".to_string();
+    }
+    statuses
+        .iter()
+        .map(|(status, count)| format!("{}: {}", status, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_matches_globs() {
+        assert!(host_matches("*.cdn.com", "a.cdn.com"));
+        assert!(host_matches("*.cdn.com", "a.b.CDN.com"));
+        assert!(!host_matches("*.cdn.com", "cdn.com"));
+        assert!(!host_matches("*.cdn.com", "evilcdn.com"));
+        assert!(host_matches("cdn.com", "CDN.COM"));
+        assert!(!host_matches("cdn.com", "cdn.com.evil"));
+        assert!(host_matches("edge-*.cdn.com", "edge-12.cdn.com"));
+        assert!(!host_matches("edge-*.cdn.com", "edge-12.cdn.net"));
+        assert!(host_matches("*cdn*", "mycdnhost"));
+        assert!(!host_matches("a*a", "a"));
+    }
+
+    #[test]
+    fn validate_proxy_rejects_misrouting_rules() {
+        let proxy = Url::parse("socks5h://tunnel.example:1080").unwrap();
+        let rules = |rules: &[&str]| -> Vec<String> {
+            rules.iter().map(|rule| rule.to_string()).collect()
+        };
+        assert!(validate_proxy(&proxy, &rules(&["*.cdn.com", "media.example"])).is_ok());
+        assert!(validate_proxy(&proxy, &rules(&["*"])).is_err());
+        assert!(validate_proxy(&proxy, &rules(&["*.example"])).is_err());
+        assert!(validate_proxy(&proxy, &rules(&["cdn.com:443"])).is_err());
+        assert!(validate_proxy(&proxy, &rules(&[""])).is_err());
+        let ftp = Url::parse("ftp://tunnel.example").unwrap();
+        assert!(validate_proxy(&ftp, &[]).is_err());
+    }
+
+    #[test]
+    fn expand_env_substitutes_each_reference() {
+        env::set_var("M3U8DL_TEST_TOKEN", "s3cret");
+        env::set_var("M3U8DL_TEST_USER", "me");
+        assert_eq!(
+            expand_env("Bearer ${env:M3U8DL_TEST_TOKEN}").unwrap(),
+            "Bearer s3cret"
+        );
+        assert_eq!(
+            expand_env("${env:M3U8DL_TEST_USER}:${env:M3U8DL_TEST_TOKEN}!").unwrap(),
+            "me:s3cret!"
+        );
+        assert_eq!(expand_env("no $references {here}").unwrap(), "no $references {here}");
+    }
+
+    #[test]
+    fn expand_env_rejects_unset_and_unterminated_references() {
+        env::remove_var("M3U8DL_TEST_UNSET");
+        let error = expand_env("Bearer ${env:M3U8DL_TEST_UNSET}").unwrap_err();
+        assert_eq!(error, "environment variable 'M3U8DL_TEST_UNSET' is not set");
+        let error = expand_env("Bearer ${env:M3U8DL_TEST_TOKEN").unwrap_err();
+        assert_eq!(error, "unterminated ${env:...} reference");
+    }
+
+    #[test]
+    fn header_values_are_expanded_and_sensitive() {
+        env::set_var("M3U8DL_TEST_COOKIE", "session=abc");
+        let header: Header = "Cookie: ${env:M3U8DL_TEST_COOKIE}".parse().unwrap();
+        assert_eq!(header.name, "cookie");
+        assert_eq!(header.value, "session=abc");
+        assert!(header.value.is_sensitive());
+        assert!(!format!("{:?}", header).contains("abc"));
+        assert!("no colon".parse::<Header>().is_err());
+    }
+
+    #[tokio::test]
+    async fn keep_alive_requests_count_one_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/seg.ts", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            // Every request head fits in one read here
+            while socket.read(&mut buffer).await.unwrap() > 0 {
+                let response = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let client = Client::builder()
+            .no_proxy()
+            .connector_layer(CountConnections)
+            .build()
+            .unwrap();
+
+        let before = CONNECTIONS.load(Ordering::Relaxed);
+        for _ in 0..3 {
+            let body = client.get(&url).send().await.unwrap().text().await.unwrap();
+            assert_eq!(body, "ok");
+        }
+        assert_eq!(CONNECTIONS.load(Ordering::Relaxed) - before, 1);
+    }
+}
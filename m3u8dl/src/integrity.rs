@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use md5::Md5;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+
+use crate::playlist::Segment;
+use crate::segment_filename;
+
+/// How thoroughly a downloaded segment was checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// Matched the digest from `--checksums`.
+    Checksum,
+    /// Matched the response's Content-Length.
+    Size,
+    /// Nothing to check against.
+    Unverified,
+    /// Kept from a previous run after the server answered 304 Not Modified.
+    Unchanged,
+    /// Linked from the download of another `--quality` variant.
+    Shared,
+}
+
+/// An expected segment digest, told apart by its length.
+#[derive(Debug, Clone)]
+pub enum Checksum {
+    Sha256(String),
+    Md5(String),
+}
+
+impl Checksum {
+    fn parse(hex: &str) -> Option<Self> {
+        if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return None;
+        }
+        match hex.len() {
+            64 => Some(Checksum::Sha256(hex.to_ascii_lowercase())),
+            32 => Some(Checksum::Md5(hex.to_ascii_lowercase())),
+            _ => None,
+        }
+    }
+
+    pub fn verify_bytes(&self, content: &[u8]) -> Result<()> {
+        self.verify(&mut &content[..])
+    }
+
+    pub fn verify_file(&self, path: &Path) -> Result<()> {
+        let mut file = File::open(path).context("Failed to open segment for verification")?;
+        self.verify(&mut file)
+    }
+
+    fn verify(&self, reader: &mut impl Read) -> Result<()> {
+        let (actual, expected) = match self {
+            Checksum::Sha256(expected) => (hex_digest::<Sha256>(reader)?, expected),
+            Checksum::Md5(expected) => (hex_digest::<Md5>(reader)?, expected),
+        };
+        anyhow::ensure!(
+            &actual == expected,
+            "Expected digest {}, got {}",
+            expected,
+            actual
+        );
+        Ok(())
+    }
+}
+
+/// SHA-256 of a file's contents, as lowercase hex.
+pub fn file_sha256(path: &Path) -> io::Result<String> {
+    hex_digest::<Sha256>(&mut File::open(path)?)
+}
+
+/// Copy `reader` to `writer`, returning the SHA-256 of what was copied, as lowercase hex.
+pub fn copy_sha256(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<String> {
+    hex_digest::<Sha256>(&mut Tee { reader, writer })
+}
+
+/// Reads from `reader`, writing everything it reads to `writer` as well.
+struct Tee<'a, R, W> {
+    reader: &'a mut R,
+    writer: &'a mut W,
+}
+
+impl<R: Read, W: Write> Read for Tee<'_, R, W> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buffer)?;
+        self.writer.write_all(&buffer[..read])?;
+        Ok(read)
+    }
+}
+
+fn hex_digest<D: Digest>(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = D::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Expected digests from a `--checksums` manifest: one `<name or index> <digest>`
+/// per line, with blank lines and `#` comments ignored.
+#[derive(Debug, Default)]
+pub struct Checksums {
+    by_name: HashMap<String, Checksum>,
+    by_index: HashMap<usize, Checksum>,
+}
+
+impl Checksums {
+    /// Read the manifest from a local file or an http(s) URL.
+    pub async fn load(client: &Client, source: &str) -> Result<Self> {
+        let manifest = if source.starts_with("http://") || source.starts_with("https://") {
+            client
+                .get(source)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?
+        } else {
+            std::fs::read_to_string(source)?
+        };
+        Self::parse(&manifest).with_context(|| format!("Invalid checksum manifest {}", source))
+    }
+
+    fn parse(manifest: &str) -> Result<Self> {
+        let mut checksums = Self::default();
+        for (number, line) in manifest.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, digest) = line.split_once(char::is_whitespace).with_context(|| {
+                format!("line {}: expected '<name or index> <digest>'", number + 1)
+            })?;
+            let checksum = Checksum::parse(digest.trim()).with_context(|| {
+                format!(
+                    "line {}: '{}' is not a SHA-256 or MD5 digest",
+                    number + 1,
+                    digest.trim()
+                )
+            })?;
+            match key.parse() {
+                Ok(index) => checksums.by_index.insert(index, checksum),
+                Err(_) => checksums.by_name.insert(key.to_string(), checksum),
+            };
+        }
+        Ok(checksums)
+    }
+
+    /// The expected digest for a segment, by file name first and then by playlist index.
+    pub fn get(&self, segment: &Segment) -> Option<&Checksum> {
+        segment_filename(&segment.url())
+            .and_then(|name| self.by_name.get(&name))
+            .or_else(|| self.by_index.get(&segment.index))
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::logging::status;
+
+/// How often a `--wait`ing run checks whether the other run has finished.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a reclaim guard may stand before it counts as left behind by a
+/// run that crashed while reclaiming; a live run holds it for a moment.
+const RECLAIM_GRACE: Duration = Duration::from_secs(10);
+/// How often a run checks back while another one reclaims a stale lock.
+const RECLAIM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exclusive claim on a (playlist URL, output path) pair, released on drop.
+///
+/// The lock file holds the owner's PID so that locks left behind by a
+/// crashed run can be recognised and reclaimed. It is written in full before
+/// it is linked into place, so another run never finds it empty.
+pub struct RunLock {
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Take the lock for downloading `m3u8_url` into `output_file`, either
+    /// failing or, with `wait`, blocking while another live process holds it.
+    pub async fn acquire(m3u8_url: &str, output_file: &str, wait: bool) -> Result<Self> {
+        let dir = std::env::temp_dir().join("m3u8dl-locks");
+        fs::create_dir_all(&dir).context("Failed to create lock directory")?;
+        let path = dir.join(format!("{:016x}.lock", lock_key(m3u8_url, output_file)));
+
+        let mut announced = false;
+        loop {
+            if create(&path).context("Failed to create lock file")? {
+                return Ok(Self { path });
+            }
+
+            let owner = read_owner(&path);
+            match owner {
+                Some(pid) if process_alive(pid) => {
+                    if !wait {
+                        anyhow::bail!(
+                            "Another run (PID {}) is already downloading this playlist to {}. \
+                             Pass --wait to start once it has finished.",
+                            pid,
+                            output_file
+                        );
+                    }
+                    if !announced {
+                        status!("Waiting for the run with PID {} to finish...", pid);
+                        announced = true;
+                    }
+                    tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+                }
+                // Left behind by a run that no longer exists
+                _ => {
+                    if !reclaim(&path, owner) {
+                        // Another run is reclaiming it
+                        tokio::time::sleep(RECLAIM_POLL_INTERVAL).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if read_owner(&self.path) == Some(std::process::id()) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Create the lock at `path` holding this run's PID, or return `false` if
+/// there is one already. The PID goes into a file of this run's own first,
+/// which is then hard-linked to `path`: the link either fails or makes a
+/// complete lock.
+fn create(path: &Path) -> io::Result<bool> {
+    let draft = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&draft, std::process::id().to_string())?;
+    let linked = fs::hard_link(&draft, path);
+    let _ = fs::remove_file(&draft);
+    match linked {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == ErrorKind::AlreadyExists => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Remove the lock at `path` if it still names `stale`, the owner that was
+/// found gone. Two runs finding the same stale lock might otherwise both
+/// remove it, the second removing the lock the first had taken in its place,
+/// so the removal happens under a guard file only one run holds at a time.
+/// Returns `false` if another run holds the guard.
+fn reclaim(path: &Path, stale: Option<u32>) -> bool {
+    let guard = path.with_extension("reclaim");
+    match File::options().write(true).create_new(true).open(&guard) {
+        Ok(_) => {}
+        Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+            let abandoned = fs::metadata(&guard)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > RECLAIM_GRACE);
+            if abandoned {
+                let _ = fs::remove_file(&guard);
+            }
+            return false;
+        }
+        Err(error) => {
+            tracing::debug!("Failed to create {}: {}", guard.display(), error);
+            return false;
+        }
+    }
+    if read_owner(path) == stale {
+        tracing::debug!("Reclaiming stale lock {}", path.display());
+        let _ = fs::remove_file(path);
+    }
+    let _ = fs::remove_file(&guard);
+    true
+}
+
+fn read_owner(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// FNV-1a over the playlist URL and the absolute output path.
+fn lock_key(m3u8_url: &str, output_file: &str) -> u64 {
+    let output = std::env::current_dir()
+        .map(|cwd| cwd.join(output_file))
+        .unwrap_or_else(|_| PathBuf::from(output_file));
+    let key = format!("{}\0{}", m3u8_url, output.display());
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists and may be signalled
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    std::process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+        .unwrap_or(true)
+}
@@ -0,0 +1,135 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crate::privacy::Scrubbed;
+
+/// Print a status line to the console and record it in the log file.
+macro_rules! status {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        let message = $crate::privacy::scrub(&message);
+        tracing::info!("{}", message);
+        $crate::logging::print(&message);
+    }};
+}
+pub(crate) use status;
+
+/// The spinner on screen, if any, which status lines are printed around.
+static ON_SCREEN: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Have status lines printed around `bar` while it is on screen, or stop with `None`.
+pub fn show_progress(bar: Option<ProgressBar>) {
+    *ON_SCREEN.lock().unwrap() = bar;
+}
+
+/// Set by `--print-path`, whose stdout carries nothing but the output's path.
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Print status lines to stderr from now on, keeping stdout for the output's path.
+pub fn keep_stdout_clean() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+/// Print a status line to stdout (stderr with `--print-path`) without
+/// garbling the spinner on screen.
+pub fn print(message: &str) {
+    let print = || match TO_STDERR.load(Ordering::Relaxed) {
+        true => eprintln!("{}", message),
+        false => println!("{}", message),
+    };
+    match &*ON_SCREEN.lock().unwrap() {
+        Some(bar) => bar.suspend(print),
+        None => print(),
+    }
+}
+
+/// Send everything at debug level to the file named by `template`, in which
+/// `{date}` expands to today's date and `{name}` to the output file's stem.
+///
+/// Writes happen on a background thread; the returned guard flushes them when dropped.
+pub fn init_file_log(template: &str, output_file: &str) -> Result<(PathBuf, WorkerGuard)> {
+    let name = Path::new(output_file)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let path = PathBuf::from(
+        template
+            .replace("{date}", &today())
+            .replace("{name}", &name),
+    );
+
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context("Failed to create log file directory")?;
+    }
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open log file {}", path.display()))?;
+
+    let (writer, guard) = tracing_appender::non_blocking(file);
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(move || Scrubbed(writer.clone()))
+                .with_ansi(false)
+                .with_filter(LevelFilter::DEBUG),
+        )
+        .init();
+
+    Ok((path, guard))
+}
+
+/// Today's UTC date as `YYYY-MM-DD`.
+fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// The current UTC time as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) calendar date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -1,196 +1,4014 @@
+use std::collections::HashSet;
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::task::{self, Poll};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
-use futures::stream::{self, StreamExt};
-use reqwest::Client;
+use clap::builder::RangedU64ValueParser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use reqwest::header::{self, HeaderValue};
+use reqwest::{Client, StatusCode};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::process::ChildStdin;
 use url::Url;
 
 use anyhow::{Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
 
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-struct Args {
-    /// URL of the M3U8 file to download
-    #[clap(value_parser)]
-    url: String,
+mod audio;
+mod benchmark;
+mod breaker;
+mod bundle;
+mod capabilities;
+mod check;
+mod cleanup;
+mod codecs;
+mod completion;
+mod concat;
+mod deadline;
+mod dedup;
+mod doh;
+mod encoding;
+mod exit;
+mod flat;
+mod fmp4;
+mod format;
+mod fsretry;
+mod fps;
+mod gaps;
+mod http;
+mod integrity;
+mod lock;
+mod logging;
+mod metrics;
+mod pace;
+mod page;
+mod playlist;
+mod pins;
+mod privacy;
+mod probe;
+mod project;
+mod provenance;
+mod quality;
+mod progress;
+mod reuse;
+mod sort;
+mod store;
+mod subtitles;
+mod timing;
+mod tracks;
+mod trim;
+mod upload;
+mod verify;
+mod wait;
+mod watchdog;
+
+use audio::{AudioMix, AudioTrack};
+use breaker::{BreakerConfig, CircuitBreaker};
+use capabilities::{Capabilities, Component, Requirement};
+use cleanup::Cleanup;
+use dedup::Dedup;
+use encoding::Encoding;
+use codecs::Tuning;
+use concat::{ConcatInput, ConcatMethod};
+use exit::{ExitKind, EXIT_CODES_HELP};
+use gaps::SegmentFailure;
+use format::OutputFormat;
+use fsretry::Target;
+use fps::FrameRate;
+use integrity::{Checksum, Checksums, Verification};
+use logging::status;
+use pace::{Pace, Pacer};
+use sort::SortOrder;
+use store::SegmentStore;
+use timing::RequestTiming;
+use playlist::{MediaPlaylist, PlaylistDiff, Segment, VariantPreferences};
+use quality::Quality;
+use progress::{Phases, SegmentProgress};
+use provenance::Provenance;
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Tool {
+    /// Download a single segment or key with the configured connection and
+    /// retry settings, without any of the playlist handling
+    Fetch(Box<FetchArgs>),
+    /// Mux the segments of a --bundle into an output file, e.g. on a machine
+    /// with more CPU than the one that downloaded them
+    Merge(MergeArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct MergeArgs {
+    /// Bundle written by --bundle
+    #[clap(long, value_name = "PATH")]
+    from_bundle: PathBuf,
 
     /// Output file name
     #[clap(short, long, default_value = "output.mp4")]
     output: String,
 
-    /// Enable compression
-    #[clap(short, long)]
-    compress: bool,
+    /// Folder the bundle is unpacked into before muxing
+    #[clap(long, default_value = "output")]
+    temp_dir: String,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct FetchArgs {
+    /// URL of the segment or key to download
+    url: Url,
+
+    /// File the response body is written to
+    #[clap(short, long)]
+    output: PathBuf,
+
+    /// Write exactly what the server returns, error pages included, without
+    /// checking or retrying it
+    #[clap(long)]
+    no_validate: bool,
+
+    #[clap(flatten)]
+    client: ClientArgs,
+
+    #[clap(flatten)]
+    retry: RetryArgs,
+
+    /// Log every retry attempt
+    #[clap(short, long)]
+    verbose: bool,
+}
+
+// How the HTTP client connects and what it asks for, for downloads and `fetch` alike
+#[derive(clap::Args, Debug, Clone)]
+struct ClientArgs {
+    /// Send requests through this proxy (http://, https://, socks5:// or socks5h://)
+    #[clap(long)]
+    proxy: Option<Url>,
+
+    /// Comma-separated host globs that connect directly instead of through --proxy
+    #[clap(long, requires = "proxy", value_delimiter = ',')]
+    proxy_bypass: Vec<String>,
+
+    /// Use HTTP/2 without negotiating it first (for servers known to speak it)
+    #[clap(long)]
+    http2_prior_knowledge: bool,
+
+    /// Force an HTTP version instead of negotiating one, e.g. 1.1 for CDNs
+    /// whose HTTP/2 stalls or resets connections
+    #[clap(long, value_enum, value_name = "VERSION", conflicts_with = "http2_prior_knowledge")]
+    http_version: Option<http::HttpVersion>,
+
+    /// Seconds an idle pooled connection is kept open
+    #[clap(long)]
+    pool_idle_timeout: Option<u64>,
+
+    /// Maximum number of idle pooled connections kept per host
+    #[clap(long)]
+    pool_max_idle_per_host: Option<usize>,
+
+    /// Accept header sent with segment requests, for origins that insist on a
+    /// particular media type
+    #[clap(long, value_name = "VALUE", default_value = DEFAULT_SEGMENT_ACCEPT)]
+    segment_accept: HeaderValue,
+
+    /// Extra header for every request, as 'Name: value' (repeatable).
+    /// ${env:VAR} in the value is replaced by that environment variable, so
+    /// tokens and cookies can stay out of the command line
+    #[clap(long = "header", short = 'H', value_name = "NAME: VALUE")]
+    headers: Vec<http::Header>,
+
+    /// Resolve hostnames with this DNS-over-HTTPS resolver (JSON API, e.g.
+    /// https://cloudflare-dns.com/dns-query), for networks whose own resolver
+    /// blocks or poisons CDN hostnames. Falls back to the system resolver
+    #[clap(long, value_name = "URL")]
+    doh: Option<Url>,
+
+    /// Fail when --doh can't resolve a hostname instead of falling back
+    #[clap(long, requires = "doh")]
+    doh_only: bool,
+}
+
+// How failed requests are retried and responses checked, for downloads and `fetch` alike
+#[derive(clap::Args, Debug, Clone)]
+struct RetryArgs {
+    /// Number of times a failed segment is retried before giving up on it
+    #[clap(long, default_value_t = 3)]
+    max_retries: usize,
+
+    /// Stop retrying a segment once this many seconds have passed since its
+    /// first attempt; whichever of this and --max-retries is reached first wins
+    #[clap(long, value_name = "SECONDS")]
+    retry_deadline: Option<f64>,
+
+    /// Give up on a request after this many seconds
+    #[clap(long, value_name = "SECONDS")]
+    timeout: Option<f64>,
+
+    /// Multiply a segment's timeout by this factor on each retry, so slow
+    /// segments get progressively longer to finish
+    #[clap(long, value_name = "FACTOR", default_value_t = 1.0, requires = "timeout")]
+    timeout_retries_increase: f64,
+
+    /// Treat segments smaller than this many bytes as corrupt and retry them
+    /// (empty segments always are)
+    #[clap(long, value_name = "BYTES", default_value_t = 0)]
+    min_segment_size: u64,
+
+    /// Give up on a segment bigger than this (e.g. 1G or 500M), by its
+    /// Content-Length or as it downloads; that is more likely a whole file
+    /// than a segment
+    #[clap(long, value_name = "SIZE", default_value = "1G", value_parser = encoding::parse_size)]
+    max_segment_size: u64,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = EXIT_CODES_HELP,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Args {
+    #[clap(subcommand)]
+    command: Option<Tool>,
+
+    /// URL of the M3U8 file to download, or `-` to read the playlist from stdin
+    #[clap(value_parser, required_unless_present = "project")]
+    url: Option<String>,
+
+    /// URL that relative URIs in a playlist read from stdin are resolved against
+    #[clap(long, required_if_eq("url", "-"))]
+    base_url: Option<Url>,
+
+    /// When the URL is a web page linking to exactly one m3u8 playlist,
+    /// download that playlist instead of failing
+    #[clap(long)]
+    scan_page: bool,
+
+    /// When the playlist 404s, 403s or lists no segments yet, as a scheduled
+    /// stream's does before it starts, keep checking until it has segments
+    #[clap(long)]
+    wait_for_stream: bool,
+
+    /// How long --wait-for-stream waits for the stream to start, e.g. 90m or 2h
+    #[clap(
+        long,
+        value_name = "DURATION",
+        default_value = "2h",
+        value_parser = wait::parse_duration,
+        requires = "wait_for_stream"
+    )]
+    wait_timeout: Duration,
+
+    /// How often --wait-for-stream checks the playlist, e.g. 30s or 5m
+    #[clap(
+        long,
+        value_name = "DURATION",
+        default_value = "30s",
+        value_parser = wait::parse_duration,
+        requires = "wait_for_stream"
+    )]
+    wait_interval: Duration,
+
+    /// Output file name; {quality}, {codec}, {bandwidth} and {source_host}
+    /// are filled in from the variant downloaded (e.g. show-{quality}-{codec}.mp4)
+    #[clap(short, long, default_value = "output.mp4")]
+    output: String,
+
+    /// Don't embed where the output came from (the variant, playlist URL,
+    /// download date and m3u8dl version) as JSON in its comment metadata
+    #[clap(long)]
+    no_provenance: bool,
+
+    /// Fail instead of creating the output file's missing parent folders
+    #[clap(long)]
+    no_mkdir: bool,
+
+    /// Enable compression
+    #[clap(short, long)]
+    compress: bool,
+
+    /// With --compress, encode the video at this average bitrate (e.g. 2500k
+    /// or 2.5M) instead of at a constant quality
+    #[clap(
+        long,
+        value_name = "BITRATE",
+        value_parser = encoding::parse_bitrate,
+        requires = "compress"
+    )]
+    video_bitrate: Option<u64>,
+
+    /// With --compress, encode the video in two passes at the bitrate that
+    /// makes the output about this size (e.g. 500M or 1.5G)
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser = encoding::parse_size,
+        requires = "compress",
+        conflicts_with_all = ["video_bitrate", "upload_cmd", "no_store", "in_memory", "bundle"]
+    )]
+    target_size: Option<u64>,
+
+    /// Re-encode the video at this frame rate (e.g. 25, 29.97 or 30000/1001)
+    /// when its frame rate changes across discontinuities or differs from
+    /// this one, rather than copying it into a variable frame rate file.
+    /// Uses --video-bitrate or --target-size if given, a constant quality otherwise
+    #[clap(
+        long,
+        value_name = "RATE",
+        value_parser = fps::parse_frame_rate,
+        conflicts_with_all = ["no_remux", "flat_output", "bundle", "in_memory", "no_store"]
+    )]
+    normalize_fps: Option<fps::FrameRate>,
+
+    /// Stop ffmpeg when it makes no progress for this long, e.g. 10m, and
+    /// retry the mux once with regenerated timestamps. Defaults to 3 minutes
+    /// plus a minute per GB of segments
+    #[clap(long, value_name = "DURATION", value_parser = wait::parse_duration)]
+    remux_stall_timeout: Option<Duration>,
+
+    /// Mux each run of segments between two #EXT-X-DISCONTINUITY tags into an
+    /// output of its own, named by replacing {part} in --output (01, 02, ...),
+    /// for a playlist that stitches several programs together
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "quality", "flat_output", "bundle", "no_store", "in_memory", "upload_cmd",
+            "no_remux", "external_audio", "target_size", "trim_leading_black", "preview_fps",
+            "print_path"
+        ]
+    )]
+    split_on_discontinuity: bool,
+
+    /// With --split-on-discontinuity, leave out parts shorter than this,
+    /// e.g. 10s to drop ad slates
+    #[clap(
+        long,
+        value_name = "DURATION",
+        value_parser = wait::parse_duration,
+        requires = "split_on_discontinuity"
+    )]
+    min_part_duration: Option<Duration>,
+
+    /// Retry a write to the temp folder this many times, with backoff, when
+    /// it fails with an error a network file system gives under load (EIO,
+    /// EAGAIN, ENETDOWN, ...)
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    temp_io_retries: u32,
+
+    /// Like --temp-io-retries, for placing the output and the files next to
+    /// it, e.g. on an SMB or NFS mount
+    #[clap(long, value_name = "N", default_value_t = 3)]
+    output_io_retries: u32,
+
+    /// Audio track (URL or local file) to mux in instead of the stream's own audio.
+    /// Repeat for several tracks, and prefix a language code (eng=URL_OR_PATH) to label one
+    #[clap(long, value_name = "URL_OR_PATH")]
+    external_audio: Vec<String>,
+
+    /// For fMP4 playlists, join the initialization section and fragments into
+    /// the mp4 directly instead of running ffmpeg (which is still used when they can't be)
+    #[clap(long, conflicts_with_all = ["compress", "external_audio", "upload_cmd", "no_store"])]
+    no_remux: bool,
+
+    /// Skip muxing and move the segments into this folder as 00000.ts,
+    /// 00001.ts, ... with an index.json of their order, durations and byte offsets
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = [
+            "compress", "external_audio", "upload_cmd", "no_store", "in_memory", "no_remux",
+            "preview_fps", "output_on_failure", "quality"
+        ]
+    )]
+    flat_output: Option<PathBuf>,
+
+    /// Instead of running ffmpeg, pack the segments, their SHA-256 digests and
+    /// the ffmpeg arguments into this tar, for `m3u8dl merge` to mux elsewhere.
+    /// The arguments suit the container of --output, e.g. mp4 by default
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "external_audio", "upload_cmd", "no_store", "in_memory", "no_remux",
+            "flat_output", "preview_fps", "concat_method", "quality"
+        ]
+    )]
+    bundle: Option<PathBuf>,
+
+    /// Keep the stream's own audio as well as the --external-audio tracks, as
+    /// separate audio streams (best with an mkv output)
+    #[clap(long, requires = "external_audio")]
+    keep_original_audio_and_video: bool,
+
+    /// Stream the output into this shell command's stdin instead of keeping it
+    /// ({name} expands to the output file name), e.g. "rclone rcat remote:bucket/{name}"
+    #[clap(long, value_name = "COMMAND")]
+    upload_cmd: Option<String>,
+
+    /// Leave out the playlist's first segment, such as a corrupt slate. Try
+    /// this when ffmpeg reports "could not find codec parameters" for it
+    #[clap(long)]
+    skip_first: bool,
+
+    /// Folder the segments are downloaded to before muxing
+    #[clap(long, default_value = "output")]
+    temp_dir: String,
+
+    /// Keep nothing on disk but the output: stream segments from memory into
+    /// ffmpeg and redact URL query strings from all output and logs
+    #[clap(long, conflicts_with_all = ["external_audio", "upload_cmd", "preview_fps"])]
+    no_store: bool,
+
+    /// Keep segments in memory instead of the temp folder and stream them into
+    /// ffmpeg in playlist order, spilling to disk past --memory-limit
+    #[clap(
+        long,
+        conflicts_with_all = ["no_store", "external_audio", "upload_cmd", "preview_fps", "no_remux"]
+    )]
+    in_memory: bool,
+
+    /// Hard-link a segment to the one next to it in the playlist when their
+    /// bytes are identical, rather than keeping both copies
+    #[clap(long, conflicts_with_all = ["no_store", "in_memory"])]
+    dedup: bool,
+
+    /// Once this many segments of the chosen variant have failed all their
+    /// retries (1 if left out), download the next best variant of the master
+    /// playlist instead
+    #[clap(
+        long,
+        value_name = "FAILURES",
+        num_args = 0..=1,
+        default_missing_value = "1",
+        conflicts_with_all = ["no_store", "in_memory", "quality"]
+    )]
+    retry_different_variant: Option<usize>,
+
+    /// Fail the run when no segment of the chosen variant downloads, rather
+    /// than falling back to the next best variant of the master playlist
+    #[clap(long, conflicts_with = "retry_different_variant")]
+    no_variant_fallback: bool,
+
+    /// When segments start failing with HTTP 404 because the playlist was
+    /// republished during the download, start over on the new version rather
+    /// than abort, keeping the downloaded segments that didn't change
+    #[clap(long, conflicts_with_all = ["no_store", "in_memory"])]
+    auto_restart_on_change: bool,
+
+    /// How much memory --in-memory may hold segments in
+    #[clap(long, value_name = "MIB", default_value_t = 1024, requires = "in_memory")]
+    memory_limit: u64,
+
+    /// Never spill --in-memory segments to disk, refusing to start if the
+    /// download is estimated not to fit in --memory-limit
+    #[clap(long, requires = "in_memory")]
+    no_spill: bool,
+
+    /// Output container format, instead of inferring it from the file name
+    #[clap(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// How to order the segment files in the concat list
+    #[clap(long, value_enum, default_value = "download-order")]
+    sort: sort::SortOrder,
+
+    /// Extension of the segment files that a --sort other than download-order
+    /// lists from the temp folder, for servers that serve MPEG-TS as e.g. .jpg.
+    /// Without it, .ts files and files of any extension that start like MPEG-TS are listed
+    #[clap(long, value_name = "EXT")]
+    segment_ext: Option<String>,
+
+    /// How ffmpeg reads the segments: the concat demuxer and file list, the
+    /// concat: protocol, or a pipe to its stdin
+    #[clap(
+        long,
+        value_enum,
+        default_value = "demuxer",
+        conflicts_with_all = ["no_store", "in_memory", "no_remux", "flat_output"]
+    )]
+    concat_method: ConcatMethod,
+
+    /// Start at most this many segment requests per second, regardless of concurrency
+    #[clap(long)]
+    requests_per_second: Option<f64>,
+
+    /// Space out segment requests so that content downloads at about FACTOR
+    /// times realtime (1.25 if left out); each segment still transfers at full speed
+    #[clap(long, value_name = "realtime[:FACTOR]")]
+    pace: Option<Pace>,
+
+    #[clap(flatten)]
+    client: ClientArgs,
+
+    /// Only consider variants at least this good (e.g. 480p or 1500k)
+    #[clap(long)]
+    min_quality: Option<Quality>,
+
+    /// Only consider variants at most this good (e.g. 1080p or 6M)
+    #[clap(long)]
+    max_quality: Option<Quality>,
+
+    /// Download each of these variants (e.g. 1080p,360p) into its own output,
+    /// named by replacing {quality} in --output
+    #[clap(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = ["min_quality", "max_quality", "pin_variant_file", "benchmark"]
+    )]
+    quality: Vec<Quality>,
+
+    /// JSON file remembering which variant was chosen for each master playlist,
+    /// so later runs stick to the same rendition
+    #[clap(long)]
+    pin_variant_file: Option<PathBuf>,
+
+    /// Don't download and check the first segment before starting the others
+    #[clap(long)]
+    no_probe_first: bool,
+
+    /// Manifest of expected segment digests (`<name or index> <sha256|md5>` per
+    /// line), as a file path or URL
+    #[clap(long)]
+    checksums: Option<String>,
+
+    /// Download again even if the output is already complete
+    #[clap(long)]
+    force: bool,
+
+    /// Number of segments to download in parallel
+    #[clap(
+        long,
+        default_value_t = 10,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrency: usize,
+
+    /// Skip segments that fail to download instead of aborting
+    #[clap(long)]
+    ignore_errors: bool,
+
+    /// When segments fail or ffmpeg errors, still make an output from the
+    /// segments that did download and keep the temp folder for inspection
+    #[clap(long, conflicts_with_all = ["no_store", "in_memory"])]
+    output_on_failure: bool,
+
+    /// Give up on the whole run after this long (e.g. 90m or 2h), with exit
+    /// code 8. With --output-on-failure, the segments downloaded by then are
+    /// still made into the output. A running ffmpeg mux is not cut short
+    #[clap(long, value_name = "DURATION", value_parser = wait::parse_duration)]
+    deadline: Option<Duration>,
+
+    /// Don't check the muxed output with ffprobe (its streams, and a duration
+    /// close to the playlist's) before removing the temp folder
+    #[clap(long)]
+    no_verify_output: bool,
+
+    #[clap(flatten)]
+    retry: RetryArgs,
+
+    /// Maximum number of retries across all segments of the run
+    #[clap(long)]
+    max_total_retries: Option<usize>,
+
+    /// Abort after this many consecutive segments fail with the same kind of error
+    #[clap(
+        long,
+        default_value_t = 10,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    breaker_consecutive: usize,
+
+    /// Number of recent segments considered by --breaker-percent
+    #[clap(long, default_value_t = 50)]
+    breaker_window: usize,
+
+    /// Abort when this percentage of the recent segments failed with the same kind of error
+    #[clap(long, default_value_t = 90.0)]
+    breaker_percent: f64,
+
+    /// Log every retry attempt and list per-segment retry counts at the end
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// If another run is already downloading the same URL to the same output, wait for it
+    #[clap(long)]
+    wait: bool,
+
+    /// Write a debug-level log of the run to this file ({date} and {name} are expanded)
+    #[clap(long, value_name = "PATH")]
+    log_file: Option<String>,
+
+    /// Also produce a low frame rate preview (e.g. 1 fps) next to the output
+    #[clap(long, value_name = "FPS")]
+    preview_fps: Option<f64>,
+
+    /// Once the output is made, cut off a black, silent slate at its start:
+    /// blackdetect and silencedetect look at the first five minutes, and the
+    /// output is cut with a stream copy from the keyframe before the program.
+    /// Nothing is cut if the picture and sound disagree on where that is
+    #[clap(
+        long,
+        conflicts_with_all = ["no_store", "in_memory", "upload_cmd", "bundle", "flat_output"]
+    )]
+    trim_leading_black: bool,
+
+    /// Share of the brightness range below which a pixel counts as black,
+    /// for --trim-leading-black
+    #[clap(long, value_name = "RATIO", default_value_t = 0.1, requires = "trim_leading_black")]
+    black_threshold: f64,
+
+    /// Shortest black (and silent) lead-in --trim-leading-black cuts, in seconds
+    #[clap(long, value_name = "SECONDS", default_value_t = 2.0, requires = "trim_leading_black")]
+    black_min_duration: f64,
+
+    /// Audio level below which --trim-leading-black counts the sound as silent
+    #[clap(
+        long,
+        value_name = "DB",
+        default_value_t = -50.0,
+        allow_negative_numbers = true,
+        requires = "trim_leading_black"
+    )]
+    silence_threshold: f64,
+
+    /// Measure download throughput at several concurrency levels instead of downloading
+    #[clap(long)]
+    benchmark: bool,
+
+    /// Print the master playlist's audio, subtitle and video renditions
+    /// (#EXT-X-MEDIA) instead of downloading
+    #[clap(long, conflicts_with_all = ["benchmark", "quality"])]
+    list_tracks: bool,
+
+    /// Also merge the master playlist's subtitle rendition (the default one,
+    /// or else the first) from its WebVTT segments into this .vtt file
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["benchmark", "list_tracks", "quality"])]
+    save_subtitles: Option<PathBuf>,
+
+    /// Fetch the playlist and probe every segment URL with a HEAD request,
+    /// reporting which are reachable, instead of downloading
+    #[clap(long, conflicts_with_all = ["benchmark", "list_tracks", "quality", "save_subtitles"])]
+    check: bool,
+
+    /// Record how long every segment request (retries included) took to its
+    /// first byte and to its last, and where redirects led, in this CSV file
+    /// (JSON lines for .json or .jsonl)
+    #[clap(long, value_name = "PATH")]
+    timing_report: Option<PathBuf>,
+
+    /// Serve counters and gauges of the run (segments, bytes, speed, HTTP
+    /// responses by host) for Prometheus at http://127.0.0.1:PORT/metrics
+    #[clap(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+
+    /// Print nothing to stdout but the absolute path of the output (one line
+    /// per --quality, in order, leaving out the ones that failed), for
+    /// scripts; status lines go to stderr instead
+    #[clap(long, conflicts_with_all = ["benchmark", "list_tracks", "check", "upload_cmd"])]
+    print_path: bool,
+
+    /// Keep the run's options, playlist and the hashes of the downloaded
+    /// segments in this file, and continue from it when it exists, e.g. on
+    /// another machine with the temp folder copied over (segments that are
+    /// missing or don't match are downloaded again)
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "quality", "no_store", "in_memory", "benchmark", "list_tracks", "check"
+        ]
+    )]
+    project: Option<PathBuf>,
+}
+
+/// Options a continued `--project` may set differently, since they are
+/// about the machine it continues on rather than what is downloaded.
+const MACHINE_OPTIONS: [&str; 12] = [
+    "output",
+    "temp_dir",
+    "log_file",
+    "verbose",
+    "concurrency",
+    "print_path",
+    "metrics_port",
+    "timing_report",
+    "proxy",
+    "proxy_bypass",
+    "doh",
+    "doh_only",
+];
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    match &args.command {
+        Some(Tool::Fetch(fetch)) => return exit_code(run_fetch(fetch).await),
+        Some(Tool::Merge(merge)) => {
+            return exit_code(bundle::merge(&merge.from_bundle, &merge.output, &merge.temp_dir))
+        }
+        None => {}
+    }
+    let args = match args.project.clone() {
+        Some(path) => match open_project(&path, args, &matches) {
+            Ok(args) => args,
+            Err(error) => return exit_code(Err(error)),
+        },
+        None => args,
+    };
+    if args.no_store {
+        privacy::enable_redaction();
+    }
+    fsretry::configure(args.temp_io_retries, args.output_io_retries);
+    if args.print_path {
+        logging::keep_stdout_clean();
+    }
+
+    let log_file = match &args.log_file {
+        Some(template) => match logging::init_file_log(template, &args.output) {
+            Ok(log_file) => Some(log_file),
+            Err(error) => {
+                eprintln!("Error: {:?}", error);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    if args.timing_report.is_some() {
+        timing::enable();
+    }
+    // Shuts down with the run
+    let metrics = match args.metrics_port {
+        Some(port) => match metrics::serve(port).await {
+            Ok(server) => Some(AbortOnDrop(server)),
+            Err(error) => return exit_code(Err(error)),
+        },
+        None => None,
+    };
+
+    let result = match args.deadline {
+        Some(limit) => {
+            deadline::start(limit);
+            tokio::select! {
+                biased;
+                result = run(&args) => result,
+                () = deadline::enforce() => Err(deadline::exceeded()),
+            }
+        }
+        None => run(&args).await,
+    };
+    drop(metrics);
+    if let Err(error) = &result {
+        tracing::error!("{:#}", error);
+    }
+    if let Some(path) = &args.timing_report {
+        match timing::write_report(path) {
+            Ok(()) => logging::print(&format!("Timing report written to {}", path.display())),
+            Err(error) => eprintln!("Warning: {:#}", error),
+        }
+    }
+    if let Some((path, _guard)) = &log_file {
+        logging::print(&format!("Log written to {}", path.display()));
+    }
+    // --quality and the --output tokens print the path of each output made
+    let named = !args.quality.is_empty() || provenance::has_tokens(&args.output);
+    if args.print_path && !named && result.is_ok() {
+        print_output_path(&args);
+    }
+
+    exit_code(result)
+}
+
+/// `--project`: start a project at `path` for this run, or continue the one
+/// there with the options it was started with. Options given again have to
+/// agree with the project's, apart from [`MACHINE_OPTIONS`].
+fn open_project(path: &Path, given: Args, matches: &ArgMatches) -> Result<Args> {
+    let Some(stored) = project::load(path)? else {
+        if given.url.is_none() {
+            return Err(anyhow::anyhow!(
+                "There is no project at {} to continue; give the playlist URL to start one",
+                path.display()
+            )
+            .context(ExitKind::Usage));
+        }
+        let arguments: Vec<_> = std::env::args_os().skip(1).collect();
+        project::start(path, &arguments)?;
+        status!("Started project {}.", path.display());
+        return Ok(given);
+    };
+
+    let program = std::iter::once(String::from("m3u8dl"));
+    let stored_matches = Args::command()
+        .try_get_matches_from(program.chain(stored.arguments.iter().cloned()))
+        .with_context(|| format!("The options kept in {} are invalid", path.display()))
+        .context(ExitKind::Usage)?;
+    let mut args = Args::from_arg_matches(&stored_matches).context(ExitKind::Usage)?;
+    let mut conflicts = Vec::new();
+    for arg in Args::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        if id == "project" || matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let (theirs, ours) = (raw_values(&stored_matches, id), raw_values(matches, id));
+        if theirs == ours {
+            continue;
+        }
+        let name = match arg.get_long() {
+            Some(long) => format!("--{}", long),
+            None => "the playlist URL".to_string(),
+        };
+        if MACHINE_OPTIONS.contains(&id) {
+            status!("Using {} {} on this machine, rather than the project's.", name, ours);
+            override_option(&mut args, &given, id);
+        } else {
+            conflicts.push(format!(
+                "  {}: {} in the project, {} on the command line",
+                name, theirs, ours
+            ));
+        }
+    }
+    if !conflicts.is_empty() {
+        return Err(anyhow::anyhow!(
+            "The command line disagrees with project {}:\n{}\nLeave these options out to \
+             continue with the project's, or start a new project",
+            path.display(),
+            conflicts.join("\n")
+        )
+        .context(ExitKind::Usage));
+    }
+    args.project = Some(path.to_path_buf());
+    project::resume(path, stored);
+    Ok(args)
+}
+
+/// The values an option was given, as typed, or `not set`.
+fn raw_values(matches: &ArgMatches, id: &str) -> String {
+    let values: Vec<String> = matches
+        .get_raw(id)
+        .into_iter()
+        .flatten()
+        .map(|value| value.to_string_lossy().into_owned())
+        .collect();
+    match values.is_empty() {
+        true => "not set".to_string(),
+        false => values.join(" "),
+    }
+}
+
+/// Take one of the [`MACHINE_OPTIONS`] from the command line over the project's.
+fn override_option(args: &mut Args, given: &Args, id: &str) {
+    match id {
+        "output" => args.output = given.output.clone(),
+        "temp_dir" => args.temp_dir = given.temp_dir.clone(),
+        "log_file" => args.log_file = given.log_file.clone(),
+        "verbose" => args.verbose = given.verbose,
+        "concurrency" => args.concurrency = given.concurrency,
+        "print_path" => args.print_path = given.print_path,
+        "metrics_port" => args.metrics_port = given.metrics_port,
+        "timing_report" => args.timing_report = given.timing_report.clone(),
+        "proxy" => args.client.proxy = given.client.proxy.clone(),
+        "proxy_bypass" => args.client.proxy_bypass = given.client.proxy_bypass.clone(),
+        "doh" => args.client.doh = given.client.doh.clone(),
+        "doh_only" => args.client.doh_only = given.client.doh_only,
+        _ => unreachable!("{} isn't one of the machine options", id),
+    }
+}
+
+/// `--print-path`: the absolute path of what the run made on stdout.
+fn print_output_path(args: &Args) {
+    let output = match (&args.flat_output, &args.bundle) {
+        (Some(dir), _) => dir.clone(),
+        (None, Some(bundle)) => bundle.clone(),
+        (None, None) => PathBuf::from(&args.output),
+    };
+    let output = fs::canonicalize(&output)
+        .or_else(|_| std::env::current_dir().map(|dir| dir.join(&output)))
+        .unwrap_or(output);
+    println!("{}", output.display());
+}
+
+fn exit_code(result: Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {}", privacy::scrub(&format!("{:?}", error)));
+            ExitCode::from(exit::code(&error))
+        }
+    }
+}
+
+impl Args {
+    /// How `--compress` encodes the video, or `None` to copy the streams. A
+    /// `--target-size` bitrate is only known once the segments are in.
+    fn encoding(&self) -> Option<Encoding> {
+        match (self.compress, self.video_bitrate) {
+            (false, _) => None,
+            (true, Some(bitrate)) => Some(Encoding::Bitrate(bitrate)),
+            (true, None) => Some(Encoding::Quality),
+        }
+    }
+
+    /// What `--trim-leading-black` counts as a black, silent lead-in, or
+    /// `None` without it.
+    fn trim_thresholds(&self) -> Option<trim::Thresholds> {
+        self.trim_leading_black.then_some(trim::Thresholds {
+            pixel: self.black_threshold,
+            min_duration: self.black_min_duration,
+            silence_db: self.silence_threshold,
+        })
+    }
+
+    /// The playlist URL, which is only missing when a subcommand runs instead.
+    fn url(&self) -> &str {
+        self.url
+            .as_deref()
+            .expect("clap requires a URL when there is no subcommand")
+    }
+}
+
+fn client_options(args: &Args) -> http::ClientOptions {
+    args.client.options(args.retry.timeout, args.verbose)
+}
+
+impl ClientArgs {
+    fn options(&self, timeout: Option<f64>, verbose: bool) -> http::ClientOptions {
+        http::ClientOptions {
+            http_version: match self.http2_prior_knowledge {
+                true => Some(http::HttpVersion::Http2),
+                false => self.http_version,
+            },
+            pool_idle_timeout: self.pool_idle_timeout.map(Duration::from_secs),
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+            timeout: timeout.map(Duration::from_secs_f64),
+            proxy: self.proxy.clone(),
+            proxy_bypass: self.proxy_bypass.clone(),
+            verbose,
+            headers: self.headers.clone(),
+            doh: self.doh.clone().map(|endpoint| doh::DohOptions {
+                endpoint,
+                only: self.doh_only,
+            }),
+        }
+    }
+
+    fn validate_proxy(&self) -> Result<()> {
+        match &self.proxy {
+            Some(proxy) => {
+                http::validate_proxy(proxy, &self.proxy_bypass).context(ExitKind::Usage)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+async fn run(args: &Args) -> Result<()> {
+    args.client.validate_proxy()?;
+
+    if args.benchmark {
+        let client = http::build_client(client_options(args))?;
+        return benchmark::run(args.url(), args.base_url.as_ref(), client).await;
+    }
+    if args.list_tracks {
+        let client = http::build_client(client_options(args))?;
+        return tracks::list(&client, args.url()).await;
+    }
+    if args.check {
+        let client = http::build_client(client_options(args))?;
+        let preferences = VariantPreferences {
+            pin_file: args.pin_variant_file.as_deref(),
+            min_quality: args.min_quality,
+            max_quality: args.max_quality,
+            exclude: &[],
+        };
+        return check::run(
+            &client,
+            args.url(),
+            args.base_url.as_ref(),
+            preferences,
+            args.scan_page,
+            args.concurrency,
+        )
+        .await;
+    }
+
+    if !args.quality.is_empty() {
+        return run_qualities(args).await;
+    }
+    // The variant a download gets is only known from its playlist
+    if provenance::has_tokens(&args.output) {
+        let named = name_output(args).await?;
+        let outcome = Box::pin(run(&named)).await;
+        if args.print_path && outcome.is_ok() {
+            print_output_path(&named);
+        }
+        return outcome;
+    }
+
+    if args.split_on_discontinuity && !args.output.contains("{part}") {
+        return Err(anyhow::anyhow!(
+            "--output needs a {{part}} token to name each part of --split-on-discontinuity, \
+             e.g. show-{{part}}.mp4"
+        )
+        .context(ExitKind::Usage));
+    }
+
+    let _lock = lock::RunLock::acquire(args.url(), &args.output, args.wait).await?;
+
+    if !args.force && completion::is_complete(&args.output, args.url()) {
+        status!(
+            "{} is already downloaded, skipping (use --force to download again).",
+            args.output
+        );
+        return Ok(());
+    }
+
+    // Find out now, rather than after the download, if ffmpeg can't do the job
+    if !args.no_remux && args.flat_output.is_none() && args.bundle.is_none() {
+        let ffmpeg = Capabilities::probe("ffmpeg")?;
+        ffmpeg.check("ffmpeg", &ffmpeg_requirements(args))?;
+    }
+    check_output_paths(args).context(ExitKind::Usage)?;
+
+    if let Some(path) = &args.save_subtitles {
+        let client = http::build_client(client_options(args))?;
+        subtitles::save(&client, args.url(), path).await?;
+    }
+
+    if args.no_store || args.in_memory {
+        return run_streaming(args).await;
+    }
+
+    // Usage
+    let cleanup = Cleanup::create(&args.temp_dir)?;
+    // The project is continued from the segments in the temp folder
+    if project::active() {
+        cleanup.keep_until_done();
+    }
+    let (playlist, failures) = download_with_fallback(args, &cleanup).await?;
+    let segments = &playlist.segments;
+    report_missing_ranges(segments, &failures);
+
+    if let Some(dir) = &args.flat_output {
+        let init_sections = fetch_init_sections(args, &playlist, &cleanup).await?;
+        let written = flat::write(dir, &playlist, &args.temp_dir, &failures, &init_sections)?;
+        status!("Wrote {} segments and index.json to '{}'.", written, dir.display());
+        cleanup.done();
+        return Ok(());
+    }
+    // Failures that --ignore-errors didn't sign off on still fail the run, as
+    // does stopping at the deadline
+    let salvaged = deadline::salvaging()
+        || (args.output_on_failure && !args.ignore_errors && !failures.is_empty());
+    if salvaged {
+        cleanup.keep();
+    }
+
+    let produced = async {
+        let init_sections = fetch_init_sections(args, &playlist, &cleanup).await?;
+        let listed = if init_sections.is_empty() {
+            create_file_list(&args.temp_dir, segments, args.sort, args.segment_ext.as_deref())?
+        } else {
+            create_region_list(&args.temp_dir, &playlist, &init_sections, &cleanup)?
+        };
+
+        if let Some(bundle) = &args.bundle {
+            write_bundle(args, bundle, &playlist, &failures, &listed)?;
+            return Ok((listed, 0.0));
+        }
+        if args.split_on_discontinuity {
+            mux_parts(args, &cleanup, segments, &failures).await?;
+            return Ok((listed, 0.0));
+        }
+        let input = ConcatInput::new(args.concat_method, "file_list.txt", &listed);
+        let remuxed = args.no_remux && join_fmp4(args, &playlist, &init_sections)?;
+        if !remuxed {
+            metrics::set_phase("muxing");
+            mux_with_ffmpeg(args, &cleanup, segments, &failures, &listed, &input).await?;
+        }
+        // A FIFO's reader has had the output already
+        let output = Path::new(&args.output);
+        let trimmed = match args.trim_thresholds() {
+            Some(thresholds) if !is_fifo(output) => {
+                metrics::set_phase("trimming the lead-in");
+                trim::trim_leading_black(output, &thresholds)?
+            }
+            _ => 0.0,
+        };
+
+        if let Some(fps) = args.preview_fps {
+            let preview_file = preview_path(&args.output);
+            execute_preview_command(&input, &preview_file, fps)?;
+        }
+        Ok::<_, anyhow::Error>((listed, trimmed))
+    }
+    .await;
+    let (listed, trimmed) = match produced {
+        Ok(produced) => produced,
+        Err(error) if error.downcast_ref::<watchdog::Stalled>().is_some() => {
+            cleanup.keep();
+            status!(
+                "Keeping temp folder '{}' so the segments can be muxed again.",
+                args.temp_dir
+            );
+            return Err(error);
+        }
+        Err(error) => {
+            if args.output_on_failure {
+                cleanup.keep();
+                status!("Keeping temp folder '{}' for inspection.", args.temp_dir);
+            }
+            return Err(error);
+        }
+    };
+
+    // An uploaded or bundled output isn't on disk to check, and parts are
+    // checked as they are made
+    let single_output =
+        args.upload_cmd.is_none() && args.bundle.is_none() && !args.split_on_discontinuity;
+    if !args.no_verify_output && single_output {
+        let source = listed.first().and_then(|path| codecs::probe(path));
+        let expected_duration = output_duration(segments, &failures) - trimmed;
+        let output = Path::new(&args.output);
+        if let Err(error) = verify::check_output(output, source.as_ref(), expected_duration) {
+            cleanup.keep();
+            status!(
+                "Keeping temp folder '{}' so the segments can be muxed again \
+                 (--no-verify-output skips this check).",
+                args.temp_dir
+            );
+            return Err(error.context(ExitKind::Verify));
+        }
+    }
+
+    if salvaged {
+        status!("Keeping temp folder '{}' for inspection.", args.temp_dir);
+        if deadline::salvaging() {
+            return Err(anyhow::anyhow!(
+                "The output was made from the {} of {} segments downloaded before the deadline",
+                segments.len() - failures.len(),
+                segments.len()
+            )
+            .context(ExitKind::Deadline));
+        }
+        return Err(anyhow::anyhow!(
+            "{} of {} segments failed to download; {} was made from the rest",
+            failures.len(),
+            segments.len(),
+            args.output
+        )
+        .context(ExitKind::Segments));
+    }
+
+    // Clean up the temp folder
+    cleanup.done();
+    drop(cleanup);
+
+    record_completion(args, &playlist, &failures)
+}
+
+/// `args` with the tokens of `--output` filled in from the provenance of the
+/// playlist the download is going to get.
+async fn name_output(args: &Args) -> Result<Args> {
+    if args.url() == "-" {
+        return Err(anyhow::anyhow!(
+            "The {{quality}}, {{codec}}, {{bandwidth}} and {{source_host}} tokens of \
+             --output need the playlist's URL rather than a playlist from stdin"
+        )
+        .context(ExitKind::Usage));
+    }
+    let client = http::build_client(client_options(args))?;
+    let phases = Phases::show();
+    let playlist = media_playlist(args.url(), args, &client, &[], &phases).await?;
+    phases.finish();
+    let mut named = args.clone();
+    named.output = Provenance::of(args.url(), &playlist).expand(&args.output);
+    status!("Downloading {} to {}", playlist.url, named.output);
+    Ok(named)
+}
+
+/// Make sure the output and the temp folder can be written before anything is
+/// downloaded, creating the output's parent folders unless `--no-mkdir`.
+fn check_output_paths(args: &Args) -> Result<()> {
+    // An uploaded output never touches the local disk
+    if args.upload_cmd.is_none() {
+        let parent = match (&args.flat_output, Path::new(&args.output).parent()) {
+            (Some(flat_output), _) => flat_output.as_path(),
+            (None, Some(parent)) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if !parent.exists() {
+            anyhow::ensure!(
+                !args.no_mkdir,
+                "The output folder '{}' doesn't exist (leave out --no-mkdir to create it)",
+                parent.display()
+            );
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create output folder '{}'", parent.display()))?;
+            status!("Created output folder '{}'.", parent.display());
+        }
+        check_writable(parent)
+            .with_context(|| format!("The output folder '{}' isn't writable", parent.display()))?;
+    }
+
+    // --no-store, and --in-memory with --no-spill, leave the temp folder alone
+    let memory_only = args.no_store || (args.in_memory && args.no_spill);
+    if !memory_only {
+        // The temp folder itself is only created once the download starts
+        let existing = Path::new(&args.temp_dir)
+            .ancestors()
+            .map(|dir| match dir.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => dir,
+            })
+            .find(|dir| dir.exists())
+            .unwrap_or(Path::new("."));
+        check_writable(existing).with_context(|| {
+            format!("The temp folder '{}' can't be written", args.temp_dir)
+        })?;
+    }
+    Ok(())
+}
+
+/// Create and remove a probe file in `dir`, which fails on a read-only mount
+/// or a folder without write permission.
+fn check_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".m3u8dl-write-test-{}", std::process::id()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+/// `--quality`: download each chosen variant of the master playlist as a run
+/// of its own, with its own output and temp folder. The variants share the
+/// HTTP client, and segments they have in common are only downloaded once.
+async fn run_qualities(args: &Args) -> Result<()> {
+    if args.quality.len() > 1 && !args.output.contains("{quality}") {
+        return Err(anyhow::anyhow!(
+            "--output needs a {{quality}} token to name the output of each --quality, \
+             e.g. show-{{quality}}.mp4"
+        )
+        .context(ExitKind::Usage));
+    }
+    if args.url() == "-" {
+        return Err(anyhow::anyhow!(
+            "--quality needs the master playlist's URL rather than a playlist from stdin"
+        )
+        .context(ExitKind::Usage));
+    }
+    let master_url = Url::parse(args.url()).context(ExitKind::Usage)?;
+    let client = http::build_client(client_options(args))?;
+    let variants = playlist::fetch_variants(&client, &master_url)
+        .await
+        .context(ExitKind::Playlist)?;
+
+    let mut chosen = Vec::new();
+    for &quality in &args.quality {
+        let variant = quality.pick(&variants).with_context(|| {
+            let available: Vec<String> =
+                variants.iter().map(|variant| format!("  {}", variant)).collect();
+            format!(
+                "No variant matches --quality {}. Available variants:\n{}",
+                quality.label(),
+                available.join("\n")
+            )
+        });
+        chosen.push((quality.label(), variant.context(ExitKind::Usage)?.clone()));
+    }
+
+    // Each variant's temp folder goes once its output is made, so shared
+    // segments are kept in a folder of their own until every variant is done
+    let shared_dir = format!("{}-shared", args.temp_dir);
+    let shared = Cleanup::create(&shared_dir)?;
+    reuse::enable(Path::new(&shared_dir));
+    let mut outcomes = Vec::new();
+    for (number, (label, variant)) in chosen.iter().enumerate() {
+        status!(
+            "[{}/{}] Downloading the {} variant ({})",
+            number + 1,
+            chosen.len(),
+            label,
+            variant
+        );
+        let mut variant_args = args.clone();
+        variant_args.quality = Vec::new();
+        variant_args.url = Some(variant.url.to_string());
+        variant_args.base_url = None;
+        let named = Provenance::new(args.url(), &variant.url, Some(variant));
+        variant_args.output = named.expand(&args.output.replace("{quality}", label));
+        provenance::choose_variant(Some((args.url(), variant)));
+        variant_args.temp_dir = format!("{}-{}", args.temp_dir, label);
+        let outcome = Box::pin(run(&variant_args)).await;
+        if args.print_path && outcome.is_ok() {
+            print_output_path(&variant_args);
+        }
+        outcomes.push((variant_args.output, outcome));
+    }
+    provenance::choose_variant(None);
+    for name in reuse::kept_files() {
+        shared.expect(name);
+    }
+    drop(shared);
+
+    status!("Outputs:");
+    let mut first_error = None;
+    for (output, outcome) in outcomes {
+        match outcome {
+            Ok(()) => status!("  {}: done", output),
+            Err(error) => {
+                status!("  {}: failed ({:#})", output, error);
+                first_error.get_or_insert(error.context(format!("Failed to make {}", output)));
+            }
+        }
+    }
+    match first_error {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// The ffmpeg components the run's options call for.
+fn ffmpeg_requirements(args: &Args) -> Vec<Requirement> {
+    let mut requirements = Vec::new();
+    if args.no_store || args.in_memory {
+        let needed_by = if args.no_store { "--no-store" } else { "--in-memory" };
+        requirements.push(Requirement::new(Component::Demuxer, "mpegts", needed_by));
+    } else if args.concat_method == ConcatMethod::Demuxer {
+        requirements.push(Requirement::new(Component::Demuxer, "concat", "joining the segments"));
+    }
+    if args.compress {
+        for encoder in ["libx264", "aac"] {
+            requirements.push(
+                Requirement::new(Component::Encoder, encoder, "--compress")
+                    .or_else("leave out --compress to copy the streams as they are"),
+            );
+        }
+    }
+    if args.preview_fps.is_some() {
+        requirements.push(
+            Requirement::new(Component::Encoder, "libx264", "--preview-fps")
+                .or_else("leave out --preview-fps"),
+        );
+    }
+    match args.format {
+        Some(format) => {
+            requirements.push(Requirement::new(Component::Muxer, format.muxer(), "--format"))
+        }
+        None => {
+            if let Some(muxer) = inferred_muxer(&args.output) {
+                requirements.push(Requirement::new(Component::Muxer, muxer, "the output file"));
+            }
+        }
+    }
+    requirements
+}
+
+/// The muxer ffmpeg picks for an output file name, for the common extensions.
+fn inferred_muxer(output_file: &str) -> Option<&'static str> {
+    let ext = Path::new(output_file).extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "mp4" | "m4v" => Some("mp4"),
+        "m4a" => Some("ipod"),
+        "mov" => Some("mov"),
+        _ => upload::streamable_format(output_file),
+    }
+}
+
+/// Whether the output is an MP4-family file: `--format mp4`, or an mp4,
+/// m4v, m4a or mov name when no format is given.
+fn mp4_output(format: Option<OutputFormat>, output_file: &str) -> bool {
+    match format {
+        Some(format) => format == OutputFormat::Mp4,
+        None => matches!(inferred_muxer(output_file), Some("mp4" | "ipod" | "mov")),
+    }
+}
+
+/// Codec-specific ffmpeg arguments for the listed segments, from ffprobe's
+/// view of the first one, printing any warnings about them. `compress` is
+/// whether the video is re-encoded rather than copied.
+fn source_tuning(args: &Args, listed: &[PathBuf], compress: bool) -> Tuning {
+    let media = listed.first().and_then(|path| codecs::probe(path));
+    let tuning = match &media {
+        Some(media) => {
+            if args.verbose {
+                status!("First segment streams: {}", media);
+            } else {
+                tracing::debug!("First segment streams: {}", media);
+            }
+            media.tuning(mp4_output(args.format, &args.output), compress)
+        }
+        None => Tuning::default(),
+    };
+    for warning in &tuning.warnings {
+        status!("Warning: {}", warning);
+    }
+    tuning
+}
+
+/// `--split-on-discontinuity`: mux each discontinuity group of `segments`
+/// into an output of its own, numbering the parts made in {part}, and list
+/// them at the end. Each part is checked as it is made.
+async fn mux_parts(
+    args: &Args,
+    cleanup: &Cleanup,
+    segments: &[Segment],
+    failures: &[SegmentFailure],
+) -> Result<()> {
+    let groups = playlist::discontinuity_groups(segments);
+    let min_duration = args.min_part_duration.unwrap_or_default().as_secs_f64();
+    let mut parts = Vec::new();
+    for (number, group) in groups.iter().enumerate() {
+        let part_segments = &segments[group.clone()];
+        let part_failures: Vec<SegmentFailure> = failures
+            .iter()
+            .filter(|failure| group.contains(&failure.index))
+            .cloned()
+            .collect();
+        let duration = output_duration(part_segments, &part_failures);
+        if duration < min_duration {
+            status!(
+                "Leaving out group {} of {} ({}, {:.1}s): under --min-part-duration.",
+                number + 1,
+                groups.len(),
+                segment_span(group),
+                duration
+            );
+            continue;
+        }
+        let files = downloaded_segments(&args.temp_dir, part_segments);
+        if files.is_empty() {
+            status!(
+                "Leaving out group {} of {}: none of its segments downloaded.",
+                number + 1,
+                groups.len()
+            );
+            continue;
+        }
+        let mut part_args = args.clone();
+        part_args.output = args.output.replace("{part}", &format!("{:02}", parts.len() + 1));
+        status!(
+            "Muxing group {} of {} ({}, {:.1}s) into {}",
+            number + 1,
+            groups.len(),
+            segment_span(group),
+            duration,
+            part_args.output
+        );
+        let listed = write_file_list(files)?;
+        let input = ConcatInput::new(args.concat_method, "file_list.txt", &listed);
+        mux_with_ffmpeg(&part_args, cleanup, part_segments, &part_failures, &listed, &input)
+            .await?;
+        if !args.no_verify_output {
+            let source = listed.first().and_then(|path| codecs::probe(path));
+            let output = Path::new(&part_args.output);
+            if let Err(error) = verify::check_output(output, source.as_ref(), duration) {
+                cleanup.keep();
+                status!(
+                    "Keeping temp folder '{}' so the segments can be muxed again \
+                     (--no-verify-output skips this check).",
+                    args.temp_dir
+                );
+                return Err(error.context(ExitKind::Verify));
+            }
+        }
+        parts.push((part_args.output, duration));
+    }
+    if parts.is_empty() {
+        return Err(anyhow::anyhow!(
+            "None of the {} discontinuity groups made a part; lower --min-part-duration",
+            groups.len()
+        )
+        .context(ExitKind::Segments));
+    }
+
+    status!("Parts:");
+    for (output, duration) in &parts {
+        status!("  {} ({})", output, playlist::format_timestamp(*duration));
+    }
+    Ok(())
+}
+
+/// "segments 4-9", or "segment 4" for a group of one.
+fn segment_span(group: &std::ops::Range<usize>) -> String {
+    match group.len() {
+        1 => format!("segment {}", group.start),
+        _ => format!("segments {}-{}", group.start, group.end - 1),
+    }
+}
+
+/// Probe a downloaded segment of each discontinuity group for its frame
+/// rate, and warn when a stream copy would change frame rate part way. The
+/// `--normalize-fps` rate is returned when the video needs re-encoding at it.
+fn frame_rate_normalization<'a>(args: &'a Args, segments: &[Segment]) -> Option<&'a FrameRate> {
+    let groups = playlist::discontinuity_groups(segments);
+    if groups.len() < 2 && args.normalize_fps.is_none() {
+        return None;
+    }
+    let samples: Vec<(usize, PathBuf)> = groups
+        .iter()
+        .filter_map(|group| {
+            segments[group.clone()].iter().find_map(|segment| {
+                let path = Path::new(&args.temp_dir).join(segment_filename(&segment.url())?);
+                path.is_file().then_some((group.start, path))
+            })
+        })
+        .collect();
+    let rates = fps::probe_groups(&samples);
+    let variable = fps::is_variable(&rates);
+    match &args.normalize_fps {
+        Some(rate) if !rates.is_empty() && !variable && fps::all_at(&rates, rate) => {
+            status!("The video is at {} fps throughout already; copying it.", rate);
+            None
+        }
+        Some(rate) => {
+            if variable {
+                let changes = fps::describe(&rates);
+                status!("The frame rate changes across discontinuities: {}.", changes);
+            }
+            status!("Re-encoding the video at {} fps (--normalize-fps).", rate);
+            Some(rate)
+        }
+        None if variable => {
+            status!(
+                "Warning: the frame rate changes across discontinuities ({}), so copying the \
+                 video makes a variable frame rate file that some editors mishandle; \
+                 --normalize-fps RATE re-encodes it at one rate.",
+                fps::describe(&rates)
+            );
+            None
+        }
+        None => None,
+    }
+}
+
+/// Mux the `listed` segment files (and any external audio) into the output
+/// with ffmpeg, tuning its arguments to the codecs found in the first one.
+async fn mux_with_ffmpeg(
+    args: &Args,
+    cleanup: &Cleanup,
+    segments: &[Segment],
+    failures: &[SegmentFailure],
+    listed: &[PathBuf],
+    input: &ConcatInput,
+) -> Result<()> {
+    let normalize_fps = frame_rate_normalization(args, segments);
+    let mut tuning = source_tuning(args, listed, args.compress || normalize_fps.is_some());
+    if let Some(rate) = normalize_fps {
+        tuning.args.extend([
+            "-vf".to_string(),
+            format!("fps={}", rate),
+            "-r".to_string(),
+            rate.to_string(),
+        ]);
+    }
+
+    let mut audio = AudioMix {
+        tracks: Vec::new(),
+        keep_original: args.keep_original_audio_and_video,
+    };
+    if !args.external_audio.is_empty() {
+        let client = http::build_client(client_options(args))?;
+        for (track, source) in args.external_audio.iter().enumerate() {
+            let (language, source) = audio::split_language(source);
+            let path = audio::fetch_external_audio(&client, source, track, &args.temp_dir).await?;
+            if let Ok(relative) = path.strip_prefix(&args.temp_dir) {
+                cleanup.expect(relative);
+            }
+            audio::check_duration(&path, output_duration(segments, failures));
+            audio.tracks.push(AudioTrack {
+                path,
+                language: language.map(str::to_string),
+            });
+        }
+    }
+
+    // Execute the ffmpeg command
+    let input_bytes = listed
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    watchdog::configure(args.remux_stall_timeout, input_bytes);
+    let mut video_encoding = args.encoding();
+    if normalize_fps.is_some() {
+        video_encoding = video_encoding.or(Some(Encoding::Quality));
+    }
+    let mut codec_args = tuning.args.clone();
+    if let Some(provenance) = provenance::current().filter(|_| !args.no_provenance) {
+        codec_args.extend(["-metadata".to_string(), format!("comment={}", provenance.comment())]);
+    }
+    if let Some(target_size) = args.target_size {
+        let audio_streams = match audio.tracks.len() {
+            0 => 1,
+            tracks => tracks + usize::from(audio.keep_original),
+        };
+        let duration = output_duration(segments, failures);
+        let bitrate = encoding::bitrate_for_size(target_size, duration, audio_streams)
+            .context(ExitKind::Usage)?;
+        video_encoding = Some(Encoding::Bitrate(bitrate));
+        for file in encoding::passlog_files() {
+            cleanup.expect(file);
+        }
+        let passlog = Path::new(&args.temp_dir).join(encoding::PASSLOG);
+        status!(
+            "Encoding the video at {} kbit/s to fit {}; pass 1 of 2 (analysis)...",
+            bitrate / 1000,
+            indicatif::HumanBytes(target_size)
+        );
+        execute_first_pass(input, bitrate, &passlog, &tuning.args)?;
+        status!("Pass 2 of 2 (encoding)...");
+        codec_args.extend(["-pass".to_string(), "2".to_string(), "-passlogfile".to_string()]);
+        codec_args.push(passlog.to_string_lossy().into_owned());
+    }
+    match &args.upload_cmd {
+        Some(upload_cmd) => upload::mux_and_upload(
+            upload_cmd,
+            input,
+            &args.output,
+            args.format,
+            video_encoding,
+            &audio,
+            &codec_args,
+        )?,
+        None => execute_ffmpeg_command(
+            input,
+            &args.output,
+            args.format,
+            video_encoding,
+            &audio,
+            &codec_args,
+        )?,
+    }
+
+    if let Some(target_size) = args.target_size {
+        encoding::remove_passlog(Path::new(&args.temp_dir));
+        let size = fs::metadata(&args.output).map_or(0, |metadata| metadata.len());
+        if size > target_size {
+            status!(
+                "Warning: {} is {}, over the --target-size of {}.",
+                args.output,
+                indicatif::HumanBytes(size),
+                indicatif::HumanBytes(target_size)
+            );
+        }
+    }
+    match video_encoding {
+        Some(Encoding::Bitrate(bitrate)) => status!(
+            "Video compressed using libx264 at {} kbit/s and aac audio.",
+            bitrate / 1000
+        ),
+        Some(Encoding::Quality) => status!("Video compressed using libx264 and aac audio."),
+        None => {}
+    }
+    Ok(())
+}
+
+/// `--bundle`: pack the `listed` segment files and the ffmpeg arguments that
+/// would have muxed them into a tar, for `m3u8dl merge` to finish.
+fn write_bundle(
+    args: &Args,
+    path: &Path,
+    playlist: &MediaPlaylist,
+    failures: &[SegmentFailure],
+    listed: &[PathBuf],
+) -> Result<()> {
+    let tuning = source_tuning(args, listed, args.compress);
+    let audio = AudioMix {
+        tracks: Vec::new(),
+        keep_original: false,
+    };
+    let input = ConcatInput::List(bundle::LIST.to_string());
+    let mut command = ffmpeg_command(&input, args.encoding(), &audio, &tuning.args);
+    if let Some(format) = args.format {
+        command.arg("-f").arg(format.muxer());
+    }
+    command.arg(bundle::OUTPUT);
+    let recipe = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+
+    let duration = output_duration(&playlist.segments, failures);
+    let bundled = bundle::write(path, &playlist.url, duration, listed, recipe)?;
+    status!(
+        "Bundled {} segments into {}; mux them with: m3u8dl merge --from-bundle {} -o {}",
+        bundled,
+        path.display(),
+        path.display(),
+        args.output
+    );
+    Ok(())
+}
+
+/// Download the playlist's initialization sections into the temp folder.
+async fn fetch_init_sections(
+    args: &Args,
+    playlist: &MediaPlaylist,
+    cleanup: &Cleanup,
+) -> Result<Vec<PathBuf>> {
+    if playlist.init_sections.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let client = http::build_client(client_options(args))?;
+    let mut paths = Vec::new();
+    for (number, section) in playlist.init_sections.iter().enumerate() {
+        let response = client.get(section.url.clone()).send().await?;
+        http::record_response(&response);
+        let init = response
+            .error_for_status()?
+            .bytes()
+            .await
+            .context("Failed to download initialization section")?;
+        let name = format!("init-{}.mp4", number + 1);
+        cleanup.expect(&name);
+        let path = Path::new(&args.temp_dir).join(name);
+        fs::write(&path, init).context("Failed to write initialization section to file")?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Join an fMP4 playlist's initialization section and fragments into the
+/// output without ffmpeg, returning false when ffmpeg has to do it after all.
+fn join_fmp4(args: &Args, playlist: &MediaPlaylist, init_sections: &[PathBuf]) -> Result<bool> {
+    let init_path = match init_sections {
+        [] => {
+            status!(
+                "--no-remux only applies to fMP4 playlists (with #EXT-X-MAP); muxing with ffmpeg."
+            );
+            return Ok(false);
+        }
+        [init_path] => init_path,
+        _ => {
+            status!(
+                "--no-remux can't join {} initialization sections into one mp4; \
+                 muxing with ffmpeg.",
+                init_sections.len()
+            );
+            return Ok(false);
+        }
+    };
+    if args.format.is_some_and(|format| format != OutputFormat::Mp4) {
+        status!("--no-remux only writes mp4; muxing with ffmpeg.");
+        return Ok(false);
+    }
+
+    let fragments = downloaded_segments(&args.temp_dir, &playlist.segments);
+    match fmp4::join(init_path, &fragments, Path::new(&args.output))? {
+        fmp4::Remux::Written => {
+            status!("Successfully created {} without ffmpeg", args.output);
+            Ok(true)
+        }
+        fmp4::Remux::Unsupported(reason) => {
+            status!("Can't join the fragments without ffmpeg ({}); muxing with ffmpeg.", reason);
+            Ok(false)
+        }
+    }
+}
+
+/// Seconds of content left once the skipped segments are dropped.
+fn output_duration(segments: &[Segment], failures: &[SegmentFailure]) -> f64 {
+    segments.iter().map(|s| s.duration).sum::<f64>()
+        - failures.iter().map(|f| f.duration).sum::<f64>()
+}
+
+/// Write the completion record, unless the output is something that can't be
+/// recognised on a re-run (an uploaded or piped output, or a playlist from stdin)
+/// or the playlist says not to cache it.
+fn record_completion(
+    args: &Args,
+    playlist: &MediaPlaylist,
+    failures: &[SegmentFailure],
+) -> Result<()> {
+    let output = Path::new(&args.output);
+    if args.url() == "-" || args.no_store || !reuse::caching() || !output.is_file() {
+        return Ok(());
+    }
+    completion::write(
+        &args.output,
+        args.url(),
+        playlist.url.as_str(),
+        output_duration(&playlist.segments, failures),
+        provenance::current(),
+    )
+}
+
+/// `fetch`: download one URL the way segments are downloaded, so that a
+/// broken stream can be inspected one response at a time.
+async fn run_fetch(args: &FetchArgs) -> Result<()> {
+    args.client.validate_proxy()?;
+    let client = http::build_client(args.client.options(args.retry.timeout, args.verbose))?;
+
+    if args.no_validate {
+        let options = SegmentRequest::new(1, None, args.client.segment_accept.clone(), u64::MAX);
+        let response = segment_request(&client, &args.url, &options).send().await?;
+        http::record_response(&response);
+        let status = response.status();
+        let content = response.bytes().await.context("Failed to read the response")?;
+        fs::write(&args.output, &content).context("Failed to write the response to file")?;
+        status!(
+            "Wrote {} bytes (HTTP {}) to {}",
+            content.len(),
+            status,
+            args.output.display()
+        );
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let retry_deadline = args.retry.retry_deadline.map(Duration::from_secs_f64);
+    let mut retries = 0;
+    let (content, verification) = loop {
+        let timeout = args.retry.timeout.map(|timeout| {
+            Duration::from_secs_f64(timeout)
+                .mul_f64(args.retry.timeout_retries_increase.powi(retries as i32))
+        });
+        let mut options = SegmentRequest::new(
+            retries + 1,
+            timeout,
+            args.client.segment_accept.clone(),
+            args.retry.max_segment_size,
+        );
+        let attempt = fetch_segment(
+            &args.url,
+            &client,
+            &mut options,
+            args.retry.min_segment_size,
+            None,
+            None,
+        )
+        .await;
+        match attempt {
+            Err(error)
+                if retries < args.retry.max_retries
+                    && before_deadline(started, retries + 1, retry_deadline) =>
+            {
+                retries += 1;
+                let delay = retry_backoff(retries);
+                if args.verbose {
+                    status!(
+                        "Attempt {} failed{} ({}), retrying in {:?}",
+                        retries,
+                        options.redirect_note(),
+                        error,
+                        delay
+                    );
+                }
+                tokio::time::sleep(delay).await;
+            }
+            result => break result.context(ExitKind::Segments)?,
+        }
+    };
+
+    fs::write(&args.output, &content).context("Failed to write the response to file")?;
+    let checked = match verification {
+        Verification::Size => "checked against its Content-Length",
+        _ => "no Content-Length to check it against",
+    };
+    status!(
+        "Wrote {} bytes to {} ({}, {} retries)",
+        content.len(),
+        args.output.display(),
+        checked,
+        retries
+    );
+    Ok(())
+}
+
+/// `--no-store` and `--in-memory`: mux straight from memory through ffmpeg's
+/// stdin. With `--no-store`, no segment ever touches the disk.
+async fn run_streaming(args: &Args) -> Result<()> {
+    let mut command = ffmpeg_pipe_command(args.encoding());
+    if let Some(format) = args.format {
+        command.arg("-f").arg(format.muxer());
+    }
+    let mut command = tokio::process::Command::from(command);
+    command.arg(&args.output);
+    tracing::debug!("Running {:?}", command);
+
+    let mut ffmpeg = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|error| exit::spawn_error(error, "ffmpeg command"))?;
+    let stdin = ffmpeg.stdin.take().context("Failed to open ffmpeg's stdin")?;
+
+    // Spilled segments go to the temp folder, which is cleaned up as usual
+    let cleanup = match args.in_memory && !args.no_spill {
+        true => Some(Cleanup::create(&args.temp_dir)?),
+        false => None,
+    };
+    let sink = if args.in_memory {
+        let spill = cleanup.as_ref().map(|cleanup| (cleanup, args.temp_dir.as_str()));
+        SegmentSink::Store(stdin, SegmentStore::new(args.memory_limit * 1024 * 1024, spill))
+    } else {
+        SegmentSink::Pipe(stdin)
+    };
+    let download = download_m3u8(args.url(), sink, args, &[]).await;
+    let output = ffmpeg
+        .wait_with_output()
+        .await
+        .map_err(|error| exit::spawn_error(error, "ffmpeg command"))?;
+
+    let (playlist, failures) = match download {
+        Ok(download) => download,
+        // A broken pipe just means ffmpeg gave up first; its stderr says why
+        Err(error) if output.status.success() => return Err(error),
+        Err(error) => {
+            let error_message = String::from_utf8_lossy(&output.stderr);
+            return Err(error.context(format!("ffmpeg exited early: {}", error_message)));
+        }
+    };
+    report_missing_ranges(&playlist.segments, &failures);
+    drop(cleanup);
+
+    if output.status.success() {
+        status!("Successfully created {}", args.output);
+        record_completion(args, &playlist, &failures)
+    } else {
+        let error_message = String::from_utf8_lossy(&output.stderr);
+        Err(anyhow::anyhow!("Error executing ffmpeg command: {}", error_message)
+            .context(ExitKind::Ffmpeg))
+    }
+}
+
+fn report_missing_ranges(segments: &[Segment], failures: &[SegmentFailure]) {
+    if !failures.is_empty() {
+        status!(
+            "Skipped {} of {} segments:",
+            failures.len(),
+            segments.len()
+        );
+        for range in gaps::missing_ranges(segments, failures) {
+            status!("  {}", range);
+        }
+    }
+}
+
+/// Where downloaded segments go.
+enum SegmentSink<'a> {
+    /// Files in the temp folder, muxed afterwards from the concat list.
+    Folder(&'a Cleanup),
+    /// Written in playlist order straight into ffmpeg's stdin.
+    Pipe(ChildStdin),
+    /// Downloaded in any order and held until they can be written into ffmpeg's stdin.
+    Store(ChildStdin, SegmentStore<'a>),
+}
+
+/// The media playlist to download: the one a continued `--project` was
+/// started on, or else the one fetched from `m3u8_url` (once it is live, with
+/// `--wait-for-stream`).
+async fn media_playlist(
+    m3u8_url: &str,
+    args: &Args,
+    client: &Client,
+    exclude: &[Url],
+    phases: &Phases,
+) -> Result<MediaPlaylist> {
+    if args.wait_for_stream && m3u8_url == "-" {
+        return Err(anyhow::anyhow!(
+            "--wait-for-stream needs the playlist's URL; \
+             a playlist from stdin can't be checked again"
+        )
+        .context(ExitKind::Usage));
+    }
+    // A continued --project downloads the playlist it was started on
+    let snapshot = match exclude.is_empty() {
+        true => project::playlist().context(ExitKind::Usage)?,
+        false => None,
+    };
+    if let Some(playlist) = snapshot {
+        return Ok(playlist);
+    }
+    let fetch = || {
+        playlist::fetch_segments(
+            client,
+            m3u8_url,
+            args.base_url.as_ref(),
+            VariantPreferences {
+                pin_file: args.pin_variant_file.as_deref(),
+                min_quality: args.min_quality,
+                max_quality: args.max_quality,
+                exclude,
+            },
+            args.scan_page,
+            phases,
+        )
+    };
+    let playlist = if args.wait_for_stream {
+        let (timeout, interval) = (args.wait_timeout, args.wait_interval);
+        wait::until_live(m3u8_url, timeout, interval, phases, fetch).await
+    } else {
+        fetch().await
+    }
+    .context(ExitKind::Playlist)?;
+    project::set_playlist(&playlist)?;
+    Ok(playlist)
+}
+
+/// Download every segment of the playlist, returning the parsed segments and,
+/// when `--ignore-errors` is set, the ones that were skipped.
+async fn download_m3u8(
+    m3u8_url: &str,
+    mut sink: SegmentSink<'_>,
+    args: &Args,
+    exclude: &[Url],
+) -> Result<(MediaPlaylist, Vec<SegmentFailure>)> {
+    let output_folder = args.temp_dir.as_str();
+    let client = Arc::new(http::build_client(client_options(args))?);
+
+    let phases = Phases::show();
+    let mut playlist = media_playlist(m3u8_url, args, &client, exclude, &phases).await?;
+    provenance::record(Provenance::of(m3u8_url, &playlist));
+    reuse::set_caching(playlist.allows_caching());
+    if !reuse::caching() {
+        status!(
+            "The playlist says EXT-X-ALLOW-CACHE:NO, so no segment is kept for reuse and no \
+             completion record is written."
+        );
+    }
+    let _saved = project::SaveOnDrop;
+    if args.split_on_discontinuity && !playlist.init_sections.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--split-on-discontinuity works on MPEG-TS playlists, and {} is fMP4",
+            playlist.url
+        )
+        .context(ExitKind::Usage));
+    }
+    if args.skip_first {
+        let skipped = playlist.skip_first().context(ExitKind::Playlist)?;
+        status!("Skipping the first segment ({}).", skipped.uri());
+    }
+    let segments = &playlist.segments;
+    if project::active() {
+        let names = segments.iter().filter_map(|segment| segment_filename(&segment.url()));
+        project::check_segments(Path::new(output_folder), names);
+    }
+
+    let rate_limiter = Arc::new(RateLimiter::new(args.requests_per_second));
+    let probed = match (args.no_probe_first, segments.first()) {
+        (false, Some(first)) => {
+            phases.start("probing the first segment");
+            rate_limiter.wait().await;
+            let mut options = SegmentRequest::new(
+                1,
+                args.retry.timeout.map(Duration::from_secs_f64),
+                args.client.segment_accept.clone(),
+                args.retry.max_segment_size,
+            );
+            probe::probe_first_segment(&client, first, &mut options, args.retry.min_segment_size)
+                .await
+                .context(ExitKind::Segments)?
+                .map(|probed| (first.index, probed))
+        }
+        _ => None,
+    };
+    let first_size = probed.as_ref().map(|(_, probed)| probed.body.len() as u64);
+    if let SegmentSink::Store(_, store) = &sink {
+        store.check_estimate(estimated_size(segments, first_size))?;
+    }
+    // The probe's body is the first segment's first attempt
+    let probed = Arc::new(std::sync::Mutex::new(probed));
+    phases.finish();
+    metrics::set_phase("downloading segments");
+    metrics::set_total_segments(segments.len());
+
+    let cleanup = match &sink {
+        SegmentSink::Folder(cleanup) => Some(*cleanup),
+        SegmentSink::Pipe(_) | SegmentSink::Store(..) => None,
+    };
+    let in_memory = cleanup.is_none();
+
+    // Download each .ts file in parallel with progress bar and ETA
+    let total_segments = segments.len();
+    // Segments already in the temp folder from an interrupted run go
+    // straight through, so resuming doesn't wait for them again
+    let pacer = args.pace.map(|pace| {
+        let total = segments
+            .iter()
+            .filter(|segment| in_memory || !already_downloaded(output_folder, segment))
+            .map(|segment| segment.duration)
+            .sum();
+        status!("Pacing the download at {}.", pace);
+        Pacer::new(pace, total)
+    });
+    let progress = SegmentProgress::new(total_segments).rate_limited(args.requests_per_second);
+    let progress = match &pacer {
+        Some(pacer) => progress.paced(pacer.finishes_at()),
+        None => progress,
+    };
+    let progress = Arc::new(progress);
+    let pb = progress.bar().clone();
+
+    let retry_budget = Arc::new(RetryBudget::new(args.max_total_retries));
+    let checksums = Arc::new(match &args.checksums {
+        Some(source) => Checksums::load(&client, source)
+            .await
+            .context("Failed to load --checksums")?,
+        None => Checksums::default(),
+    });
+    let dedup = args.dedup.then(|| Arc::new(Dedup::default()));
+    let max_retries = args.retry.max_retries;
+    let retry_deadline = args.retry.retry_deadline.map(Duration::from_secs_f64);
+    let min_segment_size = args.retry.min_segment_size;
+    let max_segment_size = args.retry.max_segment_size;
+    let timeout = args.retry.timeout.map(Duration::from_secs_f64);
+    let timeout_increase = args.retry.timeout_retries_increase;
+    let accept = args.client.segment_accept.clone();
+    let verbose = args.verbose;
+
+    // Piped segments have to reach ffmpeg in playlist order, so they are
+    // retried in place; everything else defers its retries until the first
+    // wave is done, so one flaky segment's backoff doesn't hold up a worker
+    let ordered = matches!(sink, SegmentSink::Pipe(_));
+
+    // Download one segment, starting `retries` attempts in. Deferred segments
+    // wait out their backoff first, then keep retrying in place.
+    let download = |segment: Segment, retries: usize, started: Instant| {
+        let url = segment.url();
+        // Everything the segment download may leave in the temp folder
+        if let (Some(cleanup), Some(filename)) = (cleanup, segment_filename(&url)) {
+            cleanup.expect_download(filename);
+        }
+        let client = Arc::clone(&client);
+        let output_folder = output_folder.to_string();
+        let pb = pb.clone();
+        let retry_budget = Arc::clone(&retry_budget);
+        let rate_limiter = Arc::clone(&rate_limiter);
+        let checksums = Arc::clone(&checksums);
+        let dedup = dedup.clone();
+        let accept = accept.clone();
+        let retry_in_place = ordered || retries > 0;
+        let mut probed = {
+            let mut probed = probed.lock().unwrap();
+            match &*probed {
+                Some((index, _)) if retries == 0 && *index == segment.index => {
+                    probed.take().map(|(_, probed)| probed)
+                }
+                _ => None,
+            }
+        };
+        AbortOnDrop(tokio::spawn(async move {
+            let _active = metrics::active();
+            let mut retries = retries;
+            if retries > 0 {
+                tokio::time::sleep(retry_backoff(retries)).await;
+            }
+            let mut redirected;
+            let result = loop {
+                rate_limiter.wait().await;
+                let checksum = checksums.get(&segment);
+                let mut options = SegmentRequest::new(
+                    retries + 1,
+                    timeout.map(|timeout| timeout.mul_f64(timeout_increase.powi(retries as i32))),
+                    accept.clone(),
+                    max_segment_size,
+                );
+                let attempt = if let Some(probed) = probed.take() {
+                    let output_folder = (!in_memory).then_some(output_folder.as_str());
+                    keep_probed(&url, output_folder, probed, min_segment_size, &segment, checksum)
+                        .await
+                } else if in_memory {
+                    fetch_segment(
+                        &url,
+                        &client,
+                        &mut options,
+                        min_segment_size,
+                        segment.predicted_size(),
+                        checksum,
+                    )
+                    .await
+                    .map(|(content, verification)| {
+                        (content.len() as u64, verification, Some(content))
+                    })
+                } else {
+                    download_ts_segment(
+                        &url,
+                        &output_folder,
+                        &client,
+                        &mut options,
+                        min_segment_size,
+                        segment.predicted_size(),
+                        checksum,
+                    )
+                    .await
+                    .map(|(size, verification)| (size, verification, None))
+                };
+                redirected = options.redirect_note();
+                match attempt {
+                    Err(error)
+                        if retry_in_place
+                            && retries < max_retries
+                            && before_deadline(started, retries + 1, retry_deadline)
+                            && retry_budget.take() =>
+                    {
+                        retries += 1;
+                        metrics::retrying();
+                        let delay = retry_backoff(retries);
+                        let message = format!(
+                            "Segment {} attempt {} failed{} ({}), retrying in {:?}",
+                            segment.index, retries, redirected, error, delay
+                        );
+                        tracing::debug!("{}", message);
+                        if verbose {
+                            pb.suspend(|| eprintln!("{}", privacy::scrub(&message)));
+                        }
+                        tokio::time::sleep(delay).await;
+                    }
+                    result => break result,
+                }
+            };
+            if let (Some(dedup), Ok((_, _, None)), Some(filename)) =
+                (&dedup, &result, segment_filename(&url))
+            {
+                let path = Path::new(&output_folder).join(filename);
+                if let Err(error) = dedup.merge(segment.index, &path).await {
+                    tracing::debug!("Not deduplicating segment {}: {:#}", segment.index, error);
+                }
+            }
+            (segment, retries, started, result, redirected)
+        }))
+    };
+
+    // Check for any errors as the downloads complete
+    let mut breaker = CircuitBreaker::new(BreakerConfig {
+        consecutive: args.breaker_consecutive,
+        window: args.breaker_window,
+        percent: args.breaker_percent,
+    });
+    let mut failures = Vec::new();
+    // Segments whose failure the breaker has seen
+    let mut counted = HashSet::new();
+    let mut retried = Vec::new();
+    let mut deferred = Vec::new();
+    let mut deferred_left = 0;
+    let mut first_wave = true;
+    let (mut by_checksum, mut by_size, mut unverified, mut unchanged) = (0, 0, 0, 0);
+    let mut shared = 0;
+    let mut downloaded = 0;
+    let mut finished = HashSet::new();
+    let mut stopped = false;
+    // A variant none of whose segments download is given up for the next
+    // best one, unless --no-variant-fallback (a streamed download can't restart)
+    let may_fall_back = !args.no_variant_fallback && !in_memory && playlist.variant_of.is_some();
+    loop {
+        // Segments are cloned and their URLs resolved only as they are
+        // scheduled, so just the in-flight window is materialized
+        let downloads: Pin<Box<dyn Stream<Item = _> + Send>> = if first_wave {
+            let pacer = pacer.as_ref();
+            Box::pin(
+                stream::iter(segments.iter().cloned())
+                    .then(move |segment| async move {
+                        let paced = in_memory || !already_downloaded(output_folder, &segment);
+                        if let Some(pacer) = pacer.filter(|_| paced) {
+                            pacer.admit(segment.duration).await;
+                        }
+                        segment
+                    })
+                    .map(|segment| download(segment, 0, Instant::now())),
+            )
+        } else {
+            Box::pin(
+                stream::iter(std::mem::take(&mut deferred))
+                    .map(|(segment, retries, started)| download(segment, retries, started)),
+            )
+        };
+        let mut results: Pin<Box<dyn Stream<Item = _> + Send>> = if ordered {
+            Box::pin(downloads.buffered(args.concurrency))
+        } else {
+            Box::pin(downloads.buffer_unordered(args.concurrency))
+        };
+
+        loop {
+            let result = tokio::select! {
+                result = results.next() => result,
+                () = deadline::passed() => {
+                    if !args.output_on_failure {
+                        pb.abandon();
+                        return Err(deadline::exceeded());
+                    }
+                    deadline::salvage();
+                    stopped = true;
+                    None
+                }
+            };
+            let Some(result) = result else {
+                break;
+            };
+            let (segment, retries, started, result, redirected) = result?;
+            // A first attempt that fails counts towards the breaker even when its
+            // retry is deferred, so an outage trips it without trying every
+            // segment; the segment isn't counted again if its retries fail too
+            let mut tripped = None;
+            let result = match result {
+                Err(error)
+                    if first_wave
+                        && !ordered
+                        && retries < max_retries
+                        && before_deadline(started, retries + 1, retry_deadline) =>
+                {
+                    counted.insert(segment.index);
+                    if let Some(summary) = breaker.record_failure(&breaker::error_class(&error)) {
+                        tripped = Some(summary);
+                        Err(error)
+                    } else if !retry_budget.take() {
+                        Err(error)
+                    } else {
+                        let message = format!(
+                            "Segment {} attempt 1 failed{} ({}), deferring its retry",
+                            segment.index, redirected, error
+                        );
+                        tracing::debug!("{}", message);
+                        if verbose {
+                            pb.suspend(|| eprintln!("{}", privacy::scrub(&message)));
+                        }
+                        metrics::retrying();
+                        deferred.push((segment, retries + 1, started));
+                        progress.set_deferred(deferred.len());
+                        continue;
+                    }
+                }
+                result => result,
+            };
+            if !first_wave {
+                deferred_left -= 1;
+                progress.set_deferred(deferred_left);
+            }
+            let size = result.as_ref().ok().map(|(size, ..)| *size);
+            progress.finish_segment(size);
+            metrics::segment_finished(size);
+            finished.insert(segment.index);
+            if retries > 0 {
+                retried.push((segment.index, retries, result.is_ok()));
+            }
+            match result {
+                Ok((_, verification, content)) => {
+                    breaker.record_success();
+                    downloaded += 1;
+                    if let Some(filename) = segment_filename(&segment.url()) {
+                        if project::active() {
+                            let path = Path::new(output_folder).join(&filename);
+                            project::segment_done(&filename, &path);
+                        }
+                    }
+                    match verification {
+                        Verification::Checksum => by_checksum += 1,
+                        Verification::Size => by_size += 1,
+                        Verification::Unverified => unverified += 1,
+                        Verification::Unchanged => unchanged += 1,
+                        Verification::Shared => shared += 1,
+                    }
+                    let streamed = match (&mut sink, content) {
+                        (SegmentSink::Pipe(stdin), Some(content)) => stdin
+                            .write_all(&content)
+                            .await
+                            .context("Failed to stream segment to ffmpeg")
+                            .context(ExitKind::Ffmpeg),
+                        (SegmentSink::Store(stdin, store), Some(content)) => {
+                            store.insert(segment.index, content, stdin).await
+                        }
+                        _ => Ok(()),
+                    };
+                    if let Err(error) = streamed {
+                        pb.abandon();
+                        return Err(error);
+                    }
+                }
+                Err(error)
+                    if args
+                        .retry_different_variant
+                        .is_some_and(|limit| failures.len() + 1 >= limit) =>
+                {
+                    pb.abandon();
+                    return Err(variant_failed(&playlist, failures.len() + 1, error, args));
+                }
+                Err(error)
+                    if !args.ignore_errors
+                        && !args.output_on_failure
+                        && args.retry_different_variant.is_none()
+                        && tripped.is_none() =>
+                {
+                    pb.abandon();
+                    if let Some(changed) =
+                        republished(&client, m3u8_url, &playlist, &error, args).await
+                    {
+                        return Err(error.context(changed).context(ExitKind::Segments));
+                    }
+                    if may_fall_back && downloaded == 0 {
+                        return Err(variant_failed(&playlist, failures.len() + 1, error, args));
+                    }
+                    return Err(error.context(ExitKind::Segments));
+                }
+                Err(error) => {
+                    let tripped = match tripped {
+                        Some(summary) => Some(summary),
+                        // Its first attempt was counted already
+                        None if !counted.insert(segment.index) => None,
+                        None => breaker.record_failure(&breaker::error_class(&error)),
+                    };
+                    if let Some(summary) = tripped {
+                        pb.abandon();
+                        if let Some(changed) =
+                            republished(&client, m3u8_url, &playlist, &error, args).await
+                        {
+                            let error = error.context(summary);
+                            return Err(error.context(changed).context(ExitKind::Segments));
+                        }
+                        if may_fall_back && downloaded == 0 {
+                            let error = error.context(summary);
+                            return Err(variant_failed(&playlist, failures.len() + 1, error, args));
+                        }
+                        if let SegmentSink::Folder(cleanup) = &sink {
+                            cleanup.keep();
+                        }
+                        return Err(anyhow::anyhow!(
+                            "Aborting after a systemic failure: {} ({} segments failed so far, \
+                             last: {}). Downloaded segments are kept in '{}'.",
+                            summary,
+                            failures.len() + deferred.len() + 1,
+                            error,
+                            output_folder
+                        )
+                        .context(ExitKind::Segments));
+                    }
+                    failures.push(SegmentFailure {
+                        index: segment.index,
+                        duration: segment.duration,
+                        reason: gaps::failure_reason(&error),
+                    });
+                    if let SegmentSink::Store(stdin, store) = &mut sink {
+                        if let Err(error) = store.skip(segment.index, stdin).await {
+                            pb.abandon();
+                            return Err(error);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Aborts the downloads still in flight
+        drop(results);
+        if stopped {
+            // What hadn't finished by the deadline counts as failed
+            for segment in segments.iter().filter(|segment| !finished.contains(&segment.index)) {
+                if !failures.iter().any(|failure| failure.index == segment.index) {
+                    failures.push(SegmentFailure {
+                        index: segment.index,
+                        duration: segment.duration,
+                        reason: "deadline".to_string(),
+                    });
+                }
+            }
+            failures.sort_by_key(|failure| failure.index);
+            status!("Stopped downloading at the --deadline; making the output from what there is.");
+            break;
+        }
+        if deferred.is_empty() {
+            break;
+        }
+        deferred_left = deferred.len();
+        first_wave = false;
+    }
+
+    // Without --ignore-errors, fewer failures than the limit still fail the variant
+    if args.retry_different_variant.is_some()
+        && !args.ignore_errors
+        && !args.output_on_failure
+    {
+        if let Some(last) = failures.last() {
+            pb.abandon();
+            let error = anyhow::anyhow!("segment {}: {}", last.index, last.reason);
+            return Err(variant_failed(&playlist, failures.len(), error, args));
+        }
+    }
+
+    match stopped {
+        true => pb.abandon_with_message("Stopped at the deadline"),
+        false => pb.finish_with_message("Download completed"),
+    }
+    if args.verbose {
+        status!("Segment responses: {}", http::protocol_summary());
+    } else {
+        tracing::debug!("Segment responses: {}", http::protocol_summary());
+    }
+    status!("HTTP status codes: {}", http::status_summary());
+    if let Some(timings) = timing::summary() {
+        status!("Request timings: {}.", timings);
+    }
+    if let Some(phases) = phases.summary() {
+        status!("Before the first segment: {}.", phases);
+    }
+    if let SegmentSink::Store(_, store) = &sink {
+        status!("In-memory store: {}.", store.summary());
+    }
+    // Closing ffmpeg's stdin tells it the input is complete
+    drop(sink);
+
+    if in_memory {
+        status!(
+            "Streamed {} of {} segments to ffmpeg.",
+            total_segments - failures.len(),
+            total_segments
+        );
+    } else if failures.is_empty() {
+        status!(
+            "Downloaded all segments to the '{}' folder successfully.",
+            output_folder
+        );
+    } else {
+        status!(
+            "Downloaded {} of {} segments to the '{}' folder.",
+            total_segments - failures.len(),
+            total_segments,
+            output_folder
+        );
+    }
+
+    status!(
+        "Verified {} segments by checksum and {} by size; {} unverified.",
+        by_checksum,
+        by_size,
+        unverified
+    );
+    if unchanged > 0 {
+        status!("Reused {} unchanged segments from a previous run.", unchanged);
+    }
+    if shared > 0 {
+        status!("Reused {} segments already downloaded for another variant.", shared);
+    }
+    if let Some(summary) = dedup.as_ref().and_then(|dedup| dedup.summary()) {
+        status!("{}", summary);
+    }
+
+    if !retried.is_empty() {
+        status!(
+            "{} segments needed retries ({} retries in total).",
+            retried.len(),
+            retried.iter().map(|(_, retries, _)| retries).sum::<usize>()
+        );
+        if args.verbose {
+            retried.sort();
+            for (index, retries, succeeded) in retried {
+                status!(
+                    "  segment {}: retried {}x, {}",
+                    index,
+                    retries,
+                    if succeeded { "succeeded" } else { "failed" }
+                );
+            }
+        }
+    }
+    Ok((playlist, failures))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+/// A spawned task that is cancelled when dropped, so that segment downloads
+/// still in flight stop when the download gives up.
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
 
-    // Usage
-    download_m3u8(&args.url, "output").await?;
-    create_file_list("output")?;
+impl<T> Future for AbortOnDrop<T> {
+    type Output = std::result::Result<T, tokio::task::JoinError>;
 
-    // Execute the ffmpeg command
-    execute_ffmpeg_command("file_list.txt", &args.output, args.compress)?;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
 
-    if args.compress {
-        println!("Video compressed using libx264 and aac audio.");
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
     }
+}
 
-    // Clean up the output folder
-    fs::remove_dir_all("output").context("Failed to remove output folder")?;
+/// `--retry-different-variant` gave up on the variant at `url`, whose
+/// segments would be downloaded to `files`.
+#[derive(Debug)]
+struct VariantFailed {
+    url: Url,
+    files: Vec<PathBuf>,
+}
 
-    Ok(())
+impl std::fmt::Display for VariantFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Giving up on variant {}", self.url)
+    }
 }
 
-async fn download_m3u8(
-    m3u8_url: &str,
-    output_folder: &str,
-) -> Result<()> {
-    let client = Arc::new(Client::new());
+/// The error for a variant with `failed` failed segments, the last with `error`.
+fn variant_failed(
+    playlist: &MediaPlaylist,
+    failed: usize,
+    error: anyhow::Error,
+    args: &Args,
+) -> anyhow::Error {
+    // A media playlist has no other variant to fall back to
+    if playlist.variant_of.is_none() {
+        return error.context(ExitKind::Segments);
+    }
+    let files = playlist
+        .segments
+        .iter()
+        .filter_map(|segment| segment_filename(&segment.url()))
+        .map(|filename| Path::new(&args.temp_dir).join(filename))
+        .collect();
+    error
+        .context(format!("{} segments failed", failed))
+        .context(VariantFailed {
+            url: playlist.url.clone(),
+            files,
+        })
+        .context(ExitKind::Segments)
+}
 
-    // Get the m3u8 file content
-    let m3u8_content = client.get(m3u8_url).send().await?.text().await?;
+/// The playlist was republished during the download: fetched again after a
+/// segment 404'd, it lists different segments. `stale` are the downloaded
+/// segment files whose position in the playlist changed.
+#[derive(Debug)]
+struct PlaylistChanged {
+    diff: PlaylistDiff,
+    stale: Vec<PathBuf>,
+}
 
-    // Ensure the output folder exists
-    fs::create_dir_all(output_folder)?;
+impl std::fmt::Display for PlaylistChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The playlist changed during the download: {}", self.diff)
+    }
+}
 
-    // Find all the .ts files
-    let base_url = Url::parse(m3u8_url)?;
-    let ts_urls: Vec<String> = m3u8_content
-        .lines()
-        .filter(|line| !line.starts_with('#') && !line.is_empty())
-        .map(|line| base_url.join(line).unwrap().to_string())
+/// Whether a segment failing with `error` is down to the playlist having been
+/// republished: on an HTTP 404, fetch the playlist again and compare.
+async fn republished(
+    client: &Client,
+    m3u8_url: &str,
+    playlist: &MediaPlaylist,
+    error: &anyhow::Error,
+    args: &Args,
+) -> Option<PlaylistChanged> {
+    let status = error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status);
+    // A playlist from stdin can't be fetched again
+    if status != Some(StatusCode::NOT_FOUND) || m3u8_url == "-" {
+        return None;
+    }
+    let mut newer = match playlist.refetch(client).await {
+        Ok(newer) => newer,
+        Err(error) => {
+            tracing::debug!("Failed to fetch the playlist again: {:#}", error);
+            return None;
+        }
+    };
+    if args.skip_first && newer.skip_first().is_err() {
+        return None;
+    }
+    let diff = playlist.diff(&newer);
+    if !diff.is_changed() {
+        return None;
+    }
+    let unchanged: HashSet<String> = diff
+        .unchanged
+        .iter()
+        .filter_map(|index| segment_filename(&playlist.segments[*index].url()))
         .collect();
+    let stale = playlist
+        .segments
+        .iter()
+        .filter_map(|segment| segment_filename(&segment.url()))
+        .filter(|filename| !unchanged.contains(filename))
+        .map(|filename| Path::new(&args.temp_dir).join(filename))
+        .collect();
+    Some(PlaylistChanged { diff, stale })
+}
 
-    // Download each .ts file in parallel with progress bar and ETA
-    let total_segments = ts_urls.len();
-    let pb = ProgressBar::new(total_segments as u64);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
-
-    let results = stream::iter(ts_urls)
-        .map(|ts_url| {
-            let client = Arc::clone(&client);
-            let output_folder = output_folder.to_string();
-            let pb = pb.clone();
-            tokio::spawn(async move {
-                let result = download_ts_segment(&ts_url, &output_folder, &client).await;
-                pb.inc(1);
-                result
-            })
+/// Times `--auto-restart-on-change` starts over before giving up on a
+/// playlist that keeps changing.
+const MAX_RESTARTS: usize = 3;
+
+/// Download into the temp folder, moving on to the next best variant of a
+/// master playlist whenever `--retry-different-variant` gives up on one, or
+/// none of a variant's segments download, and starting over on a playlist
+/// republished during the download with `--auto-restart-on-change`.
+async fn download_with_fallback(
+    args: &Args,
+    cleanup: &Cleanup,
+) -> Result<(MediaPlaylist, Vec<SegmentFailure>)> {
+    let mut failed = Vec::new();
+    let mut restarts = 0;
+    loop {
+        match download_m3u8(args.url(), SegmentSink::Folder(cleanup), args, &failed).await {
+            Err(error) => {
+                if let Some(changed) = error.downcast_ref::<PlaylistChanged>() {
+                    // They would otherwise be taken for the new segments
+                    // that reuse their names
+                    for file in &changed.stale {
+                        let _ = fs::remove_file(file);
+                    }
+                    project::forget_playlist(&changed.stale);
+                    let unchanged = changed.diff.unchanged.len();
+                    if !args.auto_restart_on_change || restarts == MAX_RESTARTS {
+                        cleanup.keep();
+                        status!(
+                            "The {} segments that didn't change are kept in '{}'; run again to \
+                             download the new version of the playlist{}.",
+                            unchanged,
+                            args.temp_dir,
+                            match args.auto_restart_on_change {
+                                true => "",
+                                false => ", or pass --auto-restart-on-change",
+                            }
+                        );
+                        return Err(error);
+                    }
+                    restarts += 1;
+                    status!(
+                        "{}. Restarting the download, keeping the {} segments that didn't change.",
+                        changed,
+                        unchanged
+                    );
+                    continue;
+                }
+                let Some(variant) = error.downcast_ref::<VariantFailed>() else {
+                    // Running again picks up from the segments downloaded so far
+                    let resumable = matches!(error.downcast_ref(), Some(ExitKind::Segments));
+                    if resumable && !cleanup.kept() {
+                        cleanup.keep();
+                        status!(
+                            "Keeping temp folder '{}'; running again resumes from the segments \
+                             downloaded so far.",
+                            args.temp_dir
+                        );
+                    }
+                    return Err(error);
+                };
+                status!(
+                    "Variant {} is failing ({:#}), falling back to the next best one.",
+                    variant.url,
+                    error.root_cause()
+                );
+                // The next variant's segments mustn't mix with these, even
+                // where their file names are the same
+                for file in &variant.files {
+                    let _ = fs::remove_file(file);
+                }
+                project::forget_playlist(&variant.files);
+                failed.push(variant.url.clone());
+            }
+            Ok(download) => {
+                if let Some(first) = failed.first() {
+                    status!(
+                        "Note: downloaded variant {} in place of {}, whose segments failed.",
+                        download.0.url,
+                        first
+                    );
+                }
+                return Ok(download);
+            }
+        }
+    }
+}
+
+/// Rough total size of the download: each segment's `#EXT-X-BITRATE`
+/// prediction, or else the first segment's size scaled by duration.
+fn estimated_size(segments: &[Segment], first_size: Option<u64>) -> Option<u64> {
+    let bytes_per_second = match (first_size, segments.first()) {
+        (Some(size), Some(first)) if first.duration > 0.0 => Some(size as f64 / first.duration),
+        _ => None,
+    };
+    segments
+        .iter()
+        .map(|segment| {
+            segment
+                .predicted_size()
+                .or_else(|| bytes_per_second.map(|rate| (rate * segment.duration) as u64))
         })
-        .buffer_unordered(10)
-        .collect::<Vec<_>>()
-        .await;
+        .sum()
+}
 
-    pb.finish_with_message("Download completed");
+/// Delay before the given retry attempt: exponential from 500ms, capped at 8s.
+fn retry_backoff(attempt: usize) -> Duration {
+    Duration::from_millis(500 << (attempt - 1).min(4))
+}
 
-    // Check for any errors during download
-    for result in results {
-        result??;
+/// Whether the given retry attempt, after its backoff, would still start
+/// within `--retry-deadline` of the segment's first attempt.
+fn before_deadline(started: Instant, attempt: usize, deadline: Option<Duration>) -> bool {
+    match deadline {
+        Some(deadline) => started.elapsed() + retry_backoff(attempt) < deadline,
+        None => true,
     }
+}
 
-    println!(
-        "Downloaded all segments to the '{}' folder successfully.",
-        output_folder
-    );
+/// Optional cap on the total number of retries across all segments of a run.
+struct RetryBudget {
+    remaining: Option<AtomicUsize>,
+}
+
+impl RetryBudget {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            remaining: limit.map(AtomicUsize::new),
+        }
+    }
+
+    /// Consume one retry, returning false once the budget is exhausted.
+    fn take(&self) -> bool {
+        match &self.remaining {
+            Some(remaining) => remaining
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok(),
+            None => true,
+        }
+    }
+}
+
+/// Spaces out request starts across all tasks; unlimited when no rate is set.
+struct RateLimiter {
+    interval: Option<Duration>,
+    next_slot: std::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_second: Option<f64>) -> Self {
+        Self {
+            interval: requests_per_second
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            next_slot: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for this caller's turn to start a request.
+    async fn wait(&self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + interval;
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+    }
+}
+
+/// What `Accept` segment requests send unless `--segment-accept` says
+/// otherwise: TS and fMP4 first, while still taking whatever else the origin has.
+const DEFAULT_SEGMENT_ACCEPT: &str =
+    "video/mp2t, video/mp4, video/iso.segment, application/octet-stream;q=0.9, */*;q=0.8";
+
+/// Settings for one attempt at a segment, and where the attempt ended up.
+#[derive(Debug, Clone)]
+struct SegmentRequest {
+    /// 1 for the first attempt, counting up with each retry.
+    attempt: usize,
+    /// Replaces the client's timeout, so that it can grow with each retry.
+    timeout: Option<Duration>,
+    accept: HeaderValue,
+    /// `--max-segment-size`.
+    max_size: u64,
+    /// Where the last response came from, if redirects led away from the
+    /// segment's URL. Retries still start from the segment's URL, since
+    /// redirect targets are often short-lived edge URLs.
+    redirected_to: Option<Url>,
+}
+
+impl SegmentRequest {
+    fn new(attempt: usize, timeout: Option<Duration>, accept: HeaderValue, max_size: u64) -> Self {
+        Self {
+            attempt,
+            timeout,
+            accept,
+            max_size,
+            redirected_to: None,
+        }
+    }
+
+    /// Note where a response to the request for `ts_url` came from.
+    fn record(&mut self, ts_url: &Url, response: &reqwest::Response) {
+        self.redirected_to = (response.url() != ts_url).then(|| response.url().clone());
+        if let Some(final_url) = &self.redirected_to {
+            tracing::debug!(
+                "{} attempt {} was redirected to {}",
+                ts_url,
+                self.attempt,
+                final_url
+            );
+        }
+    }
+
+    /// ` (redirected to ...)` for a log message, if the attempt was redirected.
+    fn redirect_note(&self) -> String {
+        match &self.redirected_to {
+            Some(final_url) => format!(" (redirected to {})", final_url),
+            None => String::new(),
+        }
+    }
+}
+
+/// GET for a segment, with its own timeout in place of the client's.
+fn segment_request(
+    client: &Client,
+    ts_url: &Url,
+    options: &SegmentRequest,
+) -> reqwest::RequestBuilder {
+    let request = client
+        .get(ts_url.clone())
+        .header(header::ACCEPT, options.accept.clone());
+    match options.timeout {
+        Some(timeout) => request.timeout(timeout),
+        None => request,
+    }
+}
+
+/// Segments smaller than this fraction of the size their `#EXT-X-BITRATE`
+/// predicts are taken to be truncated.
+const MIN_PREDICTED_FRACTION: f64 = 0.1;
+
+/// Reject a segment body that is too small to be real media.
+fn check_size(
+    ts_url: &Url,
+    size: u64,
+    min_segment_size: u64,
+    predicted_size: Option<u64>,
+) -> Result<()> {
+    // CDNs under load sometimes answer 200 with nothing in the body
+    if size == 0 {
+        anyhow::bail!("Segment {} has an empty body", ts_url);
+    }
+    // Tiny bodies are usually error pages served with a 200 status
+    if size < min_segment_size {
+        anyhow::bail!(
+            "Segment {} is only {} bytes (expected at least {})",
+            ts_url,
+            size,
+            min_segment_size
+        );
+    }
+    if let Some(predicted) = predicted_size {
+        if (size as f64) < predicted as f64 * MIN_PREDICTED_FRACTION {
+            anyhow::bail!(
+                "Segment {} is only {} bytes, far below the ~{} its EXT-X-BITRATE predicts; \
+                 it is likely truncated",
+                ts_url,
+                size,
+                predicted
+            );
+        }
+    }
     Ok(())
 }
 
+/// Reject a segment over `--max-segment-size`, by the size its Content-Length
+/// `advertised` before the body is read, or by the bytes counted so far while
+/// it streams. Reported as it happens, since retries may hide the error.
+fn check_max_size(ts_url: &Url, size: u64, max_segment_size: u64, advertised: bool) -> Result<()> {
+    if size <= max_segment_size {
+        return Ok(());
+    }
+    let message = match advertised {
+        true => format!(
+            "Segment {} is {} bytes by its Content-Length, over the --max-segment-size of {}",
+            ts_url, size, max_segment_size
+        ),
+        false => format!(
+            "Segment {} grew past the --max-segment-size of {} bytes while downloading",
+            ts_url, max_segment_size
+        ),
+    };
+    status!("Warning: {}; the playlist may point at a whole file.", message);
+    Err(anyhow::anyhow!(message))
+}
+
+/// Download a segment into memory.
+async fn fetch_segment(
+    ts_url: &Url,
+    client: &Client,
+    options: &mut SegmentRequest,
+    min_segment_size: u64,
+    predicted_size: Option<u64>,
+    checksum: Option<&Checksum>,
+) -> Result<(Bytes, Verification)> {
+    let mut timing = RequestTiming::start(ts_url, options.attempt);
+    let response = segment_request(client, ts_url, options).send().await?;
+    http::record_response(&response);
+    options.record(ts_url, &response);
+    timing.response(&response);
+    let mut response = response.error_for_status()?;
+    let expected_size = response.content_length();
+    if let Some(expected_size) = expected_size {
+        check_max_size(ts_url, expected_size, options.max_size, true)?;
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        check_max_size(ts_url, body.len() as u64, options.max_size, false)?;
+    }
+    let ts_content = Bytes::from(body);
+
+    let size = ts_content.len() as u64;
+    timing.body(size);
+    check_size(ts_url, size, min_segment_size, predicted_size)?;
+    let verification = verify_segment(ts_url, size, expected_size, checksum, || {
+        checksum.map_or(Ok(()), |checksum| checksum.verify_bytes(&ts_content))
+    })?;
+    timing.finish();
+
+    tracing::debug!("Downloaded {} ({} bytes)", ts_url, ts_content.len());
+    Ok((ts_content, verification))
+}
+
+/// Keep the body the first-segment probe fetched as the segment's download,
+/// checked as a download would be, either in memory or moved into place in
+/// `output_folder`.
+async fn keep_probed(
+    ts_url: &Url,
+    output_folder: Option<&str>,
+    probed: probe::Probed,
+    min_segment_size: u64,
+    segment: &Segment,
+    checksum: Option<&Checksum>,
+) -> Result<(u64, Verification, Option<Bytes>)> {
+    let size = probed.body.len() as u64;
+    check_size(ts_url, size, min_segment_size, segment.predicted_size())?;
+    let verification = verify_segment(ts_url, size, probed.content_length, checksum, || {
+        checksum.map_or(Ok(()), |checksum| checksum.verify_bytes(&probed.body))
+    })?;
+    let Some(output_folder) = output_folder else {
+        return Ok((size, verification, Some(probed.body)));
+    };
+
+    let filename = segment_filename(ts_url).context("Failed to extract filename from URL")?;
+    let output_path = Path::new(output_folder).join(&filename);
+    let part_path = Path::new(output_folder).join(format!("{}.part", filename));
+    let cached_validator_path = Path::new(output_folder).join(format!("{}.validator", filename));
+    fsretry::retry_async(Target::Temp, "write", &part_path, || {
+        fs::write(&part_path, &probed.body)
+    })
+    .await?;
+    fsretry::retry_async(Target::Temp, "move the segment to", &output_path, || {
+        fs::rename(&part_path, &output_path)
+    })
+    .await?;
+    match probed.validator.filter(|_| reuse::caching()) {
+        Some(validator) => {
+            let _ = fs::write(&cached_validator_path, validator);
+        }
+        None => {
+            let _ = fs::remove_file(&cached_validator_path);
+        }
+    }
+    let _ = fs::remove_file(Path::new(output_folder).join(format!("{}.part.validator", filename)));
+    reuse::record(ts_url, &output_path);
+    tracing::debug!("Kept the probed {} ({} bytes)", ts_url, size);
+    Ok((size, verification, None))
+}
+
+/// Check a downloaded segment against its Content-Length and, when the
+/// manifest lists one, its checksum.
+fn verify_segment(
+    ts_url: &Url,
+    size: u64,
+    expected_size: Option<u64>,
+    checksum: Option<&Checksum>,
+    verify_checksum: impl FnOnce() -> Result<()>,
+) -> Result<Verification> {
+    if let Some(expected_size) = expected_size {
+        anyhow::ensure!(
+            size == expected_size,
+            "Segment {} is {} bytes but Content-Length promised {}",
+            ts_url,
+            size,
+            expected_size
+        );
+    }
+    if checksum.is_some() {
+        verify_checksum().context("checksum mismatch")?;
+        Ok(Verification::Checksum)
+    } else if expected_size.is_some() {
+        Ok(Verification::Size)
+    } else {
+        Ok(Verification::Unverified)
+    }
+}
+
+/// Download a segment into `<name>.part`, verify it, then move it into place,
+/// returning its size.
+///
+/// A `.part` file left by a failed attempt is resumed with a `Range` request,
+/// but only when the server answers 206 for the same entity (by ETag or
+/// Last-Modified) as the partial bytes came from; otherwise it starts over.
+///
+/// A finished segment keeps its validator in `<name>.validator`, so when the
+/// temp folder is reused, the segment is revalidated with `If-None-Match` or
+/// `If-Modified-Since` and kept as-is if the server answers 304. A playlist
+/// with `#EXT-X-ALLOW-CACHE:NO` gets neither.
 async fn download_ts_segment(
-    ts_url: &str,
+    ts_url: &Url,
     output_folder: &str,
     client: &Client,
-) -> Result<()> {
+    options: &mut SegmentRequest,
+    min_segment_size: u64,
+    predicted_size: Option<u64>,
+    checksum: Option<&Checksum>,
+) -> Result<(u64, Verification)> {
     // Extract the filename from the URL
-    let url = Url::parse(ts_url).context("Failed to parse TS URL")?;
-    let filename = url
-        .path_segments()
-        .and_then(|segments| segments.last())
-        .context("Failed to extract filename from URL")?;
-    let output_path = Path::new(output_folder).join(filename);
+    let filename = segment_filename(ts_url).context("Failed to extract filename from URL")?;
+    let output_path = Path::new(output_folder).join(&filename);
+    let part_path = Path::new(output_folder).join(format!("{}.part", filename));
+    let validator_path = Path::new(output_folder).join(format!("{}.part.validator", filename));
+    let cached_validator_path = Path::new(output_folder).join(format!("{}.validator", filename));
+
+    if let Some(size) = reuse::link(ts_url, &output_path)? {
+        return Ok((size, Verification::Shared));
+    }
+
+    let cached = match fs::read_to_string(&cached_validator_path) {
+        Ok(validator) if output_path.is_file() && reuse::caching() => Some(validator),
+        _ => None,
+    };
+    let resume_from = match (fs::metadata(&part_path), fs::read_to_string(&validator_path)) {
+        (Ok(metadata), Ok(validator)) if metadata.len() > 0 && cached.is_none() => {
+            Some((metadata.len(), validator))
+        }
+        _ => None,
+    };
 
     // Download the segment
-    let ts_content = client.get(ts_url).send().await?.bytes().await?;
+    let mut timing = RequestTiming::start(ts_url, options.attempt);
+    let mut request = segment_request(client, ts_url, options);
+    if let Some(cached) = &cached {
+        request = conditional_request(request, cached);
+    } else if let Some((offset, _)) = &resume_from {
+        request = request.header(header::RANGE, format!("bytes={}-", offset));
+    }
+    let mut response = request.send().await?;
+    http::record_response(&response);
+    options.record(ts_url, &response);
+    timing.response(&response);
 
-    // Save the segment to the specified output path
-    fs::write(output_path, ts_content).context("Failed to write TS segment to file")?;
+    if cached.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+        timing.body(0);
+        let size = fs::metadata(&output_path)?.len();
+        let verification = match checksum {
+            Some(checksum) => verify_segment(ts_url, size, None, Some(checksum), || {
+                checksum.verify_file(&output_path)
+            })?,
+            None => Verification::Unchanged,
+        };
+        tracing::debug!("Reusing unchanged {} ({} bytes)", ts_url, size);
+        timing.finish();
+        return Ok((size, verification));
+    }
+    let mut validator = entity_validator(&response);
 
-    Ok(())
+    let append = match &resume_from {
+        Some((offset, recorded)) => {
+            match resume_decision(response.status(), validator.as_deref(), recorded) {
+                Resume::Append => {
+                    tracing::debug!("Resuming {} from byte {}", ts_url, offset);
+                    true
+                }
+                Resume::Restart => false,
+                Resume::Refetch => {
+                    response = segment_request(client, ts_url, options).send().await?;
+                    http::record_response(&response);
+                    options.record(ts_url, &response);
+                    timing.response(&response);
+                    validator = entity_validator(&response);
+                    false
+                }
+            }
+        }
+        None => false,
+    };
+    let mut response = response.error_for_status()?;
+    let expected_size = match (&resume_from, append) {
+        (Some((offset, _)), true) => response.content_length().map(|length| offset + length),
+        _ => response.content_length(),
+    };
+    if let Some(expected_size) = expected_size {
+        check_max_size(ts_url, expected_size, options.max_size, true)?;
+    }
+
+    let file = if append {
+        fsretry::retry_async(Target::Temp, "open", &part_path, || {
+            fs::OpenOptions::new().append(true).open(&part_path)
+        })
+        .await?
+    } else {
+        match &validator {
+            Some(validator) => {
+                fsretry::retry_async(Target::Temp, "write", &validator_path, || {
+                    fs::write(&validator_path, validator)
+                })
+                .await?
+            }
+            None => {
+                let _ = fs::remove_file(&validator_path);
+            }
+        }
+        fsretry::retry_async(Target::Temp, "create", &part_path, || File::create(&part_path))
+            .await?
+    };
+    let mut file = tokio::fs::File::from_std(file);
+    let mut received = 0;
+    let already = match (&resume_from, append) {
+        (Some((offset, _)), true) => *offset,
+        _ => 0,
+    };
+    while let Some(chunk) = response.chunk().await? {
+        let position = already + received;
+        received += chunk.len() as u64;
+        if let Err(error) = check_max_size(ts_url, already + received, options.max_size, false) {
+            drop(file);
+            let _ = fs::remove_file(&part_path);
+            let _ = fs::remove_file(&validator_path);
+            return Err(error);
+        }
+        write_segment_chunk(&mut file, &part_path, position, &chunk).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    timing.body(received);
+
+    let size = fs::metadata(&part_path)?.len();
+
+    if let Err(error) = check_size(ts_url, size, min_segment_size, predicted_size) {
+        let _ = fs::remove_file(&part_path);
+        let _ = fs::remove_file(&validator_path);
+        return Err(error);
+    }
+
+    let verification = verify_segment(ts_url, size, expected_size, checksum, || {
+        checksum.map_or(Ok(()), |checksum| checksum.verify_file(&part_path))
+    });
+    let verification = match verification {
+        Ok(verification) => verification,
+        Err(error) => {
+            // Corrupt bytes must not be resumed from
+            let _ = fs::remove_file(&part_path);
+            let _ = fs::remove_file(&validator_path);
+            return Err(error);
+        }
+    };
+
+    // Move the completed segment to the specified output path
+    tracing::debug!("Downloaded {} ({} bytes)", ts_url, size);
+    fsretry::retry_async(Target::Temp, "move the segment to", &output_path, || {
+        fs::rename(&part_path, &output_path)
+    })
+    .await?;
+    if !reuse::caching() || fs::rename(&validator_path, &cached_validator_path).is_err() {
+        let _ = fs::remove_file(&validator_path);
+        let _ = fs::remove_file(&cached_validator_path);
+    }
+
+    reuse::record(ts_url, &output_path);
+    timing.finish();
+    Ok((size, verification))
 }
 
-fn create_file_list(output_folder: &str) -> Result<()> {
-    let list_file_name = "file_list.txt";
-    let mut ts_files: Vec<PathBuf> = fs::read_dir(output_folder)?
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.path())
-        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("ts"))
+/// What to make of the answer to a `Range` request for the rest of a `.part`
+/// file.
+#[derive(Debug, PartialEq)]
+enum Resume {
+    /// The rest of the same entity: append it to the partial bytes.
+    Append,
+    /// The whole segment (the server ignored the range): start over with it.
+    Restart,
+    /// Neither (the entity changed, or the range was rejected): fetch the
+    /// whole segment again.
+    Refetch,
+}
+
+/// Whether a response with `status` and `validator` continues the partial
+/// bytes, which came from the entity `recorded`.
+fn resume_decision(status: StatusCode, validator: Option<&str>, recorded: &str) -> Resume {
+    if status == StatusCode::PARTIAL_CONTENT && validator == Some(recorded) {
+        Resume::Append
+    } else if status == StatusCode::OK {
+        Resume::Restart
+    } else {
+        Resume::Refetch
+    }
+}
+
+/// Append a chunk of a segment at `position`. With `--temp-io-retries`, each
+/// chunk is waited on so that a failed write is known to be this one, and
+/// it is written again over whatever part of it reached the file.
+async fn write_segment_chunk(
+    file: &mut tokio::fs::File,
+    path: &Path,
+    position: u64,
+    chunk: &[u8],
+) -> Result<()> {
+    if !fsretry::retries(Target::Temp) {
+        return file
+            .write_all(chunk)
+            .await
+            .context("Failed to write TS segment to file");
+    }
+    let mut attempt = 0;
+    loop {
+        let written = async {
+            if attempt > 0 {
+                file.set_len(position).await?;
+                file.seek(std::io::SeekFrom::Start(position)).await?;
+            }
+            file.write_all(chunk).await?;
+            file.flush().await
+        }
+        .await;
+        let Err(error) = written else {
+            return Ok(());
+        };
+        match fsretry::backoff(Target::Temp, attempt, "write", path, &error) {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => return Err(fsretry::failure(error, "write", path, attempt)),
+        }
+        attempt += 1;
+    }
+}
+
+/// Local file name for a segment: its last path segment, percent-decoded to
+/// UTF-8 (lossily, if need be) with characters that aren't safe in file
+/// names replaced by `_`.
+fn segment_filename(ts_url: &Url) -> Option<String> {
+    let encoded = ts_url.path_segments()?.next_back()?;
+    let decoded = percent_encoding::percent_decode_str(encoded).decode_utf8_lossy();
+    let filename: String = decoded
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
         .collect();
+    (!filename.is_empty() && filename != "." && filename != "..").then_some(filename)
+}
+
+/// Strong identifier of the entity behind a response: its ETag, or else its Last-Modified date.
+fn entity_validator(response: &reqwest::Response) -> Option<String> {
+    let headers = response.headers();
+    let etag = headers
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"));
+    match etag {
+        Some(etag) => Some(format!("ETag: {}", etag)),
+        None => headers
+            .get(header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .map(|date| format!("Last-Modified: {}", date)),
+    }
+}
+
+/// Make `request` conditional on the entity still matching a validator
+/// recorded by [`entity_validator`].
+fn conditional_request(
+    request: reqwest::RequestBuilder,
+    validator: &str,
+) -> reqwest::RequestBuilder {
+    if let Some(etag) = validator.strip_prefix("ETag: ") {
+        request.header(header::IF_NONE_MATCH, etag)
+    } else if let Some(date) = validator.strip_prefix("Last-Modified: ") {
+        request.header(header::IF_MODIFIED_SINCE, date)
+    } else {
+        request
+    }
+}
+
+/// Whether the segment's file is already in the temp folder.
+fn already_downloaded(output_folder: &str, segment: &Segment) -> bool {
+    segment_filename(&segment.url())
+        .is_some_and(|filename| Path::new(output_folder).join(filename).is_file())
+}
+
+/// Paths of the segments that were downloaded, in playlist order (leaving
+/// out segments that were skipped).
+fn downloaded_segments(output_folder: &str, segments: &[Segment]) -> Vec<PathBuf> {
+    segments
+        .iter()
+        .filter_map(|segment| segment_filename(&segment.url()))
+        .map(|filename| Path::new(output_folder).join(filename))
+        .filter(|path| path.is_file())
+        .collect()
+}
 
-    ts_files.sort();
+fn create_file_list(
+    output_folder: &str,
+    segments: &[Segment],
+    order: SortOrder,
+    segment_ext: Option<&str>,
+) -> Result<Vec<PathBuf>> {
+    let ts_files: Vec<PathBuf> = if order == SortOrder::DownloadOrder {
+        downloaded_segments(output_folder, segments)
+    } else {
+        let is_segment = |path: &Path| {
+            let extension = path.extension().and_then(|ext| ext.to_str());
+            match segment_ext {
+                Some(wanted) => extension == Some(wanted.trim_start_matches('.')),
+                // A partial download starts like the segment it becomes
+                None => {
+                    extension == Some("ts") || (extension != Some("part") && starts_like_ts(path))
+                }
+            }
+        };
+        let mut ts_files: Vec<PathBuf> = fs::read_dir(output_folder)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && is_segment(path))
+            .collect();
+        sort::sort_files(&mut ts_files, order);
+        ts_files
+    };
+    write_file_list(ts_files)
+}
+
+/// Whether the file starts with two MPEG-TS packets: the 0x47 sync byte at
+/// the start and again 188 bytes in.
+fn starts_like_ts(path: &Path) -> bool {
+    let mut head = [0; 189];
+    File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut head))
+        .is_ok_and(|()| head[0] == 0x47 && head[188] == 0x47)
+}
+
+/// For an fMP4 playlist, join each initialization section with the
+/// fragments it applies to into `region-<n>.mp4`, and list those instead of
+/// the fragments (which ffmpeg can't read on their own).
+fn create_region_list(
+    output_folder: &str,
+    playlist: &MediaPlaylist,
+    init_sections: &[PathBuf],
+    cleanup: &Cleanup,
+) -> Result<Vec<PathBuf>> {
+    let mut regions = Vec::new();
+    for (number, (init, range)) in init_sections.iter().zip(playlist.init_regions()).enumerate() {
+        let fragments = downloaded_segments(output_folder, &playlist.segments[range]);
+        if fragments.is_empty() {
+            continue;
+        }
+        let name = format!("region-{}.mp4", number + 1);
+        cleanup.expect(&name);
+        let path = Path::new(output_folder).join(name);
+        fmp4::concat(init, &fragments, &path)?;
+        regions.push(path);
+    }
+    write_file_list(regions)
+}
 
+/// Write the concat list for `ts_files`, returning them.
+fn write_file_list(ts_files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let list_file_name = "file_list.txt";
     let mut file_list = File::create(list_file_name).context("Failed to create file list")?;
     for ts_file in ts_files.iter() {
-        writeln!(file_list, "file '{}'", ts_file.display())
+        writeln!(file_list, "file '{}'", concat_escape(&ts_file.to_string_lossy()))
             .context("Failed to write to file list")?;
     }
 
-    println!(
+    status!(
         "Created {} with {} files listed.",
         list_file_name,
         ts_files.len()
     );
+    Ok(ts_files)
+}
+
+/// Quote-safe form of a path for a single-quoted entry in an ffmpeg concat list.
+fn concat_escape(path: &str) -> String {
+    path.replace('\'', r"'\''")
+}
+
+fn execute_ffmpeg_command(
+    input: &ConcatInput,
+    output_file: &str,
+    format: Option<OutputFormat>,
+    encoding: Option<Encoding>,
+    audio: &AudioMix,
+    codec_args: &[String],
+) -> Result<()> {
+    let mut command = ffmpeg_command(input, encoding, audio, codec_args);
+    let fifo = is_fifo(Path::new(output_file));
+    if fifo {
+        // The FIFO already exists and can't seek, so skip the overwrite
+        // prompt and pick a muxer that writes strictly front to back
+        command.arg("-y").arg("-flush_packets").arg("1");
+    }
+    let muxer = format.map(OutputFormat::muxer).or_else(|| {
+        fifo.then(|| match upload::streamable_format(output_file) {
+            Some(format) => format,
+            None if output_file.ends_with(".mp4") => "mp4",
+            None => "mpegts",
+        })
+    });
+    if let Some(muxer) = muxer {
+        command.arg("-f").arg(muxer);
+        if fifo && muxer == "mp4" {
+            command.arg("-movflags").arg("frag_keyframe+empty_moov");
+        }
+    }
+    command.arg(output_file);
+    tracing::debug!("Running {:?}", command);
+
+    sleep(Duration::from_secs(100));
+
+    let retry = with_genpts(&command);
+    match run_ffmpeg(command, input) {
+        // A FIFO's reader has had part of the output already
+        Err(error) if !fifo && error.downcast_ref::<watchdog::Stalled>().is_some() => {
+            status!("Warning: {}", error.root_cause());
+            status!("Retrying the mux with regenerated timestamps (-fflags +genpts)...");
+            let _ = fs::remove_file(output_file);
+            run_ffmpeg(retry, input).context("The retry with -fflags +genpts failed too")?;
+        }
+        result => result?,
+    }
+    status!("Successfully created {}", output_file);
     Ok(())
 }
 
-fn execute_ffmpeg_command(input_file: &str, output_file: &str, compress: bool) -> Result<()> {
-    let mut command = Command::new("ffmpeg");
+/// `command` again, with ffmpeg making up the timestamps its input lacks.
+fn with_genpts(command: &Command) -> Command {
+    let mut retry = Command::new(command.get_program());
+    retry.args(["-fflags", "+genpts"]).args(command.get_args());
+    retry
+}
+
+/// The analysis pass of a two-pass encode at `bitrate`, writing only the
+/// statistics under `passlog` that the second pass reads.
+fn execute_first_pass(
+    input: &ConcatInput,
+    bitrate: u64,
+    passlog: &Path,
+    codec_args: &[String],
+) -> Result<()> {
+    let no_audio = AudioMix {
+        tracks: Vec::new(),
+        keep_original: false,
+    };
+    let encoding = Some(Encoding::Bitrate(bitrate));
+    let mut command = ffmpeg_command(input, encoding, &no_audio, codec_args);
     command
+        .arg("-pass")
+        .arg("1")
+        .arg("-passlogfile")
+        .arg(passlog)
+        .arg("-an")
         .arg("-f")
-        .arg("concat")
-        .arg("-safe")
-        .arg("0")
-        .arg("-i")
-        .arg(input_file);
-
-    if compress {
-        command
-            .arg("-c:v")
-            .arg("libx264")
-            .arg("-crf")
-            .arg("23")
-            .arg("-preset")
-            .arg("medium")
-            .arg("-c:a")
-            .arg("aac")
-            .arg("-b:a")
-            .arg("128k");
+        .arg("null")
+        .arg("-");
+    tracing::debug!("Running {:?}", command);
+    run_ffmpeg(command, input).context("The first pass of the two-pass encode failed")
+}
+
+/// Run an ffmpeg `command` reading `input`, failing with its stderr.
+fn run_ffmpeg(mut command: Command, input: &ConcatInput) -> Result<()> {
+    let mut ffmpeg = command
+        .stdin(input.stdin())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| exit::spawn_error(error, "ffmpeg command"))?;
+    let feeder = input.feed(&mut ffmpeg);
+    let output = watchdog::wait_with_output(ffmpeg)?;
+    concat::finish_feed(feeder)?;
+
+    if output.status.success() {
+        Ok(())
     } else {
-        command.arg("-c").arg("copy");
+        let error_message = String::from_utf8_lossy(&output.stderr);
+        Err(anyhow::anyhow!("Error executing ffmpeg command: {}", error_message)
+            .context(ExitKind::Ffmpeg))
     }
+}
 
-    command.arg(output_file);
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
 
-    sleep(Duration::from_secs(100));
+#[cfg(windows)]
+fn is_fifo(path: &Path) -> bool {
+    path.to_string_lossy().starts_with(r"\\.\pipe\")
+}
+
+/// ffmpeg reading MPEG-TS segments from stdin, minus the output argument.
+fn ffmpeg_pipe_command(encoding: Option<Encoding>) -> Command {
+    let mut command = Command::new("ffmpeg");
+    command.arg("-f").arg("mpegts").arg("-i").arg("pipe:0");
+    add_codec_options(&mut command, encoding);
+    command
+}
+
+/// The ffmpeg invocation for muxing the segments, minus the output argument.
+fn ffmpeg_command(
+    input: &ConcatInput,
+    encoding: Option<Encoding>,
+    audio: &AudioMix,
+    codec_args: &[String],
+) -> Command {
+    let mut command = Command::new("ffmpeg");
+    input.add_to(&mut command);
+
+    // Take the video from the segments and the audio from the external tracks
+    audio.add_to(&mut command);
+
+    add_codec_options(&mut command, encoding);
+    // Later options for the same stream override the defaults above
+    command.args(codec_args);
+    command
+}
+
+fn add_codec_options(command: &mut Command, encoding: Option<Encoding>) {
+    let Some(encoding) = encoding else {
+        command.arg("-c").arg("copy");
+        return;
+    };
+    command.arg("-c:v").arg("libx264");
+    match encoding {
+        Encoding::Quality => command.arg("-crf").arg("23"),
+        Encoding::Bitrate(bitrate) => command.arg("-b:v").arg(bitrate.to_string()),
+    };
+    command
+        .arg("-preset")
+        .arg("medium")
+        .arg("-c:a")
+        .arg("aac")
+        .arg("-b:a")
+        .arg(format!("{}k", encoding::AUDIO_BITRATE / 1000));
+}
+
+/// `show.mp4` -> `show.preview.mp4`, next to the main output.
+fn preview_path(output_file: &str) -> String {
+    let path = Path::new(output_file);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}.preview.{}", stem, ext.to_string_lossy()),
+        None => format!("{}.preview", stem),
+    };
+    path.with_file_name(file_name).to_string_lossy().into_owned()
+}
 
-    let output = command.output().context("Failed to execute ffmpeg command")?;
+/// Render a video-only, low frame rate copy of the segments for quick
+/// skimming, reading them the same way as the main mux.
+fn execute_preview_command(input: &ConcatInput, output_file: &str, fps: f64) -> Result<()> {
+    let mut command = preview_command(input, output_file, fps);
+    tracing::debug!("Running {:?}", command);
+    let mut ffmpeg = command
+        .stdin(input.stdin())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| exit::spawn_error(error, "ffmpeg preview command"))?;
+    let feeder = input.feed(&mut ffmpeg);
+    let output = ffmpeg
+        .wait_with_output()
+        .context("Failed to wait for the ffmpeg preview command")?;
+    concat::finish_feed(feeder)?;
 
     if output.status.success() {
-        println!("Successfully created {}", output_file);
+        status!("Successfully created preview {}", output_file);
         Ok(())
     } else {
         let error_message = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Error executing ffmpeg command: {}", error_message);
+        Err(
+            anyhow::anyhow!("Error executing ffmpeg preview command: {}", error_message)
+                .context(ExitKind::Ffmpeg),
+        )
+    }
+}
+
+/// The ffmpeg invocation for the `--preview-fps` copy.
+fn preview_command(input: &ConcatInput, output_file: &str, fps: f64) -> Command {
+    let mut command = Command::new("ffmpeg");
+    input.add_to(&mut command);
+    command
+        .arg("-vf")
+        .arg(format!("fps={}", fps))
+        .arg("-an")
+        .arg("-c:v")
+        .arg("libx264")
+        .arg("-preset")
+        .arg("veryfast")
+        .arg(output_file);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// A folder of its own under the system temp folder for the test `name`.
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("m3u8dl-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    /// Serve `responses` to one connection each, in order, and return the
+    /// server's URL and the request heads it received.
+    async fn serve(responses: Vec<String>) -> (Url, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/seg.ts", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buffer = [0; 1024];
+                while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    head.extend_from_slice(&buffer[..read]);
+                }
+                received.lock().unwrap().push(String::from_utf8_lossy(&head).to_lowercase());
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn response(status: &str, etag: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nETag: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            etag,
+            body.len(),
+            body
+        )
+    }
+
+    /// Download `url` into `folder`, which holds `partial` from the entity
+    /// `"v1"`, and return what ends up in the segment.
+    async fn resume(url: &Url, folder: &Path, partial: &str) -> String {
+        fs::write(folder.join("seg.ts.part"), partial).unwrap();
+        fs::write(folder.join("seg.ts.part.validator"), "ETag: \"v1\"").unwrap();
+        let client = Client::builder().no_proxy().build().unwrap();
+        let mut options = SegmentRequest::new(1, None, HeaderValue::from_static("*/*"), u64::MAX);
+        let folder = folder.to_str().unwrap();
+        download_ts_segment(url, folder, &client, &mut options, 0, None, None)
+            .await
+            .unwrap();
+        fs::read_to_string(Path::new(folder).join("seg.ts")).unwrap()
+    }
+
+    #[test]
+    fn resume_decision_needs_206_for_the_same_entity() {
+        let recorded = "ETag: \"v1\"";
+        let same = Some(recorded);
+        let changed = Some("ETag: \"v2\"");
+        assert_eq!(resume_decision(StatusCode::PARTIAL_CONTENT, same, recorded), Resume::Append);
+        assert_eq!(resume_decision(StatusCode::OK, same, recorded), Resume::Restart);
+        assert_eq!(resume_decision(StatusCode::OK, changed, recorded), Resume::Restart);
+        assert_eq!(
+            resume_decision(StatusCode::PARTIAL_CONTENT, changed, recorded),
+            Resume::Refetch
+        );
+        assert_eq!(
+            resume_decision(StatusCode::PARTIAL_CONTENT, None, recorded),
+            Resume::Refetch
+        );
+        assert_eq!(
+            resume_decision(StatusCode::RANGE_NOT_SATISFIABLE, same, recorded),
+            Resume::Refetch
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_appends_the_rest_of_the_same_entity() {
+        let folder = temp_folder("resume-append");
+        let (url, requests) = serve(vec![response("206 Partial Content", "\"v1\"", "world")]).await;
+        assert_eq!(resume(&url, &folder, "hello ").await, "hello world");
+        assert!(requests.lock().unwrap()[0].contains("range: bytes=6-"));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_restarts_when_the_range_is_ignored() {
+        let folder = temp_folder("resume-ignored");
+        let (url, requests) = serve(vec![response("200 OK", "\"v1\"", "hello world")]).await;
+        assert_eq!(resume(&url, &folder, "hello ").await, "hello world");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_refetches_when_the_entity_changed() {
+        let folder = temp_folder("resume-changed");
+        let (url, requests) = serve(vec![
+            response("206 Partial Content", "\"v2\"", "there"),
+            response("200 OK", "\"v2\"", "hello there"),
+        ])
+        .await;
+        assert_eq!(resume(&url, &folder, "hello ").await, "hello there");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[1].contains("range:"));
+        fs::remove_dir_all(folder).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn segment_filename_decodes_non_ascii_names() {
+        let url = Url::parse("https://cdn.example/v/映画_第1話.ts?token=1").unwrap();
+        assert!(url.path().ends_with("/%E6%98%A0%E7%94%BB_%E7%AC%AC1%E8%A9%B1.ts"));
+        assert_eq!(segment_filename(&url).as_deref(), Some("映画_第1話.ts"));
+    }
+
+    #[test]
+    fn segment_filename_resolves_without_reencoding() {
+        let base = Url::parse("https://cdn.example/v/index.m3u8").unwrap();
+        let content = "#EXTM3U\n#EXTINF:4,\n%E6%98%A0%E7%94%BB.ts\n#EXTINF:4,\n映画.ts\n";
+        let segments = playlist::parse_segments(content.to_string(), &base).unwrap();
+        for segment in &segments {
+            assert_eq!(segment.url().as_str(), "https://cdn.example/v/%E6%98%A0%E7%94%BB.ts");
+            assert_eq!(segment_filename(&segment.url()).as_deref(), Some("映画.ts"));
+        }
+    }
+
+    #[test]
+    fn segment_filename_replaces_unsafe_characters() {
+        let name = |path: &str| segment_filename(&Url::parse(path).unwrap());
+        assert_eq!(name("https://h/a%2F..%5Cb.ts").as_deref(), Some("a_.._b.ts"));
+        assert_eq!(name("https://h/a%3A%2A%3F%22%3C%3E%7C.ts").as_deref(), Some("a_______.ts"));
+        assert_eq!(name("https://h/a%0Ab.ts").as_deref(), Some("a_b.ts"));
+        assert_eq!(name("https://h/%FF.ts").as_deref(), Some("\u{FFFD}.ts"));
+        assert_eq!(name("https://h/v/%2E%2E"), None);
+        assert_eq!(name("https://h/v/"), None);
+    }
+
+    #[test]
+    fn segment_filename_round_trips_through_the_file_system() {
+        let folder = temp_folder("non-ascii");
+        let url = Url::parse("https://cdn.example/v/%E6%98%A0%E7%94%BB_%E7%AC%AC1%E8%A9%B1.ts");
+        let filename = segment_filename(&url.unwrap()).unwrap();
+        fs::write(folder.join(&filename), b"ts").unwrap();
+        let listed: Vec<_> = fs::read_dir(&folder)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(listed, ["映画_第1話.ts"]);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn concat_escape_closes_and_reopens_quotes() {
+        assert_eq!(concat_escape("/tmp/映画_第1話.ts"), "/tmp/映画_第1話.ts");
+        assert_eq!(concat_escape("/tmp/it's.ts"), r"/tmp/it'\''s.ts");
+        assert_eq!(concat_escape(r"C:\temp\a b.ts"), r"C:\temp\a b.ts");
+    }
+
+    #[test]
+    fn preview_reads_the_segments_like_the_mux() {
+        let files = [PathBuf::from("seg/a.ts"), PathBuf::from("seg/b.ts")];
+        let args = |method| {
+            let input = ConcatInput::new(method, "file_list.txt", &files);
+            let command = preview_command(&input, "out.preview.mp4", 1.0);
+            let args: Vec<String> = command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            args[..args.iter().position(|arg| arg == "-vf").unwrap()].join(" ")
+        };
+        assert_eq!(args(ConcatMethod::Demuxer), "-f concat -safe 0 -i file_list.txt");
+        assert_eq!(args(ConcatMethod::Protocol), "-i concat:seg/a.ts|seg/b.ts");
+        assert_eq!(args(ConcatMethod::Pipe), "-i pipe:0");
+    }
+
+    #[test]
+    fn mp4_output_covers_the_mp4_family() {
+        for output in ["out.mp4", "out.M4V", "out.m4a", "out.mov"] {
+            assert!(mp4_output(None, output), "{}", output);
+        }
+        for output in ["out.mkv", "out.ts", "out.webm", "out"] {
+            assert!(!mp4_output(None, output), "{}", output);
+        }
+        assert!(mp4_output(Some(OutputFormat::Mp4), "out.mkv"));
+        assert!(!mp4_output(Some(OutputFormat::Mkv), "out.mp4"));
+    }
+
+    #[test]
+    fn check_size_rejects_empty_and_implausibly_small_bodies() {
+        let url = Url::parse("https://cdn.example/seg0.ts").unwrap();
+        let error = check_size(&url, 0, 0, None).unwrap_err().to_string();
+        assert_eq!(error, "Segment https://cdn.example/seg0.ts has an empty body");
+        assert!(check_size(&url, 1, 0, None).is_ok());
+        assert!(check_size(&url, 99, 100, None).is_err());
+        assert!(check_size(&url, 100, 100, None).is_ok());
+        assert!(check_size(&url, 9_999, 0, Some(100_000)).is_err());
+        assert!(check_size(&url, 10_000, 0, Some(100_000)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn empty_200_body_fails_the_attempt_without_writing_the_segment() {
+        let folder = temp_folder("empty-body");
+        let (url, _) = serve(vec![
+            response("200 OK", "\"v1\"", ""),
+            response("200 OK", "\"v1\"", "segment"),
+        ])
+        .await;
+        let client = Client::builder().no_proxy().build().unwrap();
+        let accept = HeaderValue::from_static("*/*");
+        let folder_name = folder.to_str().unwrap();
+
+        let mut options = SegmentRequest::new(1, None, accept.clone(), u64::MAX);
+        let error = download_ts_segment(&url, folder_name, &client, &mut options, 0, None, None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().ends_with("has an empty body"), "{}", error);
+        let left: Vec<_> = fs::read_dir(&folder).unwrap().collect();
+        assert!(left.is_empty(), "{:?}", left);
+
+        // The retry gets the real body
+        let mut options = SegmentRequest::new(2, None, accept, u64::MAX);
+        let (size, _) = download_ts_segment(&url, folder_name, &client, &mut options, 0, None, None)
+            .await
+            .unwrap();
+        assert_eq!(size, 7);
+        assert_eq!(fs::read_to_string(folder.join("seg.ts")).unwrap(), "segment");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    /// Probe the first segment of a playlist on `url`'s server.
+    async fn probe(url: &Url) -> Result<Option<probe::Probed>> {
+        let content = format!("#EXTINF:4,\n{}\n", url);
+        let segments = playlist::parse_segments(content, url).unwrap();
+        let client = Client::builder().no_proxy().build().unwrap();
+        let mut options = SegmentRequest::new(1, None, HeaderValue::from_static("*/*"), u64::MAX);
+        probe::probe_first_segment(&client, &segments[0], &mut options, 1).await
+    }
+
+    #[tokio::test]
+    async fn probe_keeps_a_valid_first_segment() {
+        let (url, requests) = serve(vec![response("200 OK", "\"v1\"", "\x47 media")]).await;
+        let probed = probe(&url).await.unwrap().unwrap();
+        assert_eq!(&probed.body[..], b"\x47 media");
+        assert_eq!(probed.content_length, Some(7));
+        assert_eq!(probed.validator.as_deref(), Some("ETag: \"v1\""));
+        assert!(requests.lock().unwrap()[0].contains("accept: */*"));
+    }
+
+    #[tokio::test]
+    async fn probe_leaves_transient_failures_to_the_retries() {
+        for status in ["503 Service Unavailable", "429 Too Many Requests"] {
+            let (url, _) = serve(vec![response(status, "\"v1\"", "busy")]).await;
+            assert!(probe(&url).await.unwrap().is_none(), "{}", status);
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = Url::parse(&format!("http://{}/seg.ts", listener.local_addr().unwrap()));
+        drop(listener);
+        assert!(probe(&closed.unwrap()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn probe_fails_on_client_errors_and_pages() {
+        let (url, _) = serve(vec![response("403 Forbidden", "\"v1\"", "denied")]).await;
+        let error = probe(&url).await.err().unwrap().to_string();
+        assert!(error.contains("it returned HTTP 403 Forbidden"), "{}", error);
+        let (url, _) = serve(vec![response("200 OK", "\"v1\"", "<html>login</html>")]).await;
+        let error = probe(&url).await.err().unwrap().to_string();
+        assert!(error.contains("an HTML/XML page"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn probed_body_is_kept_as_the_segment() {
+        let folder = temp_folder("probed");
+        let (url, requests) = serve(vec![response("200 OK", "\"v1\"", "\x47 media")]).await;
+        let probed = probe(&url).await.unwrap().unwrap();
+        let segments = playlist::parse_segments(format!("{}\n", url), &url).unwrap();
+        let (size, verification, content) =
+            keep_probed(&url, folder.to_str(), probed, 1, &segments[0], None).await.unwrap();
+        assert_eq!((size, verification, content), (7, Verification::Size, None));
+        assert_eq!(fs::read(folder.join("seg.ts")).unwrap(), b"\x47 media");
+        assert!(!folder.join("seg.ts.part").exists());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
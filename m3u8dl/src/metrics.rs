@@ -0,0 +1,304 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::exit::ExitKind;
+use crate::http;
+use crate::logging::status;
+
+static SEGMENTS_TOTAL: AtomicUsize = AtomicUsize::new(0);
+static SEGMENTS_DOWNLOADED: AtomicUsize = AtomicUsize::new(0);
+static SEGMENTS_FAILED: AtomicUsize = AtomicUsize::new(0);
+static RETRIES: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static PLAYLIST_FETCHES: AtomicUsize = AtomicUsize::new(0);
+/// Unix time of the last failed segment attempt, or zero before any.
+static LAST_ERROR: AtomicU64 = AtomicU64::new(0);
+static PHASE: Mutex<&str> = Mutex::new("starting");
+/// Bytes downloaded so far, sampled every [`SAMPLE_INTERVAL`] over the last
+/// [`SPEED_WINDOW`], which the speed is taken over.
+static SAMPLES: Mutex<VecDeque<(Instant, u64)>> = Mutex::new(VecDeque::new());
+
+/// Longest request the endpoint reads; a scrape is one short GET.
+const MAX_REQUEST: usize = 8 * 1024;
+/// How long a client may take to send its request before it is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The span the download speed is measured over, whoever scrapes and when.
+const SPEED_WINDOW: Duration = Duration::from_secs(10);
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the run is doing, e.g. "fetching the playlist" or "muxing".
+pub fn set_phase(phase: &'static str) {
+    *PHASE.lock().unwrap() = phase;
+}
+
+/// The number of segments the download is after.
+pub fn set_total_segments(total: usize) {
+    SEGMENTS_TOTAL.store(total, Ordering::Relaxed);
+}
+
+/// Count a segment that is done with, and its size if it downloaded.
+pub fn segment_finished(size: Option<u64>) {
+    match size {
+        Some(size) => {
+            SEGMENTS_DOWNLOADED.fetch_add(1, Ordering::Relaxed);
+            BYTES.fetch_add(size, Ordering::Relaxed);
+        }
+        None => {
+            SEGMENTS_FAILED.fetch_add(1, Ordering::Relaxed);
+            record_error();
+        }
+    }
+}
+
+/// Count a failed segment attempt that is to be retried.
+pub fn retrying() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+    record_error();
+}
+
+fn record_error() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    LAST_ERROR.store(now, Ordering::Relaxed);
+}
+
+pub fn playlist_fetched() {
+    PLAYLIST_FETCHES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a segment download in flight until dropped.
+pub struct Active(());
+
+pub fn active() -> Active {
+    ACTIVE.fetch_add(1, Ordering::Relaxed);
+    Active(())
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// `--metrics-port`: serve the counters in the Prometheus text format at
+/// `http://127.0.0.1:<port>/metrics` until the returned task is aborted.
+pub async fn serve(port: u16) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("Failed to listen on port {} for --metrics-port", port))
+        .context(ExitKind::Usage)?;
+    status!("Serving metrics at http://127.0.0.1:{}/metrics", port);
+    let mut samples = tokio::time::interval(SAMPLE_INTERVAL);
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = samples.tick() => sample(),
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        tokio::spawn(async move {
+                            if let Err(error) = answer(stream).await {
+                                tracing::debug!("Metrics request failed: {}", error);
+                            }
+                        });
+                    }
+                    Err(error) => {
+                        tracing::debug!("Failed to accept a metrics request: {}", error)
+                    }
+                },
+            }
+        }
+    }))
+}
+
+/// Take a sample of the bytes downloaded, dropping those out of the window.
+fn sample() {
+    let now = Instant::now();
+    let mut samples = SAMPLES.lock().unwrap();
+    samples.push_back((now, BYTES.load(Ordering::Relaxed)));
+    while samples
+        .front()
+        .is_some_and(|(at, _)| now.duration_since(*at) > SPEED_WINDOW)
+    {
+        samples.pop_front();
+    }
+}
+
+/// Bytes per second over the samples in the window, up to now.
+fn speed() -> f64 {
+    let samples = SAMPLES.lock().unwrap();
+    let Some(&(first, before)) = samples.front() else {
+        return 0.0;
+    };
+    let bytes = BYTES.load(Ordering::Relaxed);
+    (bytes - before) as f64 / first.elapsed().as_secs_f64().max(0.001)
+}
+
+async fn answer(mut stream: TcpStream) -> std::io::Result<()> {
+    let read = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await;
+    let request = match read {
+        Ok(request) => request?,
+        Err(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "no request within the timeout",
+            ))
+        }
+    };
+    let request = String::from_utf8_lossy(&request);
+    let mut line = request.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (line.next(), line.next());
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(),
+        ),
+        _ => (
+            "404 Not Found",
+            "text/plain",
+            "Not found; try /metrics\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read up to the end of the request's headers, or [`MAX_REQUEST`] bytes.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    Ok(request)
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+        let _ = writeln!(out, "# HELP m3u8dl_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE m3u8dl_{} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "m3u8dl_{}{} {}", name, labels, value);
+        }
+    };
+    let value = |value: usize| vec![(String::new(), value.to_string())];
+
+    let bytes = BYTES.load(Ordering::Relaxed);
+    let speed = speed();
+
+    metric(
+        "segments",
+        "gauge",
+        "Segments the download is after.",
+        &value(SEGMENTS_TOTAL.load(Ordering::Relaxed)),
+    );
+    metric(
+        "segments_downloaded_total",
+        "counter",
+        "Segments downloaded.",
+        &value(SEGMENTS_DOWNLOADED.load(Ordering::Relaxed)),
+    );
+    metric(
+        "segments_failed_total",
+        "counter",
+        "Segments given up on.",
+        &value(SEGMENTS_FAILED.load(Ordering::Relaxed)),
+    );
+    metric(
+        "segment_retries_total",
+        "counter",
+        "Failed segment attempts that were retried.",
+        &value(RETRIES.load(Ordering::Relaxed)),
+    );
+    metric(
+        "downloaded_bytes_total",
+        "counter",
+        "Bytes of the downloaded segments.",
+        &[(String::new(), bytes.to_string())],
+    );
+    metric(
+        "download_speed_bytes",
+        "gauge",
+        &format!(
+            "Bytes per second downloaded over the last {} seconds.",
+            SPEED_WINDOW.as_secs()
+        ),
+        &[(String::new(), format!("{:.0}", speed))],
+    );
+    metric(
+        "active_downloads",
+        "gauge",
+        "Segment downloads in flight.",
+        &value(ACTIVE.load(Ordering::Relaxed)),
+    );
+    metric(
+        "playlist_fetches_total",
+        "counter",
+        "Playlists fetched.",
+        &value(PLAYLIST_FETCHES.load(Ordering::Relaxed)),
+    );
+    metric(
+        "last_error_timestamp_seconds",
+        "gauge",
+        "Unix time of the last failed segment attempt, or 0.",
+        &[(
+            String::new(),
+            LAST_ERROR.load(Ordering::Relaxed).to_string(),
+        )],
+    );
+    metric(
+        "phase",
+        "gauge",
+        "What the run is doing.",
+        &[(
+            format!("{{phase=\"{}\"}}", escape(&PHASE.lock().unwrap())),
+            "1".to_string(),
+        )],
+    );
+    let responses: Vec<(String, String)> = http::responses_by_host()
+        .into_iter()
+        .map(|((host, status), count)| {
+            (
+                format!("{{host=\"{}\",status=\"{}\"}}", escape(&host), status),
+                count.to_string(),
+            )
+        })
+        .collect();
+    metric(
+        "http_responses_total",
+        "counter",
+        "HTTP responses, by host and status code.",
+        &responses,
+    );
+    out
+}
+
+/// Escape a label value for the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
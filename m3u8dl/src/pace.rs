@@ -0,0 +1,82 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How much faster than realtime `--pace realtime` downloads by default.
+const DEFAULT_FACTOR: f64 = 1.25;
+
+/// `--pace realtime[:FACTOR]`: download content at about FACTOR times the
+/// speed it plays at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pace {
+    pub factor: f64,
+}
+
+impl FromStr for Pace {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid pace '{}': expected realtime or realtime:FACTOR, like realtime:1.5",
+                value
+            )
+        };
+        let (mode, factor) = match value.trim().split_once(':') {
+            Some((mode, factor)) => (mode, factor.parse().map_err(|_| invalid())?),
+            None => (value.trim(), DEFAULT_FACTOR),
+        };
+        let valid = mode.eq_ignore_ascii_case("realtime") && factor > 0.0 && factor.is_finite();
+        if !valid {
+            return Err(invalid());
+        }
+        Ok(Pace { factor })
+    }
+}
+
+impl fmt::Display for Pace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x realtime", self.factor)
+    }
+}
+
+/// Holds each segment back until the content started so far, at the pace's
+/// factor, has had time to play. Transfers still run at full speed; only
+/// their starts are spread out.
+pub struct Pacer {
+    factor: f64,
+    started: Instant,
+    /// Seconds of content let through so far.
+    admitted: Mutex<f64>,
+    /// Seconds of content to let through in all.
+    total: f64,
+}
+
+impl Pacer {
+    /// A pacer for `total` seconds of content, starting now.
+    pub fn new(pace: Pace, total: f64) -> Self {
+        Self {
+            factor: pace.factor,
+            started: Instant::now(),
+            admitted: Mutex::new(0.0),
+            total,
+        }
+    }
+
+    /// Wait for the turn of a segment `duration` seconds long.
+    pub async fn admit(&self, duration: f64) {
+        let slot = {
+            let mut admitted = self.admitted.lock().unwrap();
+            let slot = self.started + Duration::from_secs_f64(*admitted / self.factor);
+            *admitted += duration;
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+    }
+
+    /// About when the last segment will be let through.
+    pub fn finishes_at(&self) -> Instant {
+        self.started + Duration::from_secs_f64(self.total / self.factor)
+    }
+}
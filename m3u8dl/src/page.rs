@@ -0,0 +1,240 @@
+use std::fmt::Write;
+
+use url::Url;
+
+/// How many candidate playlists an error lists before eliding the rest.
+const MAX_LISTED: usize = 10;
+
+/// What the playlist URL served instead of an m3u8 playlist.
+#[derive(Debug)]
+pub enum NotPlaylist {
+    /// A DASH manifest (`<MPD ...>`).
+    Dash,
+    /// A web page, such as the player page, with the m3u8 URLs it mentions.
+    Page { candidates: Vec<Url> },
+}
+
+/// Tell apart a DASH manifest or a web page from a playlist, or `None` if
+/// `content` is a playlist or neither of those.
+pub fn identify(content: &str, url: &Url) -> Option<NotPlaylist> {
+    let start = content.trim_start_matches('\u{feff}').trim_start();
+    if start.starts_with("#EXTM3U") || !start.starts_with('<') {
+        return None;
+    }
+    let head = start
+        .char_indices()
+        .nth(2048)
+        .map_or(start, |(end, _)| &start[..end])
+        .to_ascii_lowercase();
+    if head.contains("<mpd") {
+        Some(NotPlaylist::Dash)
+    } else if head.contains("<html") || head.starts_with("<!doctype html") {
+        Some(NotPlaylist::Page {
+            candidates: candidates(content, url),
+        })
+    } else {
+        None
+    }
+}
+
+impl NotPlaylist {
+    /// The playlist to follow with `--scan-page`: the page's only candidate.
+    pub fn followable(&self) -> Option<&Url> {
+        match self {
+            NotPlaylist::Page { candidates } if candidates.len() == 1 => candidates.first(),
+            _ => None,
+        }
+    }
+
+    /// An error explaining what `url` is and what to use instead.
+    pub fn error(&self, url: &Url) -> anyhow::Error {
+        let mut message = match self {
+            NotPlaylist::Dash => {
+                return anyhow::anyhow!(
+                    "{} is a DASH manifest (MPD), not an HLS playlist; only HLS (m3u8) \
+                     is supported. Check whether the site also offers an .m3u8 URL.",
+                    url
+                );
+            }
+            NotPlaylist::Page { .. } => format!("{} is a web page, not an m3u8 playlist.", url),
+        };
+        match self.followable() {
+            Some(candidate) => {
+                let _ = write!(
+                    message,
+                    " It links to one playlist:\n  {}\nDownload that URL instead, \
+                     or pass --scan-page to follow it.",
+                    candidate
+                );
+            }
+            None => match self {
+                NotPlaylist::Page { candidates } if !candidates.is_empty() => {
+                    let _ = write!(message, " It links to {} playlists:", candidates.len());
+                    for candidate in candidates.iter().take(MAX_LISTED) {
+                        let _ = write!(message, "\n  {}", candidate);
+                    }
+                    if candidates.len() > MAX_LISTED {
+                        let _ = write!(message, "\n  ...");
+                    }
+                    message.push_str("\nDownload one of them instead.");
+                }
+                _ => message.push_str(
+                    " It mentions no .m3u8 URL; the player probably builds it in \
+                     JavaScript, so look for the .m3u8 request in the browser's network tab.",
+                ),
+            },
+        }
+        anyhow::anyhow!(message)
+    }
+}
+
+/// The distinct http(s) `.m3u8` URLs mentioned in a page, in the order they
+/// first appear, resolved against the page's `url`.
+///
+/// URLs are picked out of attributes, scripts and inline JSON alike, so
+/// JSON-escaped slashes and HTML-escaped ampersands are undone first.
+pub fn candidates(html: &str, url: &Url) -> Vec<Url> {
+    let text = html
+        .replace("\\/", "/")
+        .replace("\\u002F", "/")
+        .replace("\\u002f", "/")
+        .replace("&amp;", "&");
+    let is_delimiter =
+        |c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '`' | '<' | '>' | '(' | ')' | '\\');
+
+    let mut found: Vec<Url> = Vec::new();
+    for (at, _) in text.match_indices(".m3u8") {
+        let begin = text[..at]
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| is_delimiter(c) || c == '=')
+            .map_or(0, |(index, c)| index + c.len_utf8());
+        let after = &text[at + ".m3u8".len()..];
+        let end = if after.starts_with('?') {
+            after.find(is_delimiter).unwrap_or(after.len())
+        } else if after.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+            // e.g. `.m3u8x`, not an m3u8 URL
+            continue;
+        } else {
+            0
+        };
+        let candidate = &text[begin..at + ".m3u8".len() + end];
+        // Templates such as `{id}.m3u8` or `*.m3u8` aren't URLs yet
+        if candidate.contains(['{', '}', '*', '$', '[', ']']) || candidate == ".m3u8" {
+            continue;
+        }
+        let Ok(resolved) = url.join(candidate) else {
+            continue;
+        };
+        if matches!(resolved.scheme(), "http" | "https") && !found.contains(&resolved) {
+            found.push(resolved);
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    fn page(content: &str) -> Vec<String> {
+        match identify(content, &url("https://www.example.com/watch/ep12")) {
+            Some(NotPlaylist::Page { candidates }) => {
+                candidates.iter().map(Url::to_string).collect()
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn identify_leaves_playlists_alone() {
+        let page_url = url("https://cdn.example.com/index.m3u8");
+        assert!(identify("#EXTM3U\n#EXTINF:4,\nseg0.ts\n", &page_url).is_none());
+        assert!(identify("\u{feff}\n#EXTM3U\n<html>", &page_url).is_none());
+        assert!(identify("{\"error\":\"not found\"}", &page_url).is_none());
+        assert!(identify("<?xml version=\"1.0\"?><rss></rss>", &page_url).is_none());
+    }
+
+    #[test]
+    fn identify_detects_dash_manifests() {
+        let mpd_url = url("https://cdn.example.com/vod/ep12/manifest.mpd");
+        let content = include_str!("../fixtures/pages/manifest.mpd");
+        let dash = identify(content, &mpd_url).unwrap();
+        assert!(matches!(dash, NotPlaylist::Dash));
+        assert!(dash.followable().is_none());
+        assert!(dash
+            .error(&mpd_url)
+            .to_string()
+            .contains("is a DASH manifest (MPD)"));
+    }
+
+    #[test]
+    fn player_page_offers_its_only_playlist() {
+        let content = include_str!("../fixtures/pages/player.html");
+        assert_eq!(
+            page(content),
+            ["https://cdn.example.com/vod/ep12/master.m3u8?token=abc&exp=1700000000"]
+        );
+        let page_url = url("https://www.example.com/watch/ep12");
+        let found = identify(content, &page_url).unwrap();
+        assert_eq!(found.followable(), Some(&url(&page(content)[0])));
+        let error = found.error(&page_url).to_string();
+        assert!(error.contains("It links to one playlist"), "{}", error);
+        assert!(error.contains("--scan-page"), "{}", error);
+    }
+
+    #[test]
+    fn gallery_page_lists_each_distinct_playlist() {
+        let content = include_str!("../fixtures/pages/gallery.html");
+        assert_eq!(
+            page(content),
+            [
+                "https://www.example.com/live/720p.m3u8",
+                "https://www.example.com/watch/480p.m3u8?lang=en&cc=1",
+                "https://backup.example.net/live/720p.m3u8",
+            ]
+        );
+        let page_url = url("https://www.example.com/watch/ep12");
+        let found = identify(content, &page_url).unwrap();
+        assert!(found.followable().is_none());
+        let error = found.error(&page_url).to_string();
+        assert!(error.contains("It links to 3 playlists:"), "{}", error);
+        assert!(
+            error.ends_with("Download one of them instead."),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn script_built_urls_are_not_candidates() {
+        let content = include_str!("../fixtures/pages/spa.html");
+        assert!(page(content).is_empty());
+        let page_url = url("https://www.example.com/watch/ep12");
+        let error = identify(content, &page_url)
+            .unwrap()
+            .error(&page_url)
+            .to_string();
+        assert!(error.contains("network tab"), "{}", error);
+    }
+
+    #[test]
+    fn long_candidate_lists_are_elided() {
+        let links: String = (0..12)
+            .map(|index| format!("<a href=\"v{}.m3u8\">{}</a>\n", index, index))
+            .collect();
+        let content = format!("<html><body>{}</body></html>", links);
+        let page_url = url("https://www.example.com/");
+        let error = identify(&content, &page_url)
+            .unwrap()
+            .error(&page_url)
+            .to_string();
+        assert!(error.contains("It links to 12 playlists:"), "{}", error);
+        assert!(error.contains("v9.m3u8\n  ...\n"), "{}", error);
+        assert!(!error.contains("v10.m3u8"), "{}", error);
+    }
+}
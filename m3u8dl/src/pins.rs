@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::logging::status;
+use crate::playlist::Variant;
+
+/// A lock file older than this is assumed to be left over from a crashed run.
+const STALE_LOCK: Duration = Duration::from_secs(30);
+
+/// The characteristics of a variant chosen on an earlier run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pin {
+    /// `WIDTHxHEIGHT`, if the variant declared one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codecs: Option<String>,
+    /// Informational; bandwidth drifts between runs, so it only breaks ties.
+    #[serde(default)]
+    pub bandwidth: u64,
+}
+
+impl Pin {
+    fn of(variant: &Variant) -> Self {
+        Self {
+            resolution: variant
+                .resolution
+                .map(|(width, height)| format!("{}x{}", width, height)),
+            codecs: variant.codecs.clone(),
+            bandwidth: variant.bandwidth,
+        }
+    }
+
+    fn matches(&self, variant: &Variant) -> bool {
+        let pin = Pin::of(variant);
+        pin.resolution == self.resolution && pin.codecs == self.codecs
+    }
+
+    fn pixels(&self) -> Option<i64> {
+        let (width, height) = self.resolution.as_deref()?.split_once('x')?;
+        Some(width.parse::<i64>().ok()? * height.parse::<i64>().ok()?)
+    }
+}
+
+/// Pick a variant of the master playlist at `master_url`, preferring the one
+/// pinned in `pin_file` on an earlier run and pinning the best one otherwise.
+pub fn choose<'a>(
+    pin_file: &Path,
+    master_url: &Url,
+    variants: &'a [Variant],
+) -> Result<&'a Variant> {
+    let key = pin_key(master_url);
+    let best = variants
+        .iter()
+        .max_by_key(|variant| variant.bandwidth)
+        .context("Master playlist lists no variant streams")?;
+
+    let Some(pin) = load(pin_file)?.remove(&key) else {
+        status!(
+            "Pinning variant ({}) for {} in {}",
+            best,
+            key,
+            pin_file.display()
+        );
+        update(pin_file, &key, Pin::of(best))?;
+        return Ok(best);
+    };
+
+    let by_bandwidth = |variant: &&Variant| variant.bandwidth.abs_diff(pin.bandwidth);
+    if let Some(exact) = variants
+        .iter()
+        .filter(|variant| pin.matches(variant))
+        .min_by_key(by_bandwidth)
+    {
+        return Ok(exact);
+    }
+
+    // Closest resolution first, then the same codecs, then the nearest bandwidth
+    let closest = variants
+        .iter()
+        .min_by_key(|variant| {
+            let candidate = Pin::of(variant);
+            let resolution_gap = match (candidate.pixels(), pin.pixels()) {
+                (Some(a), Some(b)) => (a - b).abs(),
+                (None, None) => 0,
+                _ => i64::MAX,
+            };
+            (
+                resolution_gap,
+                candidate.codecs != pin.codecs,
+                variant.bandwidth.abs_diff(pin.bandwidth),
+            )
+        })
+        .context("Master playlist lists no variant streams")?;
+    status!(
+        "Warning: the pinned variant ({}, {}) is no longer offered; using the closest match ({})",
+        pin.resolution.as_deref().unwrap_or("no resolution"),
+        pin.codecs.as_deref().unwrap_or("no codecs"),
+        closest
+    );
+    Ok(closest)
+}
+
+/// Pins apply to a master playlist regardless of its (often signed) query string.
+fn pin_key(master_url: &Url) -> String {
+    let mut key = master_url.clone();
+    key.set_query(None);
+    key.set_fragment(None);
+    key.to_string()
+}
+
+fn load(pin_file: &Path) -> Result<BTreeMap<String, Pin>> {
+    match fs::read_to_string(pin_file) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse pin file {}", pin_file.display())),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(error) => {
+            Err(error).with_context(|| format!("Failed to read pin file {}", pin_file.display()))
+        }
+    }
+}
+
+/// Add one pin, holding `<file>.lock` so concurrent runs don't drop each
+/// other's entries, and replacing the file atomically so readers never see
+/// a half-written one.
+fn update(pin_file: &Path, key: &str, pin: Pin) -> Result<()> {
+    let lock_path = sibling(pin_file, "lock");
+    let _lock = FileLock::acquire(&lock_path)?;
+
+    let mut pins = load(pin_file)?;
+    pins.entry(key.to_string()).or_insert(pin);
+
+    let temp_path = sibling(pin_file, "tmp");
+    fs::write(&temp_path, serde_json::to_string_pretty(&pins)? + "\n")
+        .with_context(|| format!("Failed to write pin file {}", temp_path.display()))?;
+    fs::rename(&temp_path, pin_file)
+        .with_context(|| format!("Failed to replace pin file {}", pin_file.display()))
+}
+
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+struct FileLock {
+    path: PathBuf,
+}
+
+impl FileLock {
+    fn acquire(path: &Path) -> Result<Self> {
+        loop {
+            match File::options().write(true).create_new(true).open(path) {
+                Ok(_) => {
+                    return Ok(Self {
+                        path: path.to_path_buf(),
+                    })
+                }
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                    let stale = fs::metadata(path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                        .is_some_and(|age| age > STALE_LOCK);
+                    if stale {
+                        let _ = fs::remove_file(path);
+                    } else {
+                        sleep(Duration::from_millis(50));
+                    }
+                }
+                Err(error) => {
+                    return Err(error).with_context(|| format!("Failed to lock {}", path.display()))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
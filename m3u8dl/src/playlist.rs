@@ -0,0 +1,1070 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use tokio::io::AsyncReadExt;
+use url::Url;
+
+use crate::http;
+use crate::logging::status;
+use crate::metrics;
+use crate::page;
+use crate::pins;
+use crate::progress::Phases;
+use crate::quality::{self, Quality};
+
+/// A single media segment as listed in the playlist.
+///
+/// Segments point into the shared playlist text rather than holding their
+/// own URL, so that a DVR playlist with hundreds of thousands of entries
+/// costs a few dozen bytes per segment; the URL is resolved on demand.
+#[derive(Clone)]
+pub struct Segment {
+    /// Position of the segment in the playlist, starting at zero.
+    pub index: usize,
+    /// Duration in seconds from the preceding `#EXTINF` tag, or zero if absent.
+    pub duration: f64,
+    /// Approximate bitrate in kbit/s from the latest `#EXT-X-BITRATE` tag.
+    pub bitrate: Option<u32>,
+    /// Whether an `#EXT-X-DISCONTINUITY` tag comes before the segment, e.g.
+    /// where material from another encoder is spliced in.
+    pub discontinuity: bool,
+    source: Arc<Source>,
+    uri: Range<usize>,
+}
+
+/// The text a media playlist was parsed from, and the URL it is relative to.
+struct Source {
+    base_url: Url,
+    content: String,
+}
+
+impl Segment {
+    /// The URI exactly as written in the playlist.
+    pub fn uri(&self) -> &str {
+        &self.source.content[self.uri.clone()]
+    }
+
+    /// Absolute URL of the segment, resolved against the playlist.
+    pub fn url(&self) -> Url {
+        self.source
+            .base_url
+            .join(self.uri())
+            .expect("segment URIs are validated when the playlist is parsed")
+    }
+
+    /// Size in bytes that the segment's `#EXT-X-BITRATE` and duration predict.
+    pub fn predicted_size(&self) -> Option<u64> {
+        let bitrate = self.bitrate?;
+        (self.duration > 0.0).then(|| (f64::from(bitrate) * 1000.0 / 8.0 * self.duration) as u64)
+    }
+}
+
+impl fmt::Debug for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Segment")
+            .field("index", &self.index)
+            .field("uri", &self.uri())
+            .field("duration", &self.duration)
+            .field("bitrate", &self.bitrate)
+            .field("discontinuity", &self.discontinuity)
+            .finish()
+    }
+}
+
+/// Durations closer than this are the same; `#EXTINF` values are rounded.
+const DURATION_TOLERANCE: f64 = 0.001;
+
+/// The segments between one `#EXT-X-DISCONTINUITY` and the next, as index
+/// ranges into `segments`; a single range when there are none.
+pub fn discontinuity_groups(segments: &[Segment]) -> Vec<Range<usize>> {
+    let mut starts: Vec<usize> = (1..segments.len())
+        .filter(|&index| segments[index].discontinuity)
+        .collect();
+    starts.insert(0, 0);
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain([&segments.len()]))
+        .map(|(start, end)| *start..*end)
+        .filter(|range| !range.is_empty())
+        .collect()
+}
+
+/// A media playlist and the segments it lists.
+#[derive(Debug, Clone)]
+pub struct MediaPlaylist {
+    /// Where the media playlist was fetched from (the chosen variant, for a master playlist).
+    pub url: Url,
+    /// The master playlist the variant was chosen from, if there was one.
+    pub variant_of: Option<Url>,
+    /// The variant chosen from it, as the master playlist listed it.
+    pub variant: Option<Variant>,
+    pub segments: Vec<Segment>,
+    /// The `#EXT-X-MAP` initialization sections of an fMP4 playlist, in order.
+    pub init_sections: Vec<InitSection>,
+}
+
+impl MediaPlaylist {
+    /// The segments each initialization section applies to, as index ranges.
+    pub fn init_regions(&self) -> Vec<Range<usize>> {
+        let starts: Vec<usize> = self
+            .init_sections
+            .iter()
+            .map(|section| section.first_segment)
+            .collect();
+        starts
+            .iter()
+            .zip(starts.iter().skip(1).chain([&self.segments.len()]))
+            .map(|(start, end)| *start..*end)
+            .collect()
+    }
+
+    /// Drop the first segment, for `--skip-first`, numbering the rest from
+    /// zero again. The initialization section it used still applies to the
+    /// segment after it, unless that one starts a section of its own.
+    pub fn skip_first(&mut self) -> Result<Segment> {
+        anyhow::ensure!(
+            self.segments.len() > 1,
+            "--skip-first leaves no segments to download: {} has only {}",
+            self.url,
+            self.segments.len()
+        );
+        let skipped = self.segments.remove(0);
+        for segment in &mut self.segments {
+            segment.index -= 1;
+        }
+        for section in &mut self.init_sections {
+            section.first_segment = section.first_segment.saturating_sub(1);
+        }
+        if let Some(last) = self
+            .init_sections
+            .iter()
+            .rposition(|section| section.first_segment == 0)
+        {
+            self.init_sections.drain(..last);
+        }
+        Ok(skipped)
+    }
+
+    /// The text the segments were parsed from, for `--project` to keep.
+    pub fn content(&self) -> Option<&str> {
+        self.segments
+            .first()
+            .map(|segment| segment.source.content.as_str())
+    }
+
+    /// Whether the playlist lets its segments be kept for later, i.e. it
+    /// has no `#EXT-X-ALLOW-CACHE:NO`.
+    pub fn allows_caching(&self) -> bool {
+        !self.content().is_some_and(forbids_caching)
+    }
+
+    /// The playlist fetched from `url` as `content`, e.g. a `--project` snapshot.
+    pub fn from_content(url: Url, variant_of: Option<Url>, content: String) -> Result<Self> {
+        let init_sections = parse_init_sections(&content, &url)?;
+        Ok(MediaPlaylist {
+            segments: parse_segments(content, &url)?,
+            url,
+            variant_of,
+            variant: None,
+            init_sections,
+        })
+    }
+
+    /// Fetch the media playlist again from where it came from, to see whether
+    /// it was republished since.
+    pub async fn refetch(&self, client: &Client) -> Result<MediaPlaylist> {
+        let (_, m3u8_content) = fetch_playlist(client, &self.url).await?;
+        anyhow::ensure!(
+            matches!(classify(&m3u8_content)?, PlaylistKind::Media),
+            "{} is no longer a media playlist",
+            self.url
+        );
+        let mut newer =
+            MediaPlaylist::from_content(self.url.clone(), self.variant_of.clone(), m3u8_content)?;
+        newer.variant = self.variant.clone();
+        Ok(newer)
+    }
+
+    /// How `newer`, a later fetch of the same playlist, differs from this one.
+    pub fn diff(&self, newer: &MediaPlaylist) -> PlaylistDiff {
+        let starts = |segments: &[Segment]| -> Vec<f64> {
+            segments
+                .iter()
+                .scan(0.0, |start, segment| {
+                    let this = *start;
+                    *start += segment.duration;
+                    Some(this)
+                })
+                .collect()
+        };
+        let (old_starts, new_starts) = (starts(&self.segments), starts(&newer.segments));
+        // The same URI over the same span of the stream is the same content
+        let same = |index: usize| {
+            let (old, new) = (&self.segments[index], &newer.segments[index]);
+            old.uri() == new.uri()
+                && (old.duration - new.duration).abs() < DURATION_TOLERANCE
+                && (old_starts[index] - new_starts[index]).abs() < DURATION_TOLERANCE
+        };
+        let shared = self.segments.len().min(newer.segments.len());
+        let unchanged: Vec<usize> = (0..shared).filter(|index| same(*index)).collect();
+        let first_change = (0..shared)
+            .find(|index| !same(*index))
+            .map(|index| {
+                let (old, new) = (&self.segments[index], &newer.segments[index]);
+                (index, old.uri().to_string(), new.uri().to_string())
+            });
+        let init_changed = self
+            .init_sections
+            .iter()
+            .map(|section| (&section.url, section.first_segment))
+            .ne(newer
+                .init_sections
+                .iter()
+                .map(|section| (&section.url, section.first_segment)));
+        PlaylistDiff {
+            old_count: self.segments.len(),
+            new_count: newer.segments.len(),
+            old_duration: self.segments.iter().map(|segment| segment.duration).sum(),
+            new_duration: newer.segments.iter().map(|segment| segment.duration).sum(),
+            unchanged,
+            first_change,
+            init_changed,
+        }
+    }
+}
+
+/// What changed between two fetches of a media playlist, by segment count,
+/// durations and names, from [`MediaPlaylist::diff`].
+#[derive(Debug, Clone)]
+pub struct PlaylistDiff {
+    pub old_count: usize,
+    pub new_count: usize,
+    pub old_duration: f64,
+    pub new_duration: f64,
+    /// Positions whose segment is the same in both: same URI, duration and
+    /// start time.
+    pub unchanged: Vec<usize>,
+    /// The first position both have whose segment differs, with its URI
+    /// before and now.
+    pub first_change: Option<(usize, String, String)>,
+    /// Whether the `#EXT-X-MAP` initialization sections differ.
+    pub init_changed: bool,
+}
+
+impl PlaylistDiff {
+    pub fn is_changed(&self) -> bool {
+        self.old_count != self.new_count || self.first_change.is_some() || self.init_changed
+    }
+}
+
+impl fmt::Display for PlaylistDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} segments ({}) when the download started, {} ({}) now",
+            self.old_count,
+            format_timestamp(self.old_duration),
+            self.new_count,
+            format_timestamp(self.new_duration)
+        )?;
+        if let Some((index, old, new)) = &self.first_change {
+            let shared = self.old_count.min(self.new_count);
+            write!(
+                f,
+                "; {} of the {} positions both have differ, the first at segment {} ({} became {})",
+                shared - self.unchanged.len(),
+                shared,
+                index,
+                old,
+                new
+            )?;
+        }
+        if self.init_changed {
+            write!(f, "; the initialization sections changed")?;
+        }
+        Ok(())
+    }
+}
+
+/// An `#EXT-X-MAP` initialization section, which applies from `first_segment`
+/// until the next section (an fMP4 stream switches them at discontinuities).
+#[derive(Debug, Clone)]
+pub struct InitSection {
+    pub url: Url,
+    pub first_segment: usize,
+}
+
+/// How to pick one variant of a master playlist.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VariantPreferences<'a> {
+    /// Prefer the variant pinned in this file over the highest-bandwidth one.
+    pub pin_file: Option<&'a Path>,
+    /// Only consider variants at least this good.
+    pub min_quality: Option<Quality>,
+    /// Only consider variants at most this good.
+    pub max_quality: Option<Quality>,
+    /// Variants that already failed (`--retry-different-variant`), to pass
+    /// over for the best of the rest.
+    pub exclude: &'a [Url],
+}
+
+/// Whether a playlist lists variant streams or media segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistKind {
+    /// Lists variant streams (`#EXT-X-STREAM-INF`).
+    Master,
+    /// Lists media segments (`#EXTINF`).
+    Media,
+}
+
+/// One `#EXT-X-STREAM-INF` entry of a master playlist.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub url: Url,
+    pub bandwidth: u64,
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>,
+}
+
+/// One `#EXT-X-MEDIA` rendition of a master playlist: an alternative audio,
+/// subtitle or video track.
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    /// `AUDIO`, `VIDEO`, `SUBTITLES` or `CLOSED-CAPTIONS`.
+    pub kind: String,
+    pub group_id: String,
+    pub language: Option<String>,
+    pub name: String,
+    pub default: bool,
+    pub autoselect: bool,
+    /// The rendition's media playlist; absent for closed captions, and for
+    /// renditions carried in the variant streams themselves.
+    pub url: Option<Url>,
+}
+
+impl std::fmt::Display for Variant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bps", self.bandwidth)?;
+        if let Some((width, height)) = self.resolution {
+            write!(f, ", {}x{}", width, height)?;
+        }
+        if let Some(codecs) = &self.codecs {
+            write!(f, ", {}", codecs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Classify a playlist by its tags, rejecting playlists that mix both kinds.
+pub fn classify(m3u8_content: &str) -> Result<PlaylistKind> {
+    let lines = || m3u8_content.lines().map(str::trim);
+    let has_variants = lines().any(|line| line.starts_with("#EXT-X-STREAM-INF"));
+    let has_segments = lines().any(|line| line.starts_with("#EXTINF"));
+
+    match (has_variants, has_segments) {
+        (true, true) => anyhow::bail!(
+            "Malformed playlist: it contains both variant streams (#EXT-X-STREAM-INF) \
+             and media segments (#EXTINF)"
+        ),
+        (true, false) => Ok(PlaylistKind::Master),
+        (false, _) => Ok(PlaylistKind::Media),
+    }
+}
+
+/// How many master playlists may lead to one another before the media playlist.
+const MAX_MASTER_DEPTH: usize = 8;
+
+/// Fetch the playlist and parse it into its list of segments, following a
+/// master playlist to its highest-bandwidth variant.
+///
+/// A variant that leads back to a playlist already seen (directly or through
+/// a redirect), or masters nested more than [`MAX_MASTER_DEPTH`] deep, fail
+/// the run rather than being followed forever.
+///
+/// A `m3u8_url` of `-` reads the playlist from stdin instead, resolving its
+/// URIs against `base_url`. `preferences` narrows down and steers the variant choice.
+///
+/// A DASH manifest or a web page in place of the playlist fails with what
+/// to use instead; with `scan_page`, a page linking to exactly one playlist
+/// leads to that playlist.
+///
+/// Each step is shown on the `phases` spinner as it starts.
+pub async fn fetch_segments(
+    client: &Client,
+    m3u8_url: &str,
+    base_url: Option<&Url>,
+    preferences: VariantPreferences<'_>,
+    scan_page: bool,
+    phases: &Phases,
+) -> Result<MediaPlaylist> {
+    let mut visited = HashSet::new();
+    let (mut playlist_url, mut m3u8_content) = if m3u8_url == "-" {
+        let base_url = base_url.context("Reading the playlist from stdin requires --base-url")?;
+        phases.start("reading the playlist from stdin");
+        (base_url.clone(), read_stdin().await?)
+    } else {
+        let playlist_url = Url::parse(m3u8_url)?;
+        check_scheme(&playlist_url, "playlist")?;
+        phases.start("fetching the playlist");
+        let (final_url, m3u8_content) = fetch_playlist(client, &playlist_url).await?;
+        visited.insert(final_url);
+        (playlist_url, m3u8_content)
+    };
+    visited.insert(playlist_url.clone());
+
+    if let Some(found) = page::identify(&m3u8_content, &playlist_url) {
+        let candidate = match found.followable() {
+            Some(candidate) if scan_page => candidate.clone(),
+            _ => return Err(found.error(&playlist_url)),
+        };
+        status!(
+            "{} is a web page, following the playlist it links to: {}",
+            playlist_url,
+            candidate
+        );
+        phases.start("fetching the linked playlist");
+        let (final_url, content) = fetch_playlist(client, &candidate).await?;
+        if let Some(found) = page::identify(&content, &candidate) {
+            return Err(found.error(&candidate));
+        }
+        visited.insert(final_url);
+        visited.insert(candidate.clone());
+        playlist_url = candidate;
+        m3u8_content = content;
+    }
+
+    let mut depth = 0;
+    let mut variant_of = None;
+    let mut variant = None;
+    loop {
+        match classify(&m3u8_content)? {
+            PlaylistKind::Master => {
+                phases.start("choosing a variant");
+                depth += 1;
+                anyhow::ensure!(
+                    depth <= MAX_MASTER_DEPTH,
+                    "Gave up after following {} nested master playlists (the last was {})",
+                    MAX_MASTER_DEPTH,
+                    playlist_url
+                );
+                let variants = parse_variants(&m3u8_content, &playlist_url)?;
+                let total = variants.len();
+                let mut variants = clamp_variants(variants, &preferences)?;
+                if !preferences.exclude.is_empty() {
+                    variants.retain(|variant| !preferences.exclude.contains(&variant.url));
+                    anyhow::ensure!(
+                        !variants.is_empty(),
+                        "No variant of {} is left to fall back to ({} failed)",
+                        playlist_url,
+                        preferences.exclude.len()
+                    );
+                }
+                // A pinned variant that failed isn't chosen again
+                let pin_file = preferences.pin_file.filter(|_| preferences.exclude.is_empty());
+                let chosen = match pin_file {
+                    Some(pin_file) => {
+                        let chosen = pins::choose(pin_file, &playlist_url, &variants)?;
+                        status!(
+                            "Master playlist with {} variants, using the pinned one ({})",
+                            total,
+                            chosen
+                        );
+                        chosen
+                    }
+                    None => {
+                        let best = variants
+                            .iter()
+                            .max_by_key(|variant| variant.bandwidth)
+                            .context("Master playlist lists no variant streams")?;
+                        status!(
+                            "Master playlist with {} variants, using the best one ({})",
+                            total,
+                            best
+                        );
+                        best
+                    }
+                };
+                anyhow::ensure!(
+                    visited.insert(chosen.url.clone()),
+                    "Playlist loop: master playlist {} leads back to {}, which was already fetched",
+                    playlist_url,
+                    chosen.url
+                );
+                phases.start("fetching the media playlist");
+                let (final_url, content) = fetch_playlist(client, &chosen.url).await?;
+                anyhow::ensure!(
+                    final_url == chosen.url || visited.insert(final_url.clone()),
+                    "Playlist loop: variant {} redirects back to {}, which was already fetched",
+                    chosen.url,
+                    final_url
+                );
+                variant_of = Some(playlist_url);
+                playlist_url = chosen.url.clone();
+                variant = Some(chosen.clone());
+                m3u8_content = content;
+            }
+            PlaylistKind::Media => {
+                let init_sections = parse_init_sections(&m3u8_content, &playlist_url)?;
+                let segments = parse_segments(m3u8_content, &playlist_url)?;
+                tracing::debug!(
+                    "Fetched playlist {} with {} segments",
+                    playlist_url,
+                    segments.len()
+                );
+                return Ok(MediaPlaylist {
+                    url: playlist_url,
+                    variant_of,
+                    variant,
+                    segments,
+                    init_sections,
+                });
+            }
+        }
+    }
+}
+
+/// Fetch a master playlist and parse its variant streams.
+pub async fn fetch_variants(client: &Client, master_url: &Url) -> Result<Vec<Variant>> {
+    let m3u8_content = fetch_master(client, master_url, "variants to choose from").await?;
+    parse_variants(&m3u8_content, master_url)
+}
+
+/// Fetch a master playlist and parse its `#EXT-X-MEDIA` renditions.
+pub async fn fetch_renditions(
+    client: &Client,
+    master_url: &Url,
+    missing: &str,
+) -> Result<Vec<Rendition>> {
+    let m3u8_content = fetch_master(client, master_url, missing).await?;
+    parse_renditions(&m3u8_content, master_url)
+}
+
+/// Fetch a playlist that has to be a master playlist, failing with `missing`
+/// (what a media playlist lacks) otherwise.
+async fn fetch_master(client: &Client, master_url: &Url, missing: &str) -> Result<String> {
+    check_scheme(master_url, "playlist")?;
+    let (_, m3u8_content) = fetch_playlist(client, master_url).await?;
+    if let Some(found) = page::identify(&m3u8_content, master_url) {
+        return Err(found.error(master_url));
+    }
+    anyhow::ensure!(
+        classify(&m3u8_content)? == PlaylistKind::Master,
+        "{} is a media playlist, so it has no {}",
+        master_url,
+        missing
+    );
+    Ok(m3u8_content)
+}
+
+async fn read_stdin() -> Result<String> {
+    let mut m3u8_content = String::new();
+    tokio::io::stdin()
+        .read_to_string(&mut m3u8_content)
+        .await
+        .context("Failed to read playlist from stdin")?;
+    Ok(m3u8_content)
+}
+
+/// Drop the variants outside `--min-quality`/`--max-quality`, failing with
+/// the full list when none are left.
+fn clamp_variants(variants: Vec<Variant>, preferences: &VariantPreferences) -> Result<Vec<Variant>> {
+    let (min, max) = (preferences.min_quality, preferences.max_quality);
+    if min.is_none() && max.is_none() {
+        return Ok(variants);
+    }
+
+    let (allowed, excluded): (Vec<Variant>, Vec<Variant>) = variants
+        .into_iter()
+        .partition(|variant| quality::within(variant, min, max));
+    for variant in &excluded {
+        tracing::debug!("Excluding variant ({}) by quality bounds", variant);
+    }
+    if allowed.is_empty() {
+        let available: Vec<String> = excluded
+            .iter()
+            .map(|variant| format!("  {}", variant))
+            .collect();
+        anyhow::bail!(
+            "No variant is within the quality bounds (min {}, max {}). Available variants:\n{}",
+            min.map_or("none".to_string(), |min| min.to_string()),
+            max.map_or("none".to_string(), |max| max.to_string()),
+            available.join("\n")
+        );
+    }
+    Ok(allowed)
+}
+
+/// Get the m3u8 file content, along with the URL it came from after any redirects.
+pub async fn fetch_playlist(client: &Client, playlist_url: &Url) -> Result<(Url, String)> {
+    let response = client.get(playlist_url.clone()).send().await?;
+    http::record_response(&response);
+    metrics::playlist_fetched();
+    let final_url = response.url().clone();
+    let m3u8_content = response.error_for_status()?.text().await?;
+    Ok((final_url, m3u8_content))
+}
+
+/// Parse the variant streams of a master playlist, resolving their URIs against `base_url`.
+pub fn parse_variants(m3u8_content: &str, base_url: &Url) -> Result<Vec<Variant>> {
+    let mut variants = Vec::new();
+    let mut lines = m3u8_content.lines().map(str::trim);
+
+    while let Some(line) = lines.next() {
+        let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") else {
+            continue;
+        };
+        let attributes = parse_attributes(attributes);
+        let uri = lines
+            .by_ref()
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .context("#EXT-X-STREAM-INF is not followed by a URI")?;
+        let url = base_url
+            .join(uri)
+            .with_context(|| format!("Invalid variant URL: {}", uri))?;
+        check_scheme(&url, "variant")?;
+
+        variants.push(Variant {
+            url,
+            bandwidth: attribute(&attributes, "BANDWIDTH")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0),
+            resolution: attribute(&attributes, "RESOLUTION").and_then(|value| {
+                let (width, height) = value.split_once('x')?;
+                Some((width.parse().ok()?, height.parse().ok()?))
+            }),
+            codecs: attribute(&attributes, "CODECS").map(str::to_string),
+        });
+    }
+
+    Ok(variants)
+}
+
+/// Parse the `#EXT-X-MEDIA` renditions of a master playlist, resolving their
+/// URIs against `base_url`.
+pub fn parse_renditions(m3u8_content: &str, base_url: &Url) -> Result<Vec<Rendition>> {
+    let mut renditions = Vec::new();
+    for line in m3u8_content.lines().map(str::trim) {
+        let Some(attributes) = line.strip_prefix("#EXT-X-MEDIA:") else {
+            continue;
+        };
+        let attributes = parse_attributes(attributes);
+        let url = match attribute(&attributes, "URI") {
+            Some(uri) => {
+                let url = base_url
+                    .join(uri)
+                    .with_context(|| format!("Invalid rendition URL: {}", uri))?;
+                check_scheme(&url, "rendition")?;
+                Some(url)
+            }
+            None => None,
+        };
+        let flag = |key| attribute(&attributes, key) == Some("YES");
+        renditions.push(Rendition {
+            kind: attribute(&attributes, "TYPE").unwrap_or_default().to_string(),
+            group_id: attribute(&attributes, "GROUP-ID").unwrap_or_default().to_string(),
+            language: attribute(&attributes, "LANGUAGE").map(str::to_string),
+            name: attribute(&attributes, "NAME").unwrap_or_default().to_string(),
+            default: flag("DEFAULT"),
+            autoselect: flag("AUTOSELECT"),
+            url,
+        });
+    }
+    Ok(renditions)
+}
+
+/// Reject anything but HTTP(S) URLs, so a malformed or malicious playlist
+/// can't point the downloader at `ftp:`, `file:` or `data:` resources.
+fn check_scheme(url: &Url, what: &str) -> Result<()> {
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => anyhow::bail!(
+            "Refusing {} URL with unsupported scheme '{}' (only http and https are allowed): {}",
+            what,
+            scheme,
+            url
+        ),
+    }
+}
+
+/// Split an attribute list (`KEY=value,KEY="quoted, value"`) into key/value pairs.
+pub fn parse_attributes(list: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = list.trim();
+
+    while !rest.is_empty() {
+        let Some((key, after_key)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, after_value) = match after_key.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let after = quoted.get(end + 1..).unwrap_or_default();
+                (&quoted[..end], after)
+            }
+            None => after_key.split_once(',').unwrap_or((after_key, "")),
+        };
+        attributes.push((key.trim().to_string(), value.to_string()));
+        rest = after_value.trim_start_matches(',').trim();
+    }
+
+    attributes
+}
+
+/// Look up an attribute parsed by [`parse_attributes`].
+pub fn attribute<'a>(attributes: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Check every segment line resolves against `base_url`, pairing it with its
+/// `#EXTINF` duration and the `#EXT-X-BITRATE` in effect.
+pub fn parse_segments(m3u8_content: String, base_url: &Url) -> Result<Vec<Segment>> {
+    let source = Arc::new(Source {
+        base_url: base_url.clone(),
+        content: m3u8_content,
+    });
+    let content = source.content.as_str();
+    let mut segments = Vec::new();
+    let mut duration = 0.0;
+    let mut bitrate = None;
+    let mut discontinuity = false;
+
+    for line in content.lines().map(str::trim) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let value = extinf.split(',').next().unwrap_or_default();
+            duration = value.trim().parse().unwrap_or(0.0);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-BITRATE:") {
+            // Applies to every following segment until the next one
+            bitrate = value.trim().parse().ok();
+        } else if line == "#EXT-X-DISCONTINUITY" {
+            discontinuity = true;
+        } else if !line.starts_with('#') && !line.is_empty() {
+            let url = base_url
+                .join(line)
+                .with_context(|| format!("Invalid segment URL: {}", line))?;
+            check_scheme(&url, "segment")?;
+            let start = line.as_ptr() as usize - content.as_ptr() as usize;
+            segments.push(Segment {
+                index: segments.len(),
+                duration,
+                bitrate,
+                discontinuity,
+                source: Arc::clone(&source),
+                uri: start..start + line.len(),
+            });
+            duration = 0.0;
+            discontinuity = false;
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Whether the playlist says `#EXT-X-ALLOW-CACHE:NO`. The tag was dropped
+/// from the spec in version 7, but older servers still send it.
+pub fn forbids_caching(m3u8_content: &str) -> bool {
+    m3u8_content.lines().map(str::trim).any(|line| {
+        line.strip_prefix("#EXT-X-ALLOW-CACHE:")
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("NO"))
+    })
+}
+
+/// The playlist's `#EXT-X-MAP` initialization sections, resolved against
+/// `base_url`, each with the first segment it applies to.
+///
+/// A map that repeats the one before it doesn't start a new section.
+pub fn parse_init_sections(m3u8_content: &str, base_url: &Url) -> Result<Vec<InitSection>> {
+    let mut sections: Vec<InitSection> = Vec::new();
+    let mut segment_count = 0;
+
+    for line in m3u8_content.lines().map(str::trim) {
+        if let Some(attributes) = line.strip_prefix("#EXT-X-MAP:") {
+            let attributes = parse_attributes(attributes);
+            let uri = attribute(&attributes, "URI").context("#EXT-X-MAP has no URI")?;
+            let url = base_url
+                .join(uri)
+                .with_context(|| format!("Invalid initialization section URL: {}", uri))?;
+            check_scheme(&url, "initialization section")?;
+            if sections.last().map(|section| &section.url) != Some(&url) {
+                sections.push(InitSection {
+                    url,
+                    first_segment: segment_count,
+                });
+            }
+        } else if !line.starts_with('#') && !line.is_empty() {
+            segment_count += 1;
+        }
+    }
+
+    if let Some(first) = sections.first().filter(|first| first.first_segment > 0) {
+        anyhow::bail!(
+            "The first {} segments come before any initialization section (#EXT-X-MAP)",
+            first.first_segment
+        );
+    }
+    Ok(sections)
+}
+
+/// Format a number of seconds as `HH:MM:SS`.
+pub fn format_timestamp(seconds: f64) -> String {
+    let total = seconds.round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        total / 3600,
+        (total / 60) % 60,
+        total % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=1280x720,CODECS=\"avc1.64001f,mp4a.40.2\"
+720p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=640000
+low.m3u8
+";
+
+    const MEDIA: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXTINF:4.000,
+seg0.ts
+#EXTINF:3.5,
+seg1.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn classify_tells_master_from_media() {
+        assert_eq!(classify(MASTER).unwrap(), PlaylistKind::Master);
+        assert_eq!(classify(MEDIA).unwrap(), PlaylistKind::Media);
+        assert_eq!(classify("#EXTM3U\n").unwrap(), PlaylistKind::Media);
+        let indented = "  #EXT-X-STREAM-INF:BANDWIDTH=1\n a.m3u8";
+        assert_eq!(classify(indented).unwrap(), PlaylistKind::Master);
+    }
+
+    #[test]
+    fn classify_rejects_mixed_playlists() {
+        let mixed = format!("{}#EXTINF:4,\nseg0.ts\n", MASTER);
+        let error = classify(&mixed).unwrap_err().to_string();
+        assert!(error.contains("both variant streams"), "{}", error);
+    }
+
+    #[test]
+    fn parse_attributes_splits_quoted_and_plain_values() {
+        let list = "BANDWIDTH=1280000, CODECS=\"avc1.64001f,mp4a.40.2\",RESOLUTION=1280x720";
+        let attributes = parse_attributes(list);
+        assert_eq!(
+            attributes,
+            [
+                ("BANDWIDTH".to_string(), "1280000".to_string()),
+                ("CODECS".to_string(), "avc1.64001f,mp4a.40.2".to_string()),
+                ("RESOLUTION".to_string(), "1280x720".to_string()),
+            ]
+        );
+        assert_eq!(attribute(&attributes, "CODECS"), Some("avc1.64001f,mp4a.40.2"));
+        assert_eq!(attribute(&attributes, "AUDIO"), None);
+    }
+
+    #[test]
+    fn parse_attributes_tolerates_malformed_lists() {
+        assert!(parse_attributes("").is_empty());
+        assert!(parse_attributes("NOVALUE").is_empty());
+        assert_eq!(
+            parse_attributes("URI=\"unterminated"),
+            [("URI".to_string(), "unterminated".to_string())]
+        );
+        assert_eq!(parse_attributes("A=1,B"), [("A".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn parse_variants_resolves_each_stream() {
+        let base = Url::parse("https://cdn.example/show/master.m3u8").unwrap();
+        let variants = parse_variants(MASTER, &base).unwrap();
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].url.as_str(), "https://cdn.example/show/720p.m3u8");
+        assert_eq!(variants[0].bandwidth, 1280000);
+        assert_eq!(variants[0].resolution, Some((1280, 720)));
+        assert_eq!(variants[0].codecs.as_deref(), Some("avc1.64001f,mp4a.40.2"));
+        assert_eq!(variants[1].resolution, None);
+    }
+
+    #[test]
+    fn parse_segments_pairs_uris_with_their_tags() {
+        let base = Url::parse("https://cdn.example/show/index.m3u8").unwrap();
+        let content = "#EXTM3U
+#EXT-X-BITRATE:800
+#EXTINF:4.000,title
+seg0.ts
+
+seg1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:3.5,
+  /other/seg2.ts?token=1
+#EXT-X-ENDLIST
+";
+        let segments = parse_segments(content.to_string(), &base).unwrap();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].duration, 4.0);
+        assert_eq!(segments[0].url().as_str(), "https://cdn.example/show/seg0.ts");
+        assert_eq!(segments[0].predicted_size(), Some(400_000));
+        assert_eq!(segments[1].duration, 0.0);
+        assert_eq!(segments[1].bitrate, Some(800));
+        assert!(!segments[1].discontinuity);
+        assert!(segments[2].discontinuity);
+        assert_eq!(segments[2].index, 2);
+        assert_eq!(segments[2].uri(), "/other/seg2.ts?token=1");
+        assert_eq!(segments[2].url().as_str(), "https://cdn.example/other/seg2.ts?token=1");
+        assert_eq!(discontinuity_groups(&segments), [0..2, 2..3]);
+    }
+
+    #[test]
+    fn parse_segments_rejects_other_schemes() {
+        let base = Url::parse("https://cdn.example/index.m3u8").unwrap();
+        let content = "#EXTINF:4,\nfile:///etc/passwd\n".to_string();
+        let error = parse_segments(content, &base).unwrap_err().to_string();
+        assert!(error.contains("unsupported scheme 'file'"), "{}", error);
+    }
+
+    /// An fMP4 playlist that switches initialization sections at its
+    /// discontinuity.
+    const TWO_INITS: &str = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-MAP:URI=\"init-a.mp4\"
+#EXTINF:4,
+a0.m4s
+#EXTINF:4,
+a1.m4s
+#EXT-X-MAP:URI=\"init-a.mp4\"
+#EXTINF:4,
+a2.m4s
+#EXT-X-DISCONTINUITY
+#EXT-X-MAP:URI=\"https://ads.example/init-b.mp4\",BYTERANGE=\"720@0\"
+#EXTINF:2,
+b0.m4s
+#EXTINF:2,
+b1.m4s
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn init_regions_follow_each_map() {
+        let url = Url::parse("https://cdn.example/show/index.m3u8").unwrap();
+        let playlist = MediaPlaylist::from_content(url, None, TWO_INITS.to_string()).unwrap();
+        let sections: Vec<(&str, usize)> = playlist
+            .init_sections
+            .iter()
+            .map(|section| (section.url.as_str(), section.first_segment))
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                ("https://cdn.example/show/init-a.mp4", 0),
+                ("https://ads.example/init-b.mp4", 3),
+            ]
+        );
+        assert_eq!(playlist.init_regions(), [0..3, 3..5]);
+        assert_eq!(playlist.segments[3].uri(), "b0.m4s");
+    }
+
+    #[test]
+    fn skip_first_keeps_the_sections_in_effect() {
+        let url = Url::parse("https://cdn.example/show/index.m3u8").unwrap();
+        let mut playlist = MediaPlaylist::from_content(url, None, TWO_INITS.to_string()).unwrap();
+        playlist.skip_first().unwrap();
+        assert_eq!(playlist.init_regions(), [0..2, 2..4]);
+        playlist.skip_first().unwrap();
+        playlist.skip_first().unwrap();
+        assert_eq!(playlist.init_sections.len(), 1);
+        assert_eq!(playlist.init_regions().first(), Some(&(0..2)));
+    }
+
+    #[test]
+    fn parse_init_sections_needs_a_map_before_the_first_segment() {
+        let url = Url::parse("https://cdn.example/index.m3u8").unwrap();
+        let late = "#EXTINF:4,\na0.m4s\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:4,\na1.m4s\n";
+        let error = parse_init_sections(late, &url).unwrap_err().to_string();
+        assert!(error.starts_with("The first 1 segments come before"), "{}", error);
+        assert!(parse_init_sections("#EXT-X-MAP:BYTERANGE=\"1@0\"\n", &url).is_err());
+        assert!(parse_init_sections(MEDIA, &url).unwrap().is_empty());
+    }
+
+    fn media(content: &str) -> MediaPlaylist {
+        let url = Url::parse("https://cdn.example/show/index.m3u8").unwrap();
+        MediaPlaylist::from_content(url, None, content.to_string()).unwrap()
+    }
+
+    const THREE: &str = "#EXTINF:4,\nseg0.ts\n#EXTINF:4,\nseg1.ts\n#EXTINF:4,\nseg2.ts\n";
+
+    #[test]
+    fn diff_of_the_same_playlist_is_unchanged() {
+        let diff = media(THREE).diff(&media(THREE));
+        assert!(!diff.is_changed());
+        assert_eq!(diff.unchanged, [0, 1, 2]);
+        assert!(diff.first_change.is_none());
+        assert_eq!((diff.old_count, diff.new_count), (3, 3));
+    }
+
+    #[test]
+    fn diff_notices_added_segments() {
+        let diff = media(THREE).diff(&media(&format!("{}#EXTINF:4,\nseg3.ts\n", THREE)));
+        assert!(diff.is_changed());
+        assert_eq!((diff.old_count, diff.new_count), (3, 4));
+        assert_eq!((diff.old_duration, diff.new_duration), (12.0, 16.0));
+        assert_eq!(diff.unchanged, [0, 1, 2]);
+        assert!(diff.first_change.is_none());
+    }
+
+    #[test]
+    fn diff_notices_a_duration_and_the_start_times_it_shifts() {
+        let longer = THREE.replacen("#EXTINF:4,", "#EXTINF:6,", 1);
+        let diff = media(THREE).diff(&media(&longer));
+        assert!(diff.is_changed());
+        assert_eq!((diff.old_duration, diff.new_duration), (12.0, 14.0));
+        // The later segments keep their URIs and durations but start later
+        assert!(diff.unchanged.is_empty());
+        assert_eq!(
+            diff.first_change,
+            Some((0, "seg0.ts".to_string(), "seg0.ts".to_string()))
+        );
+    }
+
+    #[test]
+    fn diff_notices_renamed_segments() {
+        let diff = media(THREE).diff(&media(&THREE.replace("seg1.ts", "seg1b.ts")));
+        assert!(diff.is_changed());
+        assert_eq!(diff.unchanged, [0, 2]);
+        assert_eq!(
+            diff.first_change,
+            Some((1, "seg1.ts".to_string(), "seg1b.ts".to_string()))
+        );
+        assert_eq!(
+            diff.to_string(),
+            "3 segments (00:00:12) when the download started, 3 (00:00:12) now; 1 of the 3 \
+             positions both have differ, the first at segment 1 (seg1.ts became seg1b.ts)"
+        );
+    }
+
+    #[test]
+    fn diff_notices_a_changed_initialization_section() {
+        let old = format!("#EXT-X-MAP:URI=\"init.mp4\"\n{}", THREE);
+        let diff = media(&old).diff(&media(&old.replace("init.mp4", "init-v2.mp4")));
+        assert!(diff.is_changed());
+        assert!(diff.init_changed);
+        assert!(diff.first_change.is_none());
+        assert_eq!(
+            diff.to_string(),
+            "3 segments (00:00:12) when the download started, 3 (00:00:12) now; \
+             the initialization sections changed"
+        );
+    }
+}
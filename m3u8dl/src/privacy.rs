@@ -0,0 +1,52 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REDACT_URLS: AtomicBool = AtomicBool::new(false);
+
+/// Strip query strings from every URL in subsequent console and log output.
+pub fn enable_redaction() {
+    REDACT_URLS.store(true, Ordering::Relaxed);
+}
+
+/// Replace the query string of any URL in `text` with `?REDACTED`, since
+/// that is where signed-URL tokens live. A no-op unless redaction is enabled.
+pub fn scrub(text: &str) -> Cow<'_, str> {
+    if !REDACT_URLS.load(Ordering::Relaxed) || !text.contains("://") {
+        return Cow::Borrowed(text);
+    }
+
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(scheme_end) = rest.find("://") {
+        let url_end = rest[scheme_end..]
+            .find(|c: char| c.is_whitespace() || matches!(c, ')' | '"' | '\'' | '>' | ']'))
+            .map_or(rest.len(), |end| scheme_end + end);
+        let url = &rest[..url_end];
+        match url.find('?') {
+            Some(query) => {
+                scrubbed.push_str(&url[..query]);
+                scrubbed.push_str("?REDACTED");
+            }
+            None => scrubbed.push_str(url),
+        }
+        rest = &rest[url_end..];
+    }
+    scrubbed.push_str(rest);
+    Cow::Owned(scrubbed)
+}
+
+/// Writer adapter that scrubs each (whole-event) write before passing it on.
+pub struct Scrubbed<W>(pub W);
+
+impl<W: Write> Write for Scrubbed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(scrub(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
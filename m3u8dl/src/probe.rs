@@ -0,0 +1,157 @@
+use anyhow::Result;
+use bytes::Bytes;
+use reqwest::{header, Client, StatusCode};
+
+use crate::http;
+use crate::playlist::Segment;
+use crate::timing::RequestTiming;
+use crate::SegmentRequest;
+
+/// The first segment as the probe fetched it, to be kept as the segment's
+/// download rather than fetched again.
+pub struct Probed {
+    pub body: Bytes,
+    pub content_length: Option<u64>,
+    /// The entity's ETag or Last-Modified, for revalidating it later.
+    pub validator: Option<String>,
+}
+
+/// Fetch the first segment on its own and make sure it looks like media, so
+/// that a blocked or misconfigured request fails once instead of hundreds of times.
+///
+/// Only a 4xx or a body that isn't media fails the run. A 5xx, a 429 or a
+/// network error may well be transient, so it returns `None` and leaves the
+/// segment to the normal retries.
+pub async fn probe_first_segment(
+    client: &Client,
+    segment: &Segment,
+    options: &mut SegmentRequest,
+    min_segment_size: u64,
+) -> Result<Option<Probed>> {
+    let url = segment.url();
+    let mut timing = RequestTiming::start(&url, options.attempt);
+    let mut response = match crate::segment_request(client, &url, options).send().await {
+        Ok(response) => response,
+        Err(error) => {
+            tracing::debug!("Probing {} failed ({}); leaving it to the retries", url, error);
+            return Ok(None);
+        }
+    };
+    http::record_response(&response);
+    options.record(&url, &response);
+    timing.response(&response);
+    let status = response.status();
+    if !status.is_success() && !fails_the_run(status) {
+        tracing::debug!("Probing {} got HTTP {}; leaving it to the retries", url, status);
+        return Ok(None);
+    }
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("none")
+        .to_string();
+    let content_length = response.content_length();
+    let validator = crate::entity_validator(&response);
+    if let Some(length) = content_length {
+        crate::check_max_size(&url, length, options.max_size, true)?;
+    }
+    let mut body = Vec::new();
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                body.extend_from_slice(&chunk);
+                crate::check_max_size(&url, body.len() as u64, options.max_size, false)?;
+            }
+            Ok(None) => break,
+            Err(error) => {
+                tracing::debug!("Probing {} failed ({}); leaving it to the retries", url, error);
+                return Ok(None);
+            }
+        }
+    }
+    timing.body(body.len() as u64);
+
+    let problem = if !status.is_success() {
+        Some(format!("it returned HTTP {}", status))
+    } else if content_type.contains("html") || looks_like_markup(&body) {
+        Some("the response is an HTML/XML page, not media".to_string())
+    } else if (body.len() as u64) < min_segment_size {
+        Some(format!(
+            "it is only {} bytes (expected at least {})",
+            body.len(),
+            min_segment_size
+        ))
+    } else {
+        None
+    };
+
+    let Some(problem) = problem else {
+        tracing::debug!(
+            "First segment {} looks valid ({} bytes)",
+            url,
+            body.len()
+        );
+        timing.finish();
+        return Ok(Some(Probed {
+            body: Bytes::from(body),
+            content_length,
+            validator,
+        }));
+    };
+
+    anyhow::bail!(
+        "The first segment failed validation: {}.\n  \
+         URL:          {}\n  \
+         Status:       {}\n  \
+         Content-Type: {}\n  \
+         Body size:    {} bytes\n\
+         Common causes:\n{}\n\
+         Use --no-probe-first to skip this check.",
+        problem,
+        url,
+        status,
+        content_type,
+        body.len(),
+        hints(status)
+            .iter()
+            .map(|hint| format!("  - {}", hint))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// Whether the probe answering `status` means every segment will fail: a
+/// client error, except 429, which only asks to slow down.
+fn fails_the_run(status: StatusCode) -> bool {
+    status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Error pages start with `<`; MPEG-TS starts with the 0x47 sync byte and
+/// fragmented MP4 with a box header, so neither does.
+fn looks_like_markup(body: &[u8]) -> bool {
+    body.iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .is_some_and(|byte| *byte == b'<')
+}
+
+fn hints(status: StatusCode) -> Vec<&'static str> {
+    let mut hints = Vec::new();
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            hints.push("the origin expects a Referer, Origin or cookie header");
+            hints.push("the signed URL or token has expired");
+            hints.push("the content is geo-blocked from this location");
+        }
+        StatusCode::NOT_FOUND | StatusCode::GONE => {
+            hints.push("the playlist is stale and its segments have rotated away");
+            hints.push("segment URIs are resolved against the wrong base URL");
+        }
+        _ => {
+            hints.push("the origin serves an error or login page to unrecognised clients");
+            hints.push("the origin expects a Referer, Origin or cookie header");
+            hints.push("the content is geo-blocked from this location");
+        }
+    }
+    hints
+}
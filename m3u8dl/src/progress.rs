@@ -0,0 +1,406 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanDuration, ProgressBar, ProgressState, ProgressStyle};
+
+use crate::logging;
+use crate::metrics;
+
+/// Least time between two updates of the segment progress bar. With many
+/// small segments, updating it for every one slows the download down.
+const DRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// How far back the throughput the time left is worked out from looks.
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+/// Least span of the window for its throughput to mean anything.
+const MIN_RATE_SPAN: Duration = Duration::from_secs(1);
+
+/// Segment progress bar whose position and time left follow bytes rather
+/// than segment count.
+///
+/// The total size is extrapolated from the average size of the segments
+/// finished so far; until the first one finishes the bar counts segments.
+/// The counts are exact, but the bar takes them at most every
+/// [`DRAW_INTERVAL`], and after the last segment.
+///
+/// The time left is the bytes still to come at the throughput of the last
+/// [`RATE_WINDOW`], or longer if `--requests-per-second` or `--pace` hold the
+/// requests back. Until there is a throughput, indicatif's own estimate shows.
+pub struct SegmentProgress {
+    bar: ProgressBar,
+    total_segments: usize,
+    /// When `--pace` lets the last segment through.
+    paced_until: Option<Instant>,
+    /// Least time between two requests, from `--requests-per-second`.
+    request_interval: Option<Duration>,
+    state: Mutex<State>,
+    /// The estimate the bar's `{left}` key shows.
+    left: Arc<Mutex<Option<Duration>>>,
+}
+
+#[derive(Default)]
+struct State {
+    finished: usize,
+    sized: usize,
+    bytes: u64,
+    /// Segments whose retries wait until the first wave is done.
+    deferred: usize,
+    /// When the bar last took the counts.
+    drawn: Option<Instant>,
+    /// Bytes downloaded so far, every time the bar took them.
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl SegmentProgress {
+    pub fn new(total_segments: usize) -> Self {
+        let left = Arc::new(Mutex::new(None));
+        let estimate = Arc::clone(&left);
+        let bar = ProgressBar::new(total_segments as u64);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {msg} ({left})",
+                )
+                .unwrap()
+                .with_key(
+                    "left",
+                    move |progress: &ProgressState, out: &mut dyn std::fmt::Write| {
+                        let left = estimate.lock().unwrap().unwrap_or_else(|| progress.eta());
+                        let _ = write!(out, "{:#}", HumanDuration(left));
+                    },
+                )
+                .progress_chars("#>-"),
+        );
+        bar.set_message(format!("0/{}", total_segments));
+        Self {
+            bar,
+            total_segments,
+            paced_until: None,
+            request_interval: None,
+            state: Mutex::new(State::default()),
+            left,
+        }
+    }
+
+    /// Count the time `--pace` still holds the requests back in the time
+    /// left, which would otherwise follow the full-speed transfers.
+    pub fn paced(mut self, until: Instant) -> Self {
+        self.paced_until = Some(until);
+        self
+    }
+
+    /// Count a `--requests-per-second` limit in the time left.
+    pub fn rate_limited(mut self, requests_per_second: Option<f64>) -> Self {
+        self.request_interval = requests_per_second
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f64(1.0 / rate));
+        self
+    }
+
+    /// The underlying bar, for printing around it and finishing it.
+    pub fn bar(&self) -> &ProgressBar {
+        &self.bar
+    }
+
+    /// Count a finished segment, with its size when it downloaded successfully.
+    pub fn finish_segment(&self, size: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.finished += 1;
+        if let Some(size) = size {
+            if state.sized == 0 {
+                // Switching from segments to bytes; the old rate means nothing now
+                self.bar.reset_eta();
+            }
+            state.sized += 1;
+            state.bytes += size;
+        }
+        let recently = state
+            .drawn
+            .is_some_and(|drawn| drawn.elapsed() < DRAW_INTERVAL);
+        if recently && state.finished < self.total_segments {
+            return;
+        }
+        let now = Instant::now();
+        state.drawn = Some(now);
+        let bytes = state.bytes;
+        state.samples.push_back((now, bytes));
+        while state
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            state.samples.pop_front();
+        }
+
+        if state.sized == 0 {
+            self.bar.set_position(state.finished as u64);
+        } else {
+            // Failed segments count as average-sized work that is done
+            let average = state.bytes / state.sized as u64;
+            self.bar.set_length(average * self.total_segments as u64);
+            self.bar
+                .set_position(state.bytes + average * (state.finished - state.sized) as u64);
+        }
+        *self.left.lock().unwrap() = self.estimate(&state, now);
+        self.set_message(&state);
+    }
+
+    /// Show how many segments are waiting to be retried.
+    pub fn set_deferred(&self, deferred: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.deferred = deferred;
+        self.set_message(&state);
+    }
+
+    /// The time left, from the throughput of the samples in the window and
+    /// the limits on how fast requests may go out.
+    fn estimate(&self, state: &State, now: Instant) -> Option<Duration> {
+        let remaining = self.total_segments - state.finished;
+        let mut left = None;
+        if let (Some(&(first, first_bytes)), true) = (state.samples.front(), state.sized > 0) {
+            let span = now.duration_since(first);
+            let transferred = state.bytes - first_bytes;
+            if span >= MIN_RATE_SPAN && transferred > 0 {
+                let average = state.bytes as f64 / state.sized as f64;
+                let rate = transferred as f64 / span.as_secs_f64();
+                left = Some(Duration::from_secs_f64(average * remaining as f64 / rate));
+            }
+        }
+        let limits = [
+            self.request_interval
+                .map(|interval| interval * remaining as u32),
+            self.paced_until
+                .map(|until| until.saturating_duration_since(now)),
+        ];
+        for limit in limits.into_iter().flatten() {
+            left = Some(left.map_or(limit, |left: Duration| left.max(limit)));
+        }
+        left
+    }
+
+    fn set_message(&self, state: &State) {
+        let mut message = format!("{}/{}", state.finished, self.total_segments);
+        if state.deferred > 0 {
+            message.push_str(&format!(", {} deferred", state.deferred));
+        }
+        if self.paced_until.is_some() {
+            message.push_str(", paced");
+        }
+        self.bar.set_message(message);
+    }
+}
+
+/// Spinner for the steps before the first segment downloads (fetching the
+/// playlists, choosing a variant, probing the first segment), which can take
+/// a while with nothing else on screen. Records how long each step took.
+pub struct Phases {
+    spinner: ProgressBar,
+    state: Mutex<PhaseState>,
+}
+
+#[derive(Default)]
+struct PhaseState {
+    current: Option<(&'static str, Instant)>,
+    /// Time spent in each step so far, in the order they first started.
+    timings: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseState {
+    fn end_current(&mut self) {
+        let Some((step, started)) = self.current.take() else {
+            return;
+        };
+        let elapsed = started.elapsed();
+        match self.timings.iter_mut().find(|(name, _)| *name == step) {
+            Some((_, total)) => *total += elapsed,
+            None => self.timings.push((step, elapsed)),
+        }
+    }
+}
+
+impl Phases {
+    /// Show the spinner; status lines print around it until [`Phases::finish`].
+    pub fn show() -> Self {
+        let spinner = ProgressBar::new_spinner();
+        spinner.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.green} [{elapsed_precise}] {msg}")
+                .unwrap(),
+        );
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        logging::show_progress(Some(spinner.clone()));
+        Self {
+            spinner,
+            state: Mutex::new(PhaseState::default()),
+        }
+    }
+
+    /// Move on to `step`, e.g. "fetching the media playlist".
+    pub fn start(&self, step: &'static str) {
+        let mut state = self.state.lock().unwrap();
+        state.end_current();
+        state.current = Some((step, Instant::now()));
+        self.spinner.set_message(step_message(step));
+        metrics::set_phase(step);
+    }
+
+    /// Say more about the current step than its name.
+    pub fn set_message(&self, message: String) {
+        self.spinner.set_message(message);
+    }
+
+    /// Clear the spinner once the segments start downloading.
+    pub fn finish(&self) {
+        self.state.lock().unwrap().end_current();
+        logging::show_progress(None);
+        self.spinner.finish_and_clear();
+    }
+
+    /// How long each step took, e.g. "fetching the playlist 0.4s, probing the
+    /// first segment 1.2s; 1.6s in all", or `None` before any step.
+    pub fn summary(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        if state.timings.is_empty() {
+            return None;
+        }
+        let steps: Vec<String> = state
+            .timings
+            .iter()
+            .map(|(step, elapsed)| format!("{} {:.1}s", step, elapsed.as_secs_f64()))
+            .collect();
+        let total: Duration = state.timings.iter().map(|(_, elapsed)| *elapsed).sum();
+        Some(format!("{}; {:.1}s in all", steps.join(", "), total.as_secs_f64()))
+    }
+}
+
+impl Drop for Phases {
+    fn drop(&mut self) {
+        if !self.spinner.is_finished() {
+            self.finish();
+        }
+    }
+}
+
+/// "Fetching the playlist..." for "fetching the playlist".
+fn step_message(step: &str) -> String {
+    let mut chars = step.chars();
+    match chars.next() {
+        Some(first) => format!("{}{}...", first.to_uppercase(), chars.as_str()),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use indicatif::{ProgressDrawTarget, TermLike};
+
+    /// A terminal that keeps the bar's last line and counts the draws.
+    #[derive(Debug, Default)]
+    struct Screen {
+        draws: Arc<AtomicUsize>,
+        text: Mutex<String>,
+    }
+
+    impl TermLike for Screen {
+        fn width(&self) -> u16 {
+            120
+        }
+        fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+            Ok(())
+        }
+        fn write_line(&self, s: &str) -> io::Result<()> {
+            self.write_str(s)
+        }
+        fn write_str(&self, s: &str) -> io::Result<()> {
+            let mut text = self.text.lock().unwrap();
+            text.clear();
+            text.push_str(s);
+            Ok(())
+        }
+        fn clear_line(&self) -> io::Result<()> {
+            Ok(())
+        }
+        fn flush(&self) -> io::Result<()> {
+            self.draws.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    /// A bar for `segments` drawn on a [`Screen`] at indicatif's terminal
+    /// rate, and the count of times it draws.
+    fn on_screen(segments: usize) -> (SegmentProgress, Arc<AtomicUsize>) {
+        let screen = Screen::default();
+        let draws = Arc::clone(&screen.draws);
+        let progress = SegmentProgress::new(segments);
+        progress
+            .bar()
+            .set_draw_target(ProgressDrawTarget::term_like(Box::new(screen)));
+        (progress, draws)
+    }
+
+    #[test]
+    fn counts_stay_exact_between_draws() {
+        let (progress, _) = on_screen(3);
+        progress.finish_segment(Some(100));
+        progress.finish_segment(None);
+        // Within DRAW_INTERVAL of the first, so the bar hasn't taken it
+        assert_eq!(progress.bar().position(), 100);
+        progress.finish_segment(Some(300));
+        assert_eq!(progress.bar().position(), 400 + 200);
+        assert_eq!(progress.bar().length(), Some(600));
+    }
+
+    /// What updating the bar costs per finished segment, at DRAW_INTERVAL
+    /// and for every segment: `just bench-progress`.
+    #[test]
+    #[ignore]
+    fn redraw_cost_at_draw_interval() {
+        const SEGMENTS: usize = 200_000;
+
+        let (throttled, throttled_draws) = on_screen(SEGMENTS);
+        let started = Instant::now();
+        for _ in 0..SEGMENTS {
+            throttled.finish_segment(Some(100_000));
+        }
+        let throttled_time = started.elapsed();
+
+        let (every, every_draws) = on_screen(SEGMENTS);
+        let started = Instant::now();
+        for _ in 0..SEGMENTS {
+            every.state.lock().unwrap().drawn = None;
+            every.finish_segment(Some(100_000));
+        }
+        let every_time = started.elapsed();
+
+        let per_segment = |time: Duration| time.as_nanos() / SEGMENTS as u128;
+        println!(
+            "at {:?}: {} ns per segment, {} draws in {:.2?}",
+            DRAW_INTERVAL,
+            per_segment(throttled_time),
+            throttled_draws.load(Ordering::Relaxed),
+            throttled_time,
+        );
+        println!(
+            "every segment: {} ns per segment, {} draws in {:.2?}",
+            per_segment(every_time),
+            every_draws.load(Ordering::Relaxed),
+            every_time,
+        );
+        assert_eq!(throttled.bar().position(), every.bar().position());
+        assert!(throttled_time < every_time);
+    }
+}
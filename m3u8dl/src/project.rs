@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::completion;
+use crate::exit::ExitKind;
+use crate::logging::status;
+use crate::playlist::MediaPlaylist;
+
+/// Format version of the project file.
+const VERSION: u32 = 1;
+/// Least time between two saves while segments finish; stopping the run
+/// loses at most this much of the record, and the files themselves stay.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `--project`: everything needed to continue a download elsewhere, in one
+/// JSON file. The segments themselves stay in the temp folder, to be copied
+/// along or downloaded again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Project {
+    version: u32,
+    /// The command line the project was started with, after the program
+    /// name and without `--project`; every option follows from it.
+    pub arguments: Vec<String>,
+    /// The media playlist as first fetched, the chosen variant and all.
+    #[serde(default)]
+    playlist: Option<Snapshot>,
+    /// Downloaded segments, by file name in the temp folder.
+    #[serde(default)]
+    completed: BTreeMap<String, Completed>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    url: String,
+    variant_of: Option<String>,
+    content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Completed {
+    size: u64,
+    sha256: String,
+    /// Frames per second of its video, once probed for `--normalize-fps`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    frame_rate: Option<f64>,
+}
+
+/// The project of this run, if it has one, and where it is saved.
+static PROJECT: Mutex<Option<Open>> = Mutex::new(None);
+
+struct Open {
+    path: PathBuf,
+    project: Project,
+    saved: Instant,
+    /// Whether the files of a continued project are still to be checked.
+    unchecked: bool,
+}
+
+/// Read the project at `path`, or `None` if there is none yet.
+pub fn load(path: &Path) -> Result<Option<Project>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to read {}", path.display()))
+        }
+    };
+    let project: Project = serde_json::from_str(&content)
+        .with_context(|| format!("{} isn't a project file", path.display()))
+        .context(ExitKind::Usage)?;
+    anyhow::ensure!(
+        project.version == VERSION,
+        "{} is a version {} project; this m3u8dl reads version {}",
+        path.display(),
+        project.version,
+        VERSION
+    );
+    Ok(Some(project))
+}
+
+/// Start a project at `path` for a run with these command-line arguments.
+pub fn start(path: &Path, arguments: &[OsString]) -> Result<()> {
+    let project = Project {
+        version: VERSION,
+        arguments: without_project(arguments),
+        playlist: None,
+        completed: BTreeMap::new(),
+    };
+    open(path, project, false);
+    save()
+}
+
+/// Carry on with a project [`load`]ed from `path`.
+pub fn resume(path: &Path, project: Project) {
+    status!(
+        "Continuing project {}: {} segments downloaded so far.",
+        path.display(),
+        project.completed.len()
+    );
+    open(path, project, true);
+}
+
+fn open(path: &Path, project: Project, unchecked: bool) {
+    *PROJECT.lock().unwrap() = Some(Open {
+        path: path.to_path_buf(),
+        project,
+        saved: Instant::now(),
+        unchecked,
+    });
+}
+
+pub fn active() -> bool {
+    PROJECT.lock().unwrap().is_some()
+}
+
+/// The command line minus `--project PATH` (or `--project=PATH`).
+fn without_project(arguments: &[OsString]) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut arguments = arguments.iter().map(|argument| argument.to_string_lossy());
+    while let Some(argument) = arguments.next() {
+        if argument == "--project" {
+            arguments.next();
+        } else if !argument.starts_with("--project=") {
+            kept.push(argument.into_owned());
+        }
+    }
+    kept
+}
+
+/// The playlist the project was started on, so that a continued run
+/// downloads the same segments.
+pub fn playlist() -> Result<Option<MediaPlaylist>> {
+    let state = PROJECT.lock().unwrap();
+    let Some(snapshot) = state.as_ref().and_then(|open| open.project.playlist.as_ref()) else {
+        return Ok(None);
+    };
+    let url = Url::parse(&snapshot.url).context("Invalid playlist URL in the project")?;
+    let variant_of = match &snapshot.variant_of {
+        Some(master) => Some(Url::parse(master).context("Invalid master URL in the project")?),
+        None => None,
+    };
+    MediaPlaylist::from_content(url, variant_of, snapshot.content.clone())
+        .context("Invalid playlist in the project")
+        .map(Some)
+}
+
+/// Keep `playlist` as the one the project downloads.
+pub fn set_playlist(playlist: &MediaPlaylist) -> Result<()> {
+    {
+        let mut state = PROJECT.lock().unwrap();
+        let Some(open) = state.as_mut() else {
+            return Ok(());
+        };
+        open.project.playlist = playlist.content().map(|content| Snapshot {
+            url: playlist.url.to_string(),
+            variant_of: playlist.variant_of.as_ref().map(Url::to_string),
+            content: content.to_string(),
+        });
+    }
+    save()
+}
+
+/// Drop the playlist snapshot, once its variant is given up on or it was
+/// republished, so that the next attempt fetches afresh, along with the
+/// segment files that were `removed` with it.
+pub fn forget_playlist(removed: &[PathBuf]) {
+    if let Some(open) = PROJECT.lock().unwrap().as_mut() {
+        open.project.playlist = None;
+        for name in removed.iter().filter_map(|path| path.file_name()) {
+            open.project.completed.remove(&*name.to_string_lossy());
+        }
+    }
+}
+
+/// Check the files of the playlist's segments (by `names`) in `temp_dir`
+/// against what the project recorded, e.g. after copying the folder from
+/// another machine. Files that don't match their hash, or that finished
+/// after the project was last saved, are removed, to be downloaded again.
+pub fn check_segments(temp_dir: &Path, names: impl Iterator<Item = String>) {
+    let mut state = PROJECT.lock().unwrap();
+    let Some(open) = state.as_mut().filter(|open| open.unchecked) else {
+        return;
+    };
+    open.unchecked = false;
+    let (mut present, mut missing, mut mismatched) = (0, 0, 0);
+    for name in names {
+        let path = temp_dir.join(&name);
+        let matches = match (open.project.completed.get(&name), fs::metadata(&path)) {
+            (_, Err(_)) => {
+                missing += open.project.completed.remove(&name).is_some() as usize;
+                continue;
+            }
+            (Some(completed), Ok(metadata)) => {
+                metadata.len() == completed.size
+                    && completion::sha256_file(&path).is_ok_and(|hash| hash == completed.sha256)
+            }
+            (None, Ok(_)) => false,
+        };
+        if matches {
+            present += 1;
+            continue;
+        }
+        mismatched += 1;
+        open.project.completed.remove(&name);
+        if let Err(error) = fs::remove_file(&path) {
+            tracing::debug!("Failed to remove {}: {}", path.display(), error);
+        }
+    }
+    if missing + mismatched == 0 {
+        status!("All {} segments the project downloaded are in place.", present);
+    } else {
+        status!(
+            "{} segments the project downloaded are in place; {} are missing and {} don't \
+             match the project, so they download again.",
+            present,
+            missing,
+            mismatched
+        );
+    }
+}
+
+/// Record a segment downloaded to `path` as `name` in the temp folder.
+pub fn segment_done(name: &str, path: &Path) {
+    let save_due = {
+        let mut state = PROJECT.lock().unwrap();
+        let Some(open) = state.as_mut() else {
+            return;
+        };
+        let hashed = fs::metadata(path).and_then(|metadata| {
+            completion::sha256_file(path).map(|sha256| Completed {
+                size: metadata.len(),
+                sha256,
+                frame_rate: None,
+            })
+        });
+        match hashed {
+            Ok(completed) => {
+                open.project.completed.insert(name.to_string(), completed);
+            }
+            Err(error) => tracing::debug!("Failed to hash {}: {}", path.display(), error),
+        }
+        open.saved.elapsed() >= SAVE_INTERVAL
+    };
+    if save_due {
+        if let Err(error) = save() {
+            status!("Warning: {:#}", error);
+        }
+    }
+}
+
+/// The frame rate probed earlier for the segment saved as `name`.
+pub fn frame_rate(name: &str) -> Option<f64> {
+    let state = PROJECT.lock().unwrap();
+    state.as_ref()?.project.completed.get(name)?.frame_rate
+}
+
+/// Keep the probed frame rates of segments by name, so that a continued
+/// run doesn't probe them again.
+pub fn cache_frame_rates(rates: &[(String, f64)]) -> Result<()> {
+    {
+        let mut state = PROJECT.lock().unwrap();
+        let Some(open) = state.as_mut() else {
+            return Ok(());
+        };
+        for (name, rate) in rates {
+            if let Some(completed) = open.project.completed.get_mut(name) {
+                completed.frame_rate = Some(*rate);
+            }
+        }
+    }
+    save()
+}
+
+/// Write the project file, replacing it whole so that a stopped run never
+/// leaves half of one.
+pub fn save() -> Result<()> {
+    let mut state = PROJECT.lock().unwrap();
+    let Some(open) = state.as_mut() else {
+        return Ok(());
+    };
+    let mut partial = open.path.clone().into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    fs::write(&partial, serde_json::to_vec_pretty(&open.project)?)
+        .and_then(|()| fs::rename(&partial, &open.path))
+        .with_context(|| format!("Failed to save project {}", open.path.display()))?;
+    open.saved = Instant::now();
+    Ok(())
+}
+
+/// Saves the project when dropped, however the download ends.
+pub struct SaveOnDrop;
+
+impl Drop for SaveOnDrop {
+    fn drop(&mut self) {
+        if let Err(error) = save() {
+            status!("Warning: {:#}", error);
+        }
+    }
+}
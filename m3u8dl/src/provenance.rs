@@ -0,0 +1,170 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::logging;
+use crate::playlist::{MediaPlaylist, Variant};
+use crate::privacy;
+use crate::quality::Quality;
+
+/// The `--output` tokens that [`Provenance::expand`] fills in.
+const TOKENS: [&str; 4] = ["{quality}", "{codec}", "{bandwidth}", "{source_host}"];
+
+/// The provenance of the download in progress, once its playlist is in.
+static CURRENT: Mutex<Option<Provenance>> = Mutex::new(None);
+/// The master playlist and variant `--quality` chose for the run it starts,
+/// which only sees the variant's own URL.
+static CHOSEN: Mutex<Option<(String, Variant)>> = Mutex::new(None);
+
+/// Where an output came from: the playlist and variant it was downloaded
+/// from, when, and with what. It names the output through the `--output`
+/// tokens, goes into the output's `comment` metadata unless
+/// `--no-provenance`, and into the completion record.
+///
+/// URLs are scrubbed as in the console output, for `--no-store`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    /// `m3u8dl` and its version.
+    pub tool: String,
+    /// The playlist URL as given (the master playlist, for `--quality`).
+    pub playlist_url: String,
+    /// The media playlist the segments came from.
+    pub variant_url: String,
+    /// The variant as the master playlist listed it, if there was one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<VariantDetails>,
+    /// When the playlist was fetched, in UTC.
+    pub downloaded_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantDetails {
+    /// Bits per second, per `BANDWIDTH`.
+    pub bandwidth: u64,
+    /// `WIDTHxHEIGHT`, per `RESOLUTION`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<String>,
+    /// The `CODECS` attribute, e.g. `avc1.64001f,mp4a.40.2`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codecs: Option<String>,
+}
+
+impl Provenance {
+    /// The provenance of `playlist`, fetched for the playlist URL given as
+    /// `playlist_url` (`-` for stdin).
+    pub fn of(playlist_url: &str, playlist: &MediaPlaylist) -> Self {
+        let chosen = CHOSEN.lock().unwrap().clone();
+        match (&playlist.variant, &chosen) {
+            (Some(variant), _) => Self::new(playlist_url, &playlist.url, Some(variant)),
+            (None, Some((master_url, variant))) => {
+                Self::new(master_url, &playlist.url, Some(variant))
+            }
+            (None, None) => Self::new(playlist_url, &playlist.url, None),
+        }
+    }
+
+    /// The provenance of a download of `variant_url`, which is `variant` of
+    /// the master playlist `playlist_url` if there is one.
+    pub fn new(playlist_url: &str, variant_url: &Url, variant: Option<&Variant>) -> Self {
+        Provenance {
+            tool: format!("m3u8dl {}", env!("CARGO_PKG_VERSION")),
+            playlist_url: privacy::scrub(playlist_url).into_owned(),
+            variant_url: privacy::scrub(variant_url.as_str()).into_owned(),
+            variant: variant.map(|variant| VariantDetails {
+                bandwidth: variant.bandwidth,
+                resolution: variant
+                    .resolution
+                    .map(|(width, height)| format!("{}x{}", width, height)),
+                codecs: variant.codecs.clone(),
+            }),
+            downloaded_at: logging::timestamp(),
+        }
+    }
+
+    /// `template` with the tokens filled in: `{quality}` (`720p`, or
+    /// `2500k` without a resolution), `{codec}` (`h264`, `hevc`, ...),
+    /// `{bandwidth}` (`2500k`) and `{source_host}`. Without a master
+    /// playlist the variant's tokens are `source` and `unknown`.
+    pub fn expand(&self, template: &str) -> String {
+        let variant = self.variant.as_ref();
+        let height = variant
+            .and_then(|variant| variant.resolution.as_deref()?.split_once('x'))
+            .and_then(|(_, height)| height.parse().ok());
+        let quality = match (height, variant) {
+            (Some(height), _) => Quality::Height(height).label(),
+            (None, Some(variant)) => Quality::Bandwidth(variant.bandwidth).label(),
+            (None, None) => "source".to_string(),
+        };
+        let bandwidth = variant.map_or_else(
+            || "unknown".to_string(),
+            |variant| Quality::Bandwidth(variant.bandwidth).label(),
+        );
+        let codecs = variant.and_then(|variant| variant.codecs.as_deref());
+        template
+            .replace("{quality}", &quality)
+            .replace("{codec}", codec_name(codecs))
+            .replace("{bandwidth}", &bandwidth)
+            .replace(
+                "{source_host}",
+                &source_host(&self.playlist_url, &self.variant_url),
+            )
+    }
+
+    /// The JSON that goes into the output's `comment` metadata.
+    pub fn comment(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Whether `template` has any of the tokens only the playlist can fill in.
+pub fn has_tokens(template: &str) -> bool {
+    TOKENS.iter().any(|token| template.contains(token))
+}
+
+/// Keep `provenance` as that of the download in progress.
+pub fn record(provenance: Provenance) {
+    *CURRENT.lock().unwrap() = Some(provenance);
+}
+
+/// The provenance of the download in progress, once its playlist is in.
+pub fn current() -> Option<Provenance> {
+    CURRENT.lock().unwrap().clone()
+}
+
+/// Have the next download credit `variant` of `master_url`, as chosen by
+/// `--quality`, or nothing when `None`.
+pub fn choose_variant(chosen: Option<(&str, &Variant)>) {
+    *CHOSEN.lock().unwrap() =
+        chosen.map(|(master_url, variant)| (master_url.to_string(), variant.clone()));
+}
+
+/// The video codec of a `CODECS` attribute by its common name, or the first
+/// codec's own name when none is a known video codec.
+fn codec_name(codecs: Option<&str>) -> &str {
+    let Some(codecs) = codecs else {
+        return "unknown";
+    };
+    let names: Vec<&str> = codecs
+        .split(',')
+        .map(|codec| codec.trim().split('.').next().unwrap_or_default())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let video = names.iter().find_map(|name| match *name {
+        "avc1" | "avc3" => Some("h264"),
+        "hvc1" | "hev1" => Some("hevc"),
+        "av01" => Some("av1"),
+        "vp09" => Some("vp9"),
+        _ => None,
+    });
+    video.or(names.first().copied()).unwrap_or("unknown")
+}
+
+/// The host the playlist came from: that of the URL given, or of the media
+/// playlist for one read from stdin.
+fn source_host(playlist_url: &str, variant_url: &str) -> String {
+    [playlist_url, variant_url]
+        .iter()
+        .find_map(|url| Some(Url::parse(url).ok()?.host_str()?.to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
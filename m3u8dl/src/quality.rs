@@ -0,0 +1,104 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::playlist::Variant;
+
+/// A bound on variant quality: a resolution (`1080p`, `1920x1080`) or a
+/// bandwidth (`2500000`, `2500k`, `2.5M`, in bits per second).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    Height(u32),
+    Bandwidth(u64),
+}
+
+impl Quality {
+    /// How `variant` compares to this bound, or `None` if the variant doesn't
+    /// declare what the bound is measured in.
+    fn compare(self, variant: &Variant) -> Option<Ordering> {
+        match self {
+            Quality::Height(height) => variant.resolution.map(|(_, h)| h.cmp(&height)),
+            Quality::Bandwidth(bandwidth) => Some(variant.bandwidth.cmp(&bandwidth)),
+        }
+    }
+}
+
+/// Whether `variant` lies within the bounds. Variants that don't declare a
+/// resolution never satisfy a resolution bound.
+pub fn within(variant: &Variant, min: Option<Quality>, max: Option<Quality>) -> bool {
+    let min_ok = match min {
+        Some(min) => min.compare(variant).is_some_and(Ordering::is_ge),
+        None => true,
+    };
+    let max_ok = match max {
+        Some(max) => max.compare(variant).is_some_and(Ordering::is_le),
+        None => true,
+    };
+    min_ok && max_ok
+}
+
+impl Quality {
+    /// The variant this quality names: the highest-bandwidth one at that
+    /// height, or the one closest to that bandwidth.
+    pub fn pick(self, variants: &[Variant]) -> Option<&Variant> {
+        match self {
+            Quality::Height(height) => variants
+                .iter()
+                .filter(|variant| variant.resolution.is_some_and(|(_, h)| h == height))
+                .max_by_key(|variant| variant.bandwidth),
+            Quality::Bandwidth(bandwidth) => variants
+                .iter()
+                .min_by_key(|variant| variant.bandwidth.abs_diff(bandwidth)),
+        }
+    }
+
+    /// Short form for file names, e.g. `1080p` or `2500k`.
+    pub fn label(self) -> String {
+        match self {
+            Quality::Height(height) => format!("{}p", height),
+            Quality::Bandwidth(bandwidth) => format!("{}k", bandwidth / 1000),
+        }
+    }
+}
+
+impl FromStr for Quality {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid quality '{}': expected a resolution like 1080p or 1920x1080, \
+                 or a bandwidth like 2500k",
+                value
+            )
+        };
+        let value = value.trim();
+
+        if let Some(height) = value.strip_suffix(['p', 'P']) {
+            return height.parse().map(Quality::Height).map_err(|_| invalid());
+        }
+        if let Some((_, height)) = value.split_once(['x', 'X']) {
+            return height.parse().map(Quality::Height).map_err(|_| invalid());
+        }
+
+        let (number, multiplier) = match value.char_indices().last() {
+            Some((at, 'k' | 'K')) => (&value[..at], 1_000.0),
+            Some((at, 'm' | 'M')) => (&value[..at], 1_000_000.0),
+            _ => (value, 1.0),
+        };
+        let bandwidth: f64 = number.parse().map_err(|_| invalid())?;
+        if bandwidth < 0.0 {
+            return Err(invalid());
+        }
+        Ok(Quality::Bandwidth((bandwidth * multiplier) as u64))
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quality::Height(height) => write!(f, "{}p", height),
+            Quality::Bandwidth(bandwidth) => write!(f, "{} bps", bandwidth),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use url::Url;
+
+use crate::fsretry;
+use crate::logging::status;
+
+/// Segments kept aside while several `--quality` variants are downloaded, so
+/// that a segment the variants share is only downloaded once. `None` unless
+/// [`enable`] was called.
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+/// Whether a kept segment has had to be copied rather than linked yet.
+static COPYING: AtomicBool = AtomicBool::new(false);
+/// Whether the playlist being downloaded lets segments be kept for later;
+/// `#EXT-X-ALLOW-CACHE:NO` turns this off.
+static CACHING: AtomicBool = AtomicBool::new(true);
+
+struct Registry {
+    /// Holds a link to every segment downloaded so far, since each variant's
+    /// temp folder is removed once its output is made.
+    dir: PathBuf,
+    /// Kept file name (relative to `dir`), by segment URL.
+    kept: BTreeMap<String, String>,
+}
+
+/// Keep every downloaded segment in `dir` for later variants to reuse.
+pub fn enable(dir: &Path) {
+    *REGISTRY.lock().unwrap() = Some(Registry {
+        dir: dir.to_path_buf(),
+        kept: BTreeMap::new(),
+    });
+}
+
+/// Let the download keep segments for later (revalidation, other variants,
+/// the completion record), or not, as the playlist says.
+pub fn set_caching(allowed: bool) {
+    CACHING.store(allowed, Ordering::Relaxed);
+}
+
+pub fn caching() -> bool {
+    CACHING.load(Ordering::Relaxed)
+}
+
+/// Keep the segment at `url`, just downloaded to `path`.
+pub fn record(url: &Url, path: &Path) {
+    if !caching() {
+        return;
+    }
+    let mut registry = REGISTRY.lock().unwrap();
+    let Some(registry) = registry.as_mut() else {
+        return;
+    };
+    if registry.kept.contains_key(url.as_str()) {
+        return;
+    }
+    let Some(filename) = path.file_name() else {
+        return;
+    };
+    let name = format!("{}-{}", registry.kept.len(), filename.to_string_lossy());
+    match link_or_copy(path, &registry.dir.join(&name)) {
+        Ok(()) => {
+            registry.kept.insert(url.to_string(), name);
+        }
+        Err(error) => tracing::debug!("Not keeping {} for reuse: {}", url, error),
+    }
+}
+
+/// Put a kept copy of the segment at `url` at `path`, returning its size, or
+/// `None` if no earlier variant downloaded it.
+pub fn link(url: &Url, path: &Path) -> io::Result<Option<u64>> {
+    if !caching() {
+        return Ok(None);
+    }
+    let registry = REGISTRY.lock().unwrap();
+    let Some(registry) = registry.as_ref() else {
+        return Ok(None);
+    };
+    let Some(name) = registry.kept.get(url.as_str()) else {
+        return Ok(None);
+    };
+    if path.exists() {
+        return Ok(None);
+    }
+    link_or_copy(&registry.dir.join(name), path)?;
+    tracing::debug!("Reusing {} from an earlier variant", url);
+    Ok(Some(fs::metadata(path)?.len()))
+}
+
+/// Names of the kept files, relative to the folder given to [`enable`].
+pub fn kept_files() -> Vec<String> {
+    match REGISTRY.lock().unwrap().as_ref() {
+        Some(registry) => registry.kept.values().cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Hard-link `source` to `path`, copying it instead across file systems
+/// (or on one without hard links).
+fn link_or_copy(source: &Path, path: &Path) -> io::Result<()> {
+    link_with(source, path, |source, path| fs::hard_link(source, path))
+}
+
+/// [`link_or_copy`], linking with `link`.
+fn link_with(
+    source: &Path,
+    path: &Path,
+    link: impl FnOnce(&Path, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let error = match link(source, path) {
+        Ok(()) => return Ok(()),
+        Err(error) if fsretry::crosses_devices(&error) || links_unsupported(&error) => error,
+        Err(error) => return Err(error),
+    };
+    fs::copy(source, path)?;
+    if !COPYING.swap(true, Ordering::Relaxed) {
+        tracing::debug!("Can't link {} to {}: {}", source.display(), path.display(), error);
+        status!(
+            "Warning: segments can't be hard-linked between {} and {}, so the segments \
+             kept for other variants are copies, taking up to twice the disk space.",
+            parent(source),
+            parent(path)
+        );
+    }
+    Ok(())
+}
+
+/// Whether a hard link failed with `error` because the file system has no
+/// hard links (e.g. FAT) or no more for the file.
+#[cfg(unix)]
+fn links_unsupported(error: &io::Error) -> bool {
+    let unsupported = [libc::EPERM, libc::EOPNOTSUPP, libc::EMLINK];
+    error
+        .raw_os_error()
+        .is_some_and(|code| unsupported.contains(&code))
+}
+
+#[cfg(windows)]
+fn links_unsupported(error: &io::Error) -> bool {
+    // ERROR_INVALID_FUNCTION, ERROR_NOT_SUPPORTED, ERROR_TOO_MANY_LINKS
+    let unsupported = [1, 50, 1142];
+    error
+        .raw_os_error()
+        .is_some_and(|code| unsupported.contains(&code))
+}
+
+fn parent(path: &Path) -> std::path::Display<'_> {
+    path.parent().unwrap_or(path).display()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A folder of its own under the system temp folder for the test `name`
+    /// holding `kept.ts`.
+    fn folder_with_segment(name: &str) -> PathBuf {
+        let name = format!("m3u8dl-reuse-{}-{}", name, std::process::id());
+        let folder = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("kept.ts"), b"segment").unwrap();
+        folder
+    }
+
+    #[test]
+    fn link_copies_across_file_systems() {
+        let folder = folder_with_segment("exdev");
+        let (source, path) = (folder.join("kept.ts"), folder.join("seg0.ts"));
+        link_with(&source, &path, |_, _| {
+            Err(io::Error::from_raw_os_error(fsretry::CROSS_DEVICE))
+        })
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"segment");
+        assert!(source.exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn link_passes_up_other_errors() {
+        let folder = folder_with_segment("missing");
+        let (source, path) = (folder.join("gone.ts"), folder.join("seg0.ts"));
+        let error = link_or_copy(&source, &path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let error = link_with(&folder.join("kept.ts"), &path, |_, _| {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        })
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!path.exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
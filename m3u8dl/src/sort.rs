@@ -0,0 +1,69 @@
+use std::cmp::Ordering;
+use std::path::PathBuf;
+
+/// How the segment files are ordered in the concat list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortOrder {
+    /// Byte-wise by file name, so `seg10.ts` comes before `seg9.ts`.
+    Lexical,
+    /// By file name, comparing runs of digits by value (`seg9.ts` before `seg10.ts`).
+    Numeric,
+    /// The order the segments appear in the playlist.
+    DownloadOrder,
+    /// Whatever order the file system lists the temp folder in.
+    None,
+}
+
+/// Sort `files` in place; `DownloadOrder` is handled by the caller, which knows the playlist.
+pub fn sort_files(files: &mut [PathBuf], order: SortOrder) {
+    match order {
+        SortOrder::Lexical => files.sort(),
+        SortOrder::Numeric => {
+            files.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()))
+        }
+        SortOrder::DownloadOrder | SortOrder::None => {}
+    }
+}
+
+/// Compare two strings, treating each run of ASCII digits as a single number.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                // Compare by magnitude first, then digit by digit; leading zeros break ties
+                let ordering = x
+                    .trim_start_matches('0')
+                    .len()
+                    .cmp(&y.trim_start_matches('0').len())
+                    .then_with(|| x.trim_start_matches('0').cmp(y.trim_start_matches('0')))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut number = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        number.push(digit);
+    }
+    number
+}
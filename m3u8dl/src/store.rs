@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+use tokio::process::ChildStdin;
+
+use crate::cleanup::Cleanup;
+use crate::exit::ExitKind;
+use crate::logging::status;
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// `--in-memory`: holds segments that finished out of order until every
+/// earlier one has been written to ffmpeg, spilling the longest-held ones to
+/// the temp folder once they take up more than the memory limit.
+pub struct SegmentStore<'a> {
+    /// Index of the next segment ffmpeg expects.
+    next: usize,
+    held: BTreeMap<usize, Held>,
+    /// Indices of the segments held in memory, in the order they arrived.
+    arrivals: VecDeque<usize>,
+    in_memory: u64,
+    limit: u64,
+    /// Where to spill to, or `None` to hold everything in memory.
+    spill: Option<(&'a Cleanup, PathBuf)>,
+    peak: u64,
+    spilled: usize,
+    warned: bool,
+}
+
+enum Held {
+    Memory(Bytes),
+    Spilled(PathBuf),
+    /// Failed for good (with `--ignore-errors`), so there is nothing to write.
+    Skipped,
+}
+
+impl<'a> SegmentStore<'a> {
+    /// A store holding at most `limit` bytes in memory, spilling into
+    /// `temp_dir` unless `spill` is `None`.
+    pub fn new(limit: u64, spill: Option<(&'a Cleanup, &str)>) -> Self {
+        Self {
+            next: 0,
+            held: BTreeMap::new(),
+            arrivals: VecDeque::new(),
+            in_memory: 0,
+            limit,
+            spill: spill.map(|(cleanup, temp_dir)| (cleanup, PathBuf::from(temp_dir))),
+            peak: 0,
+            spilled: 0,
+            warned: false,
+        }
+    }
+
+    /// Refuse to start when the download can't fit in memory and spilling is off.
+    pub fn check_estimate(&self, estimated: Option<u64>) -> Result<()> {
+        if self.spill.is_some() {
+            return Ok(());
+        }
+        match estimated {
+            Some(estimated) if estimated > self.limit => Err(anyhow::anyhow!(
+                "The download is estimated at {:.0} MiB, more than --memory-limit ({:.0} MiB); \
+                 raise the limit or allow spilling to disk by leaving out --no-spill",
+                estimated as f64 / MIB,
+                self.limit as f64 / MIB
+            )
+            .context(ExitKind::Usage)),
+            Some(_) => Ok(()),
+            None => {
+                status!(
+                    "Warning: can't estimate the download's size, so it may not fit in \
+                     --memory-limit."
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Take a downloaded segment and write whatever is now in order to ffmpeg.
+    pub async fn insert(
+        &mut self,
+        index: usize,
+        content: Bytes,
+        stdin: &mut ChildStdin,
+    ) -> Result<()> {
+        self.in_memory += content.len() as u64;
+        self.peak = self.peak.max(self.in_memory);
+        self.held.insert(index, Held::Memory(content));
+        self.arrivals.push_back(index);
+        self.write_ready(stdin).await?;
+        self.enforce_limit()
+    }
+
+    /// Pass over a segment that failed for good.
+    pub async fn skip(&mut self, index: usize, stdin: &mut ChildStdin) -> Result<()> {
+        self.held.insert(index, Held::Skipped);
+        self.write_ready(stdin).await
+    }
+
+    /// Peak memory use and spill count, for the end-of-run report.
+    pub fn summary(&self) -> String {
+        format!(
+            "peak {:.1} MiB in memory, {} segments spilled to disk",
+            self.peak as f64 / MIB,
+            self.spilled
+        )
+    }
+
+    async fn write_ready(&mut self, stdin: &mut ChildStdin) -> Result<()> {
+        while let Some(held) = self.held.remove(&self.next) {
+            let content = match held {
+                Held::Memory(content) => {
+                    self.in_memory -= content.len() as u64;
+                    self.arrivals.retain(|index| *index != self.next);
+                    content
+                }
+                Held::Spilled(path) => {
+                    let content = fs::read(&path).context("Failed to read spilled segment")?;
+                    let _ = fs::remove_file(&path);
+                    Bytes::from(content)
+                }
+                Held::Skipped => Bytes::new(),
+            };
+            stdin
+                .write_all(&content)
+                .await
+                .context("Failed to stream segment to ffmpeg")
+                .context(ExitKind::Ffmpeg)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    fn enforce_limit(&mut self) -> Result<()> {
+        while self.in_memory > self.limit {
+            let Some((cleanup, temp_dir)) = &self.spill else {
+                if !self.warned {
+                    self.warned = true;
+                    status!(
+                        "Warning: segments waiting for an earlier one now take up more than \
+                         --memory-limit."
+                    );
+                }
+                return Ok(());
+            };
+            let Some(index) = self.arrivals.pop_front() else {
+                return Ok(());
+            };
+            let Some(Held::Memory(content)) = self.held.get(&index) else {
+                continue;
+            };
+            let content = content.clone();
+
+            let name = format!("spill-{}.ts", index);
+            cleanup.expect(&name);
+            let path = Path::new(temp_dir).join(name);
+            fs::write(&path, &content).context("Failed to spill segment to disk")?;
+            tracing::debug!(
+                "Spilled segment {} ({} bytes) to disk",
+                index,
+                content.len()
+            );
+            self.in_memory -= content.len() as u64;
+            self.held.insert(index, Held::Spilled(path));
+            self.spilled += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use reqwest::Client;
+use url::Url;
+
+use crate::exit::ExitKind;
+use crate::http;
+use crate::logging::status;
+use crate::playlist;
+
+/// Subtitle segments fetched at once; they are small, and there are many.
+const CONCURRENCY: usize = 8;
+/// MPEG-TS timestamps are 33-bit, counting at 90 kHz.
+const MPEGTS_HZ: f64 = 90_000.0;
+const MPEGTS_WRAP: u64 = 1 << 33;
+
+/// `--save-subtitles`: merge the WebVTT segments of the master playlist's
+/// subtitle rendition (the default one, or else the first) into one file.
+pub async fn save(client: &Client, master_url: &str, path: &Path) -> Result<()> {
+    let master_url = Url::parse(master_url).context(ExitKind::Usage)?;
+    let renditions = playlist::fetch_renditions(client, &master_url, "subtitles to save")
+        .await
+        .context(ExitKind::Playlist)?;
+    let subtitles: Vec<_> = renditions
+        .iter()
+        .filter(|rendition| rendition.kind == "SUBTITLES")
+        .filter_map(|rendition| Some((rendition, rendition.url.as_ref()?)))
+        .collect();
+    let (rendition, url) = subtitles
+        .iter()
+        .find(|(rendition, _)| rendition.default)
+        .or_else(|| subtitles.first())
+        .with_context(|| format!("{} lists no subtitle rendition to save", master_url))
+        .context(ExitKind::Playlist)?;
+
+    let (_, content) = playlist::fetch_playlist(client, url)
+        .await
+        .context(ExitKind::Playlist)?;
+    let segments = playlist::parse_segments(content, url).context(ExitKind::Playlist)?;
+    let texts: Vec<String> = stream::iter(segments.iter().map(|segment| segment.url()))
+        .map(|url| fetch_text(client, url))
+        .buffered(CONCURRENCY)
+        .try_collect()
+        .await
+        .context(ExitKind::Segments)?;
+
+    let (merged, cues) = merge_webvtt(&texts)?;
+    fs::write(path, merged)
+        .with_context(|| format!("Failed to write subtitles to {}", path.display()))?;
+    status!(
+        "Saved the {} subtitles ({} cues from {} segments) to {}.",
+        rendition.language.as_deref().unwrap_or(&rendition.name),
+        cues,
+        segments.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+async fn fetch_text(client: &Client, url: Url) -> Result<String> {
+    let response = client.get(url.clone()).send().await?;
+    http::record_response(&response);
+    let text = response.error_for_status()?.text().await?;
+    Ok(text)
+}
+
+/// Join WebVTT segments into one file, returning it and its number of cues.
+///
+/// Each segment's `X-TIMESTAMP-MAP` maps its cue times onto the MPEG-TS
+/// clock; cues are moved by how far that mapping is from the first
+/// segment's, so that times count from the start of the stream. A cue
+/// repeated at the start of the next segment, because it spans the boundary,
+/// is kept once.
+fn merge_webvtt(texts: &[String]) -> Result<(String, usize)> {
+    let mut merged = String::from("WEBVTT\n");
+    let mut cues = 0;
+    let mut clock = MpegTsClock::default();
+    let mut base = None;
+    let mut shift = 0.0;
+    let mut previous = HashSet::new();
+
+    for (number, text) in texts.iter().enumerate() {
+        let text = text
+            .trim_start_matches('\u{feff}')
+            .replace("\r\n", "\n")
+            .replace('\r', "\n");
+        let mut blocks = text
+            .split("\n\n")
+            .map(|block| block.trim_matches('\n'))
+            .filter(|block| !block.is_empty());
+        let header = blocks.next().unwrap_or_default();
+        anyhow::ensure!(
+            header.starts_with("WEBVTT"),
+            "Subtitle segment {} is not WebVTT",
+            number
+        );
+        if let Some(map) = header
+            .lines()
+            .find_map(|line| line.strip_prefix("X-TIMESTAMP-MAP="))
+        {
+            let (mpegts, local) = parse_timestamp_map(map)
+                .with_context(|| format!("Invalid X-TIMESTAMP-MAP in segment {}", number))?;
+            let offset = clock.unwrap(mpegts) as f64 / MPEGTS_HZ - local;
+            shift = offset - *base.get_or_insert(offset);
+        }
+
+        let mut current = HashSet::new();
+        for block in blocks {
+            let first = block.lines().next().unwrap_or_default();
+            if first.starts_with("NOTE") {
+                continue;
+            }
+            // Style and region definitions have to come before the first cue
+            if first.starts_with("STYLE") || first.starts_with("REGION") {
+                if cues == 0 {
+                    merged.push('\n');
+                    merged.push_str(block);
+                    merged.push('\n');
+                }
+                continue;
+            }
+
+            let mut lines = block.lines();
+            let (identifier, timing) = match lines.next() {
+                Some(line) if line.contains("-->") => (None, line),
+                identifier => match lines.next() {
+                    Some(line) if line.contains("-->") => (identifier, line),
+                    _ => continue,
+                },
+            };
+            let Some((start, rest)) = timing.split_once("-->") else {
+                continue;
+            };
+            let rest = rest.trim();
+            let (end, settings) = rest.split_once([' ', '\t']).unwrap_or((rest, ""));
+            let (Some(start), Some(end)) = (parse_time(start.trim()), parse_time(end)) else {
+                continue;
+            };
+            let (start, end) = ((start + shift).max(0.0), (end + shift).max(0.0));
+            let payload = lines.collect::<Vec<_>>().join("\n");
+
+            let key = (millis(start), millis(end), payload.clone());
+            let repeated = previous.contains(&key);
+            current.insert(key);
+            if repeated {
+                continue;
+            }
+            merged.push('\n');
+            if let Some(identifier) = identifier {
+                merged.push_str(identifier);
+                merged.push('\n');
+            }
+            merged.push_str(&format_time(start));
+            merged.push_str(" --> ");
+            merged.push_str(&format_time(end));
+            if !settings.is_empty() {
+                merged.push(' ');
+                merged.push_str(settings.trim());
+            }
+            merged.push('\n');
+            if !payload.is_empty() {
+                merged.push_str(&payload);
+                merged.push('\n');
+            }
+            cues += 1;
+        }
+        previous = current;
+    }
+    Ok((merged, cues))
+}
+
+/// Undoes the wrap-around of the 33-bit MPEG-TS clock across segments.
+#[derive(Default)]
+struct MpegTsClock {
+    last: Option<u64>,
+    wraps: u64,
+}
+
+impl MpegTsClock {
+    fn unwrap(&mut self, timestamp: u64) -> u64 {
+        if self
+            .last
+            .is_some_and(|last| timestamp + MPEGTS_WRAP / 2 < last)
+        {
+            self.wraps += 1;
+        }
+        self.last = Some(timestamp);
+        timestamp + self.wraps * MPEGTS_WRAP
+    }
+}
+
+/// `MPEGTS:<ticks>,LOCAL:<time>`, in either order.
+fn parse_timestamp_map(map: &str) -> Option<(u64, f64)> {
+    let (mut mpegts, mut local) = (None, None);
+    for part in map.split(',') {
+        match part.trim().split_once(':') {
+            Some(("MPEGTS", value)) => mpegts = value.trim().parse().ok(),
+            Some(("LOCAL", value)) => local = parse_time(value.trim()),
+            _ => {}
+        }
+    }
+    Some((mpegts?, local?))
+}
+
+/// A WebVTT timestamp, `mm:ss.ttt` or `hh:mm:ss.ttt`, in seconds.
+fn parse_time(time: &str) -> Option<f64> {
+    let parts: Vec<&str> = time.split(':').collect();
+    let (hours, minutes, seconds) = match parts[..] {
+        [minutes, seconds] => ("0", minutes, seconds),
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        _ => return None,
+    };
+    if !seconds.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: u64 = hours.parse().ok()?;
+    let minutes: u64 = minutes.parse().ok()?;
+    let seconds: f64 = seconds.parse().ok()?;
+    Some((hours * 3600 + minutes * 60) as f64 + seconds)
+}
+
+fn millis(seconds: f64) -> u64 {
+    (seconds * 1000.0).round() as u64
+}
+
+fn format_time(seconds: f64) -> String {
+    let millis = millis(seconds);
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
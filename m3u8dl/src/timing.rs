@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use reqwest::{Response, Version};
+use serde::Serialize;
+use url::Url;
+
+use crate::privacy;
+
+/// Whether `--timing-report` asked for request timings; while it is off,
+/// [`RequestTiming::start`] records nothing.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Every timed segment request so far, in the order they finished.
+static TIMINGS: Mutex<Vec<Timing>> = Mutex::new(Vec::new());
+
+/// One segment request (retries are requests of their own).
+///
+/// reqwest doesn't expose DNS, connect or TLS phases, or whether a pooled
+/// connection was reused, so the phases are wall-clock: time to the response
+/// headers (which covers connecting) and time from there to the end of the body.
+#[derive(Debug, Serialize)]
+struct Timing {
+    url: String,
+    attempt: usize,
+    /// Where redirects led, if away from `url`.
+    final_url: Option<String>,
+    status: Option<u16>,
+    http_version: Option<&'static str>,
+    bytes: u64,
+    ttfb_ms: Option<f64>,
+    transfer_ms: Option<f64>,
+    total_ms: f64,
+    ok: bool,
+}
+
+/// Start recording request timings.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// The timing of one request in progress, recorded when dropped; a request
+/// dropped before [`RequestTiming::finish`] counts as failed.
+pub struct RequestTiming(Option<Pending>);
+
+struct Pending {
+    url: Url,
+    attempt: usize,
+    final_url: Option<Url>,
+    started: Instant,
+    headers: Option<(Instant, u16, Version)>,
+    body: Option<(Instant, u64)>,
+    ok: bool,
+}
+
+impl RequestTiming {
+    /// Start timing `attempt` (1 for the first) at the segment `url`.
+    pub fn start(url: &Url, attempt: usize) -> Self {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Self(None);
+        }
+        Self(Some(Pending {
+            url: url.clone(),
+            attempt,
+            final_url: None,
+            started: Instant::now(),
+            headers: None,
+            body: None,
+            ok: false,
+        }))
+    }
+
+    /// The response headers arrived (again, for a request that had to be re-sent).
+    pub fn response(&mut self, response: &Response) {
+        if let Some(pending) = &mut self.0 {
+            pending.final_url = (response.url() != &pending.url).then(|| response.url().clone());
+            pending.headers = Some((
+                Instant::now(),
+                response.status().as_u16(),
+                response.version(),
+            ));
+        }
+    }
+
+    /// The whole body, `bytes` long, arrived.
+    pub fn body(&mut self, bytes: u64) {
+        if let Some(pending) = &mut self.0 {
+            pending.body = Some((Instant::now(), bytes));
+        }
+    }
+
+    /// The request succeeded.
+    pub fn finish(mut self) {
+        if let Some(pending) = &mut self.0 {
+            pending.ok = true;
+        }
+    }
+}
+
+impl Drop for RequestTiming {
+    fn drop(&mut self) {
+        let Some(pending) = self.0.take() else {
+            return;
+        };
+        let finished = Instant::now();
+        let headers_at = pending.headers.map(|(at, ..)| at);
+        let timing = Timing {
+            url: privacy::scrub(pending.url.as_str()).into_owned(),
+            attempt: pending.attempt,
+            final_url: pending
+                .final_url
+                .map(|url| privacy::scrub(url.as_str()).into_owned()),
+            status: pending.headers.map(|(_, status, _)| status),
+            http_version: pending.headers.map(|(.., version)| version_name(version)),
+            bytes: pending.body.map_or(0, |(_, bytes)| bytes),
+            ttfb_ms: headers_at.map(|at| millis(at - pending.started)),
+            transfer_ms: match (headers_at, pending.body) {
+                (Some(headers_at), Some((body_at, _))) => Some(millis(body_at - headers_at)),
+                _ => None,
+            },
+            total_ms: millis(finished - pending.started),
+            ok: pending.ok,
+        };
+        TIMINGS.lock().unwrap().push(timing);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn version_name(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "unknown",
+    }
+}
+
+/// Percentiles of the recorded phases, e.g. `TTFB p50 12ms, p90 40ms, p99 95ms; ...`,
+/// or `None` if nothing was timed.
+pub fn summary() -> Option<String> {
+    let timings = TIMINGS.lock().unwrap();
+    if timings.is_empty() {
+        return None;
+    }
+    let phases: [(&str, Vec<f64>); 3] = [
+        ("TTFB", timings.iter().filter_map(|t| t.ttfb_ms).collect()),
+        (
+            "transfer",
+            timings.iter().filter_map(|t| t.transfer_ms).collect(),
+        ),
+        ("total", timings.iter().map(|t| t.total_ms).collect()),
+    ];
+    let phases: Vec<String> = phases
+        .into_iter()
+        .filter(|(_, values)| !values.is_empty())
+        .map(|(name, mut values)| {
+            values.sort_by(f64::total_cmp);
+            format!(
+                "{} p50 {:.0}ms, p90 {:.0}ms, p99 {:.0}ms",
+                name,
+                percentile(&values, 50.0),
+                percentile(&values, 90.0),
+                percentile(&values, 99.0)
+            )
+        })
+        .collect();
+    Some(format!("{} requests; {}", timings.len(), phases.join("; ")))
+}
+
+/// Nearest-rank percentile of sorted, non-empty `values`.
+fn percentile(values: &[f64], percent: f64) -> f64 {
+    let rank = (percent / 100.0 * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+/// Write every recorded request to `path`: JSON lines for a `.json` or
+/// `.jsonl` file, CSV otherwise.
+pub fn write_report(path: &Path) -> Result<()> {
+    let timings = TIMINGS.lock().unwrap();
+    let json = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("json" | "jsonl")
+    );
+    let mut writer =
+        BufWriter::new(File::create(path).context("Failed to create the timing report")?);
+    if !json {
+        writeln!(
+            writer,
+            "url,attempt,final_url,status,http_version,bytes,ttfb_ms,transfer_ms,total_ms,ok"
+        )?;
+    }
+
+    for timing in timings.iter() {
+        if json {
+            serde_json::to_writer(&mut writer, timing)?;
+            writeln!(writer)?;
+        } else {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{:.1},{}",
+                csv_quote(&timing.url),
+                timing.attempt,
+                timing
+                    .final_url
+                    .as_deref()
+                    .map(csv_quote)
+                    .unwrap_or_default(),
+                optional(timing.status),
+                timing.http_version.unwrap_or(""),
+                timing.bytes,
+                optional(timing.ttfb_ms.map(|ms| format!("{:.1}", ms))),
+                optional(timing.transfer_ms.map(|ms| format!("{:.1}", ms))),
+                timing.total_ms,
+                timing.ok
+            )?;
+        }
+    }
+    writer.flush().context("Failed to write the timing report")
+}
+
+fn csv_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+fn optional(value: Option<impl ToString>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
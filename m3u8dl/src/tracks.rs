@@ -0,0 +1,76 @@
+use anyhow::{Context, Result};
+use reqwest::Client;
+use url::Url;
+
+use crate::exit::ExitKind;
+use crate::playlist::{self, Rendition};
+
+/// `--list-tracks`: print the master playlist's `#EXT-X-MEDIA` renditions as
+/// a table, numbered from 1 in playlist order.
+pub async fn list(client: &Client, master_url: &str) -> Result<()> {
+    let master_url = Url::parse(master_url).context(ExitKind::Usage)?;
+    let renditions = playlist::fetch_renditions(client, &master_url, "tracks to list")
+        .await
+        .context(ExitKind::Playlist)?;
+    if renditions.is_empty() {
+        println!("{} lists no #EXT-X-MEDIA renditions.", master_url);
+        return Ok(());
+    }
+
+    let header = [
+        "#",
+        "TYPE",
+        "GROUP-ID",
+        "LANGUAGE",
+        "NAME",
+        "DEFAULT",
+        "AUTOSELECT",
+        "URI",
+    ];
+    let rows: Vec<[String; 8]> = renditions
+        .iter()
+        .enumerate()
+        .map(|(index, rendition)| row(index + 1, rendition))
+        .collect();
+    // Padding counts characters, so the widths do too
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let print = |cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print(&header);
+    for row in &rows {
+        print(&row.each_ref().map(String::as_str));
+    }
+    Ok(())
+}
+
+fn row(number: usize, rendition: &Rendition) -> [String; 8] {
+    let yes_no = |flag: bool| if flag { "YES" } else { "NO" }.to_string();
+    [
+        number.to_string(),
+        rendition.kind.clone(),
+        rendition.group_id.clone(),
+        rendition
+            .language
+            .clone()
+            .unwrap_or_else(|| "-".to_string()),
+        rendition.name.clone(),
+        yes_no(rendition.default),
+        yes_no(rendition.autoselect),
+        rendition
+            .url
+            .as_ref()
+            .map_or_else(|| "-".to_string(), Url::to_string),
+    ]
+}
@@ -0,0 +1,264 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Result;
+
+use crate::exit::{self, ExitKind};
+use crate::fsretry::{self, Target};
+use crate::logging::status;
+
+/// How much of the start of the output `--trim-leading-black` looks at, in
+/// seconds. A slate runs 30–90 seconds; anything longer isn't one.
+const SCAN_WINDOW: f64 = 300.0;
+/// A lead-in has to begin within this many seconds of the start.
+const START_SLACK: f64 = 0.5;
+/// Black and silence that end within this many seconds of each other mark
+/// the same lead-in; further apart, it isn't clear which one the program
+/// starts after.
+const AGREEMENT: f64 = 2.0;
+
+/// What blackdetect and silencedetect count as black and silent.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    /// Share of the picture's brightness range below which a pixel is black
+    /// (blackdetect's `pix_th`).
+    pub pixel: f64,
+    /// Shortest lead-in worth trimming, in seconds.
+    pub min_duration: f64,
+    /// Audio level below which it counts as silence, in dB.
+    pub silence_db: f64,
+}
+
+/// Black or silent stretches at the start of the output, from ffmpeg's
+/// filters: when each began and, unless it ran to the end of the scanned
+/// window, ended.
+#[derive(Debug, Default)]
+struct Detection {
+    black: Option<(f64, Option<f64>)>,
+    silence: Option<(f64, Option<f64>)>,
+    has_audio: bool,
+    /// How much was looked at: the window, or the whole of a shorter output.
+    scanned: f64,
+}
+
+/// `--trim-leading-black`: find where the program starts after a black or
+/// silent slate at the start of `output`, and cut the output there with a
+/// stream copy (from the keyframe before it). Returns how many seconds were
+/// cut; nothing is cut when there is no lead-in or it isn't clear where it
+/// ends.
+pub fn trim_leading_black(output: &Path, thresholds: &Thresholds) -> Result<f64> {
+    let detection = detect(output, thresholds)?;
+    let start = match content_start(&detection, thresholds) {
+        Ok(Some(start)) => start,
+        Ok(None) => {
+            status!(
+                "No black lead-in found at the start of {}.",
+                output.display()
+            );
+            return Ok(0.0);
+        }
+        Err(reason) => {
+            status!(
+                "Warning: not trimming the start of {}: {}.",
+                output.display(),
+                reason
+            );
+            return Ok(0.0);
+        }
+    };
+
+    let before = duration(output);
+    let trimmed = trimmed_path(output);
+    let result = Command::new("ffmpeg")
+        .args(["-hide_banner", "-y", "-ss"])
+        .arg(format!("{:.3}", start))
+        .arg("-i")
+        .arg(output)
+        .args(["-map", "0", "-c", "copy", "-avoid_negative_ts", "make_zero"])
+        .arg(&trimmed)
+        .output()
+        .map_err(|error| exit::spawn_error(error, "ffmpeg trim command"))?;
+    if !result.status.success() {
+        let _ = fs::remove_file(&trimmed);
+        return Err(anyhow::anyhow!(
+            "Error trimming the lead-in off {}: {}",
+            output.display(),
+            String::from_utf8_lossy(&result.stderr)
+        )
+        .context(ExitKind::Ffmpeg));
+    }
+    fsretry::retry(Target::Output, "move the trimmed copy to", output, || {
+        fs::rename(&trimmed, output)
+    })?;
+
+    // The cut is at the keyframe before the content, so measure what went
+    let cut = match (before, duration(output)) {
+        (Some(before), Some(after)) => (before - after).max(0.0),
+        _ => start,
+    };
+    status!(
+        "Trimmed {:.1}s of black lead-in off {} (the program starts at {:.1}s).",
+        cut,
+        output.display(),
+        start
+    );
+    Ok(cut)
+}
+
+/// Run blackdetect and silencedetect over the first [`SCAN_WINDOW`] seconds.
+fn detect(output: &Path, thresholds: &Thresholds) -> Result<Detection> {
+    let result = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-t"])
+        .arg(SCAN_WINDOW.to_string())
+        .arg("-i")
+        .arg(output)
+        .arg("-vf")
+        .arg(format!(
+            "blackdetect=d={}:pix_th={}",
+            thresholds.min_duration, thresholds.pixel
+        ))
+        .arg("-af")
+        .arg(format!(
+            "silencedetect=noise={}dB:d={}",
+            thresholds.silence_db, thresholds.min_duration
+        ))
+        .args(["-f", "null", "-"])
+        .output()
+        .map_err(|error| exit::spawn_error(error, "ffmpeg blackdetect command"))?;
+    let log = String::from_utf8_lossy(&result.stderr);
+    if !result.status.success() {
+        return Err(anyhow::anyhow!(
+            "Error looking for a black lead-in in {}: {}",
+            output.display(),
+            log
+        )
+        .context(ExitKind::Ffmpeg));
+    }
+
+    let mut detection = Detection {
+        scanned: SCAN_WINDOW,
+        ..Detection::default()
+    };
+    for line in log.lines() {
+        if let Some(duration) = line.trim().strip_prefix("Duration: ") {
+            if let Some(duration) = parse_timestamp(duration) {
+                detection.scanned = detection.scanned.min(duration);
+            }
+        } else if line.contains("Stream #") && line.contains("Audio:") {
+            detection.has_audio = true;
+        } else if line.contains("[blackdetect") && detection.black.is_none() {
+            if let Some(start) = value(line, "black_start:") {
+                detection.black = Some((start, value(line, "black_end:")));
+            }
+        } else if line.contains("[silencedetect") {
+            match (value(line, "silence_start:"), &mut detection.silence) {
+                (Some(start), silence @ None) => *silence = Some((start, None)),
+                (None, Some((_, end @ None))) => *end = value(line, "silence_end:"),
+                _ => {}
+            }
+        }
+    }
+    tracing::debug!("Lead-in of {}: {:?}", output.display(), detection);
+    Ok(detection)
+}
+
+/// Where the program starts: the end of the black from the start, if the
+/// audio (when there is any) is silent until about then too. `Ok(None)`
+/// without a lead-in; `Err` with why when it's ambiguous.
+fn content_start(detection: &Detection, thresholds: &Thresholds) -> Result<Option<f64>, String> {
+    let Some((black_start, black_end)) = detection.black else {
+        return Ok(None);
+    };
+    if black_start > START_SLACK {
+        return Ok(None);
+    }
+    let black_end = match black_end {
+        Some(end) if end < detection.scanned - START_SLACK => end,
+        _ => {
+            return Err(format!(
+                "the first {:.0}s are all black, so it isn't clear where the program starts",
+                detection.scanned
+            ))
+        }
+    };
+    if black_end < thresholds.min_duration {
+        return Ok(None);
+    }
+    if detection.has_audio {
+        let silence_end = match detection.silence {
+            Some((start, end)) if start <= START_SLACK => end,
+            _ => {
+                return Err(format!(
+                    "the picture is black until {:.1}s, but there is sound from the start",
+                    black_end
+                ))
+            }
+        };
+        match silence_end {
+            Some(end) if (end - black_end).abs() <= AGREEMENT => {}
+            Some(end) => {
+                return Err(format!(
+                    "the picture is black until {:.1}s but the sound is silent until {:.1}s",
+                    black_end, end
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "the picture is black until {:.1}s but the sound stays silent after it",
+                    black_end
+                ))
+            }
+        }
+    }
+    Ok(Some(black_end))
+}
+
+/// The number after `key` in a line of filter output, e.g. `black_end:32.5`.
+fn value(line: &str, key: &str) -> Option<f64> {
+    let rest = line[line.find(key)? + key.len()..].trim_start();
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// Seconds in an `HH:MM:SS.ss` timestamp, such as the `Duration: ` ffmpeg
+/// reports for its input (followed by more fields).
+fn parse_timestamp(text: &str) -> Option<f64> {
+    let timestamp = text.split(',').next()?.trim();
+    let mut seconds = 0.0;
+    for part in timestamp.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+/// The output's duration according to ffprobe, if it's there to ask.
+fn duration(path: &Path) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(path)
+        .output()
+        .ok()?;
+    output.status.success().then_some(())?;
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// `show.trimmed.mp4` for `show.mp4`, keeping the extension ffmpeg picks the
+/// muxer by.
+fn trimmed_path(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(extension) => format!("{}.trimmed.{}", stem, extension.to_string_lossy()),
+        None => format!("{}.trimmed", stem),
+    };
+    output.with_file_name(name)
+}
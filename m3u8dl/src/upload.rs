@@ -0,0 +1,144 @@
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use crate::audio::AudioMix;
+use crate::exit::{self, ExitKind};
+use crate::format::OutputFormat;
+use crate::logging::status;
+use crate::concat::{self, ConcatInput};
+use crate::encoding::Encoding;
+use crate::watchdog;
+use crate::{execute_ffmpeg_command, ffmpeg_command};
+
+/// Mux the segments and hand the result to `upload_cmd`.
+///
+/// Containers that can be written sequentially are piped straight from
+/// ffmpeg into the command. Others (such as mp4, whose index is written
+/// last) are muxed to `output_file` first, fed to the command, then removed.
+pub fn mux_and_upload(
+    upload_cmd: &str,
+    input: &ConcatInput,
+    output_file: &str,
+    format: Option<OutputFormat>,
+    encoding: Option<Encoding>,
+    audio: &AudioMix,
+    codec_args: &[String],
+) -> Result<()> {
+    let name = Path::new(output_file)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let upload_cmd = upload_cmd.replace("{name}", &name);
+
+    let muxer = match format {
+        Some(format) => (format != OutputFormat::Mp4).then(|| format.muxer()),
+        None => streamable_format(output_file),
+    };
+    let Some(muxer) = muxer else {
+        status!(
+            "{} can't be streamed, writing it locally before uploading.",
+            output_file
+        );
+        execute_ffmpeg_command(input, output_file, format, encoding, audio, codec_args)?;
+        let file = File::open(output_file).context("Failed to open output for upload")?;
+        let upload = shell_command(&upload_cmd)
+            .stdin(file)
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to start upload command")?;
+        wait_for_upload(upload, &upload_cmd)?;
+        fs::remove_file(output_file).context("Failed to remove local copy after upload")?;
+        return Ok(());
+    };
+
+    let mut ffmpeg = ffmpeg_command(input, encoding, audio, codec_args);
+    ffmpeg.arg("-f").arg(muxer).arg("pipe:1");
+    tracing::debug!("Running {:?} | {}", ffmpeg, upload_cmd);
+
+    let mut ffmpeg = ffmpeg
+        .stdin(input.stdin())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| exit::spawn_error(error, "ffmpeg command"))?;
+    let pipe = ffmpeg
+        .stdout
+        .take()
+        .context("Failed to capture ffmpeg output")?;
+    let upload = shell_command(&upload_cmd)
+        .stdin(pipe)
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to start upload command")?;
+    let feeder = input.feed(&mut ffmpeg);
+
+    // Collect the upload's stderr on its own thread so neither child can stall the other
+    let upload = std::thread::spawn(move || upload.wait_with_output());
+    let ffmpeg = watchdog::wait_with_output(ffmpeg);
+    let upload = upload
+        .join()
+        .map_err(|_| anyhow::anyhow!("Upload command thread panicked"))?
+        .context("Failed to wait for upload command")?;
+    let ffmpeg = ffmpeg?;
+    concat::finish_feed(feeder)?;
+
+    if !ffmpeg.status.success() {
+        let error_message = String::from_utf8_lossy(&ffmpeg.stderr);
+        return Err(
+            anyhow::anyhow!("Error executing ffmpeg command: {}", error_message)
+                .context(ExitKind::Ffmpeg),
+        );
+    }
+    check_upload_status(&upload, &upload_cmd)
+}
+
+/// ffmpeg muxer for containers that can be written to a pipe, by output extension.
+pub fn streamable_format(output_file: &str) -> Option<&'static str> {
+    let ext = Path::new(output_file).extension()?.to_str()?;
+    match ext.to_ascii_lowercase().as_str() {
+        "mkv" => Some("matroska"),
+        "webm" => Some("webm"),
+        "ts" | "mts" | "m2ts" => Some("mpegts"),
+        "flv" => Some("flv"),
+        _ => None,
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(command_line: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(command_line);
+    command
+}
+
+#[cfg(windows)]
+fn shell_command(command_line: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(command_line);
+    command
+}
+
+fn wait_for_upload(upload: Child, upload_cmd: &str) -> Result<()> {
+    let output = upload
+        .wait_with_output()
+        .context("Failed to wait for upload command")?;
+    check_upload_status(&output, upload_cmd)
+}
+
+fn check_upload_status(output: &std::process::Output, upload_cmd: &str) -> Result<()> {
+    if output.status.success() {
+        status!("Successfully uploaded with `{}`", upload_cmd);
+        Ok(())
+    } else {
+        let error_message = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!(
+            "Upload command `{}` failed ({}): {}",
+            upload_cmd,
+            output.status,
+            error_message
+        );
+    }
+}
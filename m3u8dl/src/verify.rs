@@ -0,0 +1,91 @@
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use crate::codecs::MediaInfo;
+use crate::logging::status;
+
+/// How far the output's duration may stray from the playlist's, as a
+/// fraction of it, or [`MIN_TOLERANCE`] seconds if that's more.
+const TOLERANCE: f64 = 0.05;
+const MIN_TOLERANCE: f64 = 2.0;
+
+/// Check with ffprobe that the muxed output at `path` has the streams the
+/// segments have (`source`, the first segment's streams, if known) and lasts
+/// about `expected_duration` seconds. ffmpeg can exit zero on an output it
+/// mangled, e.g. after thousands of "Non-monotonous DTS" warnings.
+///
+/// Without ffprobe on PATH this only warns, since the output can't be checked.
+pub fn check_output(path: &Path, source: Option<&MediaInfo>, expected_duration: f64) -> Result<()> {
+    let output = match Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_streams",
+            "-show_format",
+            "-of",
+            "json",
+        ])
+        .arg(path)
+        .output()
+    {
+        Ok(output) => output,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            status!(
+                "Warning: ffprobe was not found, so {} wasn't verified \
+                 (pass --no-verify-output to skip this check).",
+                path.display()
+            );
+            return Ok(());
+        }
+        Err(error) => return Err(error).context("Failed to execute ffprobe"),
+    };
+    anyhow::ensure!(
+        output.status.success(),
+        "ffprobe can't read {}: {}",
+        path.display(),
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    let info: MediaInfo = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Failed to parse ffprobe's report on {}", path.display()))?;
+
+    anyhow::ensure!(
+        !info.streams.is_empty(),
+        "{} has no streams",
+        path.display()
+    );
+    for kind in ["video", "audio"] {
+        if let Some(codec) = source.and_then(|source| source.codec(kind)) {
+            anyhow::ensure!(
+                info.codec(kind).is_some(),
+                "{} has no {} stream, though the segments have {} {}",
+                path.display(),
+                kind,
+                codec,
+                kind
+            );
+        }
+    }
+
+    // A playlist without #EXTINF durations gives nothing to compare against
+    if expected_duration > 0.0 {
+        let duration: f64 = info
+            .format
+            .as_ref()
+            .and_then(|format| format.duration.as_deref())
+            .and_then(|duration| duration.parse().ok())
+            .with_context(|| format!("ffprobe reports no duration for {}", path.display()))?;
+        let tolerance = (expected_duration * TOLERANCE).max(MIN_TOLERANCE);
+        anyhow::ensure!(
+            (duration - expected_duration).abs() <= tolerance,
+            "{} is {:.1}s long, but the playlist's segments add up to {:.1}s",
+            path.display(),
+            duration,
+            expected_duration
+        );
+    }
+    tracing::debug!("Verified {}: {}", path.display(), info);
+    Ok(())
+}
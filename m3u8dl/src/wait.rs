@@ -0,0 +1,143 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use indicatif::HumanDuration;
+use reqwest::StatusCode;
+
+use crate::logging::status;
+use crate::playlist::MediaPlaylist;
+use crate::progress::Phases;
+
+/// Parse a duration such as `30s`, `15m`, `2h` or `1h30m`; a bare number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "invalid duration '{}': expected a number of seconds or e.g. 30s, 15m, 2h, 1h30m",
+            value
+        )
+    };
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let mut total = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = number.parse().map_err(|_| invalid())?;
+        total += amount * unit;
+        number.clear();
+    }
+    if !number.is_empty() || value.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// Why a fetch of the playlist looks like a stream that hasn't started yet,
+/// or `None` if it's a playlist to download or a failure of another kind.
+fn not_live_yet(fetched: &Result<MediaPlaylist>) -> Option<String> {
+    match fetched {
+        Ok(playlist) if playlist.segments.is_empty() => Some("no segments yet".to_string()),
+        Ok(_) => None,
+        Err(error) => error
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+            .find_map(|error| error.status())
+            .filter(|&status| status == StatusCode::NOT_FOUND || status == StatusCode::FORBIDDEN)
+            .map(|status| format!("HTTP {}", status.as_u16())),
+    }
+}
+
+/// `--wait-for-stream`: fetch the playlist with `fetch` until it exists and
+/// lists at least one segment, checking every `interval` for up to `timeout`.
+///
+/// A playlist that fetches fine on the first try is returned straight away,
+/// as is any failure other than a 403, a 404 or an empty playlist. Ctrl-C
+/// while waiting returns an error, so that the run still cleans up after itself.
+/// The wait shows on the `phases` spinner.
+pub async fn until_live<F, Fut>(
+    url: &str,
+    timeout: Duration,
+    interval: Duration,
+    phases: &Phases,
+    mut fetch: F,
+) -> Result<MediaPlaylist>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<MediaPlaylist>>,
+{
+    let fetched = fetch().await;
+    let Some(mut reason) = not_live_yet(&fetched) else {
+        return fetched;
+    };
+
+    status!(
+        "{} is not live yet ({}), checking every {} for up to {}.",
+        url,
+        reason,
+        HumanDuration(interval),
+        HumanDuration(timeout)
+    );
+    let started = Instant::now();
+    let deadline = started + timeout;
+
+    let mut checks = 1;
+    let waited = loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break Err(anyhow::anyhow!(
+                "{} was still not live after waiting {} ({}, checked {} times)",
+                url,
+                HumanDuration(timeout),
+                reason,
+                checks
+            ));
+        }
+        phases.start("waiting for the stream to start");
+        phases.set_message(format!(
+            "Waiting for the stream to start: {} (checked {} times, {} left)",
+            reason,
+            checks,
+            HumanDuration(deadline - now)
+        ));
+
+        let next = (now + interval).min(deadline);
+        let fetched = tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                break Err(anyhow::anyhow!(
+                    "Interrupted while waiting for {} to go live",
+                    url
+                ));
+            }
+            fetched = async {
+                tokio::time::sleep_until(next.into()).await;
+                fetch().await
+            } => fetched,
+        };
+        checks += 1;
+        match not_live_yet(&fetched) {
+            Some(still) => reason = still,
+            None => break fetched,
+        }
+    };
+    if waited.is_ok() {
+        status!(
+            "The stream went live after {} of waiting.",
+            HumanDuration(started.elapsed())
+        );
+    }
+    waited
+}
@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Read;
+use std::process::{Child, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use indicatif::HumanDuration;
+
+use crate::exit::ExitKind;
+
+/// Least stall allowed before ffmpeg is stopped, whatever the input size.
+const BASE_TIMEOUT: Duration = Duration::from_secs(180);
+/// Extra stall allowed per GB of input, for the longer pauses ffmpeg takes
+/// over a large one, e.g. writing the moov atom at the end.
+const TIMEOUT_PER_GB: Duration = Duration::from_secs(60);
+/// How often the child is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Lines of ffmpeg's output kept for the error when it stalls.
+const TAIL_LINES: usize = 20;
+
+/// How long ffmpeg may go without progress; `None` until [`configure`]d.
+static STALL_TIMEOUT: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// `--remux-stall-timeout`: stop ffmpeg once it makes no progress for
+/// `timeout`, or for a default scaled to the `input_bytes` it reads.
+pub fn configure(timeout: Option<Duration>, input_bytes: u64) {
+    let timeout =
+        timeout.unwrap_or_else(|| BASE_TIMEOUT + TIMEOUT_PER_GB.mul_f64(input_bytes as f64 / 1e9));
+    tracing::debug!("Stopping ffmpeg after {:?} without progress", timeout);
+    *STALL_TIMEOUT.lock().unwrap() = Some(timeout);
+}
+
+/// ffmpeg stopped for making no progress for `after`.
+#[derive(Debug)]
+pub struct Stalled {
+    pub after: Duration,
+    /// The last lines ffmpeg printed.
+    pub tail: Vec<String>,
+}
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ffmpeg made no progress for {} and was stopped (--remux-stall-timeout)",
+            HumanDuration(self.after)
+        )?;
+        if !self.tail.is_empty() {
+            write!(f, "; its last output:\n{}", self.tail.join("\n"))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+/// When ffmpeg last showed progress, and the latest output time it reported.
+struct Progress {
+    at: Instant,
+    time: Option<f64>,
+    tail: VecDeque<String>,
+}
+
+/// Wait for ffmpeg like [`Child::wait_with_output`], but kill it once it
+/// goes the stall timeout without progress: without its `time=` advancing
+/// or anything else printed to stderr. The killed child is waited for, so
+/// it doesn't linger as a zombie; the error is a [`Stalled`].
+pub fn wait_with_output(mut child: Child) -> Result<Output> {
+    let timeout = STALL_TIMEOUT.lock().unwrap().unwrap_or(BASE_TIMEOUT);
+    let progress = Arc::new(Mutex::new(Progress {
+        at: Instant::now(),
+        time: None,
+        tail: VecDeque::new(),
+    }));
+    let stdout = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stdout.read_to_end(&mut output);
+            output
+        })
+    });
+    let stderr = child.stderr.take().map(|stderr| {
+        let progress = Arc::clone(&progress);
+        thread::spawn(move || watch_stderr(stderr, &progress))
+    });
+
+    let status = loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for ffmpeg")? {
+            break Some(status);
+        }
+        if progress.lock().unwrap().at.elapsed() >= timeout {
+            // SIGKILL on Unix, TerminateProcess on Windows; either way it is reaped
+            let _ = child.kill();
+            child
+                .wait()
+                .context("Failed to wait for the stopped ffmpeg")?;
+            break None;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let stdout = stdout
+        .and_then(|thread| thread.join().ok())
+        .unwrap_or_default();
+    let stderr = stderr
+        .and_then(|thread| thread.join().ok())
+        .unwrap_or_default();
+    match status {
+        Some(status) => Ok(Output {
+            status,
+            stdout,
+            stderr,
+        }),
+        None => {
+            let tail = progress.lock().unwrap().tail.drain(..).collect();
+            Err(anyhow::Error::new(Stalled {
+                after: timeout,
+                tail,
+            })
+            .context(ExitKind::Ffmpeg))
+        }
+    }
+}
+
+/// Collect ffmpeg's stderr, counting each new line as progress, except for
+/// stats lines (ended by `\r`) whose `time=` doesn't move on.
+fn watch_stderr(mut stderr: impl Read, progress: &Mutex<Progress>) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut line = Vec::new();
+    let mut buffer = [0; 4096];
+    while let Ok(read @ 1..) = stderr.read(&mut buffer) {
+        output.extend_from_slice(&buffer[..read]);
+        for &byte in &buffer[..read] {
+            if byte != b'\r' && byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+            let text = String::from_utf8_lossy(&line).trim().to_string();
+            line.clear();
+            if text.is_empty() {
+                continue;
+            }
+            let mut progress = progress.lock().unwrap();
+            let stats = text.contains("time=");
+            let advanced = match (stats, stats_time(&text)) {
+                (false, _) => true,
+                (true, Some(time)) => !progress.time.is_some_and(|last| time <= last),
+                (true, None) => false,
+            };
+            if advanced {
+                progress.at = Instant::now();
+                progress.time = stats_time(&text).or(progress.time);
+            }
+            // A run of stats lines shows as its latest
+            let last_stats = progress
+                .tail
+                .back()
+                .is_some_and(|last| last.contains("time="));
+            if stats && last_stats {
+                progress.tail.pop_back();
+            } else if progress.tail.len() == TAIL_LINES {
+                progress.tail.pop_front();
+            }
+            progress.tail.push_back(text);
+        }
+    }
+    output
+}
+
+/// The output time in seconds of an ffmpeg stats line, such as
+/// `frame= 1200 fps=300 ... time=00:00:48.00 bitrate=...`.
+fn stats_time(line: &str) -> Option<f64> {
+    let time = line.split_once("time=")?.1.split_whitespace().next()?;
+    let mut seconds = 0.0;
+    for part in time.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}